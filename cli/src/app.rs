@@ -8,6 +8,12 @@ pub fn get_app() -> App<'static, 'static> {
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .version(crate_version!())
         .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("json")
+                .help("Print the output as JSON")
+                .long("json")
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .about("Set a value")
@@ -56,6 +62,44 @@ pub fn get_app() -> App<'static, 'static> {
                 .subcommand(SubCommand::with_name("auto").about("Get auto-handle state"))
                 .subcommand(SubCommand::with_name("status").about("Get summary")),
         )
+        .subcommand(SubCommand::with_name("status").about("Get a summary of the service state"))
+        .subcommand(
+            SubCommand::with_name("set-speed")
+                .about("Set the target speed of a fan and stop handling it automatically")
+                .arg(
+                    Arg::with_name("index")
+                        .help("Index of the fan")
+                        .required(true)
+                        .value_name("INDEX"),
+                )
+                .arg(
+                    Arg::with_name("speed")
+                        .help("Speed of the fan by percentage, between 0 and 100")
+                        .required(true)
+                        .value_name("SPEED"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-auto")
+                .about("Enable or disable automatic speed management")
+                .arg(
+                    Arg::with_name("auto")
+                        .required(true)
+                        .possible_values(&["true", "false"])
+                        .value_name("AUTO"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("load").about("Load a config").arg(
+                Arg::with_name("config")
+                    .help("Name of the config")
+                    .required(true)
+                    .value_name("CONFIG"),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("list-configs").about("Get a list of the available configs"),
+        )
         .subcommand(
             SubCommand::with_name("list")
                 .about("Get a list of the available configs")
@@ -1,18 +1,15 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
-use clap::values_t;
+use clap::{value_t, values_t};
 use dbus::blocking::Connection;
-
-use std::fs::read_dir;
+use serde_json::json;
 
 mod app;
 mod interfaces;
 use app::get_app;
 use interfaces::ComMusikidFancy;
 
-static CONTROL_CONFIGS_PATH: &str = "/etc/fancy/configs";
-
 fn main() -> Result<(), anyhow::Error> {
     let conn = Connection::new_system()?;
     let proxy = conn.with_proxy(
@@ -22,8 +19,25 @@ fn main() -> Result<(), anyhow::Error> {
     );
 
     let matches = get_app().get_matches();
+    let json = matches.is_present("json");
+
+    if matches.subcommand_matches("status").is_some() {
+        print_status(&proxy, json)?;
+    } else if let Some(matches) = matches.subcommand_matches("set-speed") {
+        let index = value_t!(matches, "index", u8)?;
+        let speed = value_t!(matches, "speed", f64)?;
+        proxy.set_target_fan_speed(index, speed)?;
+    } else if let Some(matches) = matches.subcommand_matches("set-auto") {
+        proxy.set_auto(value_t!(matches, "auto", bool)?)?;
+    } else if let Some(matches) = matches.subcommand_matches("load") {
+        proxy.set_config(matches.value_of("config").unwrap().to_owned())?;
+    } else if matches.subcommand_matches("list-configs").is_some() {
+        print_configs(&proxy.available_configs()?, json);
+    } else if let Some(matches) = matches.subcommand_matches("get") {
+        if json && matches.is_present("status") {
+            return print_status(&proxy, json);
+        }
 
-    if let Some(matches) = matches.subcommand_matches("get") {
         if matches.is_present("speeds") || matches.is_present("status") {
             if matches.is_present("status") {
                 println!("Fans speeds");
@@ -63,16 +77,13 @@ fn main() -> Result<(), anyhow::Error> {
                 println!("\nTemperatures");
             }
             let temps = proxy.temperatures()?;
+            let unit = unit_symbol(&proxy.temperature_unit()?);
             for (sensor, temp) in temps {
-                println!("{}: {:.1}°C", sensor, temp);
+                println!("{}: {:.1}{}", sensor, temp, unit);
             }
         }
     } else if let Some(matches) = matches.subcommand_matches("list") {
-        let mut configs: Vec<String> = read_dir(CONTROL_CONFIGS_PATH)?
-            .filter_map(|e| e.ok())
-            .map(|e| e.path())
-            .filter_map(|e| e.file_stem().map(|e| e.to_string_lossy().into_owned()))
-            .collect();
+        let mut configs = proxy.available_configs()?;
 
         if matches.is_present("recommended") {
             //TODO: Optimize
@@ -103,13 +114,9 @@ fn main() -> Result<(), anyhow::Error> {
                     .length,
                 )
             });
-        } else {
-            configs.sort_unstable();
         }
 
-        for conf in configs {
-            println!("{}", conf);
-        }
+        print_configs(&configs, json);
     } else if let Some(matches) = matches.subcommand_matches("set") {
         if matches.is_present("target_fans_speeds") {
             let speeds = values_t!(matches, "target_fans_speeds", f64)?;
@@ -136,6 +143,91 @@ fn main() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Print a summary of the service state.
+fn print_status<P: ComMusikidFancy>(proxy: &P, json: bool) -> Result<(), anyhow::Error> {
+    let names = proxy.fans_names()?;
+    let speeds = proxy.fans_speeds()?;
+    let target_speeds = proxy.target_fans_speeds()?;
+    let fans_auto = proxy.fans_auto()?;
+    let config = proxy.config()?;
+    let auto = proxy.auto()?;
+    let critical = proxy.critical()?;
+    let temps = proxy.temperatures()?;
+    let unit = proxy.temperature_unit()?;
+
+    if json {
+        let fans: Vec<_> = (0..names.len())
+            .map(|i| {
+                json!({
+                    "name": names[i],
+                    "speed": speeds.get(i),
+                    "target_speed": target_speeds.get(i),
+                    "auto": fans_auto.get(i).copied().unwrap_or(auto),
+                })
+            })
+            .collect();
+        let status = json!({
+            "config": config,
+            "auto": auto,
+            "critical": critical,
+            "fans": fans,
+            "temperatures": temps,
+            "temperature_unit": unit,
+        });
+        println!("{}", status);
+        return Ok(());
+    }
+
+    println!("Config: {}", config);
+    println!("Auto: {}", auto);
+    println!("Critical: {}", critical);
+
+    println!("\nFans");
+    for (i, name) in names.iter().enumerate() {
+        let mode = if fans_auto.get(i).copied().unwrap_or(auto) {
+            "auto".to_owned()
+        } else {
+            format!(
+                "target {:.1}%",
+                target_speeds.get(i).copied().unwrap_or_default()
+            )
+        };
+        println!(
+            "{}: {:.1}% ({})",
+            name,
+            speeds.get(i).copied().unwrap_or_default(),
+            mode
+        );
+    }
+
+    println!("\nTemperatures");
+    let unit = unit_symbol(&unit);
+    for (sensor, temp) in temps {
+        println!("{}: {:.1}{}", sensor, temp, unit);
+    }
+
+    Ok(())
+}
+
+/// Get the symbol of the temperature unit reported by the service.
+fn unit_symbol(unit: &str) -> &'static str {
+    match unit {
+        "fahrenheit" => "°F",
+        _ => "°C",
+    }
+}
+
+/// Print the configs names, one per line.
+fn print_configs(configs: &[String], json: bool) {
+    if json {
+        println!("{}", json!(configs));
+    } else {
+        for conf in configs {
+            println!("{}", conf);
+        }
+    }
+}
+
 fn get_product_name() -> Result<String, std::io::Error> {
     std::fs::read_to_string("/sys/devices/virtual/dmi/id/product_name")
 }
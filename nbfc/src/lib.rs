@@ -9,6 +9,7 @@
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "PascalCase")]
 #[serde(from = "String")]
 pub enum RegisterWriteMode {
@@ -34,6 +35,7 @@ impl Default for RegisterWriteMode {
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "PascalCase")]
 #[serde(from = "String")]
 pub enum RegisterWriteOccasion {
@@ -51,6 +53,7 @@ impl From<String> for RegisterWriteOccasion {
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "PascalCase")]
 #[serde(from = "String")]
 pub enum OverrideTargetOperation {
@@ -70,6 +73,7 @@ impl From<String> for OverrideTargetOperation {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "PascalCase")]
 pub struct TemperatureThreshold {
     pub up_threshold: u8,
@@ -94,6 +98,7 @@ impl Ord for TemperatureThreshold {
 }
 
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "PascalCase")]
 pub struct FanSpeedPercentageOverride {
     pub fan_speed_percentage: f32,
@@ -102,6 +107,7 @@ pub struct FanSpeedPercentageOverride {
 }
 
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "PascalCase")]
 pub struct RegisterWriteConfiguration {
     #[serde(skip)] // Deprecated
@@ -174,6 +180,18 @@ pub struct XmlFanConfiguration {
     #[serde(default)]
     temperature_thresholds: TemperatureThresholds,
     fan_speed_percentage_overrides: Option<FanSpeedPercentageOverrides>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rpm_read_register: Option<u8>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    interpolate_overrides: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_register_high: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    write_register_high: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kickstart_speed: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kickstart_duration: Option<u64>,
 }
 
 impl From<FanConfiguration> for XmlFanConfiguration {
@@ -197,11 +215,18 @@ impl From<FanConfiguration> for XmlFanConfiguration {
                     fan_speed_percentage_overrides: Some(o),
                 }
             }),
+            rpm_read_register: f.rpm_read_register,
+            interpolate_overrides: f.interpolate_overrides,
+            read_register_high: f.read_register_high,
+            write_register_high: f.write_register_high,
+            kickstart_speed: f.kickstart_speed,
+            kickstart_duration: f.kickstart_duration,
         }
     }
 }
 
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "PascalCase")]
 pub struct FanConfiguration {
     pub read_register: u8,
@@ -216,6 +241,99 @@ pub struct FanConfiguration {
     pub fan_display_name: Option<String>,
     pub temperature_thresholds: Vec<TemperatureThreshold>,
     pub fan_speed_percentage_overrides: Option<Vec<FanSpeedPercentageOverride>>,
+    /// Register of the fan tachometer, holding the speed in RPM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpm_read_register: Option<u8>,
+    /// Interpolate linearly between the points of `fan_speed_percentage_overrides`,
+    /// instead of using them only for the exact values.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interpolate_overrides: bool,
+    /// Register of the high byte of the speed value, for the fans splitting it across two
+    /// registers. The value is then 16 bits, with its low byte at `read_register`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_register_high: Option<u8>,
+    /// Register of the high byte of the speed value written, with its low byte at
+    /// `write_register`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_register_high: Option<u8>,
+    /// Minimal speed percent written to the fan when it's started while stopped, during
+    /// `kickstart_duration`, for the fans which need more to start than to keep spinning.
+    /// The fan is not kickstarted if it's not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kickstart_speed: Option<f32>,
+    /// Duration (in ms) of the kickstart, 2 s if it's not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kickstart_duration: Option<u64>,
+}
+
+impl FanConfiguration {
+    /// Replace the temperature thresholds by the points (temperature in °C, speed percent) of a
+    /// curve. The speed can't decrease when the temperature increases.
+    ///
+    /// The temperatures are rounded, and each point is left below the temperature of the previous
+    /// one. Nothing is changed if the curve has any issue.
+    pub fn set_curve(
+        &mut self,
+        fan_index: usize,
+        points: &[(f64, f64)],
+    ) -> Result<(), Vec<ValidationIssue>> {
+        let mut points = points.to_vec();
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut issues = Vec::new();
+        if points.is_empty() {
+            issues.push(ValidationIssue::NoThreshold { fan_index });
+        }
+        for (i, &(temperature, speed)) in points.iter().enumerate() {
+            let up_threshold = temperature.round() as u8;
+            if !(0.0..=f64::from(u8::MAX)).contains(&temperature) {
+                issues.push(ValidationIssue::TemperatureOutOfRange {
+                    fan_index,
+                    temperature,
+                });
+            }
+            if !(0.0..=100.0).contains(&speed) {
+                issues.push(ValidationIssue::PercentOutOfRange {
+                    fan_index,
+                    percent: speed as f32,
+                });
+            }
+            if i > 0 {
+                let (previous_temperature, previous_speed) = points[i - 1];
+                if previous_temperature.round() == temperature.round() {
+                    issues.push(ValidationIssue::DuplicateThreshold {
+                        fan_index,
+                        up_threshold,
+                    });
+                }
+                if speed < previous_speed {
+                    issues.push(ValidationIssue::DecreasingSpeed {
+                        fan_index,
+                        up_threshold,
+                    });
+                }
+            }
+        }
+        if !issues.is_empty() {
+            return Err(issues);
+        }
+
+        let mut down_threshold = 0;
+        self.temperature_thresholds = points
+            .into_iter()
+            .map(|(temperature, speed)| {
+                let up_threshold = temperature.round() as u8;
+                let threshold = TemperatureThreshold {
+                    up_threshold,
+                    down_threshold,
+                    fan_speed: speed as f32,
+                };
+                down_threshold = up_threshold;
+                threshold
+            })
+            .collect();
+        Ok(())
+    }
 }
 
 impl From<XmlFanConfiguration> for FanConfiguration {
@@ -235,6 +353,12 @@ impl From<XmlFanConfiguration> for FanConfiguration {
             fan_speed_percentage_overrides: f
                 .fan_speed_percentage_overrides
                 .and_then(|o| o.fan_speed_percentage_overrides),
+            rpm_read_register: f.rpm_read_register,
+            interpolate_overrides: f.interpolate_overrides,
+            read_register_high: f.read_register_high,
+            write_register_high: f.write_register_high,
+            kickstart_speed: f.kickstart_speed,
+            kickstart_duration: f.kickstart_duration,
         }
     }
 }
@@ -261,6 +385,8 @@ pub struct XmlFanControlConfigV2 {
     #[serde(default = "default_poll_interval")]
     ec_poll_interval: u64,
     read_write_words: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    big_endian_words: bool,
     #[serde(default = "default_critic_temp")]
     critical_temperature: u8,
     fan_configurations: FanConfigurations,
@@ -280,6 +406,7 @@ impl From<FanControlConfigV2> for XmlFanControlConfigV2 {
             author: f.author,
             ec_poll_interval: f.ec_poll_interval,
             read_write_words: f.read_write_words,
+            big_endian_words: f.big_endian_words,
             critical_temperature: f.critical_temperature,
             fan_configurations: FanConfigurations {
                 fan_configurations: f
@@ -295,18 +422,253 @@ impl From<FanControlConfigV2> for XmlFanControlConfigV2 {
     }
 }
 
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct XmlFanConfigurationV1 {
+    read_register: u8,
+    write_register: u8,
+    min_speed_value: u16,
+    max_speed_value: u16,
+    #[serde(default)]
+    reset_required: bool,
+    reset_value: Option<u16>,
+    #[serde(default)]
+    temperature_thresholds: TemperatureThresholds,
+}
+
+// V1 configs read and write the fan speed with the same range, without any override.
+impl From<XmlFanConfigurationV1> for FanConfiguration {
+    fn from(f: XmlFanConfigurationV1) -> Self {
+        FanConfiguration {
+            read_register: f.read_register,
+            write_register: f.write_register,
+            min_speed_value: f.min_speed_value,
+            max_speed_value: f.max_speed_value,
+            independent_read_min_max_values: false,
+            min_speed_value_read: f.min_speed_value,
+            max_speed_value_read: f.max_speed_value,
+            reset_required: f.reset_required,
+            fan_speed_reset_value: f.reset_value,
+            fan_display_name: None,
+            temperature_thresholds: f.temperature_thresholds.temperature_thresholds,
+            fan_speed_percentage_overrides: None,
+            rpm_read_register: None,
+            interpolate_overrides: false,
+            read_register_high: None,
+            write_register_high: None,
+            kickstart_speed: None,
+            kickstart_duration: None,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FanConfigurationsV1 {
+    #[serde(rename = "FanConfiguration")]
+    fan_configurations: Vec<XmlFanConfigurationV1>,
+}
+
+/// Legacy NBFC config format, which root element is `FanControlConfig`.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct XmlFanControlConfigV1 {
+    notebook_model: String,
+    #[serde(default = "default_poll_interval")]
+    ec_poll_interval: u64,
+    #[serde(default)]
+    read_write_words: bool,
+    #[serde(default = "default_critic_temp")]
+    critical_temperature: u8,
+    fan_configurations: FanConfigurationsV1,
+    register_write_configurations: Option<RegisterWriteConfigurations>,
+}
+
+impl From<XmlFanControlConfigV1> for FanControlConfigV2 {
+    fn from(f: XmlFanControlConfigV1) -> Self {
+        FanControlConfigV2 {
+            notebook_model: f.notebook_model,
+            author: None,
+            ec_poll_interval: f.ec_poll_interval,
+            read_write_words: f.read_write_words,
+            big_endian_words: false,
+            critical_temperature: f.critical_temperature,
+            fan_configurations: f
+                .fan_configurations
+                .fan_configurations
+                .into_iter()
+                .map(FanConfiguration::from)
+                .collect(),
+            register_write_configurations: f
+                .register_write_configurations
+                .and_then(|r| r.register_write_configurations),
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "PascalCase")]
 pub struct FanControlConfigV2 {
     pub notebook_model: String,
     pub author: Option<String>,
     pub ec_poll_interval: u64,
     pub read_write_words: bool,
+    /// Read and write the words with their high byte first, instead of the low byte first.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub big_endian_words: bool,
     pub critical_temperature: u8,
     pub fan_configurations: Vec<FanConfiguration>,
     pub register_write_configurations: Option<Vec<RegisterWriteConfiguration>>,
 }
 
+impl FanControlConfigV2 {
+    /// Deserialize a configuration from the JSON format.
+    pub fn from_json(s: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(s)
+    }
+
+    /// Serialize the configuration to the JSON format.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Check that the configuration can be safely applied.
+    /// Returns all the issues found, the configuration is valid if there is none.
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+
+        if self.fan_configurations.is_empty() {
+            issues.push(ValidationIssue::NoFanConfiguration);
+        }
+
+        for (fan_index, f) in self.fan_configurations.iter().enumerate() {
+            // A word is read from the register and the next one.
+            if self.read_write_words {
+                for register in [f.read_register, f.write_register] {
+                    if register == u8::MAX {
+                        issues.push(ValidationIssue::RegisterOutOfRange { register });
+                    }
+                }
+            }
+
+            // The fan would have no speed to follow.
+            if f.temperature_thresholds.is_empty() {
+                issues.push(ValidationIssue::NoThreshold { fan_index });
+            }
+
+            for (i, t) in f.temperature_thresholds.iter().enumerate() {
+                if f.temperature_thresholds[..i]
+                    .iter()
+                    .any(|p| p.up_threshold == t.up_threshold)
+                {
+                    issues.push(ValidationIssue::DuplicateThreshold {
+                        fan_index,
+                        up_threshold: t.up_threshold,
+                    });
+                }
+
+                if t.up_threshold < t.down_threshold {
+                    issues.push(ValidationIssue::UpThresholdBelowDownThreshold {
+                        fan_index,
+                        up_threshold: t.up_threshold,
+                    });
+                }
+
+                if !(0.0..=100.0).contains(&t.fan_speed) {
+                    issues.push(ValidationIssue::PercentOutOfRange {
+                        fan_index,
+                        percent: t.fan_speed,
+                    });
+                }
+            }
+
+            for o in f.fan_speed_percentage_overrides.iter().flatten() {
+                if !(0.0..=100.0).contains(&o.fan_speed_percentage) {
+                    issues.push(ValidationIssue::PercentOutOfRange {
+                        fan_index,
+                        percent: o.fan_speed_percentage,
+                    });
+                }
+            }
+
+            if let Some(percent) = f.kickstart_speed.filter(|s| !(0.0..=100.0).contains(s)) {
+                issues.push(ValidationIssue::PercentOutOfRange { fan_index, percent });
+            }
+        }
+
+        issues
+    }
+}
+
+/// An issue found while validating a configuration with
+/// [`FanControlConfigV2::validate`](struct.FanControlConfigV2.html#method.validate).
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationIssue {
+    NoFanConfiguration,
+    NoThreshold { fan_index: usize },
+    DuplicateThreshold { fan_index: usize, up_threshold: u8 },
+    UpThresholdBelowDownThreshold { fan_index: usize, up_threshold: u8 },
+    PercentOutOfRange { fan_index: usize, percent: f32 },
+    RegisterOutOfRange { register: u8 },
+    TemperatureOutOfRange { fan_index: usize, temperature: f64 },
+    DecreasingSpeed { fan_index: usize, up_threshold: u8 },
+}
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationIssue::NoFanConfiguration => {
+                write!(f, "There should be at least one fan configuration")
+            }
+            ValidationIssue::NoThreshold { fan_index } => {
+                write!(f, "Fan #{} has no temperature threshold", fan_index)
+            }
+            ValidationIssue::DuplicateThreshold {
+                fan_index,
+                up_threshold,
+            } => write!(
+                f,
+                "Fan #{} has several thresholds with the up threshold {}",
+                fan_index, up_threshold
+            ),
+            ValidationIssue::UpThresholdBelowDownThreshold {
+                fan_index,
+                up_threshold,
+            } => write!(
+                f,
+                "Fan #{} has a down threshold higher than the up threshold {}",
+                fan_index, up_threshold
+            ),
+            ValidationIssue::PercentOutOfRange { fan_index, percent } => write!(
+                f,
+                "Fan #{} has a speed percentage outside 0-100: {}",
+                fan_index, percent
+            ),
+            ValidationIssue::RegisterOutOfRange { register } => write!(
+                f,
+                "A word can't be accessed at register {}, outside the EC range",
+                register
+            ),
+            ValidationIssue::TemperatureOutOfRange {
+                fan_index,
+                temperature,
+            } => write!(
+                f,
+                "Fan #{} has a temperature outside 0-255: {}",
+                fan_index, temperature
+            ),
+            ValidationIssue::DecreasingSpeed {
+                fan_index,
+                up_threshold,
+            } => write!(
+                f,
+                "Fan #{} has a lower speed at the up threshold {} than below",
+                fan_index, up_threshold
+            ),
+        }
+    }
+}
+
 impl From<XmlFanControlConfigV2> for FanControlConfigV2 {
     fn from(f: XmlFanControlConfigV2) -> Self {
         FanControlConfigV2 {
@@ -314,6 +676,7 @@ impl From<XmlFanControlConfigV2> for FanControlConfigV2 {
             author: f.author,
             ec_poll_interval: f.ec_poll_interval,
             read_write_words: f.read_write_words,
+            big_endian_words: f.big_endian_words,
             critical_temperature: f.critical_temperature,
             fan_configurations: f
                 .fan_configurations
@@ -537,12 +900,13 @@ mod tests {
   ]
 }
 "##;
-        let parsed_config: FanControlConfigV2 = serde_json::de::from_str(config).unwrap();
+        let parsed_config = FanControlConfigV2::from_json(config).unwrap();
         let excepted_config = FanControlConfigV2 {
             notebook_model: "HP Envy X360 13-ag0xxx Ryzen-APU".to_string(),
             author: Some("Daniel Andersen".to_string()),
             ec_poll_interval: 1000,
             read_write_words: true,
+            big_endian_words: false,
             critical_temperature: 90,
             fan_configurations: [FanConfiguration {
                 read_register: 149,
@@ -596,6 +960,12 @@ mod tests {
                     }]
                     .to_vec(),
                 ),
+                rpm_read_register: None,
+                interpolate_overrides: false,
+                read_register_high: None,
+                write_register_high: None,
+                kickstart_speed: None,
+                kickstart_duration: None,
             }]
             .to_vec(),
             register_write_configurations: Some(
@@ -615,6 +985,69 @@ mod tests {
         assert_eq!(parsed_config, excepted_config);
     }
 
+    #[test]
+    fn config_json_round_trip() {
+        let config = r##"
+{
+  "NotebookModel": "Dummy model",
+  "Author": null,
+  "EcPollInterval": 500,
+  "ReadWriteWords": false,
+  "CriticalTemperature": 80,
+  "FanConfigurations": [
+    {
+      "ReadRegister": 47,
+      "WriteRegister": 47,
+      "MinSpeedValue": 0,
+      "MaxSpeedValue": 255,
+      "IndependentReadMinMaxValues": false,
+      "MinSpeedValueRead": 0,
+      "MaxSpeedValueRead": 0,
+      "ResetRequired": true,
+      "FanSpeedResetValue": 128,
+      "FanDisplayName": null,
+      "TemperatureThresholds": [
+        {
+          "UpThreshold": 0,
+          "DownThreshold": 0,
+          "FanSpeed": 0.0
+        },
+        {
+          "UpThreshold": 60,
+          "DownThreshold": 50,
+          "FanSpeed": 100.0
+        }
+      ],
+      "FanSpeedPercentageOverrides": [
+        {
+          "FanSpeedPercentage": 0.0,
+          "FanSpeedValue": 255,
+          "TargetOperation": "Write"
+        },
+        {
+          "FanSpeedPercentage": 12.5,
+          "FanSpeedValue": 3,
+          "TargetOperation": null
+        }
+      ]
+    }
+  ],
+  "RegisterWriteConfigurations": null
+}
+"##;
+        let parsed_config = FanControlConfigV2::from_json(config).unwrap();
+        let serialized = parsed_config.to_json().unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(config).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&serialized).unwrap()
+        );
+        assert_eq!(
+            FanControlConfigV2::from_json(&serialized).unwrap(),
+            parsed_config
+        );
+    }
+
     #[test]
     fn config_xml_parse_all_fields() {
         let config = r##"
@@ -699,6 +1132,7 @@ mod tests {
             author: Some("Daniel Andersen".to_string()),
             ec_poll_interval: 1000,
             read_write_words: true,
+            big_endian_words: false,
             critical_temperature: 90,
             fan_configurations: [FanConfiguration {
                 read_register: 149,
@@ -752,6 +1186,12 @@ mod tests {
                     }]
                     .to_vec(),
                 ),
+                rpm_read_register: None,
+                interpolate_overrides: false,
+                read_register_high: None,
+                write_register_high: None,
+                kickstart_speed: None,
+                kickstart_duration: None,
             }]
             .to_vec(),
             register_write_configurations: Some(
@@ -816,6 +1256,7 @@ mod tests {
             author: None,
             ec_poll_interval: 3000,
             read_write_words: false,
+            big_endian_words: false,
             critical_temperature: 70,
             fan_configurations: [FanConfiguration {
                 read_register: 85,
@@ -849,6 +1290,12 @@ mod tests {
                     }]
                     .to_vec(),
                 ),
+                rpm_read_register: None,
+                interpolate_overrides: false,
+                read_register_high: None,
+                write_register_high: None,
+                kickstart_speed: None,
+                kickstart_duration: None,
             }]
             .to_vec(),
             register_write_configurations: None,
@@ -856,6 +1303,294 @@ mod tests {
         assert_eq!(excepted_config, parsed_config);
     }
 
+    #[test]
+    fn config_v1_parse() {
+        let config = r##"<?xml version="1.0"?>
+<FanControlConfig xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xmlns:xsd="http://www.w3.org/2001/XMLSchema">
+  <NotebookModel>Acer Aspire 5738G</NotebookModel>
+  <EcPollInterval>3000</EcPollInterval>
+  <ReadWriteWords>false</ReadWriteWords>
+  <CriticalTemperature>75</CriticalTemperature>
+  <FanConfigurations>
+    <FanConfiguration>
+      <ReadRegister>98</ReadRegister>
+      <WriteRegister>98</WriteRegister>
+      <MinSpeedValue>255</MinSpeedValue>
+      <MaxSpeedValue>0</MaxSpeedValue>
+      <ResetRequired>true</ResetRequired>
+      <ResetValue>255</ResetValue>
+      <TemperatureThresholds>
+        <TemperatureThreshold>
+          <UpThreshold>0</UpThreshold>
+          <DownThreshold>0</DownThreshold>
+          <FanSpeed>0</FanSpeed>
+        </TemperatureThreshold>
+        <TemperatureThreshold>
+          <UpThreshold>65</UpThreshold>
+          <DownThreshold>55</DownThreshold>
+          <FanSpeed>100</FanSpeed>
+        </TemperatureThreshold>
+      </TemperatureThresholds>
+    </FanConfiguration>
+  </FanConfigurations>
+  <RegisterWriteConfigurations>
+    <RegisterWriteConfiguration>
+      <WriteMode>Set</WriteMode>
+      <WriteOccasion>OnInitialization</WriteOccasion>
+      <Register>147</Register>
+      <Value>20</Value>
+      <ResetRequired>true</ResetRequired>
+      <ResetValue>4</ResetValue>
+      <ResetWriteMode>Set</ResetWriteMode>
+    </RegisterWriteConfiguration>
+  </RegisterWriteConfigurations>
+</FanControlConfig>"##;
+        let parsed_config = from_str::<XmlFanControlConfigV1>(config).unwrap();
+        let parsed_config = FanControlConfigV2::from(parsed_config);
+        let excepted_config = FanControlConfigV2 {
+            notebook_model: "Acer Aspire 5738G".to_string(),
+            author: None,
+            ec_poll_interval: 3000,
+            read_write_words: false,
+            big_endian_words: false,
+            critical_temperature: 75,
+            fan_configurations: [FanConfiguration {
+                read_register: 98,
+                write_register: 98,
+                min_speed_value: 255,
+                max_speed_value: 0,
+                independent_read_min_max_values: false,
+                min_speed_value_read: 255,
+                max_speed_value_read: 0,
+                reset_required: true,
+                fan_speed_reset_value: Some(255),
+                fan_display_name: None,
+                temperature_thresholds: [
+                    TemperatureThreshold {
+                        up_threshold: 0,
+                        down_threshold: 0,
+                        fan_speed: 0.0,
+                    },
+                    TemperatureThreshold {
+                        up_threshold: 65,
+                        down_threshold: 55,
+                        fan_speed: 100.0,
+                    },
+                ]
+                .to_vec(),
+                fan_speed_percentage_overrides: None,
+                rpm_read_register: None,
+                interpolate_overrides: false,
+                read_register_high: None,
+                write_register_high: None,
+                kickstart_speed: None,
+                kickstart_duration: None,
+            }]
+            .to_vec(),
+            register_write_configurations: Some(
+                [RegisterWriteConfiguration {
+                    write_mode: RegisterWriteMode::Set,
+                    write_occasion: Some(RegisterWriteOccasion::OnInitialization),
+                    register: 147,
+                    value: 20,
+                    reset_required: true,
+                    reset_value: Some(4),
+                    reset_write_mode: None,
+                    description: None,
+                }]
+                .to_vec(),
+            ),
+        };
+        assert_eq!(excepted_config, parsed_config);
+        assert!(check_control_config(&parsed_config).is_ok());
+    }
+
+    #[test]
+    fn config_v1_parse_minimal() {
+        let config = r##"<?xml version="1.0"?>
+<FanControlConfig xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xmlns:xsd="http://www.w3.org/2001/XMLSchema">
+  <NotebookModel>Sony Vaio VPCEB</NotebookModel>
+  <FanConfigurations>
+    <FanConfiguration>
+      <ReadRegister>147</ReadRegister>
+      <WriteRegister>148</WriteRegister>
+      <MinSpeedValue>0</MinSpeedValue>
+      <MaxSpeedValue>100</MaxSpeedValue>
+    </FanConfiguration>
+  </FanConfigurations>
+</FanControlConfig>"##;
+        let parsed_config =
+            FanControlConfigV2::from(from_str::<XmlFanControlConfigV1>(config).unwrap());
+
+        assert_eq!(parsed_config.ec_poll_interval, default_poll_interval());
+        assert_eq!(parsed_config.critical_temperature, default_critic_temp());
+        assert_eq!(parsed_config.register_write_configurations, None);
+
+        let fan = &parsed_config.fan_configurations[0];
+        assert_eq!(fan.fan_speed_reset_value, None);
+        assert_eq!(fan.fan_speed_percentage_overrides, None);
+        assert_eq!(fan.temperature_thresholds, default_temperature_thresholds());
+        assert_eq!(
+            (fan.min_speed_value_read, fan.max_speed_value_read),
+            (0, 100)
+        );
+    }
+
+    #[test]
+    fn validate_config() {
+        let threshold = |up_threshold, down_threshold, fan_speed| TemperatureThreshold {
+            up_threshold,
+            down_threshold,
+            fan_speed,
+        };
+        let fan = FanConfiguration {
+            read_register: 255,
+            write_register: 254,
+            min_speed_value: 0,
+            max_speed_value: 255,
+            independent_read_min_max_values: false,
+            min_speed_value_read: 0,
+            max_speed_value_read: 0,
+            reset_required: false,
+            fan_speed_reset_value: None,
+            fan_display_name: None,
+            temperature_thresholds: vec![
+                threshold(0, 0, 0.0),
+                threshold(60, 50, 50.0),
+                threshold(60, 55, 120.0),
+                threshold(70, 80, 100.0),
+            ],
+            fan_speed_percentage_overrides: Some(vec![FanSpeedPercentageOverride {
+                fan_speed_percentage: -1.0,
+                fan_speed_value: 0,
+                target_operation: None,
+            }]),
+            rpm_read_register: None,
+            interpolate_overrides: false,
+            read_register_high: None,
+            write_register_high: None,
+            kickstart_speed: Some(150.0),
+            kickstart_duration: None,
+        };
+        let mut config = FanControlConfigV2 {
+            read_write_words: true,
+            big_endian_words: false,
+            fan_configurations: vec![fan],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            config.validate(),
+            vec![
+                ValidationIssue::RegisterOutOfRange { register: 255 },
+                ValidationIssue::DuplicateThreshold {
+                    fan_index: 0,
+                    up_threshold: 60
+                },
+                ValidationIssue::PercentOutOfRange {
+                    fan_index: 0,
+                    percent: 120.0
+                },
+                ValidationIssue::UpThresholdBelowDownThreshold {
+                    fan_index: 0,
+                    up_threshold: 70
+                },
+                ValidationIssue::PercentOutOfRange {
+                    fan_index: 0,
+                    percent: -1.0
+                },
+                ValidationIssue::PercentOutOfRange {
+                    fan_index: 0,
+                    percent: 150.0
+                },
+            ]
+        );
+
+        config.fan_configurations[0].temperature_thresholds.clear();
+        config.fan_configurations[0].fan_speed_percentage_overrides = None;
+        config.fan_configurations[0].kickstart_speed = None;
+        assert_eq!(
+            config.validate(),
+            vec![
+                ValidationIssue::RegisterOutOfRange { register: 255 },
+                ValidationIssue::NoThreshold { fan_index: 0 },
+            ]
+        );
+
+        config.fan_configurations.clear();
+        assert_eq!(config.validate(), vec![ValidationIssue::NoFanConfiguration]);
+    }
+
+    #[test]
+    fn set_fan_curve() {
+        let mut fan = FanConfiguration {
+            read_register: 0,
+            write_register: 0,
+            min_speed_value: 0,
+            max_speed_value: 255,
+            independent_read_min_max_values: false,
+            min_speed_value_read: 0,
+            max_speed_value_read: 0,
+            reset_required: false,
+            fan_speed_reset_value: None,
+            fan_display_name: None,
+            temperature_thresholds: Vec::new(),
+            fan_speed_percentage_overrides: None,
+            rpm_read_register: None,
+            interpolate_overrides: false,
+            read_register_high: None,
+            write_register_high: None,
+            kickstart_speed: None,
+            kickstart_duration: None,
+        };
+
+        fan.set_curve(1, &[(60.2, 50.0), (40.0, 0.0), (80.0, 100.0)])
+            .unwrap();
+        let thresholds: Vec<_> = fan
+            .temperature_thresholds
+            .iter()
+            .map(|t| (t.up_threshold, t.down_threshold, t.fan_speed))
+            .collect();
+        assert_eq!(
+            thresholds,
+            vec![(40, 0, 0.0), (60, 40, 50.0), (80, 60, 100.0)]
+        );
+
+        let issues = fan
+            .set_curve(
+                1,
+                &[(40.0, 60.0), (40.4, 70.0), (60.0, 50.0), (300.0, 110.0)],
+            )
+            .unwrap_err();
+        assert_eq!(
+            issues,
+            vec![
+                ValidationIssue::DuplicateThreshold {
+                    fan_index: 1,
+                    up_threshold: 40
+                },
+                ValidationIssue::DecreasingSpeed {
+                    fan_index: 1,
+                    up_threshold: 60
+                },
+                ValidationIssue::TemperatureOutOfRange {
+                    fan_index: 1,
+                    temperature: 300.0
+                },
+                ValidationIssue::PercentOutOfRange {
+                    fan_index: 1,
+                    percent: 110.0
+                },
+            ]
+        );
+        assert_eq!(
+            fan.set_curve(1, &[]),
+            Err(vec![ValidationIssue::NoThreshold { fan_index: 1 }])
+        );
+        // The curve is not changed by the invalid ones.
+        assert_eq!(fan.temperature_thresholds.len(), 3);
+    }
+
     #[test]
     fn all_configs() {
         std::fs::read_dir("nbfc_configs/Configs")
@@ -867,6 +1602,31 @@ mod tests {
             });
     }
 
+    #[test]
+    fn validate_all_configs() {
+        let invalid: Vec<String> = std::fs::read_dir("nbfc_configs/Configs")
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let config = std::fs::read_to_string(e.path()).unwrap();
+                let config =
+                    FanControlConfigV2::from(from_str::<XmlFanControlConfigV2>(&config).unwrap());
+                let issues = config.validate();
+                if issues.is_empty() {
+                    return None;
+                }
+                let issues: Vec<String> = issues.iter().map(|i| i.to_string()).collect();
+                Some(format!("{}: {}", e.path().display(), issues.join("; ")))
+            })
+            .collect();
+
+        assert!(
+            invalid.is_empty(),
+            "Invalid configs:\n{}",
+            invalid.join("\n")
+        );
+    }
+
     const SETTINGS: &str = r##"<?xml version="1.0"?>
 <NbfcServiceSettings xmlns:xsd="http://www.w3.org/2001/XMLSchema" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
   <SettingsVersion>0</SettingsVersion>
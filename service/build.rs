@@ -2,6 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use std::io::{Read, Write};
+use std::path::Path;
+use std::process::Command;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=interfaces/fancy.xml");
@@ -20,5 +22,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut file = std::fs::File::create("src/bus/interfaces.rs")?;
     file.write_all(interface_code.as_bytes())?;
 
+    // The commit is unknown when building from a release archive.
+    if Path::new("../.git/HEAD").is_file() {
+        println!("cargo:rerun-if-changed=../.git/HEAD");
+    }
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|h| h.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=FANCY_GIT_HASH={}", git_hash);
+
     Ok(())
 }
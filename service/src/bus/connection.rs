@@ -5,12 +5,23 @@ use dbus::blocking::LocalConnection;
 use dbus_tree::{DataType, Factory, MethodErr};
 
 use super::interfaces::*;
-use crate::constants::{BUS_NAME_STR, OBJ_PATH_STR};
+use crate::config::bundle::ConfigBundle;
+use crate::config::nbfc_control::join_issues;
+use crate::config::service::{
+    ConfigSource, CurveInterpolation, FanSpeedLimit, Profile, TemperatureUnit,
+};
+use crate::constants::{BUS_NAME_STR, CONTROL_CONFIGS_DIR_PATH, GIT_HASH, OBJ_PATH_STR, VERSION};
+use crate::ec_control::{interpolate_curve, step_curve};
+use crate::nbfc::FanControlConfigV2;
+use crate::state::{MAX_BOOST_DURATION, MAX_TEMPERATURE_OFFSET};
+use crate::temp::read_hwmon_temps;
 use crate::State;
 
 use std::borrow::Borrow;
+use std::cell::Ref;
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 #[derive(Copy, Clone, Default, Debug)]
 struct TData;
@@ -26,6 +37,47 @@ impl DataType for TData {
 
 type IFaceResult<T> = Result<T, MethodErr>;
 
+/// Get the last snapshot of the EC registers, if their inspection is enabled.
+fn ec_registers(state: &State) -> IFaceResult<Ref<Vec<u8>>> {
+    if !*state.debug_ec.borrow() {
+        return Err(MethodErr::failed(
+            "The EC registers can only be read when fancyd is started with `--debug-ec`",
+        ));
+    }
+    Ok(state.ec_registers.borrow())
+}
+
+/// Whether the control config `name` is the active one, or the saved one while another is tried.
+fn is_config_in_use(state: &State, name: &str) -> bool {
+    let persisted = state
+        .persisted_config
+        .borrow()
+        .as_ref()
+        .map_or(false, |(config, _)| config == name);
+    *state.config.borrow() == name || persisted
+}
+
+/// Get the control config being applied, with the curves edited over D-Bus.
+fn current_control_config(state: &State) -> IFaceResult<FanControlConfigV2> {
+    if let Some(config) = state.edited_config.borrow().as_ref() {
+        return Ok(config.clone());
+    }
+    state
+        .config_loader
+        .borrow()
+        .load_control_config(&*state.config.borrow())
+        .map_err(|e| MethodErr::failed(&e.to_string()))
+}
+
+/// Convert the temperatures to the unit reported over D-Bus.
+fn reported_temperatures(state: &State, temps: &HashMap<String, f64>) -> HashMap<String, f64> {
+    let unit = state.core.borrow().temperature_unit;
+    temps
+        .iter()
+        .map(|(sensor, &temp)| (sensor.to_owned(), unit.convert(temp)))
+        .collect()
+}
+
 impl ComMusikidFancy for State {
     fn fans_speeds(&self) -> Result<Vec<f64>, MethodErr> {
         Ok(self.fans_speeds.borrow().to_owned())
@@ -37,15 +89,18 @@ impl ComMusikidFancy for State {
         let mut target_fans_speeds = self.target_fans_speeds.borrow_mut();
         let len = self.fans_speeds.borrow().len();
         if value.len() != len {
-            return Err(MethodErr::invalid_arg(
-                "The number of values is not equal to the number of fans.",
-            ));
+            return Err(MethodErr::invalid_arg(&format!(
+                "Expected {} values (one for each fan), got {}",
+                len,
+                value.len()
+            )));
         }
-        if value.iter().any(|&v| v > 100. || v < 0.) {
+        if value.iter().any(|v| !(0f64..=100f64).contains(v)) {
             return Err(MethodErr::invalid_arg("One of the values is out of bounds"));
         }
 
         *target_fans_speeds = value;
+        self.refresh_manual_timer(Instant::now());
         Ok(())
     }
     fn set_target_fan_speed(&self, index: u8, speed: f64) -> Result<(), MethodErr> {
@@ -60,7 +115,76 @@ impl ComMusikidFancy for State {
             return Err(MethodErr::invalid_arg("The speed is out of bounds"));
         }
         target_fans_speeds[index as usize] = speed;
-        *self.manual_set_target_speeds.borrow_mut() = true;
+
+        // Only this fan is now following its target speed.
+        if let Some(fan_auto) = self.fans_auto.borrow_mut().get_mut(index as usize) {
+            *fan_auto = false;
+        }
+        *self.auto.borrow_mut() = false;
+        self.refresh_manual_timer(Instant::now());
+
+        self.changed_properties
+            .borrow_mut()
+            .extend(["TargetFansSpeeds", "FansAuto", "Auto"]);
+        Ok(())
+    }
+    fn set_all_fans_speed(&self, speed: f64) -> Result<(), MethodErr> {
+        if !(0f64..=100f64).contains(&speed) {
+            return Err(MethodErr::invalid_arg("The speed is out of bounds"));
+        }
+        let fans_count = self.target_fans_speeds.borrow().len();
+        self.target_fans_speeds.replace(vec![speed; fans_count]);
+        self.fans_auto.replace(vec![false; fans_count]);
+        *self.auto.borrow_mut() = false;
+        self.refresh_manual_timer(Instant::now());
+
+        self.changed_properties
+            .borrow_mut()
+            .extend(["TargetFansSpeeds", "FansAuto", "Auto"]);
+        Ok(())
+    }
+    fn boost(&self, duration: u64) -> Result<(), MethodErr> {
+        if duration == 0 || duration > MAX_BOOST_DURATION {
+            return Err(MethodErr::invalid_arg(&format!(
+                "The duration must be between 1 and {} s",
+                MAX_BOOST_DURATION
+            )));
+        }
+        // The calibration needs to control the speed of the fan.
+        if *self.calibrating.borrow() {
+            return Err(MethodErr::failed(
+                "The fans can't be boosted during a calibration",
+            ));
+        }
+        // A boost during another one restarts it with the new duration.
+        self.boost_until
+            .replace(Some(Instant::now() + Duration::from_secs(duration)));
+        self.boost_started.replace(Some(duration));
+        Ok(())
+    }
+    fn calibrate_fan(&self, index: u8, register: u8) -> Result<(), MethodErr> {
+        if index as usize >= self.fans_names.borrow().len() {
+            return Err(MethodErr::invalid_arg(&format!(
+                "{} is not a valid index.",
+                index
+            )));
+        }
+        if *self.critical.borrow() {
+            return Err(MethodErr::failed(
+                "The fans can't be calibrated while the temperature is critical",
+            ));
+        }
+        if matches!(*self.boost_until.borrow(), Some(until) if until > Instant::now()) {
+            return Err(MethodErr::failed(
+                "The fans can't be calibrated during a boost",
+            ));
+        }
+        if self.calibrating.replace(true) {
+            return Err(MethodErr::failed("A calibration is already running"));
+        }
+
+        self.pending_calibration
+            .replace(Some((index as usize, register)));
         Ok(())
     }
     fn config(&self) -> Result<String, MethodErr> {
@@ -72,27 +196,554 @@ impl ComMusikidFancy for State {
             Ok(_) => {
                 let old_config = Some(self.config.replace(value));
                 self.old_config.replace(old_config);
+                self.config_source.replace(ConfigSource::Manual);
+                // The config is not tried anymore, and the edited curves are discarded.
+                self.persisted_config.take();
+                self.edited_config.take();
                 Ok(())
             }
             Err(e) => Err(MethodErr::failed(&e.to_string())),
         }
     }
+    fn try_config(&self, name: &str) -> Result<(), MethodErr> {
+        self.config_loader
+            .borrow()
+            .test_control_config(name, *self.check_control_config.borrow())
+            .map_err(|e| MethodErr::failed(&e.to_string()))?;
+
+        // The saved config is kept until the tried one is committed.
+        if self.persisted_config.borrow().is_none() {
+            let persisted = (
+                self.config.borrow().to_owned(),
+                *self.config_source.borrow(),
+            );
+            self.persisted_config.replace(Some(persisted));
+        }
+        self.config.replace(name.to_owned());
+        self.config_source.replace(ConfigSource::Manual);
+        self.edited_config.take();
+        // The config is swapped by the main loop instead of the D-Bus callback.
+        self.reload_control_config.replace(true);
+        self.changed_properties.borrow_mut().insert("Config");
+        Ok(())
+    }
+    fn commit_config(&self) -> Result<(), MethodErr> {
+        if self.persisted_config.take().is_none() {
+            return Err(MethodErr::failed("No control config is being tried"));
+        }
+        // The service config is saved along with the signal.
+        self.changed_properties.borrow_mut().insert("Config");
+        Ok(())
+    }
+    fn config_source(&self) -> Result<String, MethodErr> {
+        Ok(self.config_source.borrow().name().to_owned())
+    }
+    fn config_info(&self) -> Result<HashMap<String, String>, MethodErr> {
+        Ok(self.config_info.borrow().to_owned())
+    }
+    fn available_configs(&self) -> Result<Vec<String>, MethodErr> {
+        self.config_loader
+            .borrow()
+            .available_configs()
+            .map_err(|e| MethodErr::failed(&e.to_string()))
+    }
+    fn rename_config(&self, old: &str, new: &str) -> Result<(), MethodErr> {
+        if is_config_in_use(self, old) {
+            return Err(MethodErr::failed(
+                "The active control config can't be renamed",
+            ));
+        }
+        self.config_loader
+            .borrow()
+            .rename_config(old, new)
+            .map_err(|e| MethodErr::failed(&e.to_string()))?;
+
+        self.configs_changed.replace(true);
+        Ok(())
+    }
+    fn delete_config(&self, name: &str) -> Result<(), MethodErr> {
+        if is_config_in_use(self, name) {
+            return Err(MethodErr::failed(
+                "The active control config can't be deleted",
+            ));
+        }
+        self.config_loader
+            .borrow()
+            .delete_config(name)
+            .map_err(|e| MethodErr::failed(&e.to_string()))?;
+
+        self.configs_changed.replace(true);
+        Ok(())
+    }
+    fn detect_config(&self) -> Result<String, MethodErr> {
+        self.config_loader
+            .borrow()
+            .detect_config()
+            .map(Option::unwrap_or_default)
+            .map_err(|e| MethodErr::failed(&e.to_string()))
+    }
+    fn read_register(&self, address: u8) -> Result<u8, MethodErr> {
+        ec_registers(self)?
+            .get(address as usize)
+            .copied()
+            .ok_or_else(|| MethodErr::failed("The EC registers have not been read yet"))
+    }
+    fn dump_registers(&self, start: u8, len: u8) -> Result<Vec<u8>, MethodErr> {
+        Ok(ec_registers(self)?
+            .iter()
+            .skip(start as usize)
+            .take(len as usize)
+            .copied()
+            .collect())
+    }
+    fn computed_fans_speeds(&self) -> Result<Vec<f64>, MethodErr> {
+        Ok(self.computed_fans_speeds.borrow().to_owned())
+    }
+    fn ec_info(&self) -> Result<HashMap<String, String>, MethodErr> {
+        let status = |ok: Option<bool>| match ok {
+            Some(true) => "ok",
+            Some(false) => "failing",
+            None => "unknown",
+        };
+
+        let mut info = HashMap::new();
+        info.insert(
+            "access_mode".to_owned(),
+            format!("{:?}", *self.ec_access_mode.borrow()),
+        );
+        info.insert("device".to_owned(), self.ec_dev_path.borrow().to_owned());
+        info.insert("dry_run".to_owned(), self.dry_run.borrow().to_string());
+        info.insert("failsafe".to_owned(), self.ec_failsafe.borrow().to_string());
+        info.insert(
+            "reads".to_owned(),
+            status(*self.ec_reads_ok.borrow()).to_owned(),
+        );
+        info.insert(
+            "writes".to_owned(),
+            status(*self.ec_writes_ok.borrow()).to_owned(),
+        );
+        if let Some(e) = &*self.last_ec_error.borrow() {
+            info.insert("last_error".to_owned(), e.to_owned());
+        }
+        Ok(info)
+    }
+    fn health(&self) -> Result<HashMap<String, String>, MethodErr> {
+        let mut health = HashMap::new();
+        health.insert(
+            "ec_reachable".to_owned(),
+            (*self.ec_reads_ok.borrow() == Some(true)).to_string(),
+        );
+        if let Some(read) = *self.last_ec_read.borrow() {
+            let timestamp = read.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            health.insert("last_read".to_owned(), timestamp.to_string());
+        }
+        health.insert(
+            "iterations".to_owned(),
+            self.loop_iterations.borrow().to_string(),
+        );
+        health.insert("warnings".to_owned(), self.warnings()?.len().to_string());
+        health.insert(
+            "missing_sensors".to_owned(),
+            self.missing_sensors.borrow().len().to_string(),
+        );
+        health.insert("failsafe".to_owned(), self.ec_failsafe.borrow().to_string());
+        health.insert("critical".to_owned(), self.critical.borrow().to_string());
+        Ok(health)
+    }
+    fn fan_health(&self) -> Result<Vec<bool>, MethodErr> {
+        Ok(self.fans_health.borrow().to_owned())
+    }
+    fn fans_rpm(&self) -> Result<Vec<f64>, MethodErr> {
+        Ok(self.fans_rpm.borrow().to_owned())
+    }
+    fn fans_raw_values(&self) -> Result<Vec<u16>, MethodErr> {
+        Ok(self.fans_raw_values.borrow().to_owned())
+    }
+    fn active_sensor_per_fan(&self) -> Result<Vec<String>, MethodErr> {
+        Ok(self.active_sensors.borrow().to_owned())
+    }
+    fn dry_run(&self) -> Result<bool, MethodErr> {
+        Ok(*self.dry_run.borrow())
+    }
+    fn fan_curves(&self) -> Result<Vec<Vec<(f64, f64)>>, MethodErr> {
+        let unit = self.core.borrow().temperature_unit;
+        Ok(self
+            .fans_curves
+            .borrow()
+            .iter()
+            .map(|curve| {
+                curve
+                    .iter()
+                    .map(|&(temp, speed)| (unit.convert(temp), speed))
+                    .collect()
+            })
+            .collect())
+    }
+    fn evaluate_curve(&self, temperature: f64) -> Result<Vec<f64>, MethodErr> {
+        // The speeds are computed from the curves only, without any access to the EC.
+        let core = self.core.borrow();
+        let temp = core.temperature_unit.to_celsius(temperature);
+        let evaluate = match core.curve_interpolation {
+            CurveInterpolation::Step => step_curve,
+            CurveInterpolation::Linear => interpolate_curve,
+        };
+        Ok(self
+            .fans_curves
+            .borrow()
+            .iter()
+            .map(|curve| evaluate(curve, temp))
+            .collect())
+    }
+    fn set_fan_curve(&self, index: u8, points: Vec<(f64, f64)>) -> Result<(), MethodErr> {
+        let unit = self.core.borrow().temperature_unit;
+        let points: Vec<_> = points
+            .into_iter()
+            .map(|(temp, speed)| (unit.to_celsius(temp), speed))
+            .collect();
+
+        let mut config = current_control_config(self)?;
+        config
+            .fan_configurations
+            .get_mut(index as usize)
+            .ok_or_else(|| MethodErr::invalid_arg(&format!("{} is not a valid index.", index)))?
+            .set_curve(index as usize, &points)
+            .map_err(|issues| MethodErr::invalid_arg(&join_issues(&issues)))?;
+
+        self.edited_config.replace(Some(config));
+        // The curves are applied by the main loop.
+        self.reload_control_config.replace(true);
+        Ok(())
+    }
+    fn save_current_config(&self, name: &str) -> Result<(), MethodErr> {
+        // The saved config is restored if the tried one is not committed.
+        let persisted = self
+            .persisted_config
+            .borrow()
+            .as_ref()
+            .map_or(false, |(config, _)| config == name);
+        if persisted {
+            return Err(MethodErr::failed(
+                "The saved control config can't be replaced while another one is tried",
+            ));
+        }
+        let config = current_control_config(self)?;
+        self.config_loader
+            .borrow()
+            .save_config(name, &config, &CONTROL_CONFIGS_DIR_PATH)
+            .map_err(|e| MethodErr::failed(&e.to_string()))?;
+
+        // The saved config is the one applied now.
+        self.edited_config.take();
+        self.persisted_config.take();
+        self.config.replace(name.to_owned());
+        self.config_source.replace(ConfigSource::Manual);
+        self.changed_properties.borrow_mut().insert("Config");
+        self.configs_changed.replace(true);
+        Ok(())
+    }
     fn critical(&self) -> Result<bool, MethodErr> {
         Ok(*self.critical.borrow())
     }
+    fn critical_temperature(&self) -> Result<u8, MethodErr> {
+        let unit = self.core.borrow().temperature_unit;
+        let temp = unit.convert(State::critical_temperature(self).into());
+        Ok(temp.round().clamp(0.0, u8::MAX.into()) as u8)
+    }
+    fn set_critical_temperature(&self, value: u8) -> Result<(), MethodErr> {
+        let mut core = self.core.borrow_mut();
+        // `0` restores the critical temperature of the control config.
+        core.critical_temperature = if value > 0 {
+            let temp = core.temperature_unit.to_celsius(value.into());
+            Some(temp.round().clamp(1.0, u8::MAX.into()) as u8)
+        } else {
+            None
+        };
+        Ok(())
+    }
+    fn temperature_unit(&self) -> Result<String, MethodErr> {
+        Ok(self.core.borrow().temperature_unit.name().to_owned())
+    }
+    fn set_temperature_unit(&self, value: String) -> Result<(), MethodErr> {
+        let unit = TemperatureUnit::from_name(&value).ok_or_else(|| {
+            MethodErr::invalid_arg(&format!(
+                "{} is not a valid unit (`celsius` or `fahrenheit`)",
+                value
+            ))
+        })?;
+        self.core.borrow_mut().temperature_unit = unit;
+        Ok(())
+    }
+    fn fan_speed_limits(&self) -> Result<Vec<(f64, f64)>, MethodErr> {
+        Ok(self
+            .core
+            .borrow()
+            .fan_speed_limits
+            .iter()
+            .map(|l| (l.min, l.max))
+            .collect())
+    }
+    fn set_fan_speed_limits(&self, value: Vec<(f64, f64)>) -> Result<(), MethodErr> {
+        if let Some((min, max)) = value
+            .iter()
+            .find(|(min, max)| !(0.0..=100.0).contains(min) || !(*min..=100.0).contains(max))
+        {
+            return Err(MethodErr::invalid_arg(&format!(
+                "The limits ({}, {}) must be between 0 and 100, the minimum first",
+                min, max
+            )));
+        }
+
+        self.core.borrow_mut().fan_speed_limits = value
+            .into_iter()
+            .map(|(min, max)| FanSpeedLimit { min, max })
+            .collect();
+        Ok(())
+    }
+    fn temperature_offset(&self) -> Result<f64, MethodErr> {
+        let unit = self.core.borrow().temperature_unit;
+        // The offset is a difference of temperatures.
+        Ok(unit.convert(self.core.borrow().temperature_offset) - unit.convert(0.0))
+    }
+    fn set_temperature_offset(&self, value: f64) -> Result<(), MethodErr> {
+        if !value.is_finite() {
+            return Err(MethodErr::invalid_arg(&format!(
+                "{} is not a valid offset",
+                value
+            )));
+        }
+
+        let mut core = self.core.borrow_mut();
+        let offset =
+            core.temperature_unit.to_celsius(value) - core.temperature_unit.to_celsius(0.0);
+        core.temperature_offset = offset.clamp(-MAX_TEMPERATURE_OFFSET, MAX_TEMPERATURE_OFFSET);
+        Ok(())
+    }
     fn auto(&self) -> Result<bool, MethodErr> {
         Ok(*self.auto.borrow())
     }
     fn set_auto(&self, value: bool) -> Result<(), MethodErr> {
         *self.auto.borrow_mut() = value;
+        self.fans_auto
+            .borrow_mut()
+            .iter_mut()
+            .for_each(|a| *a = value);
+        self.refresh_manual_timer(Instant::now());
+        self.changed_properties.borrow_mut().insert("FansAuto");
         Ok(())
     }
+    fn fans_auto(&self) -> Result<Vec<bool>, MethodErr> {
+        Ok(self.fans_auto.borrow().to_owned())
+    }
+    fn set_fan_auto(&self, index: u8, value: bool) -> Result<(), MethodErr> {
+        let mut fans_auto = self.fans_auto.borrow_mut();
+        if index as usize >= fans_auto.len() {
+            return Err(MethodErr::invalid_arg(&format!(
+                "{} is not a valid index.",
+                index
+            )));
+        }
+        fans_auto[index as usize] = value;
+        *self.auto.borrow_mut() = fans_auto.iter().all(|&a| a);
+        drop(fans_auto);
+        self.refresh_manual_timer(Instant::now());
+
+        self.changed_properties
+            .borrow_mut()
+            .extend(["FansAuto", "Auto"]);
+        Ok(())
+    }
+    fn profiles(&self) -> Result<Vec<String>, MethodErr> {
+        let mut names: Vec<String> = self.profiles.borrow().keys().cloned().collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+    fn load_profile(&self, name: &str) -> Result<(), MethodErr> {
+        let profiles = self.profiles.borrow();
+        let profile = profiles
+            .get(name)
+            .ok_or_else(|| MethodErr::invalid_arg(&format!("{} is not a profile.", name)))?;
+
+        self.config_loader
+            .borrow()
+            .test_control_config(
+                &profile.selected_fan_config,
+                *self.check_control_config.borrow(),
+            )
+            .map_err(|e| MethodErr::failed(&e.to_string()))?;
+
+        self.pending_profile.replace(Some(name.to_owned()));
+        Ok(())
+    }
+    fn save_profile(&self, name: &str) -> Result<(), MethodErr> {
+        if name.trim().is_empty() {
+            return Err(MethodErr::invalid_arg("The profile name can't be empty."));
+        }
+
+        let profile = Profile {
+            selected_fan_config: self.config.borrow().to_owned(),
+            auto: *self.auto.borrow(),
+            target_fans_speeds: self.target_fans_speeds.borrow().to_owned(),
+        };
+        self.profiles.borrow_mut().insert(name.to_owned(), profile);
+        self.changed_properties.borrow_mut().insert("Profiles");
+        Ok(())
+    }
+    fn reset(&self) -> Result<(), MethodErr> {
+        let fans_count = self.target_fans_speeds.borrow().len();
+        self.target_fans_speeds.replace(vec![0.0; fans_count]);
+        self.auto.replace(true);
+        self.fans_auto.replace(vec![true; fans_count]);
+        self.reload_control_config.replace(true);
+
+        self.changed_properties
+            .borrow_mut()
+            .extend(["TargetFansSpeeds", "Auto", "FansAuto"]);
+        Ok(())
+    }
+    fn export_config(&self) -> Result<String, MethodErr> {
+        let service = self.as_service_config();
+        let control_config = self
+            .config_loader
+            .borrow()
+            .load_control_config(&service.selected_fan_config)
+            .map_err(|e| MethodErr::failed(&e.to_string()))?;
+
+        ConfigBundle::new(service, control_config)
+            .to_json()
+            .map_err(|e| MethodErr::failed(&e.to_string()))
+    }
+    fn import_config(&self, config: &str) -> Result<(), MethodErr> {
+        // Nothing is written if any part of the configuration is invalid.
+        let bundle =
+            ConfigBundle::from_json(config).map_err(|e| MethodErr::invalid_arg(&e.to_string()))?;
+        // The control config is removed if the service config can't be saved.
+        let installed = bundle
+            .install_control_config(&self.config_loader.borrow(), &CONTROL_CONFIGS_DIR_PATH)
+            .map_err(|e| MethodErr::failed(&e.to_string()))?;
+        // Without `core.persist_on_exit`, the service config is only applied for the session.
+        let persist = self.core.borrow().persist_on_exit;
+        if persist {
+            bundle
+                .service
+                .save()
+                .map_err(|e| MethodErr::failed(&e.to_string()))?;
+        }
+        installed.keep();
+        // The imported config replaces the saved one, it's applied with the reload.
+        self.persisted_config.take();
+
+        // The speeds are not reloaded with the rest of the service config.
+        let fans_count = bundle.control_config.fan_configurations.len();
+        let mut service = bundle.service;
+        service.fans_auto.resize(fans_count, service.auto);
+        service.target_fans_speeds.resize(fans_count, 0.0);
+        self.auto.replace(service.auto);
+        self.fans_auto.replace(service.fans_auto.clone());
+        self.target_fans_speeds
+            .replace(service.target_fans_speeds.clone());
+        self.refresh_manual_timer(Instant::now());
+        if !persist {
+            self.pending_service_config.replace(Some(service));
+        }
+        self.reload_service_config.replace(true);
+        self.configs_changed.replace(true);
+
+        self.changed_properties
+            .borrow_mut()
+            .extend(["TargetFansSpeeds", "Auto", "FansAuto"]);
+        Ok(())
+    }
+    fn active_profile_rule(&self) -> Result<String, MethodErr> {
+        let auto_profiles = self.auto_profiles.borrow();
+        Ok(self
+            .profile_rules
+            .borrow()
+            .active()
+            .and_then(|i| auto_profiles.rules.get(i))
+            .map(|r| r.profile.to_owned())
+            .unwrap_or_default())
+    }
+    fn power_state(&self) -> Result<String, MethodErr> {
+        Ok(self.power_state.borrow().name().to_owned())
+    }
     fn temperatures(&self) -> Result<HashMap<String, f64>, MethodErr> {
-        Ok(self.temps.borrow().to_owned())
+        Ok(reported_temperatures(self, &self.temps.borrow()))
+    }
+    fn temperatures_raw(&self) -> Result<HashMap<String, f64>, MethodErr> {
+        Ok(reported_temperatures(self, &self.temps_raw.borrow()))
+    }
+    fn available_sensors(&self) -> Result<HashMap<String, f64>, MethodErr> {
+        // Every sensor read is reported, and every labeled hwmon input is listed, whatever the
+        // sensors settings.
+        let mut temps = self.temps_raw.borrow().clone();
+        let hwmon_temps = read_hwmon_temps(
+            &self.hwmon_class_path.borrow(),
+            &self.sensors_labels.borrow(),
+        );
+        for (name, temp) in hwmon_temps {
+            temps.entry(name).or_insert(temp);
+        }
+        Ok(reported_temperatures(self, &temps))
+    }
+    fn history(&self) -> Result<Vec<(f64, f64, Vec<f64>)>, MethodErr> {
+        let unit = self.core.borrow().temperature_unit;
+        Ok(self
+            .history
+            .borrow()
+            .iter()
+            .map(|s| {
+                (
+                    s.timestamp,
+                    unit.convert(s.temperature),
+                    s.fans_speeds.clone(),
+                )
+            })
+            .collect())
+    }
+    fn missing_sensors(&self) -> Result<Vec<String>, MethodErr> {
+        Ok(self.missing_sensors.borrow().to_owned())
     }
     fn poll_interval(&self) -> IFaceResult<u64> {
         Ok(*self.poll_interval.borrow())
     }
+    fn version(&self) -> Result<String, MethodErr> {
+        Ok(VERSION.to_owned())
+    }
+    fn build_info(&self) -> Result<HashMap<String, String>, MethodErr> {
+        let mut features = Vec::new();
+        if cfg!(feature = "metrics") {
+            features.push("metrics");
+        }
+
+        let mut info = HashMap::new();
+        info.insert("version".to_owned(), VERSION.to_owned());
+        info.insert("git_hash".to_owned(), GIT_HASH.to_owned());
+        info.insert("features".to_owned(), features.join(","));
+        info.insert(
+            "profile".to_owned(),
+            if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            }
+            .to_owned(),
+        );
+        Ok(info)
+    }
+    fn warnings(&self) -> Result<Vec<String>, MethodErr> {
+        let mut warnings = self.warnings.borrow().to_owned();
+        warnings.extend(self.fans_mismatches.borrow().iter().cloned());
+        warnings.extend(self.stuck_sensors.borrow().iter().map(|name| {
+            format!(
+                "Sensor {} has not changed for a long time under load, it may be stuck",
+                name
+            )
+        }));
+        Ok(warnings)
+    }
+    fn config_error(&self) -> Result<String, MethodErr> {
+        Ok(self.config_error.borrow().to_owned().unwrap_or_default())
+    }
     fn fans_names(&self) -> Result<Vec<String>, dbus_tree::MethodErr> {
         Ok(self.fans_names.borrow().to_owned())
     }
@@ -124,7 +775,12 @@ pub(crate) fn create_dbus_conn(data: Rc<State>) -> Result<LocalConnection, dbus:
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::nbfc_control::ControlConfigLoader;
+    use crate::temp::SensorLabel;
+    use crate::test_utils::TempDir;
     use std::cell::RefCell;
+    use std::fs;
+    use std::path::Path;
 
     #[test]
     fn getters() {
@@ -200,6 +856,28 @@ mod tests {
 
         let invalid_number_speeds = vec![0.];
         assert!(state.set_target_fans_speeds(invalid_number_speeds).is_err());
+        assert!(state
+            .set_target_fans_speeds(vec![1., f64::NAN, 2.])
+            .is_err());
+        assert!(*state.target_fans_speeds.borrow() == dummy_target_speeds);
+    }
+
+    #[test]
+    fn set_all_fans_speed() {
+        let state = State {
+            target_fans_speeds: RefCell::from(vec![0., 0.]),
+            fans_auto: RefCell::from(vec![true, true]),
+            auto: RefCell::from(true),
+            ..Default::default()
+        };
+
+        assert!(state.set_all_fans_speed(101.).is_err());
+        assert!(state.auto().unwrap());
+
+        state.set_all_fans_speed(100.).unwrap();
+        assert_eq!(state.target_fans_speeds().unwrap(), vec![100., 100.]);
+        assert_eq!(state.fans_auto().unwrap(), vec![false, false]);
+        assert!(!state.auto().unwrap());
     }
 
     #[test]
@@ -212,6 +890,446 @@ mod tests {
         assert!(state.set_target_fans_speeds(dummy_target_speeds).is_err());
     }
 
+    #[test]
+    fn per_fan_auto() {
+        let state = State {
+            target_fans_speeds: RefCell::from(vec![0., 0.]),
+            fans_speeds: RefCell::from(vec![0., 0.]),
+            auto: RefCell::new(true),
+            fans_auto: RefCell::from(vec![true, true]),
+            ..Default::default()
+        };
+
+        assert!(state.set_fan_auto(2, false).is_err());
+
+        assert!(state.set_fan_auto(1, false).is_ok());
+        assert_eq!(state.fans_auto().unwrap(), vec![true, false]);
+        assert_eq!(state.auto().unwrap(), false);
+        assert!(state.is_fan_auto(0));
+        assert!(!state.is_fan_auto(1));
+
+        assert!(state.set_fan_auto(1, true).is_ok());
+        assert_eq!(state.auto().unwrap(), true);
+
+        // Setting a target speed switches only this fan to manual.
+        assert!(state.set_target_fan_speed(0, 42.).is_ok());
+        assert_eq!(state.fans_auto().unwrap(), vec![false, true]);
+        assert_eq!(state.auto().unwrap(), false);
+
+        assert!(state.set_auto(true).is_ok());
+        assert_eq!(state.fans_auto().unwrap(), vec![true, true]);
+    }
+
+    #[test]
+    fn critical_temperature() {
+        let state = State {
+            config_critical_temperature: RefCell::new(90),
+            ..Default::default()
+        };
+        assert_eq!(ComMusikidFancy::critical_temperature(&state).unwrap(), 90);
+
+        assert!(state.set_critical_temperature(75).is_ok());
+        assert_eq!(ComMusikidFancy::critical_temperature(&state).unwrap(), 75);
+        assert_eq!(
+            state.as_service_config().core.critical_temperature,
+            Some(75)
+        );
+
+        assert!(state.set_critical_temperature(0).is_ok());
+        assert_eq!(ComMusikidFancy::critical_temperature(&state).unwrap(), 90);
+    }
+
+    #[test]
+    fn boost() {
+        let state = State::default();
+        assert!(state.boost(0).is_err());
+        assert!(state.boost(u64::MAX).is_err());
+        assert!(state.boost_until.borrow().is_none());
+
+        state.boost(60).unwrap();
+        let first = state.boost_until.borrow().unwrap();
+        state.boost(10).unwrap();
+        let second = state.boost_until.borrow().unwrap();
+        assert!(second < first);
+        assert!(second > Instant::now());
+        assert_eq!(state.boost_started.take(), Some(10));
+
+        state.calibrating.replace(true);
+        assert!(state.boost(60).is_err());
+        assert_eq!(state.boost_started.take(), None);
+    }
+
+    #[test]
+    fn evaluate_curve() {
+        let state = State {
+            fans_curves: RefCell::new(vec![
+                vec![(40., 20.), (60., 50.), (80., 100.)],
+                vec![(50., 0.), (70., 60.)],
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(state.evaluate_curve(30.).unwrap(), vec![20., 0.]);
+        assert_eq!(state.evaluate_curve(65.).unwrap(), vec![50., 0.]);
+
+        state.core.borrow_mut().curve_interpolation = CurveInterpolation::Linear;
+        assert_eq!(state.evaluate_curve(65.).unwrap(), vec![62.5, 45.]);
+        assert_eq!(state.evaluate_curve(90.).unwrap(), vec![100., 60.]);
+    }
+
+    #[test]
+    fn temperature_unit() {
+        let state = State {
+            temps: RefCell::new(vec![("CPU".to_owned(), 50.)].into_iter().collect()),
+            fans_curves: RefCell::new(vec![vec![(0., 0.), (100., 100.)]]),
+            config_critical_temperature: RefCell::new(90),
+            ..Default::default()
+        };
+        assert!(state.set_temperature_unit("kelvin".to_owned()).is_err());
+        state.set_temperature_unit("fahrenheit".to_owned()).unwrap();
+
+        assert_eq!(state.temperatures().unwrap()["CPU"], 122.);
+        assert_eq!(
+            state.fan_curves().unwrap(),
+            vec![vec![(32., 0.), (212., 100.)]]
+        );
+        assert_eq!(ComMusikidFancy::critical_temperature(&state).unwrap(), 194);
+
+        // The critical temperature is still stored in Celsius.
+        state.set_critical_temperature(176).unwrap();
+        assert_eq!(state.core.borrow().critical_temperature, Some(80));
+        assert_eq!(State::critical_temperature(&state), 80);
+    }
+
+    #[test]
+    fn fan_speed_limits() {
+        let state = State::default();
+        assert!(state.set_fan_speed_limits(vec![(50., 40.)]).is_err());
+        assert!(state.set_fan_speed_limits(vec![(0., 120.)]).is_err());
+        assert!(state
+            .set_fan_speed_limits(vec![(0., 100.), (20., 70.)])
+            .is_ok());
+        assert_eq!(
+            state.core.borrow().fan_speed_limits[1],
+            FanSpeedLimit { min: 20., max: 70. }
+        );
+        assert_eq!(
+            state.fan_speed_limits().unwrap(),
+            vec![(0., 100.), (20., 70.)]
+        );
+    }
+
+    #[test]
+    fn temperature_offset() {
+        let state = State::default();
+        assert!(state.set_temperature_offset(f64::NAN).is_err());
+
+        state.set_temperature_offset(5.0).unwrap();
+        assert_eq!(state.fans_temperature_offset(), 5.0);
+        state.set_temperature_offset(-25.0).unwrap();
+        assert_eq!(state.as_service_config().core.temperature_offset, -10.0);
+
+        // The offset follows the unit, as a difference of temperatures.
+        state.core.borrow_mut().temperature_unit = TemperatureUnit::Fahrenheit;
+        assert_eq!(ComMusikidFancy::temperature_offset(&state).unwrap(), -18.0);
+        state.set_temperature_offset(9.0).unwrap();
+        assert!((state.fans_temperature_offset() - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn build_info() {
+        let state = State::default();
+        let info = state.build_info().unwrap();
+        assert_eq!(info["version"], ComMusikidFancy::version(&state).unwrap());
+        assert!(!info["git_hash"].is_empty());
+        assert_eq!(
+            info["features"].contains("metrics"),
+            cfg!(feature = "metrics")
+        );
+    }
+
+    #[test]
+    fn ec_info() {
+        let state = State {
+            ec_dev_path: RefCell::from("/dev/port".to_owned()),
+            ec_reads_ok: RefCell::from(Some(true)),
+            ec_writes_ok: RefCell::from(Some(false)),
+            ..Default::default()
+        };
+        let info = state.ec_info().unwrap();
+        assert_eq!(info["device"], "/dev/port");
+        assert_eq!(info["reads"], "ok");
+        assert_eq!(info["writes"], "failing");
+        assert_eq!(info["failsafe"], "false");
+        assert!(!info.contains_key("last_error"));
+
+        state
+            .last_ec_error
+            .replace(Some("The write was not applied".to_owned()));
+        assert_eq!(
+            state.ec_info().unwrap()["last_error"],
+            "The write was not applied"
+        );
+    }
+
+    #[test]
+    fn health() {
+        let state = State {
+            ec_reads_ok: RefCell::from(Some(true)),
+            loop_iterations: RefCell::from(42),
+            warnings: RefCell::from(vec!["thermald is running".to_owned()]),
+            ..Default::default()
+        };
+        let health = state.health().unwrap();
+        assert_eq!(health["ec_reachable"], "true");
+        assert_eq!(health["iterations"], "42");
+        assert_eq!(health["warnings"], "1");
+        assert_eq!(health["failsafe"], "false");
+        assert!(!health.contains_key("last_read"));
+
+        state
+            .last_ec_read
+            .replace(Some(UNIX_EPOCH + Duration::from_secs(1000)));
+        state.ec_failsafe.replace(true);
+        let health = state.health().unwrap();
+        assert_eq!(health["last_read"], "1000");
+        assert_eq!(health["failsafe"], "true");
+
+        // The stuck sensors are reported with the warnings.
+        state.stuck_sensors.replace(vec!["CPU".to_owned()]);
+        let warnings = state.warnings().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[1].starts_with("Sensor CPU has not changed"));
+        assert_eq!(state.health().unwrap()["warnings"], "2");
+    }
+
+    #[test]
+    fn ec_failure() {
+        let state = State {
+            ec_reads_ok: RefCell::from(Some(true)),
+            ec_writes_ok: RefCell::from(Some(true)),
+            ..Default::default()
+        };
+
+        // The EC is reported as failing from the first error.
+        state.record_ec_error("The EC did not answer".to_owned());
+        let info = state.ec_info().unwrap();
+        assert_eq!(info["reads"], "failing");
+        assert_eq!(info["writes"], "ok");
+        assert_eq!(info["last_error"], "The EC did not answer");
+        assert_eq!(state.health().unwrap()["ec_reachable"], "false");
+
+        state.enter_failsafe();
+        let info = state.ec_info().unwrap();
+        assert_eq!(info["reads"], "failing");
+        assert_eq!(info["writes"], "failing");
+        assert_eq!(info["failsafe"], "true");
+    }
+
+    #[test]
+    fn set_fan_curve() {
+        let mut config_loader = ControlConfigLoader::new(true);
+        config_loader.add_path(Path::new("tests")).unwrap();
+        let state = State {
+            config: RefCell::new("valid_json".to_owned()),
+            config_loader: RefCell::new(config_loader),
+            ..Default::default()
+        };
+        state.core.borrow_mut().temperature_unit = TemperatureUnit::Fahrenheit;
+
+        assert!(state
+            .set_fan_curve(0, vec![(104., 0.), (86., 50.)])
+            .is_err());
+        assert!(state.set_fan_curve(9, vec![(86., 0.)]).is_err());
+        assert!(state.edited_config.borrow().is_none());
+
+        state
+            .set_fan_curve(0, vec![(158., 100.), (104., 0.)])
+            .unwrap();
+        assert!(*state.reload_control_config.borrow());
+        let thresholds: Vec<_> = state
+            .edited_config
+            .borrow()
+            .as_ref()
+            .unwrap()
+            .fan_configurations[0]
+            .temperature_thresholds
+            .iter()
+            .map(|t| (t.up_threshold, t.fan_speed))
+            .collect();
+        assert_eq!(thresholds, vec![(40, 0.), (70, 100.)]);
+
+        // The edits are discarded with the config.
+        state.set_config("valid_xml".to_owned()).unwrap();
+        assert!(state.edited_config.borrow().is_none());
+    }
+
+    #[test]
+    fn try_config() {
+        let mut config_loader = ControlConfigLoader::new(true);
+        config_loader.add_path(Path::new("tests")).unwrap();
+        let state = State {
+            config: RefCell::new("valid_xml".to_owned()),
+            config_source: RefCell::new(ConfigSource::Detected),
+            config_loader: RefCell::new(config_loader),
+            ..Default::default()
+        };
+
+        assert!(state.commit_config().is_err());
+        assert!(state.try_config("unknown").is_err());
+        assert!(state.try_config("valid_json").is_ok());
+        assert_eq!(*state.config.borrow(), "valid_json");
+        assert!(*state.reload_control_config.borrow());
+        // The tried config is not saved.
+        let service_config = state.as_service_config();
+        assert_eq!(service_config.selected_fan_config, "valid_xml");
+        assert_eq!(service_config.config_source, ConfigSource::Detected);
+        assert!(state.delete_config("valid_xml").is_err());
+        assert!(state.rename_config("valid_xml", "renamed").is_err());
+        assert!(state.rename_config("valid_json", "renamed").is_err());
+        assert!(state.save_current_config("valid_xml").is_err());
+
+        assert!(state.commit_config().is_ok());
+        let service_config = state.as_service_config();
+        assert_eq!(service_config.selected_fan_config, "valid_json");
+        assert_eq!(service_config.config_source, ConfigSource::Manual);
+    }
+
+    #[test]
+    fn reload_tried_config() {
+        let mut config_loader = ControlConfigLoader::new(true);
+        config_loader.add_path(Path::new("tests")).unwrap();
+        let state = State {
+            config: RefCell::new("valid_xml".to_owned()),
+            config_source: RefCell::new(ConfigSource::Detected),
+            config_loader: RefCell::new(config_loader),
+            ..Default::default()
+        };
+        state.try_config("valid_json").unwrap();
+
+        // The service config is reloaded from the disk, where the saved config is selected.
+        let saved = state.as_service_config();
+        state.replace_config(saved.selected_fan_config, saved.config_source);
+        assert_eq!(*state.config.borrow(), "valid_xml");
+        assert_eq!(*state.config_source.borrow(), ConfigSource::Detected);
+        assert!(state.persisted_config.borrow().is_none());
+        assert!(state.commit_config().is_err());
+
+        // A config selected by a profile is not tried either.
+        state.try_config("valid_json").unwrap();
+        state.replace_config("valid_xml".to_owned(), ConfigSource::Manual);
+        assert_eq!(state.as_service_config().selected_fan_config, "valid_xml");
+        assert!(state.commit_config().is_err());
+    }
+
+    #[test]
+    fn available_sensors() {
+        let class = TempDir::new("available_sensors");
+        for (hwmon, name, inputs) in [
+            ("hwmon0", "k10temp", vec![("temp1", Some("Tctl"), "45500")]),
+            (
+                "hwmon1",
+                "nvme",
+                vec![
+                    ("temp1", None, "38000"),
+                    ("temp2", Some("Sensor 1"), "40000"),
+                ],
+            ),
+        ] {
+            let dir = class.join(hwmon);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("name"), format!("{}\n", name)).unwrap();
+            for (input, label, value) in inputs {
+                fs::write(dir.join(format!("{}_input", input)), value).unwrap();
+                if let Some(label) = label {
+                    fs::write(dir.join(format!("{}_label", input)), label).unwrap();
+                }
+            }
+        }
+        let state = State {
+            temps_raw: RefCell::new(
+                [("CPU".to_owned(), 50.), ("NVME".to_owned(), 40.)]
+                    .into_iter()
+                    .collect(),
+            ),
+            hwmon_class_path: RefCell::new(class.to_path_buf()),
+            sensors_labels: RefCell::new(vec![SensorLabel {
+                chip: "nvme-pci-*".to_owned(),
+                input: "temp1".to_owned(),
+                label: "Composite".to_owned(),
+            }]),
+            ..Default::default()
+        };
+        state.sensors.borrow_mut().only = vec!["CPU".to_owned()];
+
+        let sensors = state.available_sensors().unwrap();
+        assert_eq!(sensors.len(), 5);
+        assert_eq!(sensors["NVME"], 40.);
+        assert_eq!(sensors["k10temp/Tctl"], 45.5);
+        assert_eq!(sensors["nvme/Composite"], 38.);
+        assert_eq!(sensors["nvme/Sensor 1"], 40.);
+    }
+
+    #[test]
+    fn read_registers() {
+        let state = State {
+            ec_registers: RefCell::from((0..=255).collect::<Vec<u8>>()),
+            ..Default::default()
+        };
+        assert!(state.read_register(16).is_err());
+
+        state.debug_ec.replace(true);
+        assert_eq!(state.read_register(16).unwrap(), 16);
+        assert_eq!(
+            state.dump_registers(250, 10).unwrap(),
+            vec![250, 251, 252, 253, 254, 255]
+        );
+    }
+
+    #[test]
+    fn reset() {
+        let state = State {
+            target_fans_speeds: RefCell::from(vec![30., 40.]),
+            fans_auto: RefCell::from(vec![false, true]),
+            ..Default::default()
+        };
+
+        state.reset().unwrap();
+        assert_eq!(state.target_fans_speeds().unwrap(), vec![0., 0.]);
+        assert!(state.auto().unwrap());
+        assert_eq!(state.fans_auto().unwrap(), vec![true, true]);
+        assert!(*state.reload_control_config.borrow());
+        assert!(state.changed_properties.borrow().contains("FansAuto"));
+    }
+
+    #[test]
+    fn profiles() {
+        let state = State {
+            config: RefCell::new("Dummy config".to_owned()),
+            target_fans_speeds: RefCell::from(vec![30., 40.]),
+            ..Default::default()
+        };
+
+        assert!(state.save_profile(" ").is_err());
+        assert!(state.save_profile("Silent").is_ok());
+        assert!(state.save_profile("Performance").is_ok());
+        assert_eq!(
+            state.profiles().unwrap(),
+            vec!["Performance".to_string(), "Silent".to_string()]
+        );
+        assert_eq!(
+            state.profiles.borrow()["Silent"],
+            Profile {
+                selected_fan_config: "Dummy config".to_owned(),
+                auto: false,
+                target_fans_speeds: vec![30., 40.],
+            }
+        );
+        assert!(state.changed_properties.borrow().contains("Profiles"));
+
+        assert!(state.load_profile("Unknown").is_err());
+        assert!(state.pending_profile.borrow().is_none());
+    }
+
     //   #[test]
     //   fn connecting() {
     //       use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
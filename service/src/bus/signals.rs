@@ -0,0 +1,198 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use std::time::{Duration, Instant};
+
+use super::interfaces::ComMusikidFancyFanSpeedsChanged;
+
+/// Minimal speed change (in %) of a fan to emit `FanSpeedsChanged`.
+const FAN_SPEEDS_EPSILON: f64 = 1.0;
+/// Minimal delay between two `FanSpeedsChanged` signals.
+const FAN_SPEEDS_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// Number of consecutive reads at 0 RPM while the fan should spin to consider it stalled.
+const STALL_CYCLES: u8 = 3;
+
+/// Decides when the `FanSpeedsChanged` signal should be emitted.
+#[derive(Debug, Default)]
+pub(crate) struct FanSpeedsNotifier {
+    last_speeds: Vec<f64>,
+    last_emit: Option<Instant>,
+    /// Emit on any change, for the speeds which are already rounded.
+    pub exact: bool,
+}
+
+impl FanSpeedsNotifier {
+    /// Returns the signal to emit if a speed changed significantly since the last signal,
+    /// unless a signal has already been emitted recently.
+    pub fn check(
+        &mut self,
+        speeds: &[f64],
+        now: Instant,
+    ) -> Option<ComMusikidFancyFanSpeedsChanged> {
+        let epsilon = if self.exact { 0.0 } else { FAN_SPEEDS_EPSILON };
+        let changed = speeds.len() != self.last_speeds.len()
+            || speeds
+                .iter()
+                .zip(&self.last_speeds)
+                .any(|(new, old)| (new - old).abs() > epsilon);
+        let debounced =
+            matches!(self.last_emit, Some(last) if now.duration_since(last) < FAN_SPEEDS_DEBOUNCE);
+
+        if !changed || debounced {
+            return None;
+        }
+
+        self.last_speeds = speeds.to_vec();
+        self.last_emit = Some(now);
+
+        Some(ComMusikidFancyFanSpeedsChanged {
+            speeds: self.last_speeds.clone(),
+        })
+    }
+}
+
+/// Filters the speeds read from the EC before they are reported, so that they don't jitter.
+#[derive(Debug, Default)]
+pub(crate) struct FanSpeedsFilter {
+    smoothed: Vec<f64>,
+}
+
+impl FanSpeedsFilter {
+    /// Smooth `speeds` with an exponential moving average of factor `alpha`, then round them to
+    /// `decimals` if it's set.
+    ///
+    /// The speeds are not smoothed if `alpha` is not between `0` and `1` (excluded).
+    pub fn apply(&mut self, speeds: &[f64], alpha: f64, decimals: Option<u32>) -> Vec<f64> {
+        if alpha > 0.0 && alpha < 1.0 && self.smoothed.len() == speeds.len() {
+            for (smoothed, &speed) in self.smoothed.iter_mut().zip(speeds) {
+                *smoothed = alpha * speed + (1.0 - alpha) * *smoothed;
+            }
+        } else {
+            self.smoothed = speeds.to_vec();
+        }
+
+        match decimals {
+            Some(decimals) => {
+                let factor = 10f64.powi(decimals as i32);
+                self.smoothed
+                    .iter()
+                    .map(|s| (s * factor).round() / factor)
+                    .collect()
+            }
+            None => self.smoothed.clone(),
+        }
+    }
+}
+
+/// Detects the fans which don't spin while they should, to emit `FanStalled`.
+#[derive(Debug, Default)]
+pub(crate) struct FanStallDetector {
+    stopped_cycles: Vec<u8>,
+}
+
+impl FanStallDetector {
+    /// Returns the indices of the fans which just got stalled, from the commanded speeds (in %)
+    /// and the speeds read (in RPM, `None` if it can't be read).
+    ///
+    /// A fan is reported once, until it spins again or is turned off.
+    pub fn check(&mut self, commanded: &[f64], rpms: &[Option<f64>]) -> Vec<usize> {
+        self.stopped_cycles.resize(rpms.len(), 0);
+
+        let mut stalled = Vec::new();
+        for (i, rpm) in rpms.iter().enumerate() {
+            let should_spin = commanded.get(i).map_or(false, |&s| s > 0.0);
+
+            if should_spin && *rpm == Some(0.0) {
+                if self.stopped_cycles[i] < STALL_CYCLES {
+                    self.stopped_cycles[i] += 1;
+                    if self.stopped_cycles[i] == STALL_CYCLES {
+                        stalled.push(i);
+                    }
+                }
+            } else {
+                self.stopped_cycles[i] = 0;
+            }
+        }
+
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fan_speeds_debounce() {
+        let mut notifier = FanSpeedsNotifier::default();
+        let start = Instant::now();
+
+        let signal = notifier.check(&[20.0, 30.0], start).unwrap();
+        assert_eq!(signal.speeds, vec![20.0, 30.0]);
+
+        // Noise is ignored
+        assert!(notifier
+            .check(&[20.5, 30.0], start + FAN_SPEEDS_DEBOUNCE)
+            .is_none());
+
+        // Changes are delayed until the debounce delay is elapsed
+        assert!(notifier
+            .check(&[50.0, 30.0], start + FAN_SPEEDS_DEBOUNCE / 2)
+            .is_none());
+        let signal = notifier
+            .check(&[50.0, 30.0], start + FAN_SPEEDS_DEBOUNCE)
+            .unwrap();
+        assert_eq!(signal.speeds, vec![50.0, 30.0]);
+    }
+
+    #[test]
+    fn fan_speeds_filter() {
+        let mut filter = FanSpeedsFilter::default();
+        assert_eq!(filter.apply(&[40.0, 51.26], 0.0, None), vec![40.0, 51.26]);
+        assert_eq!(filter.apply(&[40.0, 51.26], 0.0, Some(1)), vec![40.0, 51.3]);
+
+        assert_eq!(filter.apply(&[40.0, 50.0], 0.0, None), vec![40.0, 50.0]);
+        assert_eq!(filter.apply(&[50.0, 50.0], 0.5, Some(0)), vec![45.0, 50.0]);
+        assert_eq!(filter.apply(&[50.0, 50.0], 0.5, Some(0)), vec![48.0, 50.0]);
+        // The speeds of a new control config are not smoothed with the previous ones.
+        assert_eq!(filter.apply(&[30.0], 0.5, None), vec![30.0]);
+    }
+
+    #[test]
+    fn exact_fan_speeds_changes() {
+        let mut notifier = FanSpeedsNotifier {
+            exact: true,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        assert!(notifier.check(&[20.0], start).is_some());
+        assert!(notifier
+            .check(&[20.0], start + FAN_SPEEDS_DEBOUNCE)
+            .is_none());
+        assert!(notifier
+            .check(&[20.5], start + FAN_SPEEDS_DEBOUNCE)
+            .is_some());
+    }
+
+    #[test]
+    fn fan_stall() {
+        let mut detector = FanStallDetector::default();
+        let commanded = [50.0, 0.0, 50.0];
+        let rpms = [Some(0.0), Some(0.0), None];
+
+        for _ in 1..STALL_CYCLES {
+            assert!(detector.check(&commanded, &rpms).is_empty());
+        }
+        // Only the fan which should spin and has a tachometer is stalled.
+        assert_eq!(detector.check(&commanded, &rpms), vec![0]);
+        assert!(detector.check(&commanded, &rpms).is_empty());
+
+        // The fan is reported again if it stalls after spinning.
+        detector.check(&commanded, &[Some(2000.0), Some(0.0), None]);
+        for _ in 1..STALL_CYCLES {
+            assert!(detector.check(&commanded, &rpms).is_empty());
+        }
+        assert_eq!(detector.check(&commanded, &rpms), vec![0]);
+    }
+}
@@ -0,0 +1,284 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The whole configuration in a single JSON document, to move it to another install.
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+
+use std::fs::{remove_file, rename, write};
+use std::path::{Path, PathBuf};
+
+use super::nbfc_control::{
+    is_valid_config_name, join_issues, ControlConfigLoadError, ControlConfigLoader,
+};
+use super::service::ServiceConfig;
+use crate::nbfc::{FanControlConfigV2, ValidationIssue};
+
+/// Version of the format, increased on incompatible changes.
+const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Debug, Snafu)]
+pub(crate) enum ConfigBundleError {
+    #[snafu(display("The configuration can't be serialized: {}", source))]
+    BundleSerialize { source: serde_json::Error },
+
+    #[snafu(display("The configuration can't be read: {}", source))]
+    BundleDeserialize { source: serde_json::Error },
+
+    #[snafu(display(
+        "The configuration version {} is not supported (expected {})",
+        version,
+        BUNDLE_VERSION
+    ))]
+    UnsupportedVersion { version: u32 },
+
+    #[snafu(display("The control config is invalid: {}", join_issues(issues)))]
+    InvalidControlConfig { issues: Vec<ValidationIssue> },
+
+    #[snafu(display(
+        "`{}` has {} values while the control config has {} fans",
+        field,
+        len,
+        fans
+    ))]
+    FansCountMismatch {
+        field: &'static str,
+        len: usize,
+        fans: usize,
+    },
+
+    #[snafu(display("`{}` is not a valid name for a control config", name))]
+    InvalidConfigName { name: String },
+
+    #[snafu(display("The target speed {} is out of bounds", speed))]
+    SpeedOutOfBounds { speed: f64 },
+
+    #[snafu(display("A different control config named `{}` already exists", name))]
+    ConflictingControlConfig { name: String },
+
+    #[snafu(display("{}", source))]
+    ControlConfig { source: ControlConfigLoadError },
+
+    #[snafu(display("The control config can't be written: {}", source))]
+    WriteControlConfig { source: std::io::Error },
+}
+
+type Result<T> = std::result::Result<T, ConfigBundleError>;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The service configuration along with the control config it selects.
+pub(crate) struct ConfigBundle {
+    pub version: u32,
+    pub service: ServiceConfig,
+    pub control_config: FanControlConfigV2,
+}
+
+impl ConfigBundle {
+    pub fn new(service: ServiceConfig, control_config: FanControlConfigV2) -> Self {
+        ConfigBundle {
+            version: BUNDLE_VERSION,
+            service,
+            control_config,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context(BundleSerialize {})
+    }
+
+    /// Read a bundle from `json`, refusing it if it can't be applied as a whole.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let bundle: ConfigBundle = serde_json::from_str(json).context(BundleDeserialize {})?;
+        bundle.validate()?;
+        Ok(bundle)
+    }
+
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            self.version == BUNDLE_VERSION,
+            UnsupportedVersion {
+                version: self.version
+            }
+        );
+
+        let issues = self.control_config.validate();
+        ensure!(issues.is_empty(), InvalidControlConfig { issues });
+
+        let fans = self.control_config.fan_configurations.len();
+        let service = &self.service;
+        // The name of the selected config is the name of the file it's installed to.
+        ensure!(
+            is_valid_config_name(&service.selected_fan_config),
+            InvalidConfigName {
+                name: &service.selected_fan_config
+            }
+        );
+        // The lists can be empty, they are filled with the defaults then.
+        for (field, len) in [
+            ("target_fans_speeds", service.target_fans_speeds.len()),
+            ("fans_auto", service.fans_auto.len()),
+        ] {
+            ensure!(
+                len == 0 || len == fans,
+                FansCountMismatch { field, len, fans }
+            );
+        }
+        if let Some(&speed) = service
+            .target_fans_speeds
+            .iter()
+            .find(|s| !(0f64..=100f64).contains(*s))
+        {
+            return SpeedOutOfBounds { speed }.fail();
+        }
+
+        Ok(())
+    }
+
+    /// Write the control config to `dir` as JSON, unless the same one can already be loaded.
+    ///
+    /// The config is written to a temporary file which is then renamed, so that it's never
+    /// loaded partially written. It's removed again if the returned guard is not kept.
+    pub fn install_control_config(
+        &self,
+        loader: &ControlConfigLoader,
+        dir: &Path,
+    ) -> Result<InstalledControlConfig> {
+        let name = &self.service.selected_fan_config;
+        match loader.load_control_config(name) {
+            Ok(existing) if existing == self.control_config => {
+                Ok(InstalledControlConfig { path: None })
+            }
+            Ok(_) => ConflictingControlConfig { name }.fail(),
+            Err(ControlConfigLoadError::InexistentConfig { .. }) => {
+                let content = self.control_config.to_json().context(BundleSerialize {})?;
+                let path = dir.join(name).with_extension("json");
+                let temp_path = dir.join(format!(".{}.json.tmp", name));
+
+                let written = write(&temp_path, content).and_then(|_| rename(&temp_path, &path));
+                if let Err(e) = written {
+                    let _ = remove_file(&temp_path);
+                    return Err(e).context(WriteControlConfig {});
+                }
+                Ok(InstalledControlConfig { path: Some(path) })
+            }
+            Err(e) => Err(e).context(ControlConfig {}),
+        }
+    }
+}
+
+/// A control config written by `ConfigBundle::install_control_config`,
+/// which is removed on drop unless it's kept.
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct InstalledControlConfig {
+    /// `None` if the control config was already installed.
+    path: Option<PathBuf>,
+}
+
+impl InstalledControlConfig {
+    /// Keep the control config, once the rest of the bundle has been applied.
+    pub fn keep(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for InstalledControlConfig {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn bundle() -> ConfigBundle {
+        let mut loader = ControlConfigLoader::new(true);
+        loader.add_path(Path::new("tests")).unwrap();
+        let control_config = loader.load_control_config("valid_json").unwrap();
+        let service = ServiceConfig {
+            selected_fan_config: "Imported config".to_owned(),
+            target_fans_speeds: vec![50.0; control_config.fan_configurations.len()],
+            ..Default::default()
+        };
+
+        ConfigBundle::new(service, control_config)
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        let bundle = bundle();
+        let parsed = ConfigBundle::from_json(&bundle.to_json().unwrap()).unwrap();
+        assert_eq!(parsed.control_config, bundle.control_config);
+        assert_eq!(
+            parsed.service.target_fans_speeds,
+            bundle.service.target_fans_speeds
+        );
+    }
+
+    #[test]
+    fn refuse_invalid_bundle() {
+        let mut bundle = bundle();
+        bundle.service.target_fans_speeds.push(50.0);
+        assert!(matches!(
+            ConfigBundle::from_json(&bundle.to_json().unwrap()),
+            Err(ConfigBundleError::FansCountMismatch { .. })
+        ));
+
+        let mut bundle = self::bundle();
+        bundle.version = 0;
+        assert!(matches!(
+            ConfigBundle::from_json(&bundle.to_json().unwrap()),
+            Err(ConfigBundleError::UnsupportedVersion { version: 0 })
+        ));
+
+        for name in ["", "../config", "config.json", "con\0fig"] {
+            let mut bundle = self::bundle();
+            bundle.service.selected_fan_config = name.to_owned();
+            assert!(matches!(
+                ConfigBundle::from_json(&bundle.to_json().unwrap()),
+                Err(ConfigBundleError::InvalidConfigName { .. })
+            ));
+        }
+
+        assert!(matches!(
+            ConfigBundle::from_json("{}"),
+            Err(ConfigBundleError::BundleDeserialize { .. })
+        ));
+    }
+
+    #[test]
+    fn install_control_config() {
+        let dir = TempDir::new("bundle");
+        let mut loader = ControlConfigLoader::new(false);
+        loader.add_path(&dir).unwrap();
+
+        let mut bundle = bundle();
+        // The config is removed if the rest of the bundle can't be applied.
+        let rolled_back = bundle.install_control_config(&loader, &dir).unwrap();
+        assert!(loader.load_control_config("Imported config").is_ok());
+        drop(rolled_back);
+        assert!(loader.available_configs().unwrap().is_empty());
+
+        bundle.install_control_config(&loader, &dir).unwrap().keep();
+        let installed = loader.load_control_config("Imported config").unwrap();
+        assert_eq!(installed, bundle.control_config);
+        // Only the config is left in the directory.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Installing the same config again is a no-op, but a different one is refused
+        drop(bundle.install_control_config(&loader, &dir).unwrap());
+        assert!(loader.load_control_config("Imported config").is_ok());
+        bundle.control_config.critical_temperature += 1;
+        let result = bundle.install_control_config(&loader, &dir);
+        assert!(matches!(
+            result,
+            Err(ConfigBundleError::ConflictingControlConfig { .. })
+        ));
+    }
+}
@@ -4,15 +4,17 @@
 use log::info;
 use phf::phf_map;
 use quick_xml::de::from_str as xml_from_str;
-use serde_json::de::from_str as json_from_str;
+use quick_xml::{events::Event, Reader};
+use serde_json::Value;
 use snafu::{ensure, ResultExt, Snafu};
 
-use std::fs::{read_dir, File};
+use std::fs::{read_dir, read_to_string, remove_file, rename, write, File};
 use std::io::Read;
 use std::path::{Path, PathBuf};
 
 use crate::nbfc::{
-    check_control_config, CheckControlConfigError, FanControlConfigV2, XmlFanControlConfigV2,
+    check_control_config, CheckControlConfigError, FanControlConfigV2, ValidationIssue,
+    XmlFanControlConfigV1, XmlFanControlConfigV2,
 };
 
 #[derive(Debug, Snafu)]
@@ -53,20 +55,85 @@ pub(crate) enum ControlConfigLoadError {
         source: quick_xml::DeError,
     },
 
+    #[snafu(display(
+        "Error occurred while serializing control config `{}`: {}",
+        name,
+        source
+    ))]
+    ControlJsonSerialize {
+        name: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("The control configs extend each other: {}", chain.join(" -> ")))]
+    ExtendsCycle { chain: Vec<String> },
+
     #[snafu(display("The control config `{}` does not exist", name))]
     InexistentConfig { name: String },
 
     #[snafu(display("The control config name `{}` contains invalid characters", name))]
     InvalidChars { name: String },
 
+    #[snafu(display("The control config `{}` already exists", name))]
+    AlreadyExists { name: String },
+
+    #[snafu(display("The control config `{}` is extended by {}", name, by.join(", ")))]
+    Extended { name: String, by: Vec<String> },
+
+    #[snafu(display(
+        "Error occurred while trying to modify control config `{}`: {}",
+        name,
+        source
+    ))]
+    Modify {
+        name: String,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Error occured while checking control config `{}`: {}", name, source))]
     Check {
         name: String,
         source: CheckControlConfigError,
     },
+
+    #[snafu(display("The control config `{}` is invalid: {}", name, join_issues(issues)))]
+    Invalid {
+        name: String,
+        issues: Vec<ValidationIssue>,
+    },
+}
+
+pub(crate) fn join_issues(issues: &[ValidationIssue]) -> String {
+    issues
+        .iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Refuse the control config if it has any validation issue.
+fn validate_control_config(name: &str, c: &FanControlConfigV2) -> Result<()> {
+    let issues = c.validate();
+    ensure!(issues.is_empty(), Invalid { name, issues });
+
+    Ok(())
 }
 
-const INVALID_CHARS: &[char] = &['.', '/'];
+const INVALID_CHARS: &[char] = &['.', '/', '\0'];
+
+/// Whether `name` can be used as the file name of a new control config.
+pub(crate) fn is_valid_config_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(&INVALID_CHARS[..])
+}
+
+/// DMI entries identifying the laptop model, used to detect the matching control config.
+const DMI_PATHS: &[&str] = &[
+    "/sys/class/dmi/id/product_name",
+    "/sys/class/dmi/id/board_name",
+];
+
+/// Minimal similarity for a control config to be considered as matching the laptop model.
+const MIN_MATCH_SCORE: f64 = 0.75;
 
 type Result<T> = std::result::Result<T, ControlConfigLoadError>;
 
@@ -77,16 +144,141 @@ const SUPPORTED_EXTENSIONS: phf::Map<&str, Deserializer> = phf_map! {
     "json" => json_deserializer,
 };
 
+/// Root element of the legacy NBFC configs.
+const XML_V1_ROOT: &[u8] = b"FanControlConfig";
+
+/// Get the name of the root element of a XML document.
+fn xml_root_element(buf: &str) -> Option<Vec<u8>> {
+    let mut reader = Reader::from_str(buf);
+    let mut event_buf = Vec::new();
+
+    loop {
+        event_buf.clear();
+        match reader.read_event(&mut event_buf) {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) => return Some(e.local_name().to_vec()),
+            Ok(Event::Eof) | Err(_) => return None,
+            _ => {}
+        }
+    }
+}
+
 fn xml_deserializer(name: &str, buf: String) -> Result<FanControlConfigV2> {
+    if xml_root_element(&buf).as_deref() == Some(XML_V1_ROOT) {
+        return Ok(xml_from_str::<XmlFanControlConfigV1>(&buf)
+            .context(ControlXmlDeserialize { name })?
+            .into());
+    }
+
     Ok(xml_from_str::<XmlFanControlConfigV2>(&buf)
         .context(ControlXmlDeserialize { name })?
         .into())
 }
 
 fn json_deserializer(name: &str, buf: String) -> Result<FanControlConfigV2> {
-    Ok(json_from_str::<FanControlConfigV2>(&buf)
-        .context(ControlJsonDeserialize { name })?
-        .into())
+    FanControlConfigV2::from_json(&buf).context(ControlJsonDeserialize { name })
+}
+
+/// Key of a JSON control config naming the config it overrides.
+const EXTENDS_KEY: &str = "Extends";
+
+/// Read the name of the config extended by the JSON control config at `path`, if any.
+fn read_extends(path: &Path) -> Option<String> {
+    if path.extension().map_or(true, |ext| ext != "json") {
+        return None;
+    }
+    read_to_string(path)
+        .ok()
+        .and_then(|buf| serde_json::from_str::<Value>(&buf).ok())
+        .and_then(|mut v| v.as_object_mut().and_then(|o| o.remove(EXTENDS_KEY)))
+        .and_then(|base| base.as_str().map(str::to_owned))
+}
+
+/// Merge the JSON control config `overrides` into `base`.
+///
+/// The fields of `overrides` replace the ones of `base`, except the fan configurations
+/// which are merged by index. The lists inside them (e.g. the temperature thresholds)
+/// are replaced as a whole.
+fn merge_control_config(base: &mut Value, overrides: Value) {
+    let (base, overrides) = match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => (base, overrides),
+        (base, overrides) => {
+            *base = overrides;
+            return;
+        }
+    };
+
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Array(base_fans)), Value::Array(fans)) if key == "FanConfigurations" => {
+                for (i, fan) in fans.into_iter().enumerate() {
+                    match base_fans.get_mut(i) {
+                        Some(base_fan) => merge_control_config(base_fan, fan),
+                        None => base_fans.push(fan),
+                    }
+                }
+            }
+            (Some(base_value @ Value::Object(_)), value @ Value::Object(_)) => {
+                merge_control_config(base_value, value)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Keep only the lowercased alphanumeric characters, so that case and punctuation are ignored.
+fn normalize_model_name(name: &str) -> Vec<char> {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Length of the longest common substring of `a` and `b`.
+fn longest_common_substring(a: &[char], b: &[char]) -> usize {
+    let mut longest = 0;
+    let mut previous = vec![0; b.len() + 1];
+    let mut current = vec![0; b.len() + 1];
+
+    for ca in a {
+        for (j, cb) in b.iter().enumerate() {
+            current[j + 1] = if ca == cb { previous[j] + 1 } else { 0 };
+            longest = longest.max(current[j + 1]);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    longest
+}
+
+/// Find the config whose name is the most similar to one of the models names.
+/// Returns `None` if no config is similar enough.
+fn best_config_match<S: AsRef<str>>(models: &[S], configs: &[String]) -> Option<String> {
+    let models: Vec<_> = models
+        .iter()
+        .map(|m| normalize_model_name(m.as_ref()))
+        .filter(|m| !m.is_empty())
+        .collect();
+
+    let mut best: Option<(f64, &String)> = None;
+    for config in configs {
+        let normalized = normalize_model_name(config);
+        if normalized.is_empty() {
+            continue;
+        }
+
+        for model in &models {
+            let common = longest_common_substring(model, &normalized);
+            let score = 2.0 * common as f64 / (model.len() + normalized.len()) as f64;
+
+            if score >= MIN_MATCH_SCORE && best.map_or(true, |(s, _)| score > s) {
+                best = Some((score, config));
+            }
+        }
+    }
+
+    best.map(|(_, config)| config.clone())
 }
 
 #[derive(Debug, Clone, Default)]
@@ -145,23 +337,197 @@ impl ControlConfigLoader {
         }
     }
 
+    /// Get the sorted names (without extension) of the control configs which can be loaded.
+    pub(crate) fn available_configs(&self) -> Result<Vec<String>> {
+        let mut configs = Vec::new();
+
+        for dir in &self.allowed_paths {
+            for entry in read_dir(dir).context(IterDir { dir })? {
+                let path = entry.context(IterDir { dir })?.path();
+                let supported = path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map_or(false, |ext| SUPPORTED_EXTENSIONS.contains_key(ext));
+
+                if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                    if supported && path.is_file() && !name.contains(&INVALID_CHARS[..]) {
+                        configs.push(name.to_owned());
+                    }
+                }
+            }
+        }
+
+        configs.sort_unstable();
+        configs.dedup();
+
+        Ok(configs)
+    }
+
+    /// Find the available control config matching the laptop model given by the DMI.
+    /// Returns `None` if there is no good candidate.
+    pub(crate) fn detect_config(&self) -> Result<Option<String>> {
+        let models: Vec<String> = DMI_PATHS
+            .iter()
+            .filter_map(|p| read_to_string(p).ok())
+            .map(|m| m.trim().to_owned())
+            .collect();
+        info!("Detecting fan control configuration for {:?}", models);
+
+        Ok(best_config_match(&models, &self.available_configs()?))
+    }
+
     /// Loads the fan control configuration.
     pub(crate) fn load_control_config<S: AsRef<str>>(&self, name: S) -> Result<FanControlConfigV2> {
         let name = name.as_ref();
         info!("Loading fan control configuration '{}'", name);
 
+        let c = self.read_control_config(name, &mut Vec::new())?;
+        validate_control_config(name, &c)?;
+
+        Ok(c)
+    }
+
+    /// Read the control config `name`, merged on top of the config it extends if any.
+    ///
+    /// `chain` holds the configs extended by the ones being read, to detect the cycles.
+    fn read_control_config(
+        &self,
+        name: &str,
+        chain: &mut Vec<String>,
+    ) -> Result<FanControlConfigV2> {
+        if chain.iter().any(|c| c == name) {
+            chain.push(name.to_owned());
+            return ExtendsCycle {
+                chain: chain.clone(),
+            }
+            .fail();
+        }
+
         let (path, de) = self.get_file_path(name)?;
 
-        let mut config_file = File::open(path).context(Loading { name })?;
+        let mut config_file = File::open(&path).context(Loading { name })?;
 
         let mut buf = String::new();
         config_file
             .read_to_string(&mut buf)
             .context(Loading { name })?;
 
-        let c = de(name, buf)?;
+        // Only the JSON configs can extend another one, the XML ones are the NBFC format.
+        if path.extension().map_or(true, |ext| ext != "json") {
+            return de(name, buf);
+        }
+        let mut overrides: Value =
+            serde_json::from_str(&buf).context(ControlJsonDeserialize { name })?;
+        let base_name = match overrides
+            .as_object_mut()
+            .and_then(|o| o.remove(EXTENDS_KEY))
+        {
+            Some(base_name) => serde_json::from_value::<String>(base_name)
+                .context(ControlJsonDeserialize { name })?,
+            None => return de(name, buf),
+        };
+
+        chain.push(name.to_owned());
+        let base = self.read_control_config(&base_name, chain)?;
+        chain.pop();
 
-        Ok(c)
+        let mut merged = serde_json::to_value(base).context(ControlJsonDeserialize { name })?;
+        merge_control_config(&mut merged, overrides);
+        serde_json::from_value(merged).context(ControlJsonDeserialize { name })
+    }
+
+    /// Get the names of the JSON control configs extending `name` directly.
+    /// The configs which can't be read are ignored, since they can't be loaded anyway.
+    pub(crate) fn extending_configs(&self, name: &str) -> Result<Vec<String>> {
+        let mut extending = Vec::new();
+
+        for config in self.available_configs()? {
+            let (path, _) = self.get_file_path(&config)?;
+            if read_extends(&path).as_deref() == Some(name) {
+                extending.push(config);
+            }
+        }
+
+        Ok(extending)
+    }
+
+    /// Get `name` followed by the names of the configs it extends, directly or not.
+    /// The chain ends at the first config which can't be read.
+    pub(crate) fn extended_configs(&self, name: &str) -> Vec<String> {
+        let mut chain = vec![name.to_owned()];
+        while let Some(base) = self
+            .get_file_path(&chain[chain.len() - 1])
+            .ok()
+            .and_then(|(path, _)| read_extends(&path))
+        {
+            if chain.contains(&base) {
+                break;
+            }
+            chain.push(base);
+        }
+        chain
+    }
+
+    /// Fail if another control config extends `name`, which would break it.
+    fn ensure_not_extended(&self, name: &str) -> Result<()> {
+        let by = self.extending_configs(name)?;
+        ensure!(by.is_empty(), Extended { name, by });
+        Ok(())
+    }
+
+    /// Rename the control config `old` to `new`, in the same directory and format.
+    /// A config extended by another one can't be renamed.
+    pub(crate) fn rename_config(&self, old: &str, new: &str) -> Result<()> {
+        let (path, _) = self.get_file_path(old)?;
+        ensure!(is_valid_config_name(new), InvalidChars { name: new });
+        match self.get_file_path(new) {
+            Err(ControlConfigLoadError::InexistentConfig { .. }) => {}
+            Ok(_) => return AlreadyExists { name: new }.fail(),
+            Err(e) => return Err(e),
+        }
+        self.ensure_not_extended(old)?;
+
+        let mut new_path = path.with_file_name(new);
+        if let Some(ext) = path.extension() {
+            new_path.set_extension(ext);
+        }
+        info!("Renaming fan control configuration '{}' to '{}'", old, new);
+        rename(&path, new_path).context(Modify { name: old })
+    }
+
+    /// Delete the control config `name` from the disk.
+    /// A config extended by another one can't be deleted.
+    pub(crate) fn delete_config(&self, name: &str) -> Result<()> {
+        let (path, _) = self.get_file_path(name)?;
+        self.ensure_not_extended(name)?;
+        info!("Deleting fan control configuration '{}'", name);
+        remove_file(path).context(Modify { name })
+    }
+
+    /// Write `config` to `dir` as the JSON control config `name`.
+    ///
+    /// Only a config previously saved in `dir` can be replaced, not the ones of the other paths,
+    /// and not if another config extends it.
+    pub(crate) fn save_config(
+        &self,
+        name: &str,
+        config: &FanControlConfigV2,
+        dir: &Path,
+    ) -> Result<()> {
+        ensure!(is_valid_config_name(name), InvalidChars { name });
+        validate_control_config(name, config)?;
+
+        let path = dir.join(name).with_extension("json");
+        match self.get_file_path(name) {
+            Ok((existing, _)) if existing != path => return AlreadyExists { name }.fail(),
+            Ok(_) => self.ensure_not_extended(name)?,
+            Err(ControlConfigLoadError::InexistentConfig { .. }) => {}
+            Err(e) => return Err(e),
+        }
+
+        let content = config.to_json().context(ControlJsonSerialize { name })?;
+        info!("Saving fan control configuration '{}'", name);
+        write(path, content).context(Modify { name })
     }
 
     /// Test if the fan control config provided can be loaded.
@@ -173,23 +539,14 @@ impl ControlConfigLoader {
         let name = name.as_ref();
         info!("Testing fan control configuration '{}'", name);
 
-        let (path, de) = self.get_file_path(name)?;
-
-        File::open(path)
-            .context(Loading { name })
-            .and_then(|mut f| {
-                let mut buf = String::new();
-                f.read_to_string(&mut buf).context(Loading { name })?;
+        let c = self.read_control_config(name, &mut Vec::new())?;
+        validate_control_config(name, &c)?;
 
-                let c = de(name, buf)?;
-
-                if !check_config {
-                    return Ok(());
-                }
+        if !check_config {
+            return Ok(());
+        }
 
-                check_control_config(&c).context(Check { name })
-            })
-            .map(|_| {})
+        check_control_config(&c).context(Check { name })
     }
 }
 
@@ -199,6 +556,7 @@ mod tests {
     use std::{fs::read_to_string, panic};
 
     use super::*;
+    use crate::test_utils::TempDir;
     use rstest::*;
 
     #[fixture]
@@ -286,6 +644,7 @@ mod tests {
             author: Some("Daniel Andersen".to_string()),
             ec_poll_interval: 1000,
             read_write_words: true,
+            big_endian_words: false,
             critical_temperature: 90,
             fan_configurations: [FanConfiguration {
                 read_register: 149,
@@ -339,6 +698,12 @@ mod tests {
                     }]
                     .to_vec(),
                 ),
+                rpm_read_register: None,
+                interpolate_overrides: false,
+                read_register_high: None,
+                write_register_high: None,
+                kickstart_speed: None,
+                kickstart_duration: None,
             }]
             .to_vec(),
             register_write_configurations: Some(
@@ -380,6 +745,79 @@ mod tests {
         );
     }
 
+    #[rstest]
+    fn list_available_configs(
+        not_follow_loader: ControlConfigLoader,
+        follow_loader: ControlConfigLoader,
+    ) {
+        assert!(not_follow_loader.available_configs().unwrap().is_empty());
+
+        assert_eq!(
+            follow_loader.available_configs().unwrap(),
+            vec![
+                "broken_config".to_string(),
+                "invalid".to_string(),
+                "not_complete_config".to_string(),
+                "valid_json".to_string(),
+                "valid_xml".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn xml_version_detection() {
+        let v1 = r##"<?xml version="1.0"?>
+<FanControlConfig xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <NotebookModel>Sony Vaio VPCEB</NotebookModel>
+  <FanConfigurations>
+    <FanConfiguration>
+      <ReadRegister>147</ReadRegister>
+      <WriteRegister>148</WriteRegister>
+      <MinSpeedValue>0</MinSpeedValue>
+      <MaxSpeedValue>100</MaxSpeedValue>
+      <ResetValue>50</ResetValue>
+    </FanConfiguration>
+  </FanConfigurations>
+</FanControlConfig>"##;
+        assert_eq!(xml_root_element(v1).as_deref(), Some(XML_V1_ROOT));
+
+        let config = xml_deserializer("v1", v1.to_string()).unwrap();
+        assert_eq!(config.notebook_model, "Sony Vaio VPCEB");
+        assert_eq!(config.fan_configurations[0].fan_speed_reset_value, Some(50));
+
+        let v2 = read_to_string("tests/follow/xml/valid_xml.xml").unwrap();
+        assert_eq!(
+            xml_root_element(&v2).as_deref(),
+            Some(&b"FanControlConfigV2"[..])
+        );
+        assert!(xml_deserializer("v2", v2).is_ok());
+    }
+
+    #[test]
+    fn match_model_name() {
+        let configs: Vec<String> = vec![
+            "Acer Aspire 5750G".into(),
+            "HP Pavilion dv6 Notebook PC".into(),
+            "Lenovo ThinkPad L390".into(),
+            "Lenovo ThinkPad L390 i7-8565U".into(),
+        ];
+
+        assert_eq!(
+            best_config_match(&["hp pavilion DV6 notebook-pc"], &configs),
+            Some("HP Pavilion dv6 Notebook PC".into())
+        );
+        assert_eq!(
+            best_config_match(&["20NR001LGE", "ThinkPad L390"], &configs),
+            Some("Lenovo ThinkPad L390".into())
+        );
+        assert_eq!(
+            best_config_match(&["Aspire 5750G"], &configs),
+            Some("Acer Aspire 5750G".into())
+        );
+        assert_eq!(best_config_match(&["Aspire"], &configs), None);
+        assert_eq!(best_config_match::<&str>(&[], &configs), None);
+    }
+
     #[rstest]
     fn test_and_check_config(follow_loader: ControlConfigLoader) {
         assert!(follow_loader
@@ -404,4 +842,191 @@ mod tests {
             .test_control_config("not_complete_config", true)
             .is_err());
     }
+
+    #[rstest]
+    fn refuse_invalid_config(follow_loader: ControlConfigLoader) {
+        let error = follow_loader
+            .load_control_config("broken_config")
+            .unwrap_err();
+        match &error {
+            ControlConfigLoadError::Invalid { issues, .. } => assert_eq!(
+                issues,
+                &vec![
+                    ValidationIssue::DuplicateThreshold {
+                        fan_index: 0,
+                        up_threshold: 60
+                    },
+                    ValidationIssue::PercentOutOfRange {
+                        fan_index: 0,
+                        percent: 150.0
+                    },
+                ]
+            ),
+            e => panic!("Incorrect error: {}", e),
+        }
+        assert!(error.to_string().contains("outside 0-100: 150"));
+
+        assert!(follow_loader
+            .test_control_config("broken_config", false)
+            .is_err());
+    }
+
+    #[test]
+    fn rename_and_delete_config() {
+        let dir = TempDir::new("manage");
+        std::fs::copy("tests/follow/json/valid_json.json", dir.join("first.json")).unwrap();
+        std::fs::copy("tests/follow/json/valid_json.json", dir.join("second.json")).unwrap();
+        std::fs::write(dir.join("quiet.json"), r#"{ "Extends": "second" }"#).unwrap();
+        let mut loader = ControlConfigLoader::new(false);
+        loader.add_path(&dir).unwrap();
+
+        let extended_rename = loader.rename_config("second", "other");
+        let extended_delete = loader.delete_config("second");
+        loader.delete_config("quiet").unwrap();
+        let invalid_name = loader.rename_config("first", "../first");
+        let existing_name = loader.rename_config("first", "second");
+        loader.rename_config("first", "renamed").unwrap();
+        loader.delete_config("second").unwrap();
+        let configs = loader.available_configs().unwrap();
+        let inexistent = loader.delete_config("second");
+
+        for extended in [extended_rename, extended_delete] {
+            match extended {
+                Err(ControlConfigLoadError::Extended { name, by }) => {
+                    assert_eq!(name, "second");
+                    assert_eq!(by, vec!["quiet"]);
+                }
+                _ => panic!("The extended config was modified"),
+            }
+        }
+        assert!(matches!(
+            invalid_name,
+            Err(ControlConfigLoadError::InvalidChars { .. })
+        ));
+        assert!(matches!(
+            existing_name,
+            Err(ControlConfigLoadError::AlreadyExists { .. })
+        ));
+        assert_eq!(configs, vec!["renamed".to_string()]);
+        assert!(matches!(
+            inexistent,
+            Err(ControlConfigLoadError::InexistentConfig { .. })
+        ));
+    }
+
+    #[test]
+    fn save_config() {
+        let dir = TempDir::new("save");
+        let mut loader = ControlConfigLoader::new(true);
+        loader.add_path(&PathBuf::from("tests")).unwrap();
+        loader.add_path(&dir).unwrap();
+        let mut config = loader.load_control_config("valid_json").unwrap();
+        config.fan_configurations[0]
+            .set_curve(0, &[(40.0, 0.0), (70.0, 100.0)])
+            .unwrap();
+
+        let saved = loader.save_config("edited", &config, &dir);
+        let replaced = loader.save_config("edited", &config, &dir);
+        let loaded = loader.load_control_config("edited");
+        std::fs::write(dir.join("quiet.json"), r#"{ "Extends": "edited" }"#).unwrap();
+        let extended = loader.save_config("edited", &config, &dir);
+        let shadowing = loader.save_config("valid_json", &config, &dir);
+        let invalid_name = loader.save_config("../edited", &config, &dir);
+        config.fan_configurations.clear();
+        let invalid_config = loader.save_config("other", &config, &dir);
+
+        assert!(saved.is_ok());
+        assert!(replaced.is_ok());
+        assert_eq!(
+            loaded.unwrap().fan_configurations[0]
+                .temperature_thresholds
+                .len(),
+            2
+        );
+        assert!(matches!(
+            extended,
+            Err(ControlConfigLoadError::Extended { .. })
+        ));
+        assert!(matches!(
+            shadowing,
+            Err(ControlConfigLoadError::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            invalid_name,
+            Err(ControlConfigLoadError::InvalidChars { .. })
+        ));
+        assert!(matches!(
+            invalid_config,
+            Err(ControlConfigLoadError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn extend_config() {
+        let dir = TempDir::new("extends");
+        std::fs::copy("tests/follow/json/valid_json.json", dir.join("base.json")).unwrap();
+        std::fs::write(
+            dir.join("quiet.json"),
+            r#"{
+                "Extends": "base",
+                "Author": "Me",
+                "FanConfigurations": [
+                    {
+                        "TemperatureThresholds": [
+                            { "UpThreshold": 70, "DownThreshold": 0, "FanSpeed": 0.0 },
+                            { "UpThreshold": 90, "DownThreshold": 60, "FanSpeed": 100.0 }
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("quieter.json"),
+            r#"{ "Extends": "quiet", "Author": null }"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("first.json"), r#"{ "Extends": "second" }"#).unwrap();
+        std::fs::write(dir.join("second.json"), r#"{ "Extends": "first" }"#).unwrap();
+        let mut loader = ControlConfigLoader::new(false);
+        loader.add_path(&dir).unwrap();
+
+        let base = loader.load_control_config("base");
+        let quiet = loader.load_control_config("quiet");
+        let quieter = loader.load_control_config("quieter");
+        let cycle = loader.load_control_config("first");
+
+        let (base, quiet) = (base.unwrap(), quiet.unwrap());
+        assert_eq!(quiet.author.as_deref(), Some("Me"));
+        assert_eq!(quiet.notebook_model, base.notebook_model);
+        // The thresholds are replaced, the other fields of the fan are kept.
+        let (base_fan, fan) = (&base.fan_configurations[0], &quiet.fan_configurations[0]);
+        assert_eq!(fan.temperature_thresholds.len(), 2);
+        assert_eq!(fan.write_register, base_fan.write_register);
+        assert_eq!(
+            quiet.fan_configurations.len(),
+            base.fan_configurations.len()
+        );
+
+        let quieter = quieter.unwrap();
+        assert_eq!(quieter.author, None);
+        assert_eq!(
+            quieter.fan_configurations[0].temperature_thresholds.len(),
+            2
+        );
+
+        match cycle {
+            Err(ControlConfigLoadError::ExtendsCycle { chain }) => {
+                assert_eq!(chain, vec!["first", "second", "first"])
+            }
+            _ => panic!("The cycle was not detected"),
+        }
+
+        assert_eq!(
+            loader.extended_configs("quieter"),
+            vec!["quieter", "quiet", "base"]
+        );
+        assert_eq!(loader.extended_configs("first"), vec!["first", "second"]);
+        assert_eq!(loader.extended_configs("missing"), vec!["missing"]);
+    }
 }
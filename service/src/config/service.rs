@@ -3,24 +3,28 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use once_cell::sync::Lazy;
 use quick_xml::de::from_str as xml_from_str;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use snafu::{ResultExt, Snafu};
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
 use crate::constants::ROOT_CONFIG_PATH;
 use crate::nbfc::NbfcServiceSettings;
+use crate::temp::HWMON_CLASS_PATH;
 
 static EC_SYS_DEV_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/sys/kernel/debug/ec/ec0/io"));
 static ACPI_EC_DEV_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/dev/ec"));
 static PORT_DEV_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/dev/port"));
 static CONFIG_FILE_PATH: Lazy<PathBuf> = Lazy::new(|| ROOT_CONFIG_PATH.join("config.toml"));
+static YAML_CONFIG_FILE_PATH: Lazy<PathBuf> = Lazy::new(|| ROOT_CONFIG_PATH.join("config.yaml"));
 static NBFC_SETTINGS_PATH: Lazy<&Path> =
     Lazy::new(|| Path::new("/etc/NbfcService/NbfcServiceSettings.xml"));
 
-#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 /// Describe the way to access to the EC.
 pub(crate) enum ECAccessMode {
     /// Access to the EC using the `/dev/port` file.
@@ -29,6 +33,9 @@ pub(crate) enum ECAccessMode {
     AcpiEC,
     /// Access to the EC using the module `ec_sys` with `write_support=1`.
     ECSys,
+    /// Control the fans exposed by a hwmon driver (`/sys/class/hwmon/hwmonX/pwmN`) instead of the EC.
+    /// The control config addresses them through virtual registers.
+    PwmSysfs,
     /// Determine the way to access to the EC at run.
     Either,
 }
@@ -45,6 +52,7 @@ impl ECAccessMode {
             ECAccessMode::RawPort => *PORT_DEV_PATH,
             ECAccessMode::AcpiEC => *ACPI_EC_DEV_PATH,
             ECAccessMode::ECSys => *EC_SYS_DEV_PATH,
+            ECAccessMode::PwmSysfs => Path::new(HWMON_CLASS_PATH),
             ECAccessMode::Either => {
                 if PORT_DEV_PATH.exists() {
                     *PORT_DEV_PATH
@@ -58,30 +66,65 @@ impl ECAccessMode {
             }
         }
     }
+
+    /// Get how the accesses to the EC are serialized with the other users of the EC
+    /// (the kernel, the firmware, other programs) with this access mode.
+    pub fn lock_strategy(self, lock_enabled: bool) -> ECLockStrategy {
+        match self {
+            ECAccessMode::AcpiEC | ECAccessMode::ECSys | ECAccessMode::PwmSysfs => {
+                ECLockStrategy::Kernel
+            }
+            _ if lock_enabled => ECLockStrategy::Flock,
+            _ => ECLockStrategy::None,
+        }
+    }
+
+    /// Get the access modes to try for this value, in order.
+    /// [Either](#enum.ECAccessMode) is replaced by all the modes accessing the EC.
+    pub fn candidates(self) -> Vec<ECAccessMode> {
+        match self {
+            ECAccessMode::Either => vec![
+                ECAccessMode::RawPort,
+                ECAccessMode::AcpiEC,
+                ECAccessMode::ECSys,
+            ],
+            mode => vec![mode],
+        }
+    }
 }
 impl Default for ECAccessMode {
     fn default() -> Self {
         ECAccessMode::Either
     }
 }
-impl From<&Path> for ECAccessMode {
-    fn from(s: &Path) -> Self {
-        match s {
-            _s if _s == *PORT_DEV_PATH => ECAccessMode::RawPort,
-            _s if _s == *ACPI_EC_DEV_PATH => ECAccessMode::AcpiEC,
-            _s if _s == *EC_SYS_DEV_PATH => ECAccessMode::ECSys,
-            _ => unreachable!(),
-        }
-    }
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+/// Describe how the accesses to the EC are serialized.
+pub(crate) enum ECLockStrategy {
+    /// The accesses go through the kernel driver, which holds the EC mutex during each transaction.
+    Kernel,
+    /// An advisory lock is held on the device (`core.ec_lock`), for the programs which access
+    /// the I/O ports directly.
+    Flock,
+    /// The accesses are not serialized.
+    None,
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 /// Describe how to get the temperature.
+///
+/// The computed temperature selects the thresholds of the fans which don't have a
+/// `sensors.temperature_source`. It's the CPU temperature by default, the other sensors are
+/// only aggregated when another method is chosen.
 pub(crate) enum TempComputeMethod {
     /// Get the CPU sensor data only.
     CPUOnly,
     /// Compute the average from all valid sensors.
     AllSensors,
+    /// Get the highest temperature of all valid sensors.
+    Max,
+    /// Compute the average from all valid sensors, weighted with `sensors.weights`.
+    WeightedAverage,
 }
 impl Default for TempComputeMethod {
     fn default() -> Self {
@@ -89,18 +132,557 @@ impl Default for TempComputeMethod {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+/// Unit of the temperatures reported over D-Bus.
+/// The temperatures are always handled in Celsius internally.
+pub(crate) enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+impl Default for TemperatureUnit {
+    fn default() -> Self {
+        TemperatureUnit::Celsius
+    }
+}
+impl TemperatureUnit {
+    /// Convert a temperature in Celsius to this unit.
+    pub fn convert(self, temp: f64) -> f64 {
+        match self {
+            TemperatureUnit::Celsius => temp,
+            TemperatureUnit::Fahrenheit => temp * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    /// Convert a temperature in this unit to Celsius.
+    pub fn to_celsius(self, temp: f64) -> f64 {
+        match self {
+            TemperatureUnit::Celsius => temp,
+            TemperatureUnit::Fahrenheit => (temp - 32.0) * 5.0 / 9.0,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "celsius",
+            TemperatureUnit::Fahrenheit => "fahrenheit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "celsius" => Some(TemperatureUnit::Celsius),
+            "fahrenheit" => Some(TemperatureUnit::Fahrenheit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+/// How the selected control config was chosen.
+pub(crate) enum ConfigSource {
+    /// Kept from the default service configuration (or the NBFC one).
+    Default,
+    /// Detected from the model of the laptop when no config was selected.
+    Detected,
+    /// Selected by the user.
+    Manual,
+}
+impl Default for ConfigSource {
+    fn default() -> Self {
+        ConfigSource::Default
+    }
+}
+impl ConfigSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConfigSource::Default => "default",
+            ConfigSource::Detected => "detected",
+            ConfigSource::Manual => "manual",
+        }
+    }
+}
+
 // ANCHOR: ServiceConfig
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Describe how the speed of the fans in automatic mode is selected.
+pub(crate) enum ControlMode {
+    /// Follow the thresholds of the control config.
+    Curve,
+    /// Keep the temperature around `core.pid.setpoint` with a PID controller.
+    Pid,
+}
+impl Default for ControlMode {
+    fn default() -> Self {
+        ControlMode::Curve
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+/// Describe how the speed is computed between the thresholds in the `Curve` control mode.
+pub(crate) enum CurveInterpolation {
+    /// Use the speed of the current threshold, like NBFC.
+    Step,
+    /// Interpolate the speed linearly between the thresholds around the temperature.
+    Linear,
+}
+impl Default for CurveInterpolation {
+    fn default() -> Self {
+        CurveInterpolation::Step
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+/// Describe how the fans are handled when the selected control config can't be loaded.
+pub(crate) enum NoConfigBehavior {
+    /// Give the control of the fans back to the firmware.
+    Firmware,
+    /// Follow a conservative built-in curve.
+    SafeCurve,
+    /// Set the fans to full speed.
+    FullSpeed,
+}
+impl Default for NoConfigBehavior {
+    fn default() -> Self {
+        NoConfigBehavior::Firmware
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+/// Stores the settings of the PID controller.
+pub(crate) struct PidConfig {
+    /// Temperature (in °C) to maintain.
+    pub setpoint: f64,
+    /// Proportional gain (in % per °C).
+    pub kp: f64,
+    /// Integral gain (in % per °C and per second).
+    pub ki: f64,
+    /// Derivative gain (in % per °C per second).
+    pub kd: f64,
+    /// Minimal speed percent.
+    pub min_speed: f64,
+    /// Maximal speed percent.
+    pub max_speed: f64,
+}
+impl Default for PidConfig {
+    fn default() -> Self {
+        PidConfig {
+            setpoint: 60.0,
+            kp: 5.0,
+            ki: 0.1,
+            kd: 0.0,
+            min_speed: 0.0,
+            max_speed: 100.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+/// Speed limits of a fan set by the user.
+pub(crate) struct FanSpeedLimit {
+    /// Minimal speed percent.
+    pub min: f64,
+    /// Maximal speed percent.
+    pub max: f64,
+}
+impl Default for FanSpeedLimit {
+    fn default() -> Self {
+        FanSpeedLimit {
+            min: 0.0,
+            max: 100.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+/// Stores the core settings of the service.
+pub(crate) struct CoreConfig {
+    /// Increase the poll interval while the temperature is stable.
+    pub adaptive_poll: bool,
+    /// Maximum poll interval (in ms) reachable with `adaptive_poll`.
+    pub max_poll_interval: u64,
+    /// Temperature change (in °C) between two reads under which the temperature is considered stable.
+    pub poll_temp_delta: f64,
+    /// Maximum speed change (in % per second) applied to the fans. `0` disables the limit.
+    pub ramp_rate: f64,
+    /// Run the control loop without writing to the EC (same as `--dry-run`).
+    pub dry_run: bool,
+    /// Number of retries for a failed or invalid read of a fan speed. `0` disables the retries.
+    pub read_retries: u8,
+    /// Delay (in ms) between two read retries. The retries of a poll stop once they have waited
+    /// for a tenth of the EC poll interval.
+    pub read_retry_delay: u64,
+    /// Read the fans speeds back after writing them, to check that the EC applied them.
+    pub verify_writes: bool,
+    /// Interval (in ms) after which a fan speed is written again even if it didn't change,
+    /// in case the EC dropped it. `0` writes the speeds at each poll.
+    pub force_write_interval: u64,
+    /// Number of EC errors in a row after which the control is given back to the firmware,
+    /// until the service configuration is reloaded. The service stops at the first error if
+    /// it's not set.
+    pub max_consecutive_ec_errors: Option<u32>,
+    /// Critical temperature (in °C) overriding the one of the control config.
+    pub critical_temperature: Option<u8>,
+    /// Number of polls in a row with the critical temperature reached before entering
+    /// the critical state.
+    pub critical_entry_samples: u32,
+    /// The critical state is left once the temperature is more than this value (in °C)
+    /// below the critical temperature.
+    pub critical_exit_hysteresis: u8,
+    /// Port of the Prometheus metrics endpoint (requires the `metrics` feature).
+    /// The endpoint is disabled if it's not set.
+    pub metrics_port: Option<u16>,
+    /// Address of the Prometheus metrics endpoint.
+    pub metrics_address: String,
+    /// Temperature (in °C) under which the fans in automatic mode are turned off.
+    pub fan_off_below: Option<u8>,
+    /// Access modes tried in order to open the EC, instead of `ec_access_mode`.
+    pub ec_access_modes: Vec<ECAccessMode>,
+    /// Interval (in s) between the tries to open the EC when it can't be opened at startup,
+    /// during which the D-Bus interface is available. The service stops instead if it's `0`.
+    pub retry_ec_open: u64,
+    /// Lock `/dev/port` during each access to the EC.
+    /// The EC is accessed anyway if the lock can't be acquired in time.
+    pub ec_lock: bool,
+    /// Check that every fan spins when the service starts.
+    pub startup_test: bool,
+    /// Time (in s) left to the firmware to initialize the fans before the service takes
+    /// the control of the EC. The temperatures are still read meanwhile.
+    pub startup_delay: u64,
+    /// Give the control back to the firmware before the system goes to sleep.
+    pub restore_on_sleep: bool,
+    /// Give the control back to the firmware when the service stops.
+    /// The control is always given back after an error.
+    pub restore_on_exit: bool,
+    /// Save the state changed at runtime (selected config, target speeds, access mode...) to the
+    /// configuration file. When false, the service never writes the file and the changes only
+    /// live in memory.
+    pub persist_on_exit: bool,
+    /// How the fans are handled when the selected control config can't be loaded and no other one
+    /// is applied, the applied one being kept otherwise.
+    /// Without any control config at startup, the fans are left to the firmware,
+    /// since their registers are not known.
+    pub no_config_behavior: NoConfigBehavior,
+    /// How the speed of the fans in automatic mode is selected.
+    pub control_mode: ControlMode,
+    /// How the speed is computed between the thresholds in the `Curve` control mode
+    /// (`step` or `linear`).
+    pub curve_interpolation: CurveInterpolation,
+    /// Settings of the `Pid` control mode.
+    pub pid: PidConfig,
+    /// Interval (in ms) between two accesses to the EC, overriding the `EcPollInterval` of the
+    /// control config.
+    pub ec_poll_interval: Option<u64>,
+    /// Interval (in ms) between two reads of the temperatures. Defaults to the EC poll interval.
+    pub temp_poll_interval: Option<u64>,
+    /// Write the state of the service to `/run/fancy/state.json` when it changes.
+    pub export_state: bool,
+    /// Time (in minutes) after which the fans in manual mode are set back in automatic mode,
+    /// unless a new manual command is received. Disabled if it's not set.
+    pub manual_timeout: Option<u64>,
+    /// Path of a Unix socket accepting the main D-Bus methods as length-prefixed JSON.
+    /// The socket is disabled if it's not set.
+    pub socket_path: Option<PathBuf>,
+    /// Unit of the temperatures reported over D-Bus (`celsius` or `fahrenheit`).
+    pub temperature_unit: TemperatureUnit,
+    /// Name used for each fan (by index) instead of the one of the control config, in what the
+    /// service reports and in the sensors settings.
+    /// An empty alias keeps the name of the control config.
+    pub fan_aliases: Vec<String>,
+    /// Speed limits set by the user for each fan (by index), applied to every speed written
+    /// (but the one stopping the fan). The fans without limits are not limited.
+    pub fan_speed_limits: Vec<FanSpeedLimit>,
+    /// Offset (in °C, between `-10` and `10`) by which the temperature can rise before the fans in
+    /// automatic mode speed up, whatever the curves. Positive values make the fans quieter,
+    /// negative ones cool more. It adds up to the offset of the power source.
+    pub temperature_offset: f64,
+    /// Run the fans at full speed at the critical temperature whatever their speed limits.
+    pub critical_bypasses_speed_limits: bool,
+    /// Number of samples (temperature and fans speeds) kept in the history. `0` disables it.
+    pub history_length: usize,
+    /// Smoothing factor (between `0` and `1`) of the exponential moving average applied to the
+    /// reported fans speeds. Lower values smooth more, `0` disables the smoothing.
+    /// The control of the fans is not affected.
+    pub reported_speeds_smoothing: f64,
+    /// Number of decimals to which the reported fans speeds are rounded, not rounded if unset.
+    /// `FanSpeedsChanged` is then emitted as soon as a rounded speed changes.
+    pub reported_speeds_decimals: Option<u32>,
+    /// File to which every read and write of the EC is appended (as CSV), for debugging.
+    /// The accesses are not traced if it's not set.
+    pub ec_trace_path: Option<PathBuf>,
+    /// Watch the service configuration and the control configs directory, to reload them
+    /// automatically when they are modified. Only read at startup.
+    pub watch_config: bool,
+}
+impl Default for CoreConfig {
+    fn default() -> Self {
+        CoreConfig {
+            adaptive_poll: false,
+            max_poll_interval: 2000,
+            poll_temp_delta: 1.0,
+            ramp_rate: 0.0,
+            dry_run: false,
+            read_retries: 0,
+            read_retry_delay: 10,
+            verify_writes: false,
+            force_write_interval: 5000,
+            max_consecutive_ec_errors: None,
+            critical_temperature: None,
+            critical_entry_samples: 1,
+            critical_exit_hysteresis: 10,
+            metrics_port: None,
+            metrics_address: "127.0.0.1".to_owned(),
+            fan_off_below: None,
+            ec_access_modes: Vec::new(),
+            retry_ec_open: 0,
+            ec_lock: false,
+            startup_test: false,
+            startup_delay: 0,
+            restore_on_sleep: false,
+            restore_on_exit: true,
+            persist_on_exit: true,
+            no_config_behavior: NoConfigBehavior::default(),
+            control_mode: ControlMode::default(),
+            curve_interpolation: CurveInterpolation::default(),
+            pid: PidConfig::default(),
+            ec_poll_interval: None,
+            temp_poll_interval: None,
+            export_state: false,
+            manual_timeout: None,
+            socket_path: None,
+            temperature_unit: TemperatureUnit::default(),
+            fan_aliases: Vec::new(),
+            fan_speed_limits: Vec::new(),
+            temperature_offset: 0.0,
+            critical_bypasses_speed_limits: true,
+            history_length: 300,
+            reported_speeds_smoothing: 0.0,
+            reported_speeds_decimals: None,
+            ec_trace_path: None,
+            watch_config: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+/// Stores the settings of the temperature sensors.
+pub(crate) struct SensorsConfig {
+    /// Sensors categories (`CPU`, `GPU`, `ACPI`, `NVME`, `DRIVE`) used to compute the temperature,
+    /// along with the labeled GPUs temperatures (e.g. `GPU_EDGE`, `GPU_JUNCTION_1`).
+    /// All the categories are used if it's empty, but not the labeled GPUs temperatures,
+    /// which are already averaged in `GPU`.
+    pub only: Vec<String>,
+    /// Weight of each sensor category for the `WeightedAverage` method.
+    /// Missing categories have a weight of `1`.
+    pub weights: HashMap<String, f64>,
+    /// Smoothing factor (between `0` and `1`) of the exponential moving average
+    /// applied to the temperatures. Lower values smooth more, `0` disables the smoothing.
+    pub smoothing: f64,
+    /// Number of the last samples over which the highest computed temperature is followed by the
+    /// fans, so that a brief dip doesn't slow them down during a sustained load. Unlike the
+    /// smoothing, a rise is followed immediately. The fans following their own sensors are not
+    /// affected. `0` and `1` disable it.
+    pub rolling_max_window: usize,
+    /// Offset (in °C) added to the temperature of each sensor category, to calibrate the sensors
+    /// which read too low or too high. The offsets are applied before the smoothing.
+    pub offsets: HashMap<String, f64>,
+    /// Sensor category followed by each fan (by name, its alias if it has one) in automatic mode.
+    /// The other fans follow the computed temperature.
+    pub temperature_source: HashMap<String, String>,
+    /// Sensors read from the output of a command, used like the other categories.
+    pub external: Vec<ExternalSensor>,
+    /// How each fan (by name, its alias if it has one) follows several sensors in automatic mode,
+    /// instead of its `temperature_source`.
+    pub fan_aggregation: HashMap<String, FanAggregation>,
+    /// Detection of the sensors stuck at the same value.
+    pub stuck: StuckSensorsConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+/// Settings of the detection of the sensors stuck at the same value (e.g. a driver bug).
+pub(crate) struct StuckSensorsConfig {
+    /// Time (in s) under load after which a sensor which kept the same value is suspect,
+    /// `0` (the default) disables the detection.
+    pub timeout: u64,
+    /// Load average (over a minute) per CPU from which the system is under load.
+    pub load: f64,
+    /// Temperature (in °C) used at least for the suspect sensors, which are only reported if unset.
+    pub fallback_temperature: Option<f64>,
+}
+impl Default for StuckSensorsConfig {
+    fn default() -> Self {
+        StuckSensorsConfig {
+            timeout: 0,
+            load: 0.5,
+            fallback_temperature: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case", tag = "mode")]
+/// Aggregation of the sensors followed by a fan.
+pub(crate) enum FanAggregation {
+    /// The fan follows the highest temperature of the sensors, with the curve of the control config.
+    MaxTemperature { sensors: Vec<String> },
+    /// Each sensor has its own curve, and the fan runs at the highest speed required by them
+    /// (in the `Curve` control mode). The threshold and the PID still follow the `temperature_source`.
+    MaxSpeed {
+        curves: HashMap<String, Vec<CurvePoint>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Point of a curve of a sensor.
+pub(crate) struct CurvePoint {
+    /// Temperature (in °C).
+    pub temperature: f64,
+    /// Speed percent.
+    pub speed: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+/// A temperature sensor read by running a command.
+pub(crate) struct ExternalSensor {
+    /// Name of the sensor, as used in `only`, `weights` or `temperature_source`.
+    pub name: String,
+    /// Program and its arguments, printing the temperature (in °C) on its standard output.
+    pub command: Vec<String>,
+    /// Time (in ms) after which the command is killed and the sensor is considered missing.
+    pub timeout: u64,
+}
+impl Default for ExternalSensor {
+    fn default() -> Self {
+        ExternalSensor {
+            name: String::new(),
+            command: Vec::new(),
+            timeout: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// A named set of settings which are applied at once.
+pub(crate) struct Profile {
+    pub selected_fan_config: String,
+    #[serde(default)]
+    pub auto: bool,
+    pub target_fans_speeds: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Activates a profile when the average temperature stays in a range.
+pub(crate) struct ProfileRule {
+    /// Name of the profile to activate.
+    pub profile: String,
+    /// The rule matches when the average temperature (in °C) is above this value.
+    pub above: Option<f64>,
+    /// The rule matches when the average temperature (in °C) is below this value.
+    pub below: Option<f64>,
+    /// Time (in s) during which the rule must match before the profile is activated.
+    #[serde(default)]
+    pub dwell: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+/// Stores the settings of the automatic switching between profiles.
+pub(crate) struct AutoProfilesConfig {
+    /// Duration (in s) of the rolling average of the temperature.
+    pub window: u64,
+    /// Rules checked in order, the first one which matches is used.
+    pub rules: Vec<ProfileRule>,
+}
+impl Default for AutoProfilesConfig {
+    fn default() -> Self {
+        AutoProfilesConfig {
+            window: 30,
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+/// Minimal speed of the fans in automatic mode from a power draw.
+pub(crate) struct PowerFloor {
+    /// Power draw (in W).
+    pub watts: f64,
+    /// Minimal speed percent.
+    pub speed: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default, JsonSchema)]
+#[serde(default)]
+/// Settings applied while the system is on a power source.
+pub(crate) struct PowerSourceConfig {
+    /// Profile activated when the system switches to this power source.
+    pub profile: Option<String>,
+    /// Offset (in °C) by which the temperature can rise before the fans in automatic mode speed up.
+    /// Positive values make the fans quieter. The critical state is not affected.
+    pub temperature_offset: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+/// Stores the settings of the power draw used to speed the fans up before the temperature rises,
+/// and the ones of each power source.
+pub(crate) struct PowerConfig {
+    /// Powercap zone read from `/sys/class/powercap` (the CPU package for Intel RAPL).
+    pub zone: String,
+    /// Points (sorted by power draw) between which the minimal speed is interpolated.
+    /// The power draw is not read if it's empty.
+    pub floor: Vec<PowerFloor>,
+    /// Settings applied while the system is on AC.
+    pub ac: PowerSourceConfig,
+    /// Settings applied while the system is on battery.
+    pub battery: PowerSourceConfig,
+}
+impl Default for PowerConfig {
+    fn default() -> Self {
+        PowerConfig {
+            zone: "intel-rapl:0".to_owned(),
+            floor: Vec::new(),
+            ac: PowerSourceConfig::default(),
+            battery: PowerSourceConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Default, JsonSchema)]
 /// Stores the service configuration which can be written to the disk.
 pub(crate) struct ServiceConfig {
     pub ec_access_mode: ECAccessMode,
     pub selected_fan_config: String,
+    /// How `selected_fan_config` was chosen.
+    #[serde(default)]
+    pub config_source: ConfigSource,
     pub auto: bool,
+    #[serde(default)]
+    pub fans_auto: Vec<bool>,
     pub target_fans_speeds: Vec<f64>,
     #[serde(default)]
     pub temp_compute: TempComputeMethod,
     #[serde(default)]
     pub check_control_config: bool,
+    #[serde(default)]
+    pub core: CoreConfig,
+    #[serde(default)]
+    pub sensors: SensorsConfig,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+    #[serde(default)]
+    pub auto_profiles: AutoProfilesConfig,
+    #[serde(default)]
+    pub power: PowerConfig,
 }
 // ANCHOR_END: ServiceConfig
 
@@ -109,10 +691,17 @@ impl From<NbfcServiceSettings> for ServiceConfig {
         ServiceConfig {
             ec_access_mode: ECAccessMode::default(),
             selected_fan_config: s.selected_config_id,
+            config_source: ConfigSource::Default,
             auto: true, // Doesn't have the same meaning as in NBFC
+            fans_auto: Vec::new(),
             target_fans_speeds: s.target_fan_speeds.iter().map(|s| *s as f64).collect(),
             temp_compute: TempComputeMethod::default(),
             check_control_config: false,
+            core: CoreConfig::default(),
+            sensors: SensorsConfig::default(),
+            profiles: HashMap::new(),
+            auto_profiles: AutoProfilesConfig::default(),
+            power: PowerConfig::default(),
         }
     }
 }
@@ -130,6 +719,9 @@ pub(crate) enum ServiceConfigSaveError {
         source
     ))]
     SaveConfig { source: std::io::Error },
+
+    #[snafu(display("Error occured while serializing service configuration: {}", source))]
+    YamlSerialize { source: serde_yaml::Error },
 }
 
 #[derive(Debug, Snafu)]
@@ -152,11 +744,23 @@ pub(crate) enum ServiceConfigLoadError {
     ))]
     LoadService { source: std::io::Error },
 
-    #[snafu(display("Error occured while deserializing NBFC settings: {}", source))]
-    NbfcSettingsXmlDeserialize { source: quick_xml::DeError },
+    #[snafu(display("The NBFC settings `{}` are invalid: {}", path.display(), source))]
+    NbfcSettingsXmlDeserialize {
+        path: PathBuf,
+        source: quick_xml::DeError,
+    },
+
+    #[snafu(display("The service configuration `{}` is invalid: {}", path.display(), source))]
+    TomlDeserialize {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
 
-    #[snafu(display("Error occured while deserializing service configuration: {}", source))]
-    TomlDeserialize { source: toml::de::Error },
+    #[snafu(display("The service configuration `{}` is invalid: {}", path.display(), source))]
+    YamlDeserialize {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
 
     #[snafu(display("There is no configuration available"))]
     NoConfig {},
@@ -164,6 +768,9 @@ pub(crate) enum ServiceConfigLoadError {
 
 impl ServiceConfig {
     /// Loads the `ServiceConfig` from the disk (Fancy or NBFC format).
+    ///
+    /// The Fancy configuration can be written in TOML (`config.toml`, used first)
+    /// or in YAML (`config.yaml`).
     pub(crate) fn load_service_config() -> Result<Self, ServiceConfigLoadError> {
         let mut buf = String::new();
 
@@ -173,7 +780,18 @@ impl ServiceConfig {
                 .read_to_string(&mut buf)
                 .context(LoadService {})?;
 
-            toml::from_str::<ServiceConfig>(&buf).context(TomlDeserialize {})
+            toml::from_str::<ServiceConfig>(&buf).context(TomlDeserialize {
+                path: &*CONFIG_FILE_PATH,
+            })
+        } else if YAML_CONFIG_FILE_PATH.is_file() {
+            File::open(&*YAML_CONFIG_FILE_PATH)
+                .context(OpenServiceConfig {})?
+                .read_to_string(&mut buf)
+                .context(LoadService {})?;
+
+            serde_yaml::from_str::<ServiceConfig>(&buf).context(YamlDeserialize {
+                path: &*YAML_CONFIG_FILE_PATH,
+            })
         } else if NBFC_SETTINGS_PATH.is_file() {
             File::open(*NBFC_SETTINGS_PATH)
                 .context(OpenNbfcServiceConfig {})?
@@ -181,18 +799,65 @@ impl ServiceConfig {
                 .context(LoadService {})?;
 
             xml_from_str::<NbfcServiceSettings>(&buf)
-                .context(NbfcSettingsXmlDeserialize {})
+                .context(NbfcSettingsXmlDeserialize {
+                    path: *NBFC_SETTINGS_PATH,
+                })
                 .map(|e| e.into())
         } else {
             Err(ServiceConfigLoadError::NoConfig {})
         }
     }
 
+    /// Paths from which the `ServiceConfig` can be loaded, in the order they are tried.
+    pub(crate) fn paths() -> [&'static Path; 3] {
+        [
+            &*CONFIG_FILE_PATH,
+            &*YAML_CONFIG_FILE_PATH,
+            *NBFC_SETTINGS_PATH,
+        ]
+    }
+
     /// Save the `ServiceConfig` to the disk.
+    /// It's saved in YAML if the configuration has been written in this format, in TOML otherwise.
     pub(crate) fn save(&self) -> Result<(), ServiceConfigSaveError> {
-        File::create(&*CONFIG_FILE_PATH)
-            .context(CreateConfig {})?
-            .write_all(toml::to_string_pretty(self).unwrap().as_bytes())
-            .context(SaveConfig {})
+        let (path, content) = if !CONFIG_FILE_PATH.is_file() && YAML_CONFIG_FILE_PATH.is_file() {
+            (
+                &*YAML_CONFIG_FILE_PATH,
+                serde_yaml::to_string(self).context(YamlSerialize {})?,
+            )
+        } else {
+            (&*CONFIG_FILE_PATH, toml::to_string_pretty(self).unwrap())
+        };
+
+        // The configuration is replaced as a whole, it's never left partially written.
+        let temp_path = path.with_extension("tmp");
+        let saved = File::create(&temp_path)
+            .context(CreateConfig {})
+            .and_then(|mut f| f.write_all(content.as_bytes()).context(SaveConfig {}))
+            .and_then(|_| std::fs::rename(&temp_path, path).context(SaveConfig {}));
+        if saved.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        saved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_round_trip() {
+        let mut config = ServiceConfig {
+            selected_fan_config: "Dummy config".to_owned(),
+            target_fans_speeds: vec![30.0, 40.0],
+            ..Default::default()
+        };
+        config.core.fan_off_below = Some(45);
+        config.sensors.weights.insert("GPU".to_owned(), 2.0);
+
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        let parsed: ServiceConfig = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(serde_yaml::to_string(&parsed).unwrap(), yaml);
     }
 }
@@ -0,0 +1,86 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Detection of the other programs which control the fans, and would fight with the service.
+use std::fs;
+use std::path::Path;
+
+pub const PROC_PATH: &str = "/proc";
+
+/// Name of the processes (as in `/proc/<pid>/comm`) controlling the fans,
+/// with the name of the program.
+const CONFLICTING_PROCESSES: &[(&str, &str)] = &[
+    ("thermald", "thermald"),
+    ("asusd", "asusctl"),
+    ("thinkfan", "thinkfan"),
+    ("fancontrol", "fancontrol (lm-sensors)"),
+    ("mbpfan", "mbpfan"),
+    ("i8kmon", "i8kutils"),
+    ("nbfc_service", "NBFC-Linux"),
+];
+
+/// Get a warning for each conflicting program running, from the processes listed in `proc_path`.
+pub(crate) fn find_conflicts(proc_path: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(proc_path) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut programs: Vec<&str> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            e.file_name()
+                .to_str()
+                .map_or(false, |n| n.parse::<u32>().is_ok())
+        })
+        .filter_map(|e| fs::read_to_string(e.path().join("comm")).ok())
+        .filter_map(|comm| {
+            CONFLICTING_PROCESSES
+                .iter()
+                .find(|(process, _)| *process == comm.trim())
+                .map(|(_, program)| *program)
+        })
+        .collect();
+    programs.sort_unstable();
+    programs.dedup();
+
+    programs
+        .into_iter()
+        .map(|program| {
+            format!(
+                "{} is running and may also control the fans, which can make them behave erratically",
+                program
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn conflicting_processes() {
+        let proc_path = TempDir::new("proc");
+        for (pid, comm) in [
+            ("1", "systemd"),
+            ("42", "thermald"),
+            ("43", "thermald"),
+            ("50", "asusd"),
+        ] {
+            fs::create_dir_all(proc_path.join(pid)).unwrap();
+            fs::write(proc_path.join(pid).join("comm"), format!("{}\n", comm)).unwrap();
+        }
+        // Not a process
+        fs::create_dir_all(proc_path.join("sys")).unwrap();
+        fs::write(proc_path.join("sys").join("comm"), "thinkfan\n").unwrap();
+
+        let warnings = find_conflicts(&proc_path);
+
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("asusctl is running"));
+        assert!(warnings[1].starts_with("thermald is running"));
+    }
+}
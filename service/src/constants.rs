@@ -7,5 +7,9 @@ use std::path::{Path, PathBuf};
 
 pub const OBJ_PATH_STR: &str = "/com/musikid/fancy";
 pub const BUS_NAME_STR: &str = "com.musikid.fancy";
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Commit the service was built from, `unknown` outside of a git repository.
+pub const GIT_HASH: &str = env!("FANCY_GIT_HASH");
 pub static ROOT_CONFIG_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/etc/fancy"));
 pub static CONTROL_CONFIGS_DIR_PATH: Lazy<PathBuf> = Lazy::new(|| ROOT_CONFIG_PATH.join("configs"));
+pub static STATE_FILE_PATH: Lazy<&Path> = Lazy::new(|| Path::new("/run/fancy/state.json"));
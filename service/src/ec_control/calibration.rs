@@ -0,0 +1,99 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use std::time::{Duration, Instant};
+
+/// Difference between two values written during the sweep.
+const CALIBRATION_STEP: u8 = 16;
+/// Time given to the fan to reach its speed after a write.
+const CALIBRATION_DWELL: Duration = Duration::from_secs(5);
+
+/// What to do at an EC poll during a calibration.
+#[derive(Debug, PartialEq)]
+pub(crate) enum CalibrationStep {
+    /// The fan has not reached its speed yet.
+    Wait,
+    /// Write the value to the register.
+    Write(u8),
+    /// All the values have been measured.
+    Done,
+}
+
+/// Sweep of the values of a write register, to measure the speed (in RPM) of a fan for each of them.
+#[derive(Debug)]
+pub(crate) struct Calibration {
+    pub fan_index: usize,
+    pub register: u8,
+    /// Value of the register before the sweep, written back afterwards.
+    pub original_value: u8,
+    /// Measured speed for each value written.
+    pub measures: Vec<(u8, f64)>,
+    /// Last value written and when.
+    written: Option<(u8, Instant)>,
+}
+
+impl Calibration {
+    pub fn new(fan_index: usize, register: u8, original_value: u8) -> Self {
+        Calibration {
+            fan_index,
+            register,
+            original_value,
+            measures: Vec::new(),
+            written: None,
+        }
+    }
+
+    /// Record the speed of the fan for the last value once it had time to settle,
+    /// and get the next value to write.
+    pub fn update(&mut self, now: Instant, rpm: f64) -> CalibrationStep {
+        let next = match self.written {
+            None => 0,
+            Some((_, at)) if now.saturating_duration_since(at) < CALIBRATION_DWELL => {
+                return CalibrationStep::Wait
+            }
+            Some((u8::MAX, _)) => {
+                self.measures.push((u8::MAX, rpm));
+                return CalibrationStep::Done;
+            }
+            Some((value, _)) => {
+                self.measures.push((value, rpm));
+                value.saturating_add(CALIBRATION_STEP)
+            }
+        };
+
+        self.written = Some((next, now));
+        CalibrationStep::Write(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_register() {
+        let mut calibration = Calibration::new(0, 0x2f, 42);
+        let mut now = Instant::now();
+        let mut written = Vec::new();
+
+        loop {
+            match calibration.update(now, f64::from(written.last().copied().unwrap_or(0)) * 10.0) {
+                CalibrationStep::Write(value) => written.push(value),
+                CalibrationStep::Wait => panic!("The dwell time has elapsed"),
+                CalibrationStep::Done => break,
+            }
+            assert_eq!(
+                calibration.update(now + Duration::from_secs(1), 0.0),
+                CalibrationStep::Wait
+            );
+            now += CALIBRATION_DWELL;
+        }
+
+        assert_eq!(written.len(), 17);
+        assert_eq!(written[..3], [0, 16, 32]);
+        assert_eq!(written.last(), Some(&255));
+        assert_eq!(calibration.measures.len(), 17);
+        assert_eq!(calibration.measures[1], (16, 160.0));
+        assert_eq!(calibration.measures.last(), Some(&(255, 2550.0)));
+    }
+}
@@ -0,0 +1,67 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::debug;
+
+use super::RW;
+
+use std::io::{Read, Result, Seek, SeekFrom, Write};
+
+/// Discards every write to the EC while keeping the reads, to run the service without any
+/// effect on the hardware.
+#[derive(Debug)]
+pub(crate) struct DryRun<T: RW> {
+    inner: T,
+}
+
+impl<T: RW> From<T> for DryRun<T> {
+    fn from(inner: T) -> Self {
+        DryRun { inner }
+    }
+}
+
+impl<T: RW> Write for DryRun<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        debug!("Dry run: discarding write of {:?}", buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// The fans are never taken from their automatic control, so there is nothing to release.
+impl<T: RW> RW for DryRun<T> {}
+
+impl<T: RW> Read for DryRun<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: RW> Seek for DryRun<T> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn writes_are_discarded() {
+        let mut ec = DryRun::from(Cursor::new(vec![0u8, 42, 0]));
+
+        ec.seek(SeekFrom::Start(1)).unwrap();
+        ec.write_all(&[100]).unwrap();
+
+        let mut value = [0u8; 1];
+        ec.seek(SeekFrom::Start(1)).unwrap();
+        ec.read_exact(&mut value).unwrap();
+        assert_eq!(value[0], 42);
+        assert_eq!(ec.inner.into_inner(), vec![0u8, 42, 0]);
+    }
+}
@@ -1,16 +1,18 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
-use snafu::{ResultExt, Snafu};
+use snafu::{ensure, ResultExt, Snafu};
 
 use std::cell::RefCell;
-use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::rc::Rc;
-use std::time::Duration;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
+use super::pid::PidController;
 use super::read::ECReader;
 use super::write::ECWriter;
-use super::RW;
+use super::{RcWrapper, RW};
 use crate::nbfc::*;
 
 #[derive(Debug, Snafu)]
@@ -20,26 +22,137 @@ pub(crate) enum ECError {
 
     #[snafu(display("An I/O error occured with the reader: {}", source))]
     Reader { source: std::io::Error },
+
+    #[snafu(display("The EC did not apply the speed written for the fan #{}", fan_index))]
+    WriteNotApplied { fan_index: usize },
 }
 
 type Result<T = ()> = std::result::Result<T, ECError>;
 
+/// Speed percent written to the fans during the spin-up test.
+const SPIN_UP_TEST_SPEED: f64 = 50.0;
+
+/// Temperature increase (in °C) above `fan_off_below` required to turn a fan on again.
+const FAN_OFF_HYSTERESIS: f64 = 3.0;
+
+/// Duration of the kickstart of the fans whose control config doesn't set it.
+const DEFAULT_KICKSTART_DURATION: Duration = Duration::from_secs(2);
+
 /// Holds useful information about a fan (not used by the writer or the reader).
 #[derive(Debug)]
 pub(crate) struct FanConfig {
     pub name: String,
     pub thresholds: Vec<TemperatureThreshold>,
     pub current_threshold: usize,
+    /// The last speed percent written for this fan.
+    pub current_speed: Option<f64>,
+    /// The fan is kept off because the temperature is low enough.
+    pub off: bool,
+    /// Controller used in the `Pid` control mode.
+    pub pid: PidController,
+    /// Speed percent written at least when the fan is started while stopped, during
+    /// `kickstart_duration`. The fan is not kickstarted if it's zero.
+    pub kickstart_speed: f64,
+    pub kickstart_duration: Duration,
+    /// End of the kickstart of the fan, if it's starting.
+    pub kickstart_until: Option<Instant>,
+    /// The last raw value written to the EC for this fan.
+    pub raw_value: Option<u16>,
+}
+
+impl FanConfig {
+    /// Get the points (temperature, speed percent) of the fan curve, sorted by temperature.
+    pub fn curve(&self) -> Vec<(f64, f64)> {
+        let mut thresholds: Vec<_> = self.thresholds.iter().collect();
+        thresholds.sort_by_key(|t| t.up_threshold);
+
+        thresholds
+            .into_iter()
+            .map(|t| (f64::from(t.up_threshold), f64::from(t.fan_speed)))
+            .collect()
+    }
+
+    /// Get the speed percent at `temp`, interpolated linearly between the points of the curve.
+    ///
+    /// The speed of the first (or last) point is used below (or above) the curve.
+    pub fn interpolated_speed(&self, temp: f64) -> f64 {
+        interpolate_curve(&self.curve(), temp)
+    }
+}
+
+/// Get the speed percent at `temp`, interpolated linearly between the points of `curve`
+/// (sorted by temperature).
+///
+/// The speed of the first (or last) point is used below (or above) the curve.
+pub(crate) fn interpolate_curve(curve: &[(f64, f64)], temp: f64) -> f64 {
+    match curve.iter().position(|&(t, _)| t > temp) {
+        Some(0) => curve[0].1,
+        Some(i) => {
+            let ((low_temp, low_speed), (high_temp, high_speed)) = (curve[i - 1], curve[i]);
+            low_speed + (temp - low_temp) / (high_temp - low_temp) * (high_speed - low_speed)
+        }
+        None => curve.last().map_or(0.0, |&(_, speed)| speed),
+    }
+}
+
+/// Get the speed percent of the threshold of `curve` (sorted by temperature) reached at `temp`
+/// while the temperature rises.
+///
+/// The speed of the first point is used below the curve.
+pub(crate) fn step_curve(curve: &[(f64, f64)], temp: f64) -> f64 {
+    curve
+        .iter()
+        .rev()
+        .find(|&&(t, _)| t <= temp)
+        .or_else(|| curve.first())
+        .map_or(0.0, |&(_, speed)| speed)
+}
+
+/// Get the highest speed percent required by `curves`, each one evaluated with `evaluate` at the
+/// temperature of its sensor in `temps`, with the sensor requiring it.
+///
+/// Unlike the evaluation of a single curve at the highest temperature, each sensor can require
+/// a high speed at a different temperature. Returns `None` if no sensor is available.
+pub(crate) fn max_curves_speed<'a>(
+    curves: impl IntoIterator<Item = (&'a String, &'a Vec<(f64, f64)>)>,
+    temps: &HashMap<String, f64>,
+    evaluate: fn(&[(f64, f64)], f64) -> f64,
+) -> Option<(&'a String, f64)> {
+    curves
+        .into_iter()
+        .filter_map(|(sensor, curve)| temps.get(sensor).map(|&t| (sensor, evaluate(curve, t))))
+        .fold(None, |max, (sensor, speed)| match max {
+            Some((_, m)) if m >= speed => max,
+            _ => Some((sensor, speed)),
+        })
 }
 
 /// Manages accesses to the EC.
 #[derive(Debug)]
 pub(crate) struct ECManager<T: RW> {
+    pub notebook_model: String,
+    pub author: Option<String>,
     pub poll_interval: Duration,
     pub fan_configs: Vec<FanConfig>,
     pub critical_temperature: u8,
+    /// Read the fans speeds back after writing them to check that they are applied.
+    pub verify_writes: bool,
+    /// Interval after which a speed is written again even if its value didn't change,
+    /// in case the EC dropped it. The speeds are written each time if it's zero.
+    pub force_write_interval: Duration,
+    /// Minimal and maximal speed percent of each fan (by index) set by the user, applied last to
+    /// the speeds written (but the one of `stop_fan`). The fans without limits are not limited.
+    pub speed_limits: Vec<(f64, f64)>,
+    /// Ignore `speed_limits`, to cool the system at the critical temperature.
+    pub bypass_speed_limits: bool,
+    /// Problems of the control config suggesting that it doesn't match the machine (e.g. a fan
+    /// which doesn't exist), found when it was applied.
+    pub mismatches: Vec<String>,
+    /// Last raw value written for each fan, and when.
+    last_writes: Vec<Option<([u8; 2], Instant)>>,
     reader: ECReader<T>,
     writer: ECWriter<T>,
+    ec_device: RcWrapper<T>,
 }
 
 impl<T: RW> ECManager<T> {
@@ -47,11 +160,20 @@ impl<T: RW> ECManager<T> {
         let ec_device = Rc::from(RefCell::from(ec_device));
 
         ECManager {
+            notebook_model: String::new(),
+            author: None,
             poll_interval: Duration::from_nanos(0),
             fan_configs: Vec::new(),
             critical_temperature: 0,
+            verify_writes: false,
+            force_write_interval: Duration::ZERO,
+            speed_limits: Vec::new(),
+            bypass_speed_limits: false,
+            mismatches: Vec::new(),
+            last_writes: Vec::new(),
             writer: ECWriter::new(Rc::clone(&ec_device)),
             reader: ECReader::new(Rc::clone(&ec_device)),
+            ec_device,
         }
     }
 
@@ -69,10 +191,22 @@ impl<T: RW> ECManager<T> {
                         .unwrap_or(format!("Fan #{}", acc)),
                     thresholds: f.temperature_thresholds.to_owned(),
                     current_threshold: 0,
+                    current_speed: None,
+                    off: false,
+                    pid: PidController::default(),
+                    kickstart_speed: f.kickstart_speed.map_or(0.0, f64::from),
+                    kickstart_duration: f
+                        .kickstart_duration
+                        .map_or(DEFAULT_KICKSTART_DURATION, Duration::from_millis),
+                    kickstart_until: None,
+                    raw_value: None,
                 })
             })
             .collect();
 
+        self.last_writes = vec![None; self.fan_configs.len()];
+        self.notebook_model = c.notebook_model.to_owned();
+        self.author = c.author.to_owned();
         self.critical_temperature = c.critical_temperature;
         self.poll_interval = Duration::from_millis(c.ec_poll_interval);
 
@@ -80,9 +214,14 @@ impl<T: RW> ECManager<T> {
             .iter_mut()
             .for_each(|c| c.thresholds.sort());
 
+        self.reader.set_big_endian_words(c.big_endian_words);
         self.reader
             .refresh_config(c.read_write_words, &c.fan_configurations);
 
+        // The registers are checked before the config writes anything.
+        self.mismatches = self.find_mismatches(&c);
+
+        self.writer.set_big_endian_words(c.big_endian_words);
         self.writer
             .refresh_config(
                 c.read_write_words,
@@ -92,50 +231,325 @@ impl<T: RW> ECManager<T> {
             .context(Writer {})
     }
 
+    /// Check that the fans of the control config `c` are plausible for the machine: each one
+    /// should have its own write register, not written by the register write configurations,
+    /// and read a value within its range.
+    fn find_mismatches(&self, c: &FanControlConfigV2) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        let fans = &c.fan_configurations;
+
+        for (i, fan) in fans.iter().enumerate() {
+            let name = &self.fan_configs[i].name;
+            if let Some(other) = fans[..i]
+                .iter()
+                .position(|f| f.write_register == fan.write_register)
+            {
+                mismatches.push(format!(
+                    "{} and {} write to the same register {:#x}",
+                    self.fan_configs[other].name, name, fan.write_register
+                ));
+            }
+
+            let overwritten = c
+                .register_write_configurations
+                .iter()
+                .flatten()
+                .any(|r| r.register == fan.write_register);
+            if overwritten {
+                mismatches.push(format!(
+                    "The write register {:#x} of {} is also written by a register write configuration",
+                    fan.write_register, name
+                ));
+            }
+
+            if let Ok(Some(value)) = self.reader.implausible_value(i) {
+                mismatches.push(format!(
+                    "{} reads {} at register {:#x}, outside of its range: the control config may not match this machine",
+                    name, value, fan.read_register
+                ));
+            }
+        }
+
+        mismatches
+    }
+
+    /// Replace the curve of each fan by `curve` (temperature in °C, speed percent, sorted by
+    /// temperature), when the control config can't be used.
+    ///
+    /// Each point is left below the temperature of the previous one.
+    pub fn set_curves(&mut self, curve: &[(u8, f32)]) {
+        let thresholds: Vec<_> = curve
+            .iter()
+            .enumerate()
+            .map(|(i, &(up_threshold, fan_speed))| TemperatureThreshold {
+                up_threshold,
+                down_threshold: i.checked_sub(1).map_or(0, |p| curve[p].0),
+                fan_speed,
+            })
+            .collect();
+
+        for fan in &mut self.fan_configs {
+            fan.thresholds = thresholds.clone();
+            fan.current_threshold = 0;
+        }
+    }
+
+    /// Get the metadata of the control config (`NotebookModel`, `Author` if it has one,
+    /// `EcPollInterval` and `CriticalTemperature`), or nothing if no config controls the fans.
+    pub fn config_info(&self) -> HashMap<String, String> {
+        let mut info = HashMap::new();
+        if self.fan_configs.is_empty() {
+            return info;
+        }
+
+        info.insert("NotebookModel".to_owned(), self.notebook_model.to_owned());
+        if let Some(author) = &self.author {
+            info.insert("Author".to_owned(), author.to_owned());
+        }
+        info.insert(
+            "EcPollInterval".to_owned(),
+            self.poll_interval.as_millis().to_string(),
+        );
+        info.insert(
+            "CriticalTemperature".to_owned(),
+            self.critical_temperature.to_string(),
+        );
+        info
+    }
+
+    /// Give the control of the fans back to the firmware, and stop controlling them
+    /// until a control config is applied again.
+    pub fn release_fans(&mut self) -> Result {
+        self.reset_ec(true)?;
+        self.fan_configs.clear();
+        self.last_writes.clear();
+        self.mismatches.clear();
+        Ok(())
+    }
+
     /// Refresh the index of the current fan threshold according to the temperature (if necessary).
+    ///
+    /// The fan switches to an upper threshold once the temperature reaches its `UpThreshold`
+    /// and only goes back to a lower one when the temperature falls below the current `DownThreshold`,
+    /// which prevents oscillations around a threshold.
     /// Returns false if the threshold didn't need change.
     ///
     /// # Panics
     ///
-    /// Panics if the temperature cannot be converted to `u8`.
     /// Panics if the thresholds has no elements.
     pub fn refresh_fan_threshold(&mut self, temp: f64, fan_index: usize) -> bool {
-        let temp = temp as u8;
         let fan_config = &mut self.fan_configs[fan_index];
         let thresholds = &fan_config.thresholds;
-        let current = &mut fan_config.current_threshold;
+        let previous = fan_config.current_threshold;
+        let mut current = previous.min(thresholds.len() - 1);
 
-        if temp >= thresholds.last().unwrap().up_threshold {
-            *current = thresholds.len() - 1;
-        } else if temp >= thresholds[*current].down_threshold
-            && temp <= thresholds[*current].up_threshold
+        while current + 1 < thresholds.len()
+            && temp >= f64::from(thresholds[current + 1].up_threshold)
         {
-            return false;
-        } else if matches!(thresholds.iter().find(|t| t.down_threshold != 0), Some(thr) if temp <= thr.down_threshold)
-            || thresholds.len() == 1
-        {
-            *current = 0;
-        } else if let Ok(i) = thresholds.binary_search_by(|el| match el {
-            _t if _t.down_threshold > temp => Ordering::Greater,
-            _t if _t.up_threshold < temp => Ordering::Less,
-            _ => Ordering::Equal,
-        }) {
-            *current = i;
+            current += 1;
+        }
+
+        if current == previous {
+            while current > 0 && temp < f64::from(thresholds[current].down_threshold) {
+                current -= 1;
+            }
         }
 
-        true
+        fan_config.current_threshold = current;
+        current != previous
+    }
+
+    /// Refresh whether the fan specified by `fan_index` should be turned off.
+    ///
+    /// The fan is turned off when the temperature falls below `fan_off_below`
+    /// and turned on again once it reaches `fan_off_below` plus a small hysteresis.
+    /// Returns false if the state didn't need change.
+    pub fn refresh_fan_off(&mut self, temp: f64, fan_index: usize, fan_off_below: f64) -> bool {
+        let fan_config = &mut self.fan_configs[fan_index];
+        let previous = fan_config.off;
+
+        fan_config.off = if previous {
+            temp < fan_off_below + FAN_OFF_HYSTERESIS
+        } else {
+            temp < fan_off_below
+        };
+
+        fan_config.off != previous
+    }
+
+    /// Turn off the fan specified by `fan_index` by writing its minimum speed value.
+    ///
+    /// If `verify` is true, the value is read back to check that the EC stopped the fan.
+    pub fn stop_fan(&mut self, fan_index: usize, verify: bool) -> Result {
+        let applied = if verify {
+            self.writer
+                .write_speed_percent_verified(fan_index, 0.0)
+                .context(Writer {})?
+        } else {
+            self.writer
+                .write_speed_percent(fan_index, 0.0)
+                .context(Writer {})?;
+            true
+        };
+        // The value is not written again on each loop if the EC ignores it.
+        let value = self.writer.raw_speed_value(fan_index, 0.0);
+        self.fan_configs[fan_index].current_speed = Some(0.0);
+        self.fan_configs[fan_index].kickstart_until = None;
+        self.fan_configs[fan_index].raw_value = Some(self.writer.written_value(fan_index, value));
+        self.last_writes[fan_index] = Some((value, Instant::now()));
+        ensure!(applied, WriteNotApplied { fan_index });
+
+        Ok(())
     }
 
     /// Write the speed percent to the EC for the fan specified by `fan_index`.
+    ///
+    /// The write is skipped if the same value was written less than `force_write_interval` ago.
+    /// A stopped fan is started at its `kickstart_speed` at least, until its `kickstart_duration`
+    /// elapsed.
+    /// The speed is then limited to the `speed_limits` of the fan.
     pub fn write_fan_speed(&mut self, fan_index: usize, speed_percent: f64) -> Result {
-        self.writer
-            .write_speed_percent(fan_index, speed_percent)
-            .context(Writer {})
+        let now = Instant::now();
+        let speed_percent = self.kickstarted_speed(fan_index, speed_percent, now);
+        let speed_percent = self.limited_speed(fan_index, speed_percent);
+        let value = self.writer.raw_speed_value(fan_index, speed_percent);
+        if let Some((last_value, at)) = self.last_writes[fan_index] {
+            if last_value == value && now.saturating_duration_since(at) < self.force_write_interval
+            {
+                self.fan_configs[fan_index].current_speed = Some(speed_percent);
+                return Ok(());
+            }
+        }
+
+        if self.verify_writes {
+            let applied = self
+                .writer
+                .write_speed_percent_verified(fan_index, speed_percent)
+                .context(Writer {})?;
+            ensure!(applied, WriteNotApplied { fan_index });
+        } else {
+            self.writer
+                .write_speed_percent(fan_index, speed_percent)
+                .context(Writer {})?;
+        }
+        self.fan_configs[fan_index].current_speed = Some(speed_percent);
+        self.fan_configs[fan_index].raw_value = Some(self.writer.written_value(fan_index, value));
+        self.last_writes[fan_index] = Some((value, now));
+
+        Ok(())
+    }
+
+    /// Get `speed_percent` within the speed limits of the fan specified by `fan_index`.
+    fn limited_speed(&self, fan_index: usize, speed_percent: f64) -> f64 {
+        match self.speed_limits.get(fan_index) {
+            Some(&(min, max)) if !self.bypass_speed_limits => speed_percent.max(min).min(max),
+            _ => speed_percent,
+        }
+    }
+
+    /// Get the speed percent to write instead of `speed_percent` to start the fan specified by
+    /// `fan_index`, if it's stopped or still starting.
+    fn kickstarted_speed(&mut self, fan_index: usize, speed_percent: f64, now: Instant) -> f64 {
+        let fan_config = &mut self.fan_configs[fan_index];
+        if speed_percent <= 0.0 || fan_config.kickstart_speed <= 0.0 {
+            fan_config.kickstart_until = None;
+            return speed_percent;
+        }
+
+        // The state of the fan is unknown before the first write, it may be stopped.
+        let stopped = fan_config.current_speed.map_or(true, |s| s <= 0.0);
+        if stopped && speed_percent < fan_config.kickstart_speed {
+            fan_config.kickstart_until = Some(now + fan_config.kickstart_duration);
+        }
+        match fan_config.kickstart_until {
+            Some(until) if now < until => speed_percent.max(fan_config.kickstart_speed),
+            _ => {
+                fan_config.kickstart_until = None;
+                speed_percent
+            }
+        }
+    }
+
+    /// Write a speed percent closer to `target_percent` for the fan specified by `fan_index`,
+    /// changing the last written speed by `max_step` at most.
+    pub fn write_fan_speed_ramped(
+        &mut self,
+        fan_index: usize,
+        target_percent: f64,
+        max_step: f64,
+    ) -> Result {
+        let speed_percent = match self.fan_configs[fan_index].current_speed {
+            Some(current) => current + (target_percent - current).clamp(-max_step, max_step),
+            None => target_percent,
+        };
+
+        self.write_fan_speed(fan_index, speed_percent)
+    }
+
+    /// Command every fan to a test speed, wait `delay` for them to spin up and check they spin.
+    ///
+    /// The speed is read from the tachometer register if there is one,
+    /// otherwise the speed percent read back is used.
+    /// Returns whether each fan spins. The fans have to be written again afterwards.
+    pub fn spin_up_test(&mut self, delay: Duration) -> Result<Vec<bool>> {
+        for i in 0..self.fan_configs.len() {
+            self.write_fan_speed(i, SPIN_UP_TEST_SPEED)?;
+        }
+
+        sleep(delay);
+
+        (0..self.fan_configs.len())
+            .map(|i| {
+                let speed = match self.read_fan_rpm(i)? {
+                    Some(rpm) => rpm,
+                    None => self.read_fan_speed(i)?,
+                };
+                Ok(speed > 0.0)
+            })
+            .collect()
     }
 
     /// Reset the EC, including non-required registers when `reset_all` is true.
+    /// The device then also gives the fans back to their automatic control (see `RW::release`).
     pub fn reset_ec(&mut self, reset_all: bool) -> Result {
-        self.writer.reset(reset_all).context(Writer {})
+        self.last_writes.iter_mut().for_each(|w| *w = None);
+        self.writer.reset(reset_all).context(Writer {})?;
+        if reset_all {
+            self.ec_device.borrow_mut().release().context(Writer {})?;
+        }
+        Ok(())
+    }
+
+    /// Set how many times a failed read is retried and the delay between each try.
+    pub fn set_read_retries(&mut self, retries: u8, delay: Duration) {
+        self.reader.set_retries(retries, delay);
+    }
+
+    /// Set the total time the retries of the reads of all the fans can wait during a poll,
+    /// shared equally between the fans.
+    pub fn set_read_retries_budget(&mut self, budget: Duration) {
+        let fans = self.fan_configs.len().max(1) as u32;
+        self.reader.set_max_retries_delay(budget / fans);
+    }
+
+    /// Read the speed in RPM from the EC for the fan specified by `fan_index`,
+    /// if it has a tachometer register.
+    pub fn read_fan_rpm(&mut self, fan_index: usize) -> Result<Option<f64>> {
+        self.reader.read_rpm(fan_index).context(Reader {})
+    }
+
+    /// Read `len` raw registers of the EC from `start`, for debugging.
+    pub fn read_registers(&mut self, start: u8, len: usize) -> Result<Vec<u8>> {
+        self.reader.read_registers(start, len).context(Reader {})
+    }
+
+    /// Write a raw `value` to the `register` of the EC, for the calibration of the fans.
+    pub fn write_register(&mut self, register: u8, value: u8) -> Result {
+        // The register may be the one of a fan.
+        self.last_writes.iter_mut().for_each(|w| *w = None);
+        self.writer
+            .write_register(register, value)
+            .context(Writer {})
     }
 
     /// Read the speed percent from the EC for the fan specified by `fan_index`.
@@ -187,6 +601,38 @@ mod tests {
         });
     }
 
+    #[test]
+    fn config_info() {
+        let mut manager = ECManager::new(Cursor::new(vec![0u8; 256]));
+        assert!(manager.config_info().is_empty());
+
+        let config = FanControlConfigV2 {
+            notebook_model: "Laptop".to_owned(),
+            author: Some("Me".to_owned()),
+            ec_poll_interval: 500,
+            critical_temperature: 85,
+            ..CONFIGS_PARSED[0].clone()
+        };
+        manager.refresh_control_config(config.clone()).unwrap();
+        let info = manager.config_info();
+        assert_eq!(info.len(), 4);
+        assert_eq!(info["NotebookModel"], "Laptop");
+        assert_eq!(info["Author"], "Me");
+        assert_eq!(info["EcPollInterval"], "500");
+        assert_eq!(info["CriticalTemperature"], "85");
+
+        let config = FanControlConfigV2 {
+            author: None,
+            ..config
+        };
+        manager.refresh_control_config(config).unwrap();
+        assert!(!manager.config_info().contains_key("Author"));
+
+        // The fans are not controlled by the config anymore.
+        manager.release_fans().unwrap();
+        assert!(manager.config_info().is_empty());
+    }
+
     #[test]
     fn select_threshold() {
         CONFIGS_PARSED.iter().for_each(|c| {
@@ -199,7 +645,8 @@ mod tests {
             for i in 0..c.fan_configurations.len() {
                 let thresholds = &c.fan_configurations[i].temperature_thresholds;
 
-                let very_high_temperature = 90.0;
+                let very_high_temperature =
+                    f64::from(thresholds.iter().map(|t| t.up_threshold).max().unwrap());
                 manager.refresh_fan_threshold(very_high_temperature, i);
                 assert!(manager.fan_configs[i].current_threshold == thresholds.len() - 1);
 
@@ -239,6 +686,638 @@ mod tests {
         });
     }
 
+    #[test]
+    fn threshold_hysteresis() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.fan_configurations.truncate(1);
+        config.fan_configurations[0].temperature_thresholds = vec![
+            TemperatureThreshold {
+                up_threshold: 0,
+                down_threshold: 0,
+                fan_speed: 0.0,
+            },
+            TemperatureThreshold {
+                up_threshold: 60,
+                down_threshold: 50,
+                fan_speed: 50.0,
+            },
+            TemperatureThreshold {
+                up_threshold: 70,
+                down_threshold: 65,
+                fan_speed: 100.0,
+            },
+        ];
+        manager.refresh_control_config(config).unwrap();
+
+        assert!(!manager.refresh_fan_threshold(59.0, 0));
+        assert_eq!(manager.fan_configs[0].current_threshold, 0);
+
+        assert!(manager.refresh_fan_threshold(60.0, 0));
+        assert_eq!(manager.fan_configs[0].current_threshold, 1);
+
+        // Between the down and the up thresholds, the speed must not change.
+        assert!(!manager.refresh_fan_threshold(55.0, 0));
+        assert_eq!(manager.fan_configs[0].current_threshold, 1);
+        assert!(!manager.refresh_fan_threshold(50.0, 0));
+        assert_eq!(manager.fan_configs[0].current_threshold, 1);
+
+        assert!(manager.refresh_fan_threshold(49.0, 0));
+        assert_eq!(manager.fan_configs[0].current_threshold, 0);
+
+        // A big jump selects the highest reached threshold directly.
+        assert!(manager.refresh_fan_threshold(75.0, 0));
+        assert_eq!(manager.fan_configs[0].current_threshold, 2);
+
+        // A big fall goes through every threshold whose down threshold is reached.
+        assert!(manager.refresh_fan_threshold(64.0, 0));
+        assert_eq!(manager.fan_configs[0].current_threshold, 1);
+        assert!(manager.refresh_fan_threshold(30.0, 0));
+        assert_eq!(manager.fan_configs[0].current_threshold, 0);
+    }
+
+    #[test]
+    fn minimal_thresholds() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.fan_configurations.truncate(1);
+        // A single threshold is followed at any temperature.
+        config.fan_configurations[0].temperature_thresholds = vec![TemperatureThreshold {
+            up_threshold: 60,
+            down_threshold: 50,
+            fan_speed: 40.0,
+        }];
+        manager.refresh_control_config(config.clone()).unwrap();
+
+        for temp in [20.0, 60.0, 90.0] {
+            manager.refresh_fan_threshold(temp, 0);
+            assert_eq!(manager.fan_configs[0].current_threshold, 0);
+            assert_eq!(manager.fan_configs[0].interpolated_speed(temp), 40.0);
+        }
+
+        // The speed of the first threshold is used below it, and the one of the last above it.
+        config.fan_configurations[0].temperature_thresholds = vec![
+            TemperatureThreshold {
+                up_threshold: 70,
+                down_threshold: 60,
+                fan_speed: 100.0,
+            },
+            TemperatureThreshold {
+                up_threshold: 50,
+                down_threshold: 0,
+                fan_speed: 20.0,
+            },
+        ];
+        manager.refresh_control_config(config).unwrap();
+
+        let curve = manager.fan_configs[0].curve();
+        assert_eq!(curve, vec![(50.0, 20.0), (70.0, 100.0)]);
+        for (temp, step, linear) in [
+            (20.0, 20.0, 20.0),
+            (60.0, 20.0, 60.0),
+            (70.0, 100.0, 100.0),
+            (90.0, 100.0, 100.0),
+        ] {
+            assert_eq!(step_curve(&curve, temp), step);
+            assert_eq!(interpolate_curve(&curve, temp), linear);
+        }
+
+        manager.refresh_fan_threshold(20.0, 0);
+        assert_eq!(manager.fan_configs[0].current_threshold, 0);
+        manager.refresh_fan_threshold(90.0, 0);
+        assert_eq!(manager.fan_configs[0].current_threshold, 1);
+    }
+
+    #[test]
+    fn max_speed_of_curves() {
+        let curves: HashMap<String, Vec<(f64, f64)>> = [
+            ("CPU".to_owned(), vec![(50.0, 20.0), (90.0, 100.0)]),
+            // The GPU is hotter but needs less cooling from this fan.
+            ("GPU".to_owned(), vec![(60.0, 0.0), (100.0, 50.0)]),
+            ("NVME".to_owned(), vec![(0.0, 100.0)]),
+        ]
+        .into_iter()
+        .collect();
+        let temps: HashMap<String, f64> = [("CPU".to_owned(), 70.0), ("GPU".to_owned(), 84.0)]
+            .into_iter()
+            .collect();
+
+        assert_eq!(
+            max_curves_speed(&curves, &temps, interpolate_curve),
+            Some((&"CPU".to_owned(), 60.0))
+        );
+        // The highest temperature with a single curve gives another speed.
+        assert_eq!(interpolate_curve(&curves["CPU"], 84.0), 88.0);
+        assert_eq!(
+            max_curves_speed(&curves, &temps, step_curve),
+            Some((&"CPU".to_owned(), 20.0))
+        );
+        assert_eq!(max_curves_speed(&curves, &HashMap::new(), step_curve), None);
+    }
+
+    #[test]
+    fn independent_fan_curves() {
+        let fan = |name: &str, register: u8, thresholds: &str| {
+            format!(
+                r#"<FanConfiguration>
+  <ReadRegister>{0}</ReadRegister>
+  <WriteRegister>{0}</WriteRegister>
+  <MinSpeedValue>0</MinSpeedValue>
+  <MaxSpeedValue>100</MaxSpeedValue>
+  <FanDisplayName>{1}</FanDisplayName>
+  <TemperatureThresholds>{2}</TemperatureThresholds>
+</FanConfiguration>"#,
+                register, name, thresholds
+            )
+        };
+        let threshold = |up: u8, down: u8, speed: u8| {
+            format!(
+                "<TemperatureThreshold><UpThreshold>{}</UpThreshold>\
+                <DownThreshold>{}</DownThreshold><FanSpeed>{}</FanSpeed></TemperatureThreshold>",
+                up, down, speed
+            )
+        };
+        let xml = format!(
+            r#"<FanControlConfigV2>
+<NotebookModel>Two fans</NotebookModel>
+<EcPollInterval>1000</EcPollInterval>
+<ReadWriteWords>false</ReadWriteWords>
+<CriticalTemperature>90</CriticalTemperature>
+<FanConfigurations>{}{}</FanConfigurations>
+</FanControlConfigV2>"#,
+            fan(
+                "Exhaust",
+                10,
+                &[threshold(0, 0, 20), threshold(50, 45, 60)].concat()
+            ),
+            fan(
+                "Intake",
+                11,
+                &[threshold(0, 0, 0), threshold(70, 65, 40)].concat()
+            ),
+        );
+        let config: FanControlConfigV2 = quick_xml::de::from_str::<XmlFanControlConfigV2>(&xml)
+            .unwrap()
+            .into();
+        let fans_speeds: Vec<Vec<f32>> = config
+            .fan_configurations
+            .iter()
+            .map(|f| {
+                f.temperature_thresholds
+                    .iter()
+                    .map(|t| t.fan_speed)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(fans_speeds, vec![vec![20.0, 60.0], vec![0.0, 40.0]]);
+
+        let mut manager = ECManager::new(Cursor::new(vec![0u8; 256]));
+        manager.refresh_control_config(config).unwrap();
+        let speeds: Vec<f32> = (0..2)
+            .map(|i| {
+                manager.refresh_fan_threshold(55.0, i);
+                let fan = &manager.fan_configs[i];
+                fan.thresholds[fan.current_threshold].fan_speed
+            })
+            .collect();
+        assert_eq!(speeds, vec![60.0, 0.0]);
+    }
+
+    #[test]
+    fn ramped_speed() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        manager
+            .refresh_control_config(CONFIGS_PARSED[0].clone())
+            .unwrap();
+
+        // Nothing has been written yet, the target is directly applied.
+        manager.write_fan_speed_ramped(0, 20.0, 10.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(20.0));
+
+        manager.write_fan_speed_ramped(0, 90.0, 10.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(30.0));
+
+        manager.write_fan_speed_ramped(0, 0.0, 25.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(5.0));
+
+        manager.write_fan_speed_ramped(0, 0.0, 25.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(0.0));
+
+        // A direct write is never limited.
+        manager.write_fan_speed(0, 100.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(100.0));
+    }
+
+    /// EC counting the writes.
+    #[derive(Debug)]
+    struct CountingEC {
+        ec: Cursor<Vec<u8>>,
+        writes: Rc<std::cell::Cell<usize>>,
+    }
+    impl Read for CountingEC {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.ec.read(buf)
+        }
+    }
+    impl std::io::Write for CountingEC {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes.set(self.writes.get() + 1);
+            self.ec.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.ec.flush()
+        }
+    }
+    impl RW for CountingEC {}
+    impl std::io::Seek for CountingEC {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.ec.seek(pos)
+        }
+    }
+
+    #[test]
+    fn write_on_change() {
+        let writes = Rc::new(std::cell::Cell::new(0));
+        let mut manager = ECManager::new(CountingEC {
+            ec: Cursor::new(vec![0u8; 256]),
+            writes: Rc::clone(&writes),
+        });
+        manager
+            .refresh_control_config(CONFIGS_PARSED[0].clone())
+            .unwrap();
+        manager.force_write_interval = Duration::from_secs(60);
+
+        manager.write_fan_speed(0, 0.0).unwrap();
+        let count = writes.get();
+        manager.write_fan_speed(0, 0.0).unwrap();
+        assert_eq!(writes.get(), count);
+
+        manager.write_fan_speed(0, 100.0).unwrap();
+        assert!(writes.get() > count);
+        assert_eq!(manager.fan_configs[0].current_speed, Some(100.0));
+
+        // The value is written again once the interval has elapsed.
+        let count = writes.get();
+        manager.force_write_interval = Duration::ZERO;
+        manager.write_fan_speed(0, 100.0).unwrap();
+        assert!(writes.get() > count);
+
+        // And after a reset.
+        manager.force_write_interval = Duration::from_secs(60);
+        manager.reset_ec(false).unwrap();
+        let count = writes.get();
+        manager.write_fan_speed(0, 100.0).unwrap();
+        assert!(writes.get() > count);
+    }
+
+    #[test]
+    fn fan_off() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        manager
+            .refresh_control_config(CONFIGS_PARSED[0].clone())
+            .unwrap();
+
+        assert!(!manager.refresh_fan_off(50.0, 0, 45.0));
+        assert!(manager.refresh_fan_off(44.0, 0, 45.0));
+        assert!(manager.fan_configs[0].off);
+
+        // The fan stays off until the hysteresis is exceeded.
+        assert!(!manager.refresh_fan_off(47.0, 0, 45.0));
+        assert!(manager.fan_configs[0].off);
+        assert!(manager.refresh_fan_off(48.0, 0, 45.0));
+        assert!(!manager.fan_configs[0].off);
+
+        manager.write_fan_speed(0, 50.0).unwrap();
+        manager.stop_fan(0, true).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(0.0));
+    }
+
+    #[test]
+    fn inverted_speed_values() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.read_write_words = false;
+        config.fan_configurations.truncate(1);
+        let fan = &mut config.fan_configurations[0];
+        fan.read_register = fan.write_register;
+        fan.independent_read_min_max_values = false;
+        fan.fan_speed_percentage_overrides = None;
+        // The fan is faster with lower values.
+        fan.min_speed_value = 200;
+        fan.max_speed_value = 50;
+        manager.refresh_control_config(config).unwrap();
+
+        for (percent, value) in [(0.0, 200), (20.0, 170), (100.0, 50)] {
+            assert_eq!(manager.writer.raw_speed_value(0, percent), [value, 0]);
+            manager.write_fan_speed(0, percent).unwrap();
+            assert_eq!(manager.fan_configs[0].raw_value, Some(value.into()));
+            assert!((manager.read_fan_speed(0).unwrap() - percent).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn split_speed_registers() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.read_write_words = false;
+        config.register_write_configurations = None;
+        config.fan_configurations.truncate(1);
+        let fan = &mut config.fan_configurations[0];
+        fan.read_register = 0x40;
+        fan.write_register = 0x40;
+        fan.read_register_high = Some(0x50);
+        fan.write_register_high = Some(0x50);
+        fan.independent_read_min_max_values = false;
+        fan.fan_speed_percentage_overrides = None;
+        fan.min_speed_value = 0;
+        fan.max_speed_value = 1000;
+        fan.fan_speed_reset_value = Some(0x1234);
+        manager.refresh_control_config(config).unwrap();
+
+        // The value is split in little-endian, even if the config doesn't use words.
+        let registers = |manager: &mut ECManager<Cursor<Vec<u8>>>| {
+            let low = manager.read_registers(0x40, 2).unwrap();
+            let high = manager.read_registers(0x50, 2).unwrap();
+            (low, high)
+        };
+        assert_eq!(registers(&mut manager), (vec![0x34, 0], vec![0x12, 0]));
+
+        manager.write_fan_speed(0, 50.0).unwrap();
+        assert_eq!(registers(&mut manager), (vec![0xf4, 0], vec![0x01, 0]));
+        assert_eq!(manager.fan_configs[0].raw_value, Some(500));
+        assert!(manager
+            .writer
+            .write_speed_percent_verified(0, 50.0)
+            .unwrap());
+
+        manager.write_register(0x40, 0xee).unwrap();
+        manager.write_register(0x50, 0x02).unwrap();
+        assert_eq!(manager.read_fan_speed(0).unwrap(), 75.0);
+    }
+
+    #[test]
+    fn word_byte_order() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.register_write_configurations = None;
+        config.fan_configurations.truncate(1);
+        let fan = &mut config.fan_configurations[0];
+        fan.read_register = 0x40;
+        fan.write_register = 0x40;
+        fan.independent_read_min_max_values = false;
+        fan.fan_speed_percentage_overrides = None;
+        fan.fan_speed_reset_value = None;
+        fan.min_speed_value = 0;
+        fan.max_speed_value = 0x2468;
+
+        // Only the low byte is written without words, whatever the byte order.
+        for (words, big_endian, expected) in [
+            (false, false, vec![0x34, 0]),
+            (false, true, vec![0x34, 0]),
+            (true, false, vec![0x34, 0x12]),
+            (true, true, vec![0x12, 0x34]),
+        ] {
+            config.read_write_words = words;
+            config.big_endian_words = big_endian;
+            manager.write_register(0x40, 0).unwrap();
+            manager.write_register(0x41, 0).unwrap();
+            manager.refresh_control_config(config.clone()).unwrap();
+
+            manager.write_fan_speed(0, 50.0).unwrap();
+            assert_eq!(manager.read_registers(0x40, 2).unwrap(), expected);
+            assert!(manager
+                .writer
+                .write_speed_percent_verified(0, 50.0)
+                .unwrap());
+            if words {
+                assert_eq!(manager.read_fan_speed(0).unwrap(), 50.0);
+            }
+        }
+    }
+
+    #[test]
+    fn fans_mismatches() {
+        let mut ec = vec![0u8; 256];
+        ec[0x10] = 200;
+        let mut manager = ECManager::new(Cursor::new(ec));
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.read_write_words = false;
+        config.register_write_configurations = None;
+        let mut fan = config.fan_configurations[0].clone();
+        fan.fan_display_name = None;
+        fan.independent_read_min_max_values = false;
+        fan.fan_speed_percentage_overrides = None;
+        fan.fan_speed_reset_value = None;
+        fan.min_speed_value = 0;
+        fan.max_speed_value = 100;
+        fan.read_register = 0x10;
+        fan.write_register = 0x11;
+        // The config has a second fan, on the same write register.
+        let mut second = fan.clone();
+        second.read_register = 0x12;
+        config.fan_configurations = vec![fan, second];
+        manager.refresh_control_config(config.clone()).unwrap();
+
+        assert_eq!(manager.mismatches.len(), 2);
+        assert!(manager.mismatches[0].starts_with("Fan #1 reads 200 at register 0x10"));
+        assert_eq!(
+            manager.mismatches[1],
+            "Fan #1 and Fan #2 write to the same register 0x11"
+        );
+
+        manager.write_register(0x10, 50).unwrap();
+        config.fan_configurations[1].write_register = 0x13;
+        manager.refresh_control_config(config.clone()).unwrap();
+        assert!(manager.mismatches.is_empty());
+
+        // A word can't be written past the last register.
+        config.read_write_words = true;
+        config.fan_configurations[1].write_register = 0xff;
+        manager.refresh_control_config(config).unwrap();
+        assert!(manager.write_fan_speed(0, 50.0).is_ok());
+        assert!(manager.write_fan_speed(1, 50.0).is_err());
+    }
+
+    #[test]
+    fn release_control() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.read_write_words = false;
+        config.register_write_configurations = None;
+        config.fan_configurations.truncate(1);
+        let fan = &mut config.fan_configurations[0];
+        fan.reset_required = true;
+        fan.fan_speed_reset_value = Some(0xff);
+        let register = fan.write_register;
+        manager.refresh_control_config(config).unwrap();
+
+        manager.write_fan_speed(0, 50.0).unwrap();
+        assert_ne!(manager.read_registers(register, 1).unwrap(), vec![0xff]);
+        // The service stops.
+        manager.reset_ec(true).unwrap();
+        assert_eq!(manager.read_registers(register, 1).unwrap(), vec![0xff]);
+    }
+
+    #[test]
+    fn without_control_config() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.read_write_words = false;
+        config.register_write_configurations = None;
+        config.fan_configurations.truncate(1);
+        let fan = &mut config.fan_configurations[0];
+        fan.reset_required = true;
+        fan.fan_speed_reset_value = Some(0xff);
+        let register = fan.write_register;
+        manager.refresh_control_config(config).unwrap();
+
+        manager.set_curves(&[(0, 40.0), (60, 100.0)]);
+        assert_eq!(
+            manager.fan_configs[0].curve(),
+            vec![(0.0, 40.0), (60.0, 100.0)]
+        );
+        assert_eq!(manager.fan_configs[0].thresholds[1].down_threshold, 0);
+        manager.refresh_fan_threshold(70.0, 0);
+        assert_eq!(manager.fan_configs[0].current_threshold, 1);
+
+        manager.release_fans().unwrap();
+        assert!(manager.fan_configs.is_empty());
+        assert_eq!(manager.read_registers(register, 1).unwrap(), vec![0xff]);
+    }
+
+    #[test]
+    fn kickstart() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.fan_configurations[0].kickstart_speed = Some(60.0);
+        config.fan_configurations[0].kickstart_duration = Some(60_000);
+        manager.refresh_control_config(config).unwrap();
+
+        manager.stop_fan(0, false).unwrap();
+        manager.write_fan_speed(0, 15.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(60.0));
+        // The fan keeps the kickstart speed until the end of the duration.
+        manager.write_fan_speed(0, 20.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(60.0));
+        manager.write_fan_speed(0, 80.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(80.0));
+
+        manager.fan_configs[0].kickstart_until = Some(Instant::now());
+        manager.write_fan_speed(0, 20.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(20.0));
+
+        // A running fan is not kickstarted.
+        manager.write_fan_speed(0, 10.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(10.0));
+
+        // The other fans have no kickstart.
+        if manager.fan_configs.len() > 1 {
+            manager.stop_fan(1, false).unwrap();
+            manager.write_fan_speed(1, 15.0).unwrap();
+            assert_eq!(manager.fan_configs[1].current_speed, Some(15.0));
+        }
+    }
+
+    #[test]
+    fn speed_limits() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        manager
+            .refresh_control_config(CONFIGS_PARSED[0].clone())
+            .unwrap();
+        manager.speed_limits = vec![(20.0, 70.0)];
+
+        manager.write_fan_speed(0, 100.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(70.0));
+        manager.write_fan_speed_ramped(0, 10.0, 100.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(20.0));
+        // The fan can still be stopped.
+        manager.stop_fan(0, false).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(0.0));
+
+        if manager.fan_configs.len() > 1 {
+            manager.write_fan_speed(1, 100.0).unwrap();
+            assert_eq!(manager.fan_configs[1].current_speed, Some(100.0));
+        }
+
+        manager.bypass_speed_limits = true;
+        manager.write_fan_speed(0, 100.0).unwrap();
+        assert_eq!(manager.fan_configs[0].current_speed, Some(100.0));
+    }
+
+    #[test]
+    fn spin_up_test() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        let mut config = CONFIGS_PARSED[0].clone();
+        config.fan_configurations.truncate(1);
+        config.fan_configurations[0].rpm_read_register = Some(200);
+        manager.refresh_control_config(config).unwrap();
+
+        // The tachometer always reads 0
+        assert_eq!(manager.spin_up_test(Duration::ZERO).unwrap(), vec![false]);
+        assert_eq!(
+            manager.fan_configs[0].current_speed,
+            Some(SPIN_UP_TEST_SPEED)
+        );
+    }
+
+    #[test]
+    fn fan_curve() {
+        let ec = Cursor::new(vec![0u8; 256]);
+        let mut manager = ECManager::new(ec);
+        manager
+            .refresh_control_config(CONFIGS_PARSED[0].clone())
+            .unwrap();
+
+        for fan in &manager.fan_configs {
+            let curve = fan.curve();
+            assert_eq!(curve.len(), fan.thresholds.len());
+            assert!(curve.windows(2).all(|p| p[0].0 <= p[1].0));
+        }
+    }
+
+    #[test]
+    fn interpolate_curve() {
+        let threshold = |up_threshold, fan_speed| TemperatureThreshold {
+            up_threshold,
+            down_threshold: 0,
+            fan_speed,
+        };
+        let fan = FanConfig {
+            name: "Fan".to_owned(),
+            thresholds: vec![
+                threshold(40, 20.0),
+                threshold(60, 50.0),
+                threshold(80, 100.0),
+            ],
+            current_threshold: 0,
+            current_speed: None,
+            off: false,
+            pid: PidController::default(),
+            kickstart_speed: 0.0,
+            kickstart_duration: DEFAULT_KICKSTART_DURATION,
+            kickstart_until: None,
+            raw_value: None,
+        };
+
+        assert_eq!(fan.interpolated_speed(30.0), 20.0);
+        assert_eq!(fan.interpolated_speed(40.0), 20.0);
+        assert_eq!(fan.interpolated_speed(50.0), 35.0);
+        assert_eq!(fan.interpolated_speed(70.0), 75.0);
+        assert_eq!(fan.interpolated_speed(90.0), 100.0);
+    }
+
     // #[test]
     // fn requests() {
 
@@ -0,0 +1,143 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::warn;
+
+use super::RW;
+
+use std::io::{Read, Result, Seek, SeekFrom, Write};
+use std::os::unix::io::RawFd;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Maximum time waited for the lock before accessing the EC anyway.
+const LOCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Holds an exclusive advisory lock (`flock`) on the EC device during each read and write,
+/// so that the accesses are serialized with the other programs locking the device.
+///
+/// If the lock can't be acquired in time, the access is done without it (with a warning)
+/// instead of blocking the service.
+#[derive(Debug)]
+pub(crate) struct Locked<T: RW> {
+    inner: T,
+    fd: RawFd,
+    warned: bool,
+}
+
+impl<T: RW> Locked<T> {
+    /// Wrap `inner`, locking the file descriptor `fd`.
+    /// `fd` must stay open as long as `inner` is used (it's usually the file wrapped by `inner`).
+    pub fn new(inner: T, fd: RawFd) -> Self {
+        Locked {
+            inner,
+            fd,
+            warned: false,
+        }
+    }
+
+    /// Try to acquire the lock until `LOCK_TIMEOUT` is reached.
+    /// Returns false if the lock has not been acquired.
+    fn lock(&mut self) -> bool {
+        let start = Instant::now();
+
+        loop {
+            // SAFETY: `flock` has no effect on the memory and fails if `fd` is not valid.
+            if unsafe { libc::flock(self.fd, libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+                self.warned = false;
+                return true;
+            }
+
+            if start.elapsed() >= LOCK_TIMEOUT {
+                // We warn only once until the lock is acquired again.
+                if !self.warned {
+                    warn!(
+                        "Could not lock the EC device after {:?}, accessing it without lock",
+                        LOCK_TIMEOUT
+                    );
+                    self.warned = true;
+                }
+                return false;
+            }
+
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn unlock(&self) {
+        // SAFETY: See `lock`.
+        unsafe { libc::flock(self.fd, libc::LOCK_UN) };
+    }
+
+    fn with_lock<R>(&mut self, f: impl FnOnce(&mut T) -> Result<R>) -> Result<R> {
+        let locked = self.lock();
+        let res = f(&mut self.inner);
+        if locked {
+            self.unlock();
+        }
+
+        res
+    }
+}
+
+impl<T: RW> Write for Locked<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.with_lock(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: RW> RW for Locked<T> {
+    fn release(&mut self) -> Result<()> {
+        self.with_lock(|inner| inner.release())
+    }
+}
+
+impl<T: RW> Read for Locked<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.with_lock(|inner| inner.read(buf))
+    }
+}
+
+impl<T: RW> Seek for Locked<T> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn lock_fallback() {
+        let dir = TempDir::new("lock");
+        let path = dir.join("ec");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        let other = OpenOptions::new().read(true).open(&path).unwrap();
+
+        let fd = file.as_raw_fd();
+        let mut ec = Locked::new(file, fd);
+        ec.write_all(&[42]).unwrap();
+        assert!(!ec.warned);
+
+        // The access is done anyway when another program holds the lock.
+        assert_eq!(unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX) }, 0);
+        ec.seek(SeekFrom::Start(0)).unwrap();
+        let mut value = [0u8; 1];
+        ec.read_exact(&mut value).unwrap();
+        assert_eq!(value[0], 42);
+        assert!(ec.warned);
+    }
+}
@@ -1,16 +1,59 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+mod calibration;
+mod dry_run;
 mod ec_manager;
+mod locked;
+mod overrides;
+mod pid;
+mod pwm_sysfs;
 mod raw_port;
 mod read;
+mod trace;
 mod write;
 use std::io::{Read, Seek, Write};
 
 type RcWrapper<T> = std::rc::Rc<std::cell::RefCell<T>>;
 
-pub(crate) use ec_manager::{ECError, ECManager};
+pub(crate) use ec_manager::{interpolate_curve, max_curves_speed, step_curve, ECError, ECManager};
 
-pub(crate) trait RW: Read + Write + Seek + std::fmt::Debug {}
-impl<T: Read + Write + Seek + std::fmt::Debug> RW for T {}
+/// Device giving access to the EC registers, which must follow the contract expected by the
+/// reader and the writer:
+/// - a register is addressed by seeking to its offset from the start (`SeekFrom::Start`),
+///   from `0` to `255`;
+/// - a read (or a write) accesses the register at the offset, then the next ones for the
+///   following bytes. The words (for the configs with `ReadWriteWords`) are two consecutive
+///   registers, in little-endian (or big-endian with `BigEndianWords`, which the reader and the
+///   writer handle themselves);
+/// - a failed access returns an error instead of a default value.
+///
+/// The devices which don't access the EC through a single file (like the fans of a hwmon driver)
+/// map what they control to registers.
+pub(crate) trait RW: Read + Write + Seek + std::fmt::Debug {
+    /// Give the fans back to their automatic control, once the registers of the control config
+    /// have been reset. Nothing else is needed for an EC.
+    fn release(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl RW for std::fs::File {}
+#[cfg(test)]
+impl RW for std::io::Cursor<Vec<u8>> {}
+impl<T: RW + ?Sized> RW for &mut T {
+    fn release(&mut self) -> std::io::Result<()> {
+        (**self).release()
+    }
+}
+impl<T: RW + ?Sized> RW for Box<T> {
+    fn release(&mut self) -> std::io::Result<()> {
+        (**self).release()
+    }
+}
+pub(crate) use calibration::{Calibration, CalibrationStep};
+pub(crate) use dry_run::DryRun;
+pub(crate) use locked::Locked;
+pub(crate) use pid::PidController;
+pub(crate) use pwm_sysfs::PwmSysfs;
 pub(crate) use raw_port::RawPort;
+pub(crate) use trace::{open_trace, Traced};
@@ -0,0 +1,82 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use crate::nbfc::FanSpeedPercentageOverride;
+
+/// Get the raw value mapped to `percent` by the `overrides` table.
+///
+/// Without `interpolate`, only the exact percentages of the table are mapped (like NBFC does).
+/// Otherwise, the value is interpolated linearly between the two surrounding points.
+pub(super) fn percent_to_value(
+    overrides: &[FanSpeedPercentageOverride],
+    percent: f64,
+    interpolate: bool,
+) -> Option<f64> {
+    let points = overrides
+        .iter()
+        .map(|o| (o.fan_speed_percentage as f64, o.fan_speed_value as f64))
+        .collect();
+    lookup(points, percent, interpolate)
+}
+
+/// Get the percentage mapped to the raw `value` by the `overrides` table.
+///
+/// This is the reverse of [`percent_to_value`].
+pub(super) fn value_to_percent(
+    overrides: &[FanSpeedPercentageOverride],
+    value: u16,
+    interpolate: bool,
+) -> Option<f64> {
+    let points = overrides
+        .iter()
+        .map(|o| (o.fan_speed_value as f64, o.fan_speed_percentage as f64))
+        .collect();
+    lookup(points, value as f64, interpolate)
+}
+
+/// Find `x` in the `(x, y)` points and return the matching `y`.
+fn lookup(mut points: Vec<(f64, f64)>, x: f64, interpolate: bool) -> Option<f64> {
+    if let Some(&(_, y)) = points.iter().find(|(px, _)| (px - x).abs() < f64::EPSILON) {
+        return Some(y);
+    }
+    if !interpolate {
+        return None;
+    }
+
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    points
+        .windows(2)
+        .find(|w| w[0].0 < x && x < w[1].0)
+        .map(|w| w[0].1 + (x - w[0].0) * (w[1].1 - w[0].1) / (w[1].0 - w[0].0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nbfc::OverrideTargetOperation;
+
+    #[test]
+    fn interpolate_overrides() {
+        let overrides: Vec<FanSpeedPercentageOverride> = [(0.0, 10), (50.0, 200), (100.0, 250)]
+            .iter()
+            .map(
+                |&(fan_speed_percentage, fan_speed_value)| FanSpeedPercentageOverride {
+                    fan_speed_percentage,
+                    fan_speed_value,
+                    target_operation: Some(OverrideTargetOperation::ReadWrite),
+                },
+            )
+            .collect();
+
+        assert_eq!(percent_to_value(&overrides, 50.0, false), Some(200.0));
+        assert_eq!(percent_to_value(&overrides, 25.0, false), None);
+        assert_eq!(percent_to_value(&overrides, 25.0, true), Some(105.0));
+        assert_eq!(percent_to_value(&overrides, 75.0, true), Some(225.0));
+
+        assert_eq!(value_to_percent(&overrides, 250, false), Some(100.0));
+        assert_eq!(value_to_percent(&overrides, 105, false), None);
+        assert_eq!(value_to_percent(&overrides, 105, true), Some(25.0));
+        assert_eq!(value_to_percent(&overrides, 225, true), Some(75.0));
+        assert_eq!(value_to_percent(&overrides, 5, true), None);
+    }
+}
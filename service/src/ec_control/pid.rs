@@ -0,0 +1,76 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use crate::config::service::PidConfig;
+
+/// Computes a fan speed percent from the difference between the temperature and a setpoint.
+#[derive(Debug, Default)]
+pub(crate) struct PidController {
+    integral: f64,
+    last_error: Option<f64>,
+}
+
+impl PidController {
+    /// Get the speed percent for the temperature `temp`, `dt` seconds after the last update.
+    ///
+    /// The output is clamped between `min_speed` and `max_speed`, and the integral term
+    /// stops growing while the output is saturated (anti-windup).
+    pub fn update(&mut self, temp: f64, dt: f64, c: &PidConfig) -> f64 {
+        let min = c.min_speed.min(c.max_speed);
+        let max = c.min_speed.max(c.max_speed);
+
+        let error = temp - c.setpoint;
+        let derivative = match self.last_error {
+            Some(last) if dt > 0.0 => (error - last) / dt,
+            _ => 0.0,
+        };
+        self.last_error = Some(error);
+
+        let integral = self.integral + error * dt;
+        let output = c.kp * error + c.ki * integral + c.kd * derivative;
+
+        // The integral is kept only if it doesn't push the output further out of the range.
+        if (min..=max).contains(&output)
+            || (output > max && error < 0.0)
+            || (output < min && error > 0.0)
+        {
+            self.integral = integral;
+        }
+
+        output.clamp(min, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_output() {
+        let config = PidConfig {
+            setpoint: 60.0,
+            kp: 5.0,
+            ki: 1.0,
+            kd: 0.0,
+            min_speed: 20.0,
+            max_speed: 100.0,
+        };
+        let mut pid = PidController::default();
+
+        // Below the setpoint, the output is clamped to the minimal speed.
+        assert_eq!(pid.update(50.0, 1.0, &config), 20.0);
+        assert_eq!(pid.integral, 0.0);
+
+        assert_eq!(pid.update(64.0, 1.0, &config), 24.0);
+        assert_eq!(pid.integral, 4.0);
+
+        // The integral doesn't grow while the output is saturated.
+        for _ in 0..10 {
+            assert_eq!(pid.update(90.0, 1.0, &config), 100.0);
+        }
+        assert_eq!(pid.integral, 4.0);
+
+        // So the speed decreases as soon as the temperature goes back to the setpoint.
+        assert_eq!(pid.update(60.0, 1.0, &config), 20.0);
+    }
+}
@@ -0,0 +1,300 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Fans exposed by a hwmon driver (`/sys/class/hwmon/hwmonX`) instead of the EC registers.
+//!
+//! The attributes of the fans are mapped to virtual registers, so that the control configs address
+//! them like the registers of an EC: `pwmN` (from `0` to `255`) is at register `4 * (N - 1)`
+//! and `fanN_input` (in RPM) is the word at register `4 * (N - 1) + 2`, in little-endian.
+//! The register following `pwmN` reads `0` and ignores the writes, so that `pwmN` can be
+//! accessed as a word too.
+use log::{debug, error, info};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use super::RW;
+
+/// Number of virtual registers.
+const REGISTERS: u64 = 256;
+const REGISTERS_PER_FAN: u64 = 4;
+/// Value of `pwmN_enable` for the manual control of the fan.
+const PWM_MANUAL: &str = "1";
+/// Value of `pwmN_enable` for the automatic control of the fan by the driver.
+const PWM_AUTO: &str = "2";
+/// Names of the drivers of the GPUs, which control their own fans.
+const GPU_DRIVERS: [&str; 3] = ["amdgpu", "nouveau", "radeon"];
+
+#[derive(Debug)]
+pub(crate) struct PwmSysfs {
+    dir: PathBuf,
+    /// The directory stays open for the lifetime of the device.
+    handle: File,
+    position: u64,
+    /// Value of `pwmN_enable` before the service wrote to the fans, restored on release.
+    previous_enable: BTreeMap<u64, String>,
+}
+
+impl PwmSysfs {
+    /// Use the fans of the hwmon device at `dir`.
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        let handle = File::open(&dir)?;
+        Ok(PwmSysfs {
+            dir,
+            handle,
+            position: 0,
+            previous_enable: BTreeMap::new(),
+        })
+    }
+
+    /// Find the first hwmon device in `class_path` which exposes a fan to control,
+    /// the GPUs excepted, and open it.
+    pub fn open(class_path: &'static Path) -> Result<(Box<dyn RW>, RawFd)> {
+        let dir = find_hwmon(class_path)?;
+        info!("Controlling the fans of {}", dir.display());
+
+        let device = PwmSysfs::new(dir)?;
+        let fd = device.handle.as_raw_fd();
+        Ok((Box::from(device) as Box<dyn RW>, fd))
+    }
+
+    fn read_attribute(&self, name: &str) -> Result<u64> {
+        let value = fs::read_to_string(self.dir.join(name))?;
+        value
+            .trim()
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", name, e)))
+    }
+
+    /// Set the fan `fan` in manual control, unless it already is (the driver may have been
+    /// reset since the last write). The fans without `pwmN_enable` are always controllable.
+    fn take_control(&mut self, fan: u64) -> Result<()> {
+        let path = self.dir.join(format!("pwm{}_enable", fan));
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let enable = fs::read_to_string(&path)?.trim().to_owned();
+        if enable != PWM_MANUAL {
+            debug!("Setting pwm{} in manual control", fan);
+            fs::write(&path, PWM_MANUAL)?;
+        }
+        // A fan already in manual control (e.g. after a crash) is given to the driver on release.
+        self.previous_enable.entry(fan).or_insert(enable);
+        Ok(())
+    }
+
+    /// Read the virtual registers from the current position, each attribute being read once.
+    fn read_registers(&self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf
+            .len()
+            .min(REGISTERS.saturating_sub(self.position) as usize);
+        let mut rpm: Option<(u64, [u8; 2])> = None;
+
+        for (register, byte) in (self.position..).zip(buf[..len].iter_mut()) {
+            let fan = register / REGISTERS_PER_FAN + 1;
+            *byte = match register % REGISTERS_PER_FAN {
+                0 => self
+                    .read_attribute(&format!("pwm{}", fan))?
+                    .min(u8::MAX.into()) as u8,
+                1 => 0,
+                offset => {
+                    let value = match rpm {
+                        Some((rpm_fan, value)) if rpm_fan == fan => value,
+                        _ => {
+                            let input = self.read_attribute(&format!("fan{}_input", fan))?;
+                            (input.min(u16::MAX.into()) as u16).to_le_bytes()
+                        }
+                    };
+                    rpm = Some((fan, value));
+                    value[offset as usize - 2]
+                }
+            };
+        }
+        Ok(len)
+    }
+
+    fn write_register(&mut self, register: u64, value: u8) -> Result<()> {
+        let fan = register / REGISTERS_PER_FAN + 1;
+        match register % REGISTERS_PER_FAN {
+            0 => {
+                self.take_control(fan)?;
+                fs::write(self.dir.join(format!("pwm{}", fan)), value.to_string())
+            }
+            1 => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("fan{}_input can't be written", fan),
+            )),
+        }
+    }
+}
+
+/// Find the first hwmon device (in the order of their names) with a `pwm1` attribute, which is
+/// not a GPU.
+fn find_hwmon(class_path: &Path) -> Result<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(class_path)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    dirs.sort();
+
+    dirs.into_iter()
+        .find(|dir| {
+            let name = fs::read_to_string(dir.join("name")).unwrap_or_default();
+            dir.join("pwm1").exists() && !GPU_DRIVERS.contains(&name.trim())
+        })
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "No hwmon device exposes a pwm fan"))
+}
+
+impl Read for PwmSysfs {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.read_registers(buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for PwmSysfs {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = buf
+            .len()
+            .min(REGISTERS.saturating_sub(self.position) as usize);
+        for &value in &buf[..len] {
+            self.write_register(self.position, value)?;
+            self.position += 1;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PwmSysfs {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (REGISTERS, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+
+        let position = base as i64 + offset;
+        if position < 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative register",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+/// The fans are given back to their previous control (usually the automatic one) when the service
+/// doesn't use them anymore, or to the automatic one if they were already in manual control.
+impl RW for PwmSysfs {
+    fn release(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for (fan, enable) in std::mem::take(&mut self.previous_enable) {
+            let enable = if enable == PWM_MANUAL {
+                PWM_AUTO
+            } else {
+                enable.as_str()
+            };
+            debug!("Restoring pwm{}_enable to {}", fan, enable);
+            let written = fs::write(self.dir.join(format!("pwm{}_enable", fan)), enable);
+            // The other fans are restored anyway.
+            result = result.and(written);
+        }
+        result
+    }
+}
+
+impl Drop for PwmSysfs {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            error!(
+                "Error while giving the fans back to {}: {}",
+                self.dir.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn pwm_registers() {
+        let dir = TempDir::new("hwmon");
+        fs::write(dir.join("pwm1"), "128\n").unwrap();
+        fs::write(dir.join("pwm1_enable"), "2\n").unwrap();
+        fs::write(dir.join("fan1_input"), "2400\n").unwrap();
+        fs::write(dir.join("pwm2"), "0\n").unwrap();
+
+        let mut device = PwmSysfs::new(dir.to_path_buf()).unwrap();
+        let mut buf = [0u8; 6];
+        device.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [128, 0, 0x60, 0x09, 0, 0]);
+
+        // The words are written like on an EC, the padding register is ignored.
+        device.seek(SeekFrom::Start(0)).unwrap();
+        device.write_all(&[200, 0]).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("pwm1")).unwrap(), "200");
+        assert_eq!(fs::read_to_string(dir.join("pwm1_enable")).unwrap(), "1");
+        assert!(device.write_all(&[1]).is_err());
+
+        // pwm2 has no `pwm2_enable`.
+        device.seek(SeekFrom::Start(4)).unwrap();
+        device.write_all(&[50]).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("pwm2")).unwrap(), "50");
+
+        // Nothing is accessed past the last register.
+        device.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(device.read(&mut buf).unwrap(), 0);
+        assert!(device.write_all(&[1]).is_err());
+        assert!(device.seek(SeekFrom::Current(-300)).is_err());
+
+        device.release().unwrap();
+        assert_eq!(fs::read_to_string(dir.join("pwm1_enable")).unwrap(), "2");
+
+        // A fan already in manual control is given to the driver.
+        device.seek(SeekFrom::Start(0)).unwrap();
+        fs::write(dir.join("pwm1_enable"), "1\n").unwrap();
+        device.write_all(&[100]).unwrap();
+        drop(device);
+        assert_eq!(fs::read_to_string(dir.join("pwm1_enable")).unwrap(), "2");
+    }
+
+    #[test]
+    fn find_pwm_hwmon() {
+        let class = TempDir::new("hwmon-class");
+        for (dir, name, pwm) in [
+            ("hwmon0", "acpitz", false),
+            ("hwmon1", "amdgpu", true),
+            ("hwmon2", "thinkpad", true),
+        ] {
+            fs::create_dir(class.join(dir)).unwrap();
+            fs::write(class.join(dir).join("name"), format!("{}\n", name)).unwrap();
+            if pwm {
+                fs::write(class.join(dir).join("pwm1"), "0\n").unwrap();
+            }
+        }
+
+        assert_eq!(find_hwmon(&class).unwrap(), class.join("hwmon2"));
+        fs::remove_dir_all(class.join("hwmon2")).unwrap();
+        assert_eq!(find_hwmon(&class).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
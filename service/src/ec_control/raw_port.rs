@@ -118,6 +118,8 @@ impl<T: RW> Write for RawPort<T> {
     }
 }
 
+impl<T: RW> RW for RawPort<T> {}
+
 impl<T: RW> Read for RawPort<T> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         for byte in buf.iter_mut() {
@@ -226,6 +228,8 @@ mod tests {
         }
     }
 
+    impl RW for BufferTest {}
+
     impl Seek for BufferTest {
         fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
             if let SeekFrom::Start(pos) = pos {
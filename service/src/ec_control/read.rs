@@ -3,8 +3,11 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use log::debug;
 
-use std::io::{Error, Read, Seek, SeekFrom};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::thread::sleep;
+use std::time::Duration;
 
+use super::overrides::value_to_percent;
 use super::RcWrapper;
 use crate::nbfc::*;
 
@@ -14,17 +17,25 @@ type Result<T> = std::result::Result<T, Error>;
 /// This strcture contains information for reading from the EC for a fan.
 struct FanReadConfig {
     read_register: u8,
+    read_register_high: Option<u8>,
     max_speed_read: u16,
     min_speed_read: u16,
     read_percent_overrides: Option<Vec<FanSpeedPercentageOverride>>,
+    interpolate_overrides: bool,
+    rpm_read_register: Option<u8>,
 }
 
 #[derive(Debug)]
 /// A structure to manage reads from the EC.
 pub(crate) struct ECReader<R: Read + Seek> {
     read_words: bool,
+    big_endian_words: bool,
     ec_dev: RcWrapper<R>,
     fans_read_config: Vec<FanReadConfig>,
+    retries: u8,
+    retry_delay: Duration,
+    /// Total time a read can wait for its retries.
+    max_retries_delay: Duration,
 }
 
 impl<R: Read + Seek> ECReader<R> {
@@ -32,11 +43,32 @@ impl<R: Read + Seek> ECReader<R> {
     pub fn new(ec_dev: RcWrapper<R>) -> Self {
         ECReader {
             read_words: false,
+            big_endian_words: false,
             ec_dev,
             fans_read_config: Vec::new(),
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            max_retries_delay: Duration::MAX,
         }
     }
 
+    /// Set how many times a failed or invalid read is retried, waiting `delay` between each try.
+    /// Values outside of the fan range are considered invalid only when `retries` is not zero.
+    pub fn set_retries(&mut self, retries: u8, delay: Duration) {
+        self.retries = retries;
+        self.retry_delay = delay;
+    }
+
+    /// Set the total time a read can wait for its retries, the read fails once it's spent.
+    pub fn set_max_retries_delay(&mut self, max_delay: Duration) {
+        self.max_retries_delay = max_delay;
+    }
+
+    /// Set if the words are read with their high byte first.
+    pub fn set_big_endian_words(&mut self, big_endian_words: bool) {
+        self.big_endian_words = big_endian_words;
+    }
+
     /// Refresh the configuration used for reading. NOTE: It doesn't read anything from the controller.
     pub fn refresh_config(&mut self, read_words: bool, fan_configs: &[FanConfiguration]) {
         self.read_words = read_words;
@@ -44,6 +76,7 @@ impl<R: Read + Seek> ECReader<R> {
             .iter()
             .map(|fan| FanReadConfig {
                 read_register: fan.read_register,
+                read_register_high: fan.read_register_high,
                 min_speed_read: if fan.independent_read_min_max_values {
                     fan.min_speed_value_read
                 } else {
@@ -63,6 +96,8 @@ impl<R: Read + Seek> ECReader<R> {
                         .map(|e| e.to_owned())
                         .collect::<Vec<FanSpeedPercentageOverride>>()
                 }),
+                interpolate_overrides: fan.interpolate_overrides,
+                rpm_read_register: fan.rpm_read_register,
             })
             .collect();
     }
@@ -70,18 +105,12 @@ impl<R: Read + Seek> ECReader<R> {
     /// Read the speed value for the fan specified at `fan_index`.
     pub fn read_speed_percent(&self, fan_index: usize) -> Result<f64> {
         let fan = &self.fans_read_config[fan_index];
-        let read_off = SeekFrom::Start(fan.read_register as u64);
-        let speed = self.read_value(read_off)?;
-
-        let percentage: f64 = if let Some(speed_percent) =
-            fan.read_percent_overrides.as_ref().and_then(|f| {
-                f.iter()
-                    .filter(|e| e.fan_speed_value == speed)
-                    .map(|e| e.fan_speed_percentage)
-                    .next()
-            }) {
-            speed_percent.into()
+        let speed = self.read_valid_value(fan)?;
+
+        let percentage: f64 = if let Some(speed_percent) = Self::override_percent(fan, speed) {
+            speed_percent
         } else {
+            // The range is reversed for the fans which are faster with lower values.
             ((speed as f64 - fan.min_speed_read as f64)
                 / (fan.max_speed_read as f64 - fan.min_speed_read as f64))
                 * 100.0
@@ -90,6 +119,113 @@ impl<R: Read + Seek> ECReader<R> {
         Ok(percentage.clamp(0.0, 100.0))
     }
 
+    /// Read the speed (in RPM) of the fan specified at `fan_index`, a word if the config reads
+    /// words (in its byte order), a byte otherwise.
+    /// Returns `None` if the fan has no tachometer register.
+    pub fn read_rpm(&self, fan_index: usize) -> Result<Option<f64>> {
+        match self.fans_read_config[fan_index].rpm_read_register {
+            Some(register) => {
+                let read_off = SeekFrom::Start(register as u64);
+                Ok(Some(self.read_value(read_off)?.into()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read the speed value of the fan specified at `fan_index` once, and return it if it's not a
+    /// possible value for the fan.
+    pub fn implausible_value(&self, fan_index: usize) -> Result<Option<u16>> {
+        let fan = &self.fans_read_config[fan_index];
+        let value = self.read_fan_value(fan)?;
+
+        Ok(Some(value).filter(|&v| !Self::is_valid_value(fan, v)))
+    }
+
+    /// Read `len` registers from `start` as they are, stopping at the last register.
+    pub fn read_registers(&self, start: u8, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len.min(256 - start as usize)];
+        let mut dev = (*self.ec_dev).borrow_mut();
+
+        dev.seek(SeekFrom::Start(start as u64))?;
+        dev.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Read the fan speed value, retrying if the read fails or if the value is out of the fan range.
+    fn read_valid_value(&self, fan: &FanReadConfig) -> Result<u16> {
+        let mut attempt = 0;
+        let mut waited = Duration::ZERO;
+        loop {
+            let result = self.read_fan_value(fan).and_then(|value| {
+                if self.retries == 0 || Self::is_valid_value(fan, value) {
+                    Ok(value)
+                } else {
+                    Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "invalid value {:#x} for register {:#x}",
+                            value, fan.read_register
+                        ),
+                    ))
+                }
+            });
+
+            let can_wait = waited.saturating_add(self.retry_delay) <= self.max_retries_delay;
+            match result {
+                Err(e) if attempt < self.retries && can_wait => {
+                    attempt += 1;
+                    debug!(
+                        "Read failed ({}), retrying ({}/{})",
+                        e, attempt, self.retries
+                    );
+                    sleep(self.retry_delay);
+                    waited += self.retry_delay;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    /// Check if `value` is a possible speed value for the fan.
+    fn is_valid_value(fan: &FanReadConfig, value: u16) -> bool {
+        let min = fan.min_speed_read.min(fan.max_speed_read);
+        let max = fan.min_speed_read.max(fan.max_speed_read);
+
+        (min..=max).contains(&value) || Self::override_percent(fan, value).is_some()
+    }
+
+    /// Get the percentage mapped to `value` by the read overrides of the fan.
+    fn override_percent(fan: &FanReadConfig, value: u16) -> Option<f64> {
+        fan.read_percent_overrides
+            .as_ref()
+            .and_then(|o| value_to_percent(o, value, fan.interpolate_overrides))
+    }
+
+    /// Read the speed value of `fan` from its register, or from its two registers without any
+    /// other access in between.
+    fn read_fan_value(&self, fan: &FanReadConfig) -> Result<u16> {
+        let read_off = SeekFrom::Start(fan.read_register as u64);
+        let high_register = match fan.read_register_high {
+            Some(register) => register,
+            None => return self.read_value(read_off),
+        };
+
+        let mut buf = [0u8; 2];
+        let mut dev = (*self.ec_dev).borrow_mut();
+
+        dev.seek(read_off)?;
+        dev.read_exact(&mut buf[..=0])?;
+        dev.seek(SeekFrom::Start(high_register as u64))?;
+        dev.read_exact(&mut buf[1..])?;
+
+        debug!(
+            "Reading at offsets {:#x} (low) and {:#x} (high) the value {:?}",
+            fan.read_register, high_register, &buf
+        );
+
+        Ok(u16::from_le_bytes(buf))
+    }
+
     /// Low-level read function.
     // XXX: The function returns an u16 even if just a u8 is needed
     fn read_value(&self, read_off: SeekFrom) -> Result<u16> {
@@ -106,7 +242,9 @@ impl<R: Read + Seek> ECReader<R> {
 
         debug!("Reading at offset {:?} the value {:?}", read_off, &buf);
 
-        if self.read_words {
+        if self.read_words && self.big_endian_words {
+            Ok(u16::from_be_bytes(buf))
+        } else if self.read_words {
             Ok(u16::from_le_bytes(buf))
         } else {
             Ok(buf[0].into())
@@ -116,6 +254,7 @@ impl<R: Read + Seek> ECReader<R> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::fan_config;
     use once_cell::sync::Lazy;
     use rand::Rng;
     use std::cell::RefCell;
@@ -287,6 +426,125 @@ mod tests {
         });
     }
 
+    /// Returns garbage for the first `garbage_reads` reads.
+    #[derive(Debug)]
+    struct FlakyEC {
+        inner: Cursor<Vec<u8>>,
+        garbage_reads: usize,
+    }
+
+    impl Read for FlakyEC {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.garbage_reads > 0 {
+                self.garbage_reads -= 1;
+                buf.iter_mut().for_each(|b| *b = 0xFF);
+                return Ok(buf.len());
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for FlakyEC {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn read_retries() {
+        let fan = fan_config();
+        let new_reader = |garbage_reads| {
+            let ec = FlakyEC {
+                inner: Cursor::new(vec![0, 50, 0]),
+                garbage_reads,
+            };
+            let mut reader = ECReader::new(Rc::new(RefCell::new(ec)));
+            reader.refresh_config(false, &[fan.clone()]);
+            reader
+        };
+
+        // Without retries, the value is not checked
+        assert_eq!(new_reader(1).read_speed_percent(0).unwrap(), 100.0);
+
+        let mut reader = new_reader(2);
+        reader.set_retries(2, Duration::ZERO);
+        assert_eq!(reader.read_speed_percent(0).unwrap(), 50.0);
+
+        let mut reader = new_reader(3);
+        reader.set_retries(2, Duration::ZERO);
+        assert_eq!(
+            reader.read_speed_percent(0).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        // The retries stop once their delay is spent.
+        let mut reader = new_reader(2);
+        reader.set_retries(2, Duration::from_millis(5));
+        reader.set_max_retries_delay(Duration::from_millis(7));
+        assert_eq!(
+            reader.read_speed_percent(0).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        let mut reader = new_reader(2);
+        reader.set_retries(2, Duration::from_millis(5));
+        reader.set_max_retries_delay(Duration::from_millis(10));
+        assert_eq!(reader.read_speed_percent(0).unwrap(), 50.0);
+    }
+
+    #[test]
+    fn read_interpolated_overrides() {
+        let fan = FanConfiguration {
+            fan_speed_percentage_overrides: Some(
+                [(0.0, 10), (50.0, 200), (100.0, 250)]
+                    .iter()
+                    .map(
+                        |&(fan_speed_percentage, fan_speed_value)| FanSpeedPercentageOverride {
+                            fan_speed_percentage,
+                            fan_speed_value,
+                            target_operation: Some(OverrideTargetOperation::ReadWrite),
+                        },
+                    )
+                    .collect(),
+            ),
+            interpolate_overrides: true,
+            ..fan_config()
+        };
+        let ec = Rc::new(RefCell::new(Cursor::new(vec![0; 256])));
+        let mut reader = ECReader::new(Rc::clone(&ec));
+        reader.refresh_config(false, &[fan]);
+
+        for (value, percent) in [(10, 0.0), (105, 25.0), (200, 50.0), (240, 90.0)] {
+            write(Rc::clone(&ec), 1, &[value]);
+            assert_eq!(reader.read_speed_percent(0).unwrap(), percent);
+        }
+
+        // Out of the overrides, the min and max values are used
+        write(Rc::clone(&ec), 1, &[5]);
+        assert_eq!(reader.read_speed_percent(0).unwrap(), 5.0);
+    }
+
+    #[test]
+    fn read_rpm_size() {
+        let fan = FanConfiguration {
+            rpm_read_register: Some(4),
+            ..fan_config()
+        };
+        // 2400 RPM, in little-endian.
+        let ec = Rc::new(RefCell::new(Cursor::new(vec![0, 0, 0, 0, 0x60, 0x09])));
+        let mut reader = ECReader::new(ec);
+
+        reader.refresh_config(false, &[fan.clone()]);
+        assert_eq!(reader.read_rpm(0).unwrap(), Some(96.0));
+        reader.refresh_config(true, &[fan]);
+        assert_eq!(reader.read_rpm(0).unwrap(), Some(2400.0));
+        reader.set_big_endian_words(true);
+        assert_eq!(reader.read_rpm(0).unwrap(), Some(24585.0));
+
+        reader.refresh_config(true, &[fan_config()]);
+        assert_eq!(reader.read_rpm(0).unwrap(), None);
+    }
+
     fn write(ec: RcWrapper<Cursor<Vec<u8>>>, pos: u64, value: &[u8]) {
         let mut ec = (*ec).borrow_mut();
         ec.set_position(pos);
@@ -0,0 +1,119 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use super::RW;
+
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Read, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TRACE_HEADER: &str = "timestamp,operation,register,value";
+
+/// Records each read and write of the EC in a CSV trace, with its time (in seconds since the Unix
+/// epoch), the first register accessed and the bytes in hexadecimal (`error` if it failed).
+#[derive(Debug)]
+pub(crate) struct Traced<T: RW, W: Write + std::fmt::Debug = LineWriter<File>> {
+    inner: T,
+    trace: W,
+    /// Register at the current position of `inner`.
+    position: u64,
+}
+
+/// Open the trace at `path`, appending to it if it already exists.
+pub(crate) fn open_trace(path: &Path) -> Result<LineWriter<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut trace = LineWriter::new(file);
+    writeln!(trace, "{}", TRACE_HEADER)?;
+    Ok(trace)
+}
+
+impl<T: RW, W: Write + std::fmt::Debug> Traced<T, W> {
+    pub fn new(inner: T, trace: W) -> Self {
+        Traced {
+            inner,
+            trace,
+            position: 0,
+        }
+    }
+
+    fn record(&mut self, operation: &str, result: &Result<usize>, buf: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        let value = match result {
+            Ok(len) => buf[..*len].iter().map(|b| format!("{:02x}", b)).collect(),
+            Err(_) => "error".to_owned(),
+        };
+
+        // The trace is only a debugging help, its errors never stop the accesses.
+        let _ = writeln!(
+            self.trace,
+            "{:.6},{},{:#04x},{}",
+            timestamp, operation, self.position, value
+        );
+        if let Ok(len) = result {
+            self.position += *len as u64;
+        }
+    }
+}
+
+impl<T: RW, W: Write + std::fmt::Debug> Write for Traced<T, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let result = self.inner.write(buf);
+        self.record("write", &result, buf);
+        result
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let _ = self.trace.flush();
+        self.inner.flush()
+    }
+}
+
+impl<T: RW, W: Write + std::fmt::Debug> RW for Traced<T, W> {
+    fn release(&mut self) -> Result<()> {
+        self.inner.release()
+    }
+}
+
+impl<T: RW, W: Write + std::fmt::Debug> Read for Traced<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let result = self.inner.read(buf);
+        self.record("read", &result, buf);
+        result
+    }
+}
+
+impl<T: RW, W: Write + std::fmt::Debug> Seek for Traced<T, W> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = self.inner.seek(pos)?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn trace_accesses() {
+        let mut ec = Traced::new(Cursor::new(vec![0u8; 256]), Vec::new());
+
+        ec.seek(SeekFrom::Start(0x2f)).unwrap();
+        ec.write_all(&[0x80, 0x01]).unwrap();
+        ec.seek(SeekFrom::Start(0x30)).unwrap();
+        let mut value = [0u8; 1];
+        ec.read_exact(&mut value).unwrap();
+        assert_eq!(value[0], 0x01);
+
+        let trace = String::from_utf8(ec.trace).unwrap();
+        let lines: Vec<Vec<&str>> = trace.lines().map(|l| l.split(',').collect()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0][1..], ["write", "0x2f", "8001"]);
+        assert_eq!(lines[1][1..], ["read", "0x30", "01"]);
+        assert!(lines[0][0].parse::<f64>().unwrap() > 0.0);
+    }
+}
@@ -3,20 +3,26 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use log::debug;
 
-use std::io::{Error, Seek, SeekFrom, Write};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 
+use super::overrides::percent_to_value;
 use super::RcWrapper;
 use crate::nbfc::*;
 
+/// Number of registers of the EC.
+const EC_SIZE: u64 = 256;
+
 #[derive(Debug)]
 /// Contains information about writing to the EC for a fan.
 struct FanWriteConfig {
     write_register: u8,
+    write_register_high: Option<u8>,
     reset_required: bool,
     reset_value: Option<u16>,
     max_speed: u16,
     min_speed: u16,
     write_percent_overrides: Option<Vec<FanSpeedPercentageOverride>>,
+    interpolate_overrides: bool,
 }
 
 #[derive(Debug)]
@@ -26,6 +32,7 @@ pub(crate) struct ECWriter<W: Write + Seek> {
     init_reg_confs: Option<Vec<RegisterWriteConfiguration>>,
     fans_write_config: Vec<FanWriteConfig>,
     write_words: bool,
+    big_endian_words: bool,
     ec_dev: RcWrapper<W>,
 }
 
@@ -39,10 +46,16 @@ impl<W: Write + Seek> ECWriter<W> {
             init_reg_confs: None,
             fans_write_config: Vec::new(),
             write_words: false,
+            big_endian_words: false,
             ec_dev,
         }
     }
 
+    /// Set if the words are written with their high byte first.
+    pub fn set_big_endian_words(&mut self, big_endian_words: bool) {
+        self.big_endian_words = big_endian_words;
+    }
+
     /// Refresh the configuration used for the writer.
     /// NOTE: This function does write the required values to initialize the controller (using `init_write`).
     pub fn refresh_config(
@@ -71,6 +84,7 @@ impl<W: Write + Seek> ECWriter<W> {
             .iter()
             .map(|fan| FanWriteConfig {
                 write_register: fan.write_register,
+                write_register_high: fan.write_register_high,
                 reset_required: fan.reset_required,
                 reset_value: fan.fan_speed_reset_value,
                 min_speed: fan.min_speed_value,
@@ -84,6 +98,7 @@ impl<W: Write + Seek> ECWriter<W> {
                         .cloned()
                         .collect()
                 }),
+                interpolate_overrides: fan.interpolate_overrides,
             })
             .collect();
 
@@ -101,8 +116,7 @@ impl<W: Write + Seek> ECWriter<W> {
 
         for c in &self.fans_write_config {
             if let Some(value) = c.reset_value {
-                let write_off = SeekFrom::Start(c.write_register as u64);
-                self.write_value(self.write_words, write_off, &value.to_le_bytes())?;
+                self.write_fan_value(c, value.to_le_bytes())?;
             }
         }
 
@@ -136,8 +150,7 @@ impl<W: Write + Seek> ECWriter<W> {
         for c in &self.fans_write_config {
             if reset_all || c.reset_required {
                 if let Some(value) = c.reset_value {
-                    let write_off = SeekFrom::Start(c.write_register as u64);
-                    self.write_value(self.write_words, write_off, &value.to_le_bytes())?;
+                    self.write_fan_value(c, value.to_le_bytes())?;
                 }
             }
         }
@@ -155,22 +168,98 @@ impl<W: Write + Seek> ECWriter<W> {
         }
 
         let fan = &self.fans_write_config[fan_index];
-        let speed = if let Some(speed_value) = fan.write_percent_overrides.as_ref().and_then(|f| {
-            f.iter()
-                .filter(|e| (e.fan_speed_percentage as f64 - speed_percent).abs() < f64::EPSILON)
-                .map(|e| e.fan_speed_value)
-                .next()
-        }) {
-            speed_value.to_le_bytes()
+        let speed = Self::speed_value(fan, speed_percent);
+
+        self.write_fan_value(fan, speed)
+    }
+
+    /// Write a raw `value` to the `register`, for the calibration of the fans.
+    pub fn write_register(&mut self, register: u8, value: u8) -> Result {
+        self.write_value(false, SeekFrom::Start(register as u64), &[value])
+    }
+
+    /// Get the value written to the EC for the `speed_percent` of the fan specified by `fan_index`.
+    pub fn raw_speed_value(&self, fan_index: usize, speed_percent: f64) -> [u8; 2] {
+        Self::speed_value(&self.fans_write_config[fan_index], speed_percent)
+    }
+
+    /// Get the value actually written to the EC for the raw `value` of the fan specified by
+    /// `fan_index` (only its first byte when neither the config nor the fan write words).
+    pub fn written_value(&self, fan_index: usize, value: [u8; 2]) -> u16 {
+        if self.is_word(&self.fans_write_config[fan_index]) {
+            u16::from_le_bytes(value)
         } else {
-            ((fan.min_speed as f64
-                + (((fan.max_speed as f64 - fan.min_speed as f64) * speed_percent) / 100.0))
-                .round() as u16)
-                .to_le_bytes()
-        };
+            value[0].into()
+        }
+    }
+
+    /// Get the value to write to the EC for the `speed_percent` of `fan`.
+    fn speed_value(fan: &FanWriteConfig, speed_percent: f64) -> [u8; 2] {
+        if let Some(speed_value) = fan
+            .write_percent_overrides
+            .as_ref()
+            .and_then(|o| percent_to_value(o, speed_percent, fan.interpolate_overrides))
+        {
+            (speed_value.round() as u16).to_le_bytes()
+        } else {
+            // The bounds are swapped for the fans which are faster with lower values.
+            let low = fan.min_speed.min(fan.max_speed) as f64;
+            let high = fan.min_speed.max(fan.max_speed) as f64;
+            let value = fan.min_speed as f64
+                + (((fan.max_speed as f64 - fan.min_speed as f64) * speed_percent) / 100.0);
+
+            // A value out of the range of the fan may stall it.
+            (value.round().max(low).min(high) as u16).to_le_bytes()
+        }
+    }
 
+    /// Check if the speed value of `fan` is a word, either from the config or split across two
+    /// registers.
+    fn is_word(&self, fan: &FanWriteConfig) -> bool {
+        self.write_words || fan.write_register_high.is_some()
+    }
+
+    /// Write the speed `value` of `fan` to its register, or to its two registers (the high byte
+    /// first) without any other access in between.
+    fn write_fan_value(&self, fan: &FanWriteConfig, value: [u8; 2]) -> Result {
         let write_off = SeekFrom::Start(fan.write_register as u64);
-        self.write_value(self.write_words, write_off, &speed)
+        let high_register = match fan.write_register_high {
+            Some(register) => register,
+            None => return self.write_value(self.write_words, write_off, &self.word_order(value)),
+        };
+
+        debug!(
+            "Writing {:?} to offsets {:#x} (low) and {:#x} (high)",
+            value, fan.write_register, high_register
+        );
+        let mut dev = (*self.ec_dev).borrow_mut();
+
+        dev.seek(SeekFrom::Start(high_register as u64))?;
+        dev.write_all(&value[1..])?;
+        dev.seek(write_off)?;
+        dev.write_all(&value[..=0])
+    }
+
+    /// Swap the bytes of the little-endian `value` if the EC words are big-endian (and back, for
+    /// the words read from the EC).
+    fn word_order(&self, value: [u8; 2]) -> [u8; 2] {
+        if self.write_words && self.big_endian_words {
+            [value[1], value[0]]
+        } else {
+            value
+        }
+    }
+
+    /// Refuse to write `len` bytes at `write_off` if they go past the last register of the EC,
+    /// to not write anything to an unrelated register.
+    fn check_range(write_off: SeekFrom, len: usize) -> Result {
+        match write_off {
+            SeekFrom::Start(offset) if offset + len as u64 > EC_SIZE => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} bytes can't be written at register {:#x}", len, offset),
+            )),
+            _ => Ok(()),
+        }
     }
 
     /// Low-level write function.
@@ -180,6 +269,7 @@ impl<W: Write + Seek> ECWriter<W> {
             if write_word { &value } else { &value[..=0] },
             write_off
         );
+        Self::check_range(write_off, if write_word { value.len() } else { 1 })?;
 
         let mut dev = (*self.ec_dev).borrow_mut();
 
@@ -188,12 +278,70 @@ impl<W: Write + Seek> ECWriter<W> {
     }
 }
 
+impl<W: Read + Write + Seek> ECWriter<W> {
+    /// Write the `speed_percent` like [`write_speed_percent`](#method.write_speed_percent),
+    /// then read the write register back to check that the EC accepted the value.
+    /// The value is written once more on mismatch.
+    ///
+    /// Returns false if the value has still not been applied.
+    pub fn write_speed_percent_verified(
+        &mut self,
+        fan_index: usize,
+        speed_percent: f64,
+    ) -> Result<bool> {
+        self.write_speed_percent(fan_index, speed_percent)?;
+
+        let fan = &self.fans_write_config[fan_index];
+        let mut expected = Self::speed_value(fan, speed_percent);
+        if !self.is_word(fan) {
+            expected[1] = 0;
+        }
+
+        if self.read_back(fan)? == expected {
+            return Ok(true);
+        }
+
+        debug!(
+            "The EC did not apply the speed of fan #{}, writing it again",
+            fan_index
+        );
+        self.write_fan_value(fan, expected)?;
+
+        Ok(self.read_back(fan)? == expected)
+    }
+
+    /// Read the value of the write register of `fan`, with the same size as the written values.
+    fn read_back(&self, fan: &FanWriteConfig) -> Result<[u8; 2]> {
+        let mut buf = [0u8; 2];
+        let mut dev = (*self.ec_dev).borrow_mut();
+
+        if let Some(register) = fan.write_register_high {
+            dev.seek(SeekFrom::Start(register as u64))?;
+            dev.read_exact(&mut buf[1..])?;
+            dev.seek(SeekFrom::Start(fan.write_register as u64))?;
+            dev.read_exact(&mut buf[..=0])?;
+            return Ok(buf);
+        }
+
+        dev.seek(SeekFrom::Start(fan.write_register as u64))?;
+        dev.read_exact(if self.write_words {
+            &mut buf[..]
+        } else {
+            &mut buf[..=0]
+        })?;
+
+        Ok(self.word_order(buf))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ec_control::DryRun;
+    use crate::test_utils::fan_config;
     use once_cell::sync::Lazy;
     use std::cell::RefCell;
-    use std::io::{Cursor, Read};
+    use std::io::Cursor;
     use std::rc::Rc;
 
     static CONFIGS_PARSED: Lazy<Vec<FanControlConfigV2>> = Lazy::new(|| {
@@ -522,6 +670,77 @@ mod tests {
         });
     }
 
+    #[test]
+    fn verify_writes() {
+        let fans = [FanConfiguration {
+            max_speed_value: 200,
+            ..fan_config()
+        }];
+
+        let ec = Rc::new(RefCell::new(Cursor::new(vec![0; 256])));
+        let mut writer = ECWriter::new(Rc::clone(&ec));
+        writer.refresh_config(false, None, &fans).unwrap();
+        assert!(writer.write_speed_percent_verified(0, 50.0).unwrap());
+        assert_eq!((*ec).borrow().get_ref()[2], 100);
+
+        // The writes are never applied
+        let ec = Rc::new(RefCell::new(DryRun::from(Cursor::new(vec![0; 256]))));
+        let mut writer = ECWriter::new(Rc::clone(&ec));
+        writer.refresh_config(false, None, &fans).unwrap();
+        assert!(!writer.write_speed_percent_verified(0, 50.0).unwrap());
+    }
+
+    #[test]
+    fn write_interpolated_overrides() {
+        let fans = [FanConfiguration {
+            fan_speed_percentage_overrides: Some(
+                [(0.0, 10), (50.0, 200), (100.0, 250)]
+                    .iter()
+                    .map(
+                        |&(fan_speed_percentage, fan_speed_value)| FanSpeedPercentageOverride {
+                            fan_speed_percentage,
+                            fan_speed_value,
+                            target_operation: Some(OverrideTargetOperation::ReadWrite),
+                        },
+                    )
+                    .collect(),
+            ),
+            interpolate_overrides: true,
+            ..fan_config()
+        }];
+
+        let ec = Rc::new(RefCell::new(Cursor::new(vec![0; 256])));
+        let mut writer = ECWriter::new(Rc::clone(&ec));
+        writer.refresh_config(false, None, &fans).unwrap();
+        for (percent, value) in [(0.0, 10), (25.0, 105), (50.0, 200), (90.0, 240)] {
+            writer.write_speed_percent(0, percent).unwrap();
+            assert_eq!((*ec).borrow().get_ref()[2], value);
+        }
+    }
+
+    #[test]
+    fn clamp_computed_values() {
+        let fan = |min_speed_value, max_speed_value| FanConfiguration {
+            min_speed_value,
+            max_speed_value,
+            ..fan_config()
+        };
+
+        let ec = Rc::new(RefCell::new(Cursor::new(vec![0; 256])));
+        let mut writer = ECWriter::new(Rc::clone(&ec));
+        writer
+            .refresh_config(false, None, &[fan(50, 200), fan(200, 50)])
+            .unwrap();
+        for (percent, value, inverted_value) in
+            [(-10.0, 50, 200), (50.0, 125, 125), (150.0, 200, 50)]
+        {
+            writer.write_speed_percent(0, percent).unwrap();
+            assert_eq!((*ec).borrow().get_ref()[2], value);
+            writer.write_speed_percent(1, percent).unwrap();
+            assert_eq!((*ec).borrow().get_ref()[2], inverted_value);
+        }
+    }
+
     #[test]
     fn write_good_offset() {
         CONFIGS_PARSED.iter().for_each(|c| {
@@ -0,0 +1,138 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Export of the service state to a JSON file, for the scripts which are not D-Bus clients.
+use serde_json::{json, Value};
+
+use std::fs;
+use std::io::Result;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::state::State;
+
+/// Render the state from the same data as the D-Bus properties.
+pub(crate) fn render(state: &State, timestamp: f64) -> Value {
+    let names = state.fans_names.borrow();
+    let speeds = state.fans_speeds.borrow();
+    let targets = state.target_fans_speeds.borrow();
+    let computed = state.computed_fans_speeds.borrow();
+    let fans: Vec<Value> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            json!({
+                "name": name,
+                "speed": speeds.get(i),
+                "target": targets.get(i),
+                "computed": computed.get(i),
+                "auto": state.is_fan_auto(i),
+            })
+        })
+        .collect();
+
+    json!({
+        "timestamp": timestamp,
+        "config": *state.config.borrow(),
+        "auto": *state.auto.borrow(),
+        "critical": *state.critical.borrow(),
+        "fans": fans,
+        "temperatures": *state.temps.borrow(),
+    })
+}
+
+/// Writes the state to a file when it changes.
+#[derive(Debug, Default)]
+pub(crate) struct StateExporter {
+    /// State last written, without its timestamp.
+    last: Option<Value>,
+}
+
+impl StateExporter {
+    /// Write the state to `path` if it changed since the last write, with the current time as
+    /// its timestamp. Returns true if it was written.
+    ///
+    /// The file is replaced at once, so it is never read partially written.
+    pub fn export(&mut self, state: &State, path: &Path) -> Result<bool> {
+        let value = render(state, 0.0);
+        if self.last.as_ref() == Some(&value) {
+            return Ok(false);
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        let mut content = value.clone();
+        content["timestamp"] = json!(timestamp);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, content.to_string())?;
+        fs::rename(tmp_path, path)?;
+
+        self.last = Some(value);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+    use std::cell::RefCell;
+
+    #[test]
+    fn export_on_change() {
+        let dir = TempDir::new("export");
+        let path = dir.join("state.json");
+        let state = State {
+            fans_names: RefCell::from(vec!["CPU fan".to_owned()]),
+            fans_speeds: RefCell::from(vec![40.]),
+            ..Default::default()
+        };
+        let mut exporter = StateExporter::default();
+
+        let first = exporter.export(&state, &path).unwrap();
+        let unchanged = exporter.export(&state, &path).unwrap();
+        state.fans_speeds.borrow_mut()[0] = 60.;
+        let changed = exporter.export(&state, &path).unwrap();
+        let written: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert!(first);
+        assert!(!unchanged);
+        assert!(changed);
+        assert_eq!(written["fans"][0]["speed"], 60.);
+        assert!(written["timestamp"].as_f64().unwrap() > 0.);
+    }
+
+    #[test]
+    fn render_state() {
+        let state = State {
+            fans_names: RefCell::from(vec!["CPU fan".to_owned(), "GPU fan".to_owned()]),
+            fans_speeds: RefCell::from(vec![40., 50.]),
+            target_fans_speeds: RefCell::from(vec![0., 80.]),
+            computed_fans_speeds: RefCell::from(vec![40., 80.]),
+            fans_auto: RefCell::from(vec![true, false]),
+            temps: RefCell::from(vec![("CPU".to_owned(), 55.)].into_iter().collect()),
+            ..Default::default()
+        };
+
+        let value = render(&state, 1.5);
+        assert_eq!(value["timestamp"], 1.5);
+        assert_eq!(value["critical"], false);
+        assert_eq!(value["temperatures"]["CPU"], 55.);
+        assert_eq!(
+            value["fans"][1],
+            json!({
+                "name": "GPU fan",
+                "speed": 50.,
+                "target": 80.,
+                "computed": 80.,
+                "auto": false,
+            })
+        );
+    }
+}
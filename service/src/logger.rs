@@ -0,0 +1,120 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Logging setup. The level is configured with `RUST_LOG`, like `env_logger`.
+use log::Record;
+use serde_json::json;
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+/// Describe how the log records are written.
+pub(crate) enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl LogFormat {
+    /// Get the format given with `--log-format <format>` (or `--log-format=<format>`) in `args`.
+    /// Returns an error with the value if it's not a valid format.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut args = args.into_iter();
+        let mut value = None;
+        while let Some(arg) = args.next() {
+            if arg == "--log-format" {
+                value = args.next();
+            } else if let Some(v) = arg.strip_prefix("--log-format=") {
+                value = Some(v.to_owned());
+            }
+        }
+
+        match value.as_deref() {
+            None | Some("text") => Ok(LogFormat::Text),
+            Some("json") => Ok(LogFormat::Json),
+            Some(v) => Err(v.to_owned()),
+        }
+    }
+}
+
+/// Format a record as a JSON object.
+fn json_record(record: &Record, timestamp: f64) -> String {
+    json!({
+        "timestamp": timestamp,
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+    .to_string()
+}
+
+/// Initialize the global logger with `format`.
+pub(crate) fn init(format: LogFormat) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |d| d.as_secs_f64());
+            writeln!(buf, "{}", json_record(record, timestamp))
+        });
+    }
+
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn log_format_args() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(LogFormat::from_args(args(&["fancyd"])), Ok(LogFormat::Text));
+        assert_eq!(
+            LogFormat::from_args(args(&["fancyd", "--log-format", "json"])),
+            Ok(LogFormat::Json)
+        );
+        assert_eq!(
+            LogFormat::from_args(args(&["fancyd", "--dry-run", "--log-format=text"])),
+            Ok(LogFormat::Text)
+        );
+        assert_eq!(
+            LogFormat::from_args(args(&["fancyd", "--log-format", "xml"])),
+            Err("xml".to_owned())
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        // The record borrows the arguments, so it has to be used in the same statement.
+        let line = json_record(
+            &Record::builder()
+                .args(format_args!("Fan \"CPU\" stalled"))
+                .level(Level::Error)
+                .target("fancyd")
+                .build(),
+            1.5,
+        );
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "timestamp": 1.5,
+                "level": "ERROR",
+                "target": "fancyd",
+                "message": "Fan \"CPU\" stalled",
+            })
+        );
+    }
+}
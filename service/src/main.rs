@@ -2,45 +2,100 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use dbus::arg::Variant;
+use dbus::arg::{RefArg, Variant};
 use dbus::blocking::LocalConnection;
 use dbus::channel::Sender;
 use dbus::ffidisp::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
-use dbus::message::SignalArgs;
+use dbus::message::{MatchRule, SignalArgs};
 use dbus::strings::{BusName, Path as DBusPath};
-use log::{debug, error, info};
+use dbus::Message;
+use log::{debug, error, info, warn};
 use nbfc_config as nbfc;
 use once_cell::sync::Lazy;
-use signal_hook::{consts::SIGTERM, flag::register};
+use signal_hook::{
+    consts::{SIGHUP, SIGTERM},
+    flag::register,
+};
 use snafu::{ResultExt, Snafu};
 
+use std::collections::HashMap;
 use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
 use std::rc::Rc;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc, Mutex,
 };
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 mod bus;
 mod config;
+mod conflicts;
 mod constants;
 mod ec_control;
+mod export;
+mod logger;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod power;
+mod socket;
 mod state;
+mod systemd;
 mod temp;
+#[cfg(test)]
+mod test_utils;
+mod watch;
 
 use bus::connection::create_dbus_conn;
-use config::service::{ECAccessMode, ServiceConfig, TempComputeMethod};
-use constants::{BUS_NAME_STR, CONTROL_CONFIGS_DIR_PATH, OBJ_PATH_STR};
-use ec_control::{ECManager, RawPort, RW};
-use state::State;
-use temp::Temperatures;
+use bus::interfaces::{
+    ComMusikidFancyAvailableConfigsChanged, ComMusikidFancyBoostEnded, ComMusikidFancyBoostStarted,
+    ComMusikidFancyCalibrationFinished, ComMusikidFancyCriticalTemperatureReached,
+    ComMusikidFancyFailsafeEntered, ComMusikidFancyFanStalled, ComMusikidFancyPowerStateChanged,
+};
+use bus::signals::{FanSpeedsFilter, FanSpeedsNotifier, FanStallDetector};
+use config::service::{
+    ConfigSource, ControlMode, CurveInterpolation, ECAccessMode, ECLockStrategy, FanAggregation,
+    NoConfigBehavior, ServiceConfig,
+};
+use constants::{BUS_NAME_STR, CONTROL_CONFIGS_DIR_PATH, OBJ_PATH_STR, STATE_FILE_PATH};
+use ec_control::{
+    interpolate_curve, max_curves_speed, open_trace, step_curve, Calibration, CalibrationStep,
+    DryRun, ECManager, Locked, PwmSysfs, RawPort, Traced, RW,
+};
+use export::StateExporter;
+use logger::LogFormat;
+use power::{
+    read_power_state, speed_floor, PowerReader, POWERCAP_CLASS_PATH, POWER_SUPPLY_CLASS_PATH,
+};
+use state::{HistorySample, State};
+use systemd::Watchdog;
+use temp::{
+    computed_sensor, fan_sensor_temperature, missing_sensors, read_labeled_temps, read_load,
+    read_sensors_labels, CriticalDetector, ExternalSensors, PollController, RollingMax,
+    StuckDetector, Temperatures, LOADAVG_PATH, SENSORS_CONF_PATHS,
+};
+use watch::ConfigWatcher;
 
-const CRITICAL_INTERVAL: u8 = 10;
+const LOGIND_MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+/// Time given to the fans to spin up during the startup test.
+const SPIN_UP_DELAY: Duration = Duration::from_secs(3);
+/// Curve (temperature in °C, speed percent) followed by the fans when the control config can't be
+/// loaded, with `NoConfigBehavior::SafeCurve`.
+const SAFE_CURVE: &[(u8, f32)] = &[(0, 40.0), (50, 60.0), (65, 80.0), (75, 100.0)];
+/// Interval between two reads of the power source.
+const POWER_STATE_INTERVAL: Duration = Duration::from_secs(2);
+/// The retries of the reads of the fans wait in total at most for the EC poll interval divided
+/// by this, so that they don't delay the main loop.
+const READ_RETRIES_SHARE: u32 = 10;
+
+type Manager = ECManager<Box<dyn RW>>;
 
 static BUS_NAME: Lazy<BusName> = Lazy::new(|| BusName::new(BUS_NAME_STR).unwrap());
 static DBUS_PATH: Lazy<DBusPath> = Lazy::new(|| DBusPath::new(OBJ_PATH_STR).unwrap());
+static DRY_RUN_ARG: Lazy<bool> = Lazy::new(|| std::env::args().any(|a| a == "--dry-run"));
+static DEBUG_EC_ARG: Lazy<bool> = Lazy::new(|| std::env::args().any(|a| a == "--debug-ec"));
 
 type Result<T> = std::result::Result<T, ServiceError>;
 
@@ -52,6 +107,9 @@ enum ServiceError {
         source: std::io::Error,
     },
 
+    #[snafu(display("No access mode could open the EC ({})", tried))]
+    NoECAccess { tried: String },
+
     #[snafu(display("{}", source))]
     ECIO { source: ec_control::ECError },
 
@@ -65,9 +123,6 @@ enum ServiceError {
         source: config::nbfc_control::ControlConfigLoadError,
     },
 
-    #[snafu(display("{}", source))]
-    Sensor { source: temp::SensorError },
-
     #[snafu(display("{}", source))]
     DBus { source: dbus::Error },
 
@@ -75,11 +130,59 @@ enum ServiceError {
     Signal { source: std::io::Error },
 }
 
-fn main() -> Result<()> {
-    pretty_env_logger::init();
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|a| a == "--print-schema") {
+        std::process::exit(print_schema(
+            args.get(i + 1).map_or("service", |s| s.as_str()),
+        ));
+    }
 
+    let log_format = LogFormat::from_args(std::env::args());
+    logger::init(log_format.unwrap_or(LogFormat::Text));
+    if let Err(format) = log_format {
+        error!("Unknown log format `{}`, using `text`", format);
+    }
+    // The panics are logged like the errors, the EC is reset while unwinding.
+    panic::set_hook(Box::new(|info| error!("{}", info)));
+
+    // The errors are logged so that they are also available with the JSON format.
+    if let Err(e) = run() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Print the JSON schema of the service configuration (`service`) or of the control configs
+/// (`control`), to validate them in an editor. Returns the exit code.
+fn print_schema(kind: &str) -> i32 {
+    let schema = match kind {
+        "service" => schemars::schema_for!(ServiceConfig),
+        "control" => schemars::schema_for!(nbfc::FanControlConfigV2),
+        _ => {
+            eprintln!("Unknown schema `{}`, expected `service` or `control`", kind);
+            return 2;
+        }
+    };
+
+    match serde_json::to_string_pretty(&schema) {
+        Ok(schema) => {
+            println!("{}", schema);
+            0
+        }
+        Err(e) => {
+            eprintln!("Error while serializing the schema: {}", e);
+            1
+        }
+    }
+}
+
+fn run() -> Result<()> {
     info!("Loading service configuration");
 
+    // An invalid configuration of the service is not replaced, so the user can fix it.
+    // Only the NBFC settings, which are never written, are replaced by the default values.
+    let mut config_error = None;
     let service_config = ServiceConfig::load_service_config()
         .or_else(|e| match e {
             config::service::ServiceConfigLoadError::NoConfig {} => {
@@ -91,9 +194,10 @@ fn main() -> Result<()> {
                     ..Default::default()
                 })
             }
-            config::service::ServiceConfigLoadError::NbfcSettingsXmlDeserialize { source: _ } => {
+            config::service::ServiceConfigLoadError::NbfcSettingsXmlDeserialize { .. } => {
                 error!("{}", e);
                 info!("Using default values");
+                config_error = Some(e.to_string());
                 Ok(ServiceConfig {
                     ..Default::default()
                 })
@@ -102,67 +206,129 @@ fn main() -> Result<()> {
         })
         .context(ServiceConfigLoad {})?;
 
-    // We have to check if it's /dev/port because we have to "wrap" the file in this case.
-    let is_raw_port = service_config.ec_access_mode == ECAccessMode::RawPort;
-    let dev_path = service_config.ec_access_mode.to_path().clone();
-    let ec_dev = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(dev_path)
-        .context(OpenDev { dev_path })?;
-
-    // XXX: Sorry...
-    let ec_dev = if is_raw_port {
-        Box::from(RawPort::from(ec_dev)) as Box<dyn RW>
+    let dry_run = *DRY_RUN_ARG || service_config.core.dry_run;
+    if dry_run {
+        info!("Running in dry run mode, nothing will be written to the EC");
+    }
+    let ec_access_modes = if service_config.core.ec_access_modes.is_empty() {
+        vec![service_config.ec_access_mode]
     } else {
-        Box::from(ec_dev) as Box<dyn RW>
+        service_config.core.ec_access_modes.clone()
     };
 
     let state = Rc::from(State::from(service_config));
+    state.dry_run.replace(dry_run);
+    state.config_error.replace(config_error);
+
+    // The other fan controllers are only reported, they may be configured to not touch the fans.
+    let warnings = conflicts::find_conflicts(Path::new(conflicts::PROC_PATH));
+    for warning in &warnings {
+        warn!("{}", warning);
+    }
+    state.warnings.replace(warnings);
+    state.debug_ec.replace(*DEBUG_EC_ARG);
     state
         .config_loader
         .borrow_mut()
         .add_path(&CONTROL_CONFIGS_DIR_PATH)
         .context(ControlConfigLoad {})?;
-    let dbus_conn = create_dbus_conn(Rc::clone(&state)).context(DBus {})?;
+    // The socket is enough to control the service, e.g. in a container without a system bus.
+    let dbus_conn = match create_dbus_conn(Rc::clone(&state)) {
+        Ok(conn) => Some(conn),
+        Err(e) if state.core.borrow().socket_path.is_some() => {
+            warn!(
+                "Error while connecting to D-Bus: {}, only the socket will be available",
+                e
+            );
+            None
+        }
+        Err(e) => return Err(e).context(DBus {}),
+    };
 
-    let fan_config = get_fan_config(Rc::clone(&state), &dbus_conn)?;
+    if let Err(e) = systemd::notify("READY=1") {
+        error!("Error while notifying systemd: {}", e);
+    }
+    let mut watchdog = Watchdog::from_env();
 
-    state
-        .fans_speeds
-        .replace(vec![0.0; fan_config.fan_configurations.len()]);
+    let (ec_dev, dev_path, detected_mode) =
+        wait_ec_dev(&state, dbus_conn.as_ref(), &mut watchdog, &ec_access_modes)?;
+    state.ec_dev_path.replace(dev_path.display().to_string());
+    let ec_dev = trace_ec_dev(ec_dev, state.core.borrow().ec_trace_path.as_deref());
 
-    state.poll_interval.replace(fan_config.ec_poll_interval);
-    let ec_manager = ECManager::new(ec_dev);
+    let fan_config = get_fan_config(Rc::clone(&state), dbus_conn.as_ref(), &mut watchdog)?;
 
-    state.fans_names.replace(
-        ec_manager
-            .fan_configs
-            .iter()
-            .map(|f| f.name.to_string())
-            .collect(),
-    );
+    let startup_delay = Duration::from_secs(state.core.borrow().startup_delay);
+    wait_startup_delay(&state, dbus_conn.as_ref(), &mut watchdog, startup_delay)?;
 
+    let mut ec_manager = ECManager::new(ec_dev);
+    ec_manager
+        .refresh_control_config(fan_config)
+        .context(ECIO {})?;
     let ec_manager = Rc::from(Mutex::new(ec_manager));
 
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        start(
+            Rc::clone(&state),
+            Rc::clone(&ec_manager),
+            dbus_conn,
+            watchdog,
+            detected_mode,
+        )
+    }));
+    // The control is given back to the firmware when the service fails,
+    // so that the fans are never left at a fixed speed.
+    if state.core.borrow().restore_on_exit || !matches!(result, Ok(Ok(()))) {
+        release_control(&ec_manager);
+    } else {
+        info!("Leaving the fans at their last speed");
+    }
+    result.unwrap_or_else(|panic| panic::resume_unwind(panic))
+}
+
+/// Apply the configuration to the EC, set up the D-Bus callbacks and run the main loop.
+fn start(
+    state: Rc<State>,
+    ec_manager: Rc<Mutex<Manager>>,
+    dbus_conn: Option<LocalConnection>,
+    watchdog: Watchdog,
+    detected_mode: ECAccessMode,
+) -> Result<()> {
     {
         let mut ec_manager = ec_manager.lock().unwrap();
-        ec_manager
-            .refresh_control_config(fan_config)
-            .context(ECIO {})?;
+        update_fans_info(&state, &ec_manager);
+        apply_core_config(&state, &mut ec_manager);
+    }
 
-        state.fans_names.replace(
-            ec_manager
+    if state.core.borrow().startup_test {
+        if *state.dry_run.borrow() {
+            info!("Skipping the fans test in dry run mode");
+        } else {
+            info!("Testing the fans");
+            let mut ec_manager = ec_manager.lock().unwrap();
+            let health = ec_manager.spin_up_test(SPIN_UP_DELAY).context(ECIO {})?;
+            for (i, (fan, _)) in ec_manager
                 .fan_configs
                 .iter()
-                .map(|f| f.name.to_string())
-                .collect(),
-        );
+                .zip(&health)
+                .enumerate()
+                .filter(|(_, (_, h))| !**h)
+            {
+                error!("{} does not spin", state.fan_name(i, &fan.name));
+            }
+            state.fans_health.replace(health);
+        }
     }
 
-    state.ec_access_mode.replace(ECAccessMode::from(dev_path));
-
+    // The working mode is saved so it is tried first on the next start.
+    if state.ec_access_mode.replace(detected_mode) != detected_mode
+        && state.core.borrow().persist_on_exit
     {
+        if let Err(e) = state.as_service_config().save() {
+            error!("Error while saving service config: {}", e);
+        }
+    }
+
+    if let Some(dbus_conn) = &dbus_conn {
         // We have to clone the references to move them to the closure.
         let state = Rc::clone(&state);
         let ec_manager = Rc::clone(&ec_manager);
@@ -174,13 +340,11 @@ fn main() -> Result<()> {
                 move |props: PropertiesPropertiesChanged, _, _| {
                     for (property, _val) in props.changed_properties {
                         match &*property {
-                            "Config" => {
+                            // The config is already applied when the service changed it itself.
+                            "Config" if state.old_config.borrow().is_some() => {
                                 let config = state.config.borrow();
                                 info!("Swapping configuration to '{}'", &*config);
 
-                                let mut target_fans_speeds = state.target_fans_speeds.borrow_mut();
-                                let old_target_fans_speeds = target_fans_speeds.clone();
-
                                 let config_loader = state.config_loader.borrow();
                                 let conf = match config_loader.load_control_config(&*config) {
                                     Ok(c) => c,
@@ -193,7 +357,6 @@ fn main() -> Result<()> {
                                         return true;
                                     }
                                 };
-                                state.poll_interval.replace(conf.ec_poll_interval);
 
                                 let mut ec_manager = ec_manager.lock().unwrap();
                                 if let Err(e) = ec_manager.refresh_control_config(conf) {
@@ -205,21 +368,7 @@ fn main() -> Result<()> {
                                     return true;
                                 };
 
-                                let fans_count = ec_manager.fan_configs.len();
-
-                                state.fans_speeds.replace(vec![0.0; fans_count]);
-
-                                state.fans_names.replace(
-                                    ec_manager
-                                        .fan_configs
-                                        .iter()
-                                        .map(|f| f.name.to_string())
-                                        .collect(),
-                                );
-
-                                *target_fans_speeds = vec![0.0; fans_count];
-
-                                target_fans_speeds.splice(0..fans_count, old_target_fans_speeds);
+                                update_fans_info(&state, &*ec_manager);
 
                                 // We remove the old config when there is no error
                                 state.old_config.take();
@@ -228,29 +377,945 @@ fn main() -> Result<()> {
                         }
                     }
 
-                    info!("Saving service configuration");
-                    if let Err(e) = state.as_service_config().save() {
-                        error!("Error while saving service config: {}", e);
-                    };
+                    if state.core.borrow().persist_on_exit {
+                        info!("Saving service configuration");
+                        if let Err(e) = state.as_service_config().save() {
+                            error!("Error while saving service config: {}", e);
+                        };
+                    }
+                    true
+                },
+            )
+            .context(DBus {})?;
+    }
+
+    if let Some(dbus_conn) = &dbus_conn {
+        let state = Rc::clone(&state);
+        let ec_manager = Rc::clone(&ec_manager);
+        // The firmware may reset the EC during the sleep, so the config is applied again on resume.
+        dbus_conn
+            .add_match(
+                MatchRule::new_signal(LOGIND_MANAGER_INTERFACE, "PrepareForSleep"),
+                move |(start,): (bool,), _, _| {
+                    state.suspended.replace(start);
+
+                    if start {
+                        info!("Preparing for sleep");
+                        if state.core.borrow().restore_on_sleep {
+                            if let Err(e) = ec_manager.lock().unwrap().reset_ec(true) {
+                                error!("Error while resetting the EC before sleep: {}", e);
+                            }
+                        }
+                    } else {
+                        info!("Resuming from sleep, applying the configuration again");
+                        state.reload_control_config.replace(true);
+                    }
                     true
                 },
             )
             .context(DBus {})?;
     }
 
-    main_loop(ec_manager, dbus_conn, state)
+    main_loop(ec_manager, dbus_conn, state, watchdog)
+}
+
+/// Give the control of the fans back to the firmware, by resetting the EC.
+fn release_control(ec_manager: &Mutex<Manager>) {
+    // The lock is poisoned if the service panicked while holding it.
+    let mut ec_manager = ec_manager.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = ec_manager.reset_ec(true) {
+        error!("Error while giving the control back to the firmware: {}", e);
+    }
+}
+
+/// Open the EC device with the first access mode of `modes` which works.
+/// Returns the device along with its path and the mode used, or an error listing every mode tried.
+fn open_first_ec_dev(
+    modes: &[ECAccessMode],
+    dry_run: bool,
+    lock: bool,
+) -> Result<(Box<dyn RW>, &'static Path, ECAccessMode)> {
+    let mut errors = Vec::new();
+
+    for mode in modes.iter().flat_map(|m| m.candidates()) {
+        match open_ec_dev(mode, dry_run, lock) {
+            Ok((ec_dev, dev_path)) => {
+                info!(
+                    "Accessing the EC with {:?} (lock: {:?})",
+                    mode,
+                    mode.lock_strategy(lock)
+                );
+                return Ok((ec_dev, dev_path, mode));
+            }
+            Err(e) => {
+                debug!("Could not access the EC with {:?}: {}", mode, e);
+                errors.push(format!("{:?}: {}", mode, e));
+            }
+        }
+    }
+
+    NoECAccess {
+        tried: errors.join(", "),
+    }
+    .fail()
+}
+
+/// Open the EC device with the first of `modes` which works.
+///
+/// With `core.retry_ec_open`, the service keeps answering the D-Bus requests while the device can't
+/// be opened (e.g. if the EC is initialized late), and tries again at this interval.
+fn wait_ec_dev(
+    state: &State,
+    dbus_conn: Option<&LocalConnection>,
+    watchdog: &mut Watchdog,
+    modes: &[ECAccessMode],
+) -> Result<(Box<dyn RW>, &'static Path, ECAccessMode)> {
+    let (dry_run, lock) = (*state.dry_run.borrow(), state.core.borrow().ec_lock);
+    let interval = Duration::from_secs(state.core.borrow().retry_ec_open);
+
+    loop {
+        let e = match open_first_ec_dev(modes, dry_run, lock) {
+            Ok(dev) => {
+                state.last_ec_error.replace(None);
+                return Ok(dev);
+            }
+            Err(e) if interval.is_zero() => return Err(e),
+            Err(e) => e,
+        };
+        // The failure is exposed through `EcInfo` until the EC can be opened.
+        if state.last_ec_error.replace(Some(e.to_string())).is_none() {
+            error!("{}, trying again every {}s", e, interval.as_secs());
+        }
+
+        let until = Instant::now() + interval;
+        loop {
+            let remaining = until.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            watchdog.ping();
+            process_dbus(dbus_conn, remaining.min(Duration::from_secs(1)))?;
+        }
+    }
+}
+
+/// Open the EC device according to the access mode, discarding the writes if `dry_run` is set
+/// and locking the device during the accesses if `lock` is set (when it's needed).
+/// Returns the device along with its path.
+fn open_ec_dev(
+    mode: ECAccessMode,
+    dry_run: bool,
+    lock: bool,
+) -> Result<(Box<dyn RW>, &'static Path)> {
+    let dev_path = mode.to_path();
+    let (ec_dev, fd) = match mode {
+        // The fans of the hwmon drivers are not behind a single file.
+        ECAccessMode::PwmSysfs => PwmSysfs::open(dev_path),
+        _ => OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dev_path)
+            .map(|file| {
+                let fd = file.as_raw_fd();
+                // `/dev/port` is mapped to the I/O ports, the EC protocol is handled by `RawPort`,
+                // while the kernel modules expose the registers directly.
+                let ec_dev = if dev_path == ECAccessMode::RawPort.to_path() {
+                    Box::from(RawPort::from(file)) as Box<dyn RW>
+                } else {
+                    Box::from(file) as Box<dyn RW>
+                };
+                (ec_dev, fd)
+            }),
+    }
+    .context(OpenDev { dev_path })?;
+
+    // The file is owned by the device, so the descriptor stays valid.
+    let ec_dev = if mode.lock_strategy(lock) == ECLockStrategy::Flock {
+        Box::from(Locked::new(ec_dev, fd)) as Box<dyn RW>
+    } else {
+        ec_dev
+    };
+
+    let ec_dev = if dry_run {
+        Box::from(DryRun::from(ec_dev)) as Box<dyn RW>
+    } else {
+        ec_dev
+    };
+
+    Ok((ec_dev, dev_path))
+}
+
+/// Record the accesses to the EC device in the trace at `path`, if it's set.
+fn trace_ec_dev(ec_dev: Box<dyn RW>, path: Option<&Path>) -> Box<dyn RW> {
+    let path = match path {
+        Some(path) => path,
+        None => return ec_dev,
+    };
+
+    match open_trace(path) {
+        Ok(trace) => {
+            info!("Tracing the EC accesses to {}", path.display());
+            Box::from(Traced::new(ec_dev, trace))
+        }
+        Err(e) => {
+            error!("Error while opening the EC trace {}: {}", path.display(), e);
+            ec_dev
+        }
+    }
+}
+
+/// Refresh the fans information shared through D-Bus with the current manager configuration.
+fn update_fans_info<T: RW>(state: &State, ec_manager: &ECManager<T>) {
+    let fans_count = ec_manager.fan_configs.len();
+
+    state
+        .poll_interval
+        .replace(ec_manager.poll_interval.as_millis() as u64);
+    state.fans_speeds.replace(vec![0.0; fans_count]);
+    state.computed_fans_speeds.replace(vec![0.0; fans_count]);
+    state.fans_rpm.replace(vec![0.0; fans_count]);
+    state.fans_raw_values.replace(vec![0; fans_count]);
+    state
+        .active_sensors
+        .replace(vec![String::new(); fans_count]);
+    state.set_fans_names(
+        ec_manager
+            .fan_configs
+            .iter()
+            .map(|f| f.name.to_string())
+            .collect(),
+    );
+    for mismatch in &ec_manager.mismatches {
+        warn!("{}", mismatch);
+    }
+    state.fans_mismatches.replace(ec_manager.mismatches.clone());
+    state
+        .config_critical_temperature
+        .replace(ec_manager.critical_temperature);
+    state.config_info.replace(ec_manager.config_info());
+    state
+        .fans_curves
+        .replace(ec_manager.fan_configs.iter().map(|f| f.curve()).collect());
+    // We keep the target speeds and the modes which are still valid.
+    state
+        .target_fans_speeds
+        .borrow_mut()
+        .resize(fans_count, 0.0);
+    let auto = *state.auto.borrow();
+    state.fans_auto.borrow_mut().resize(fans_count, auto);
+}
+
+/// Apply the EC access settings of the `core` configuration to the manager.
+fn apply_core_config<T: RW>(state: &State, ec_manager: &mut ECManager<T>) {
+    let core = state.core.borrow();
+    ec_manager.set_read_retries(
+        core.read_retries,
+        Duration::from_millis(core.read_retry_delay),
+    );
+    // The writes are never applied in dry run mode.
+    ec_manager.verify_writes = core.verify_writes && !*state.dry_run.borrow();
+    ec_manager.force_write_interval = Duration::from_millis(core.force_write_interval);
+    // The aliases may have changed.
+    state.set_fans_names(
+        ec_manager
+            .fan_configs
+            .iter()
+            .map(|f| f.name.to_string())
+            .collect(),
+    );
+}
+
+/// Send a signal, logging if it can't be sent. Nothing is sent without a D-Bus connection.
+fn send_signal(dbus_conn: Option<&LocalConnection>, signal: Message) {
+    if let Some(dbus_conn) = dbus_conn {
+        if dbus_conn.send(signal).is_err() {
+            error!("Error while sending a D-Bus signal");
+        }
+    }
+}
+
+/// Answer the D-Bus requests for at most `timeout`, or only wait without a D-Bus connection.
+fn process_dbus(dbus_conn: Option<&LocalConnection>, timeout: Duration) -> Result<()> {
+    match dbus_conn {
+        Some(dbus_conn) => dbus_conn.process(timeout).map(|_| ()).context(DBus {}),
+        None => {
+            std::thread::sleep(timeout);
+            Ok(())
+        }
+    }
+}
+
+/// Emit the `PropertiesChanged` signal for the properties modified by method calls.
+fn emit_changed_properties(state: &State, dbus_conn: Option<&LocalConnection>) {
+    let changed_properties = state.changed_properties.take();
+    if changed_properties.is_empty() {
+        return;
+    }
+
+    let mut prop_changed = PropertiesPropertiesChanged {
+        interface_name: BUS_NAME_STR.to_string(),
+        ..Default::default()
+    };
+    for property in changed_properties {
+        let value = match property {
+            "TargetFansSpeeds" => {
+                Box::new(state.target_fans_speeds.borrow().clone()) as Box<dyn RefArg>
+            }
+            "Auto" => Box::new(*state.auto.borrow()) as Box<dyn RefArg>,
+            "FansAuto" => Box::new(state.fans_auto.borrow().clone()) as Box<dyn RefArg>,
+            "Config" => Box::new(state.config.borrow().clone()) as Box<dyn RefArg>,
+            "Profiles" => {
+                let mut names: Vec<String> = state.profiles.borrow().keys().cloned().collect();
+                names.sort_unstable();
+                Box::new(names) as Box<dyn RefArg>
+            }
+            _ => continue,
+        };
+        prop_changed
+            .changed_properties
+            .insert(property.into(), Variant(value));
+    }
+
+    send_signal(
+        dbus_conn,
+        prop_changed.to_emit_message(&DBusPath::from(OBJ_PATH_STR)),
+    );
+}
+
+/// Load the selected control config from the disk again (or take the one with the edited curves)
+/// and apply it to the manager.
+fn reload_control_config(state: &State, ec_manager: &Mutex<Manager>) -> Result<()> {
+    let edited_config = state.edited_config.borrow().clone();
+    let fan_config = match edited_config {
+        Some(config) => config,
+        None => state
+            .config_loader
+            .borrow()
+            .load_control_config(&*state.config.borrow())
+            .context(ControlConfigLoad {})?,
+    };
+
+    let mut ec_manager = ec_manager.lock().unwrap();
+    ec_manager
+        .refresh_control_config(fan_config)
+        .context(ECIO {})?;
+    update_fans_info(state, &*ec_manager);
+
+    Ok(())
+}
+
+/// Handle the fans following `core.no_config_behavior`, since the selected control config can't be
+/// loaded and no other one is applied.
+fn apply_no_config_behavior(state: &State, ec_manager: &mut Manager) -> Result<()> {
+    let behavior = state.core.borrow().no_config_behavior;
+    match behavior {
+        NoConfigBehavior::Firmware => {
+            info!("Giving the control of the fans back to the firmware");
+            ec_manager.release_fans().context(ECIO {})?;
+        }
+        NoConfigBehavior::SafeCurve => {
+            info!("The fans follow the built-in safe curve");
+            ec_manager.set_curves(SAFE_CURVE);
+        }
+        NoConfigBehavior::FullSpeed => {
+            info!("Setting the fans to full speed");
+            ec_manager.set_curves(&[(0, 100.0)]);
+        }
+    }
+    update_fans_info(state, ec_manager);
+
+    Ok(())
+}
+
+/// Apply the fan config and the target speeds stored in a profile.
+///
+/// Nothing is changed if the fan config can't be loaded.
+fn apply_profile(state: &State, ec_manager: &Mutex<Manager>, name: &str) -> Result<()> {
+    let profile = match state.profiles.borrow().get(name) {
+        Some(p) => p.clone(),
+        None => return Ok(()),
+    };
+    info!("Loading profile '{}'", name);
+
+    if profile.selected_fan_config != *state.config.borrow() {
+        let fan_config = state
+            .config_loader
+            .borrow()
+            .load_control_config(&profile.selected_fan_config)
+            .context(ControlConfigLoad {})?;
+        let mut ec_manager = ec_manager.lock().unwrap();
+        ec_manager
+            .refresh_control_config(fan_config)
+            .context(ECIO {})?;
+
+        state.replace_config(profile.selected_fan_config, ConfigSource::Manual);
+        update_fans_info(state, &*ec_manager);
+    }
+
+    let fans_count = state.fans_names.borrow().len();
+    let mut target_fans_speeds = profile.target_fans_speeds;
+    target_fans_speeds.resize(fans_count, 0.0);
+    state.target_fans_speeds.replace(target_fans_speeds);
+    state.auto.replace(profile.auto);
+    state.fans_auto.replace(vec![profile.auto; fans_count]);
+    state.refresh_manual_timer(Instant::now());
+
+    state.changed_properties.borrow_mut().extend([
+        "Config",
+        "TargetFansSpeeds",
+        "Auto",
+        "FansAuto",
+    ]);
+    Ok(())
+}
+
+/// Reload the service configuration from the disk and apply the changes to the running service.
+///
+/// A new manager is entirely set up before replacing the current one,
+/// so the running configuration is kept if any step fails.
+fn reload_service_config(state: &State, ec_manager: &Mutex<Manager>) -> Result<()> {
+    info!("Reloading service configuration");
+    let loaded = match state.pending_service_config.take() {
+        Some(config) => Ok(config),
+        None => ServiceConfig::load_service_config(),
+    };
+    let new_config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            // The reason is exposed until a configuration is loaded successfully.
+            state.config_error.replace(Some(e.to_string()));
+            return Err(e).context(ServiceConfigLoad {});
+        }
+    };
+    state.config_error.replace(None);
+
+    let current_mode = *state.ec_access_mode.borrow();
+    let mode_changed = new_config.ec_access_mode != ECAccessMode::Either
+        && new_config.ec_access_mode != current_mode;
+    let config_changed = new_config.selected_fan_config != *state.config.borrow();
+    let dry_run = *DRY_RUN_ARG || new_config.core.dry_run;
+    let dry_run_changed = dry_run != *state.dry_run.borrow();
+    let lock_changed = new_config.core.ec_lock != state.core.borrow().ec_lock;
+    let trace_changed = new_config.core.ec_trace_path != state.core.borrow().ec_trace_path;
+
+    if mode_changed || config_changed || dry_run_changed || lock_changed || trace_changed {
+        let fan_config = state
+            .config_loader
+            .borrow()
+            .load_control_config(&new_config.selected_fan_config)
+            .context(ControlConfigLoad {})?;
+
+        let mode = if mode_changed {
+            new_config.ec_access_mode
+        } else {
+            current_mode
+        };
+        let (ec_dev, dev_path) = open_ec_dev(mode, dry_run, new_config.core.ec_lock)?;
+        let ec_dev = trace_ec_dev(ec_dev, new_config.core.ec_trace_path.as_deref());
+        let mut new_manager = ECManager::new(ec_dev);
+        new_manager
+            .refresh_control_config(fan_config)
+            .context(ECIO {})?;
+
+        // Everything is ready, we can swap to the new configuration.
+        // The registers of the old config are reset first, as when the service stops,
+        // since the new one may not write them.
+        release_control(ec_manager);
+        let mut ec_manager = ec_manager.lock().unwrap();
+        *ec_manager = new_manager;
+
+        state.ec_access_mode.replace(mode);
+        state.ec_dev_path.replace(dev_path.display().to_string());
+        state.dry_run.replace(dry_run);
+        update_fans_info(state, &*ec_manager);
+    }
+
+    if state.ec_failsafe.replace(false) {
+        // The control config is applied again, as after a sleep.
+        info!("Taking the control of the fans again");
+        state.reload_control_config.replace(true);
+    }
+    // The config selected on the disk is applied now, even if another one was tried.
+    state.replace_config(new_config.selected_fan_config, new_config.config_source);
+    state.temp_compute.replace(new_config.temp_compute);
+    state
+        .check_control_config
+        .replace(new_config.check_control_config);
+    state.core.replace(new_config.core);
+    state.sensors.replace(new_config.sensors);
+    state.profiles.replace(new_config.profiles);
+    if state.auto_profiles.replace(new_config.auto_profiles) != *state.auto_profiles.borrow() {
+        state.profile_rules.take();
+    }
+    state.power.replace(new_config.power);
+    apply_core_config(state, &mut *ec_manager.lock().unwrap());
+
+    info!("Service configuration reloaded");
+    Ok(())
+}
+
+/// Read the speed of the fans and write the new ones, driving the calibration if there is one.
+/// Returns the speed in RPM of each fan.
+fn control_fans(
+    state: &State,
+    ec_manager: &mut Manager,
+    dbus_conn: Option<&LocalConnection>,
+    calibration: &mut Option<Calibration>,
+    temp: f64,
+    elapsed: f64,
+    power_floor: f64,
+) -> Result<Vec<Option<f64>>> {
+    let critical = *state.critical.borrow();
+    let temperature_offset = state.fans_temperature_offset();
+    {
+        // The limits can be changed over D-Bus.
+        let core = state.core.borrow();
+        ec_manager.speed_limits = core
+            .fan_speed_limits
+            .iter()
+            .map(|l| (l.min, l.max))
+            .collect();
+        ec_manager.bypass_speed_limits = critical && core.critical_bypasses_speed_limits;
+    }
+    let max_step = {
+        let ramp_rate = state.core.borrow().ramp_rate;
+        if ramp_rate > 0.0 {
+            ramp_rate * elapsed
+        } else {
+            f64::INFINITY
+        }
+    };
+
+    if let Some((fan_index, register)) = state.pending_calibration.take() {
+        let started = start_calibration(state, ec_manager, fan_index, register);
+        // Another calibration can be requested if this one couldn't start.
+        if !matches!(started, Ok(Some(_))) {
+            state.calibrating.replace(false);
+        }
+        *calibration = started?;
+    }
+    // The calibration is stopped so that the fan can cool at full speed.
+    if critical {
+        if let Some(c) = calibration.take() {
+            warn!("Critical temperature reached, stopping the calibration");
+            finish_calibration(state, ec_manager, dbus_conn, c)?;
+        }
+    }
+
+    let boosted = state.boost_until.borrow().is_some();
+    let mut fans_speeds = state.fans_speeds.borrow_mut();
+    let mut fans_rpm = Vec::with_capacity(ec_manager.fan_configs.len());
+    let mut writes_ok = true;
+    // Only the automatic speeds are decided by a sensor.
+    let mut active_sensors = vec![String::new(); ec_manager.fan_configs.len()];
+
+    for i in 0..ec_manager.fan_configs.len() {
+        let name = state.fan_name(i, &ec_manager.fan_configs[i].name);
+        fans_speeds[i] = ec_manager.read_fan_speed(i).context(ECIO {})?;
+        debug!(
+            "Fan speed for {} with index {}: {:#?}",
+            name, i, fans_speeds[i]
+        );
+        fans_rpm.push(ec_manager.read_fan_rpm(i).context(ECIO {})?);
+
+        // If there is a target fan speed set by the user
+        let user_defined_speed =
+            !state.is_fan_auto(i) && state.target_fans_speeds.borrow().get(i).is_some();
+
+        if critical || boosted {
+            // The ramp rate is bypassed for safety.
+            ec_manager.write_fan_speed(i, 100.0).context(ECIO {})?;
+        } else if matches!(calibration, Some(c) if c.fan_index == i) {
+            // The fan is driven by the calibration until it's finished.
+        } else if user_defined_speed {
+            debug!(
+                "Target fan speed for {} with index {}: {}",
+                name,
+                i,
+                state.target_fans_speeds.borrow()[i]
+            );
+            ec_manager
+                .write_fan_speed_ramped(i, state.target_fans_speeds.borrow()[i], max_step)
+                .context(ECIO {})?;
+        } else {
+            let (sensor, fan_temp) = {
+                let sensors = state.sensors.borrow();
+                let temps = state.temps.borrow();
+                match fan_sensor_temperature(&sensors, &name, &temps) {
+                    Some((sensor, t)) => (sensor.to_owned(), t),
+                    None => (
+                        computed_sensor(&temps, *state.temp_compute.borrow(), &sensors),
+                        temp,
+                    ),
+                }
+            };
+            active_sensors[i] = sensor;
+            let fan_temp = fan_temp - temperature_offset;
+            // If the function returns `true`, the threshold has changed.
+            if ec_manager.refresh_fan_threshold(fan_temp, i) {
+                debug!(
+                    "Selected threshold #{}",
+                    ec_manager.fan_configs[i].current_threshold
+                );
+            }
+
+            let fan_off_below = state.core.borrow().fan_off_below;
+            let fan_off = match fan_off_below {
+                Some(t) => {
+                    if ec_manager.refresh_fan_off(fan_temp, i, f64::from(t)) {
+                        info!(
+                            "Turning {} {}",
+                            name,
+                            if ec_manager.fan_configs[i].off {
+                                "off"
+                            } else {
+                                "on"
+                            }
+                        );
+                    }
+                    ec_manager.fan_configs[i].off
+                }
+                None => false,
+            };
+
+            if fan_off && power_floor <= 0.0 {
+                // We write only if the fan is not already off.
+                if ec_manager.fan_configs[i].current_speed != Some(0.0) {
+                    // The writes are never applied in dry run mode.
+                    match ec_manager.stop_fan(i, !*state.dry_run.borrow()) {
+                        Err(e @ ec_control::ECError::WriteNotApplied { .. }) => {
+                            error!("Could not turn {} off: {}", name, e);
+                            writes_ok = false;
+                            state.last_ec_error.replace(Some(e.to_string()));
+                        }
+                        r => r.context(ECIO {})?,
+                    }
+                }
+            } else {
+                let core = state.core.borrow();
+                let sensors_speed = match state.sensors.borrow().fan_aggregation.get(&name) {
+                    Some(FanAggregation::MaxSpeed { curves }) => {
+                        let curves: Vec<(&String, Vec<(f64, f64)>)> = curves
+                            .iter()
+                            .map(|(sensor, points)| {
+                                let mut curve: Vec<_> =
+                                    points.iter().map(|p| (p.temperature, p.speed)).collect();
+                                curve.sort_by(|a, b| {
+                                    a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal)
+                                });
+                                (sensor, curve)
+                            })
+                            .collect();
+                        let temps: HashMap<String, f64> = state
+                            .temps
+                            .borrow()
+                            .iter()
+                            .map(|(name, t)| (name.to_owned(), t - temperature_offset))
+                            .collect();
+                        let evaluate = match core.curve_interpolation {
+                            CurveInterpolation::Step => step_curve,
+                            CurveInterpolation::Linear => interpolate_curve,
+                        };
+                        max_curves_speed(curves.iter().map(|(s, c)| (*s, c)), &temps, evaluate).map(
+                            |(sensor, speed)| {
+                                active_sensors[i] = sensor.to_owned();
+                                speed
+                            },
+                        )
+                    }
+                    _ => None,
+                };
+                let value: f64 = match (core.control_mode, sensors_speed) {
+                    (ControlMode::Curve, Some(speed)) => speed,
+                    // The curve of the control config is used if none of the sensors is available.
+                    (ControlMode::Curve, None) => match core.curve_interpolation {
+                        CurveInterpolation::Step => {
+                            let threshold = ec_manager.fan_configs[i].current_threshold;
+                            ec_manager.fan_configs[i].thresholds[threshold]
+                                .fan_speed
+                                .into()
+                        }
+                        CurveInterpolation::Linear => {
+                            ec_manager.fan_configs[i].interpolated_speed(fan_temp)
+                        }
+                    },
+                    (ControlMode::Pid, _) => ec_manager.fan_configs[i]
+                        .pid
+                        .update(fan_temp, elapsed, &core.pid),
+                };
+                let value = value.max(power_floor);
+
+                // The manager skips the write if the speed is already reached.
+                debug!("Automatic fan speed: {}", value);
+                ec_manager
+                    .write_fan_speed_ramped(i, value, max_step)
+                    .context(ECIO {})?;
+            }
+        }
+
+        if let Some(speed) = ec_manager.fan_configs[i].current_speed {
+            state.computed_fans_speeds.borrow_mut()[i] = speed;
+        }
+    }
+    state.active_sensors.replace(active_sensors);
+    // The other EC errors are returned to the main loop.
+    state.ec_reads_ok.replace(Some(true));
+    state.ec_writes_ok.replace(Some(writes_ok));
+    state.record_history(HistorySample {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64()),
+        temperature: temp,
+        fans_speeds: fans_speeds.clone(),
+    });
+
+    if let Some(c) = calibration.as_mut() {
+        let rpm = fans_rpm.get(c.fan_index).copied().flatten().unwrap_or(0.0);
+        match c.update(Instant::now(), rpm) {
+            CalibrationStep::Wait => {}
+            CalibrationStep::Write(value) => ec_manager
+                .write_register(c.register, value)
+                .context(ECIO {})?,
+            CalibrationStep::Done => {
+                if let Some(c) = calibration.take() {
+                    finish_calibration(state, ec_manager, dbus_conn, c)?;
+                }
+            }
+        }
+    }
+
+    Ok(fans_rpm)
+}
+
+/// Read the power source, and activate the profile set for it when it changes.
+fn update_power_state(state: &State, dbus_conn: Option<&LocalConnection>) {
+    let power_state = read_power_state(Path::new(POWER_SUPPLY_CLASS_PATH));
+    if state.power_state.replace(power_state) == power_state {
+        return;
+    }
+
+    info!("Power source: {}", power_state.name());
+    if let Some(profile) = power_state
+        .config(&state.power.borrow())
+        .and_then(|c| c.profile.to_owned())
+    {
+        info!("Switching to profile `{}` for the power source", profile);
+        state.pending_profile.replace(Some(profile));
+    }
+
+    let signal = ComMusikidFancyPowerStateChanged {
+        state: power_state.name().to_owned(),
+    };
+    send_signal(dbus_conn, signal.to_emit_message(&DBUS_PATH));
+}
+
+/// Count the EC errors in a row, and give the control of the fans back to the firmware after
+/// `max_consecutive_ec_errors` of them.
+///
+/// The error is returned (which stops the service) if there is no maximum or it's not an EC error.
+fn handle_ec_error(
+    state: &State,
+    ec_manager: &mut Manager,
+    dbus_conn: Option<&LocalConnection>,
+    ec_errors: &mut u32,
+    error: ServiceError,
+) -> Result<()> {
+    let max_errors = match state.core.borrow().max_consecutive_ec_errors {
+        Some(max) if matches!(error, ServiceError::ECIO { .. }) => max,
+        _ => return Err(error),
+    };
+
+    *ec_errors += 1;
+    error!("EC error ({}/{}): {}", ec_errors, max_errors, error);
+    state.record_ec_error(error.to_string());
+    if *ec_errors <= max_errors {
+        return Ok(());
+    }
+
+    error!("Too many EC errors, giving the control of the fans back to the firmware until the configuration is reloaded");
+    *ec_errors = 0;
+    state.enter_failsafe();
+    if let Err(e) = ec_manager.reset_ec(true) {
+        error!("Error while giving the control back to the firmware: {}", e);
+    }
+    let signal = ComMusikidFancyFailsafeEntered {
+        error: error.to_string(),
+    };
+    send_signal(dbus_conn, signal.to_emit_message(&DBUS_PATH));
+    Ok(())
+}
+
+/// Prepare the calibration of a fan, which requires to read its speed in RPM.
+fn start_calibration(
+    state: &State,
+    ec_manager: &mut Manager,
+    fan_index: usize,
+    register: u8,
+) -> Result<Option<Calibration>> {
+    let name = match ec_manager.fan_configs.get(fan_index) {
+        Some(fan) => state.fan_name(fan_index, &fan.name),
+        None => return Ok(None),
+    };
+    if ec_manager
+        .read_fan_rpm(fan_index)
+        .context(ECIO {})?
+        .is_none()
+    {
+        error!(
+            "{} can't be calibrated, its speed in RPM can't be read (no `RpmReadRegister`)",
+            name
+        );
+        return Ok(None);
+    }
+
+    let original_value = ec_manager.read_registers(register, 1).context(ECIO {})?[0];
+    info!("Calibrating {} with the register {:#04x}", name, register);
+    Ok(Some(Calibration::new(fan_index, register, original_value)))
+}
+
+/// Write back the register swept by the calibration, give the fan back to the control and emit
+/// the measures.
+fn finish_calibration(
+    state: &State,
+    ec_manager: &mut Manager,
+    dbus_conn: Option<&LocalConnection>,
+    calibration: Calibration,
+) -> Result<()> {
+    ec_manager
+        .write_register(calibration.register, calibration.original_value)
+        .context(ECIO {})?;
+    // The speed is written again at the next poll.
+    if let Some(fan) = ec_manager.fan_configs.get_mut(calibration.fan_index) {
+        fan.current_speed = None;
+    }
+    state.calibrating.replace(false);
+    info!(
+        "Calibration finished with {} measures",
+        calibration.measures.len()
+    );
+
+    let signal = ComMusikidFancyCalibrationFinished {
+        index: calibration.fan_index as u8,
+        register: calibration.register,
+        measures: calibration.measures,
+    };
+    send_signal(dbus_conn, signal.to_emit_message(&DBUS_PATH));
+    Ok(())
+}
+
+/// Start the metrics endpoint if a port is configured.
+#[cfg(feature = "metrics")]
+fn start_metrics_server(state: &State) -> Option<metrics::MetricsServer> {
+    let core = state.core.borrow();
+    let port = core.metrics_port?;
+
+    match metrics::MetricsServer::bind(&core.metrics_address, port) {
+        Ok(server) => {
+            info!("Serving metrics on {}:{}", core.metrics_address, port);
+            Some(server)
+        }
+        Err(e) => {
+            error!("Error while starting the metrics endpoint: {}", e);
+            None
+        }
+    }
+}
+
+/// Start the control socket if a path is configured.
+fn start_socket_server(state: &State) -> Option<socket::SocketServer> {
+    let core = state.core.borrow();
+    let path = core.socket_path.as_ref()?;
+
+    match socket::SocketServer::bind(path) {
+        Ok(server) => {
+            info!("Listening on {}", path.display());
+            Some(server)
+        }
+        Err(e) => {
+            error!("Error while starting the control socket: {}", e);
+            None
+        }
+    }
+}
+
+fn start_config_watcher(state: &State) -> Option<ConfigWatcher> {
+    if !state.core.borrow().watch_config {
+        return None;
+    }
+
+    match ConfigWatcher::watch(&ServiceConfig::paths(), &CONTROL_CONFIGS_DIR_PATH) {
+        Ok(watcher) => {
+            info!("Watching the configuration files");
+            Some(watcher)
+        }
+        Err(e) => {
+            error!("Error while watching the configuration files: {}", e);
+            None
+        }
+    }
+}
+
+/// Check if the service configuration on the disk differs from the running one, to not reload
+/// the configuration the service has just saved itself.
+fn service_config_modified(state: &State) -> bool {
+    // The error is reported by the reload.
+    ServiceConfig::load_service_config().map_or(true, |c| c != state.as_service_config())
+}
+
+/// Select the control config matching the model of the laptop, if there is one which can be loaded.
+fn detect_fan_config(state: &State) {
+    let detected = match state.config_loader.borrow().detect_config() {
+        Ok(Some(config)) => config,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Error while detecting the control config: {}", e);
+            return;
+        }
+    };
+    let check = *state.check_control_config.borrow();
+    if let Err(e) = state
+        .config_loader
+        .borrow()
+        .test_control_config(&detected, check)
+    {
+        error!(
+            "The detected configuration `{}` cannot be loaded: {}",
+            detected, e
+        );
+        return;
+    }
+
+    info!("Using the detected configuration `{}`", detected);
+    state.config.replace(detected);
+    state.config_source.replace(ConfigSource::Detected);
+    if state.core.borrow().persist_on_exit {
+        if let Err(e) = state.as_service_config().save() {
+            error!("Error while saving service config: {}", e);
+        }
+    }
 }
 
 /// Get the fan configuration in the `state` if applicable, else blocks the process until a
 /// valid one is provided.
 fn get_fan_config(
     state: Rc<State>,
-    dbus_conn: &LocalConnection,
+    dbus_conn: Option<&LocalConnection>,
+    watchdog: &mut Watchdog,
 ) -> Result<nbfc::FanControlConfigV2> {
     if state.config.borrow().trim().is_empty() {
+        detect_fan_config(&state);
+    }
+
+    if state.config.borrow().trim().is_empty() {
+        // The registers of the fans are only known from a control config.
+        info!("No control config is selected, the fans are left to the firmware until one is set");
+        if state.core.borrow().no_config_behavior != NoConfigBehavior::Firmware {
+            warn!("`core.no_config_behavior` only applies once a control config has been loaded");
+        }
         // Blocking the process until a valid configuration is provided.
         loop {
-            dbus_conn.process(Duration::from_millis(1000)).unwrap();
+            watchdog.ping();
+            process_dbus(dbus_conn, Duration::from_millis(1000))?;
             let fan_config = state.config.borrow();
             let config_loader = state.config_loader.borrow();
             match config_loader.test_control_config(&*fan_config, false) {
@@ -271,114 +1336,560 @@ fn get_fan_config(
         .context(ControlConfigLoad {})
 }
 
-fn main_loop<T: RW>(
-    ec_manager: Rc<Mutex<ECManager<T>>>,
-    dbus_conn: LocalConnection,
+/// Wait `delay` before the first access to the EC, to let the firmware initialize the fans.
+///
+/// The D-Bus requests are still answered and the temperatures still read meanwhile.
+fn wait_startup_delay(
+    state: &State,
+    dbus_conn: Option<&LocalConnection>,
+    watchdog: &mut Watchdog,
+    delay: Duration,
+) -> Result<()> {
+    if delay.is_zero() {
+        return Ok(());
+    }
+    info!(
+        "Waiting {}s before taking the control of the fans",
+        delay.as_secs()
+    );
+
+    let until = Instant::now() + delay;
+    loop {
+        let remaining = until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+
+        watchdog.ping();
+        if let Ok(temps) = Temperatures::get_temps(HashMap::new()) {
+            temps.update_map(&mut state.temps_raw.borrow_mut());
+            temps
+                .calibrated(&state.sensors.borrow().offsets)
+                .update_map(&mut state.temps.borrow_mut());
+        }
+        process_dbus(dbus_conn, remaining.min(Duration::from_secs(1)))?;
+    }
+}
+
+fn main_loop(
+    ec_manager: Rc<Mutex<Manager>>,
+    dbus_conn: Option<LocalConnection>,
     state: Rc<State>,
+    mut watchdog: Watchdog,
 ) -> Result<()> {
+    let dbus_conn = dbus_conn.as_ref();
     let signal_received = Arc::new(AtomicBool::new(false));
     register(SIGTERM, Arc::clone(&signal_received)).context(Signal {})?;
+    let reload_received = Arc::new(AtomicBool::new(false));
+    register(SIGHUP, Arc::clone(&reload_received)).context(Signal {})?;
+
+    let mut poll_controller = PollController::default();
+    let mut critical_detector = CriticalDetector::default();
+    let mut stuck_detector = StuckDetector::default();
+    let mut rolling_max = RollingMax::default();
+    // The labels of lm-sensors are read once, the sensors are read at each poll.
+    state.sensors_labels.replace(read_sensors_labels(
+        &SENSORS_CONF_PATHS.iter().map(Path::new).collect::<Vec<_>>(),
+    ));
+    let mut smoothed_temps: Option<Temperatures> = None;
+    let mut external_sensors = ExternalSensors::default();
+    let mut speeds_notifier = FanSpeedsNotifier::default();
+    let mut speeds_filter = FanSpeedsFilter::default();
+    let mut stall_detector = FanStallDetector::default();
+    #[cfg(feature = "metrics")]
+    let metrics_server = start_metrics_server(&state);
+    let mut socket_server = start_socket_server(&state);
+    let mut config_watcher = start_config_watcher(&state);
+    #[cfg(not(feature = "metrics"))]
+    if state.core.borrow().metrics_port.is_some() {
+        error!(
+            "The metrics endpoint is not available, fancyd was built without the `metrics` feature"
+        );
+    }
+    let mut last_tick = Instant::now();
+    let mut last_poll: Option<Instant> = None;
+    let mut sensors_lost = false;
+    let mut state_exporter = StateExporter::default();
+    let mut export_failed = false;
+    let mut power_reader = PowerReader::default();
+    let mut power_failed = false;
+    let mut calibration: Option<Calibration> = None;
+    let mut ec_errors = 0;
+    let mut last_power_check: Option<Instant> = None;
 
     while !signal_received.load(Ordering::Relaxed) {
+        // The ping stops if the loop is stuck, so systemd can restart the service.
+        watchdog.ping();
+        *state.loop_iterations.borrow_mut() += 1;
+
+        if let Some(changes) = config_watcher.as_mut().and_then(|w| w.changes()) {
+            if changes.service_config && service_config_modified(&state) {
+                info!("The service configuration has been modified");
+                state.reload_service_config.replace(true);
+            }
+            if !changes.control_configs.is_empty() {
+                state.configs_changed.replace(true);
+                // Only the changes of the selected config, or of one it extends, are applied.
+                let selected = state
+                    .config_loader
+                    .borrow()
+                    .extended_configs(&state.config.borrow());
+                if changes.control_configs.iter().any(|c| selected.contains(c)) {
+                    info!("The control configuration has been modified");
+                    state.reload_control_config.replace(true);
+                }
+            }
+        }
+
+        if reload_received.swap(false, Ordering::Relaxed) || state.reload_service_config.take() {
+            if let Err(e) = reload_service_config(&state, &ec_manager) {
+                error!(
+                    "Error while reloading service configuration: {}
+                Keeping old configuration",
+                    e
+                );
+            }
+        }
+
         // We should normally use a timer (or convert service to async?) to call the function at an interval but instead of losing time,
         // we treat the D-Bus requests.
-        let timeout = {
-            let t = ec_manager.lock().unwrap().poll_interval;
-            if t > Duration::ZERO {
-                t
+        // The EC poll interval of the control config is used unless the service config overrides
+        // it, and the temperatures are read at the same interval unless specified otherwise.
+        let (ec_interval, base_interval) = {
+            let core = state.core.borrow();
+            let ec_interval = match core.ec_poll_interval {
+                Some(t) => Duration::from_millis(t),
+                None => ec_manager.lock().unwrap().poll_interval,
+            };
+            let ec_interval = if ec_interval > Duration::ZERO {
+                ec_interval
             } else {
                 Duration::from_millis(100)
-            }
+            };
+            let temp_interval = core
+                .temp_poll_interval
+                .map(Duration::from_millis)
+                .unwrap_or(ec_interval);
+            (ec_interval, temp_interval)
+        };
+        let timeout = if state.core.borrow().adaptive_poll {
+            poll_controller.interval().max(base_interval)
+        } else {
+            base_interval
         };
-        dbus_conn.process(timeout).context(DBus {})?;
+        // The EC can't be accessed more often than the loop runs.
+        let ec_interval = ec_interval.max(timeout);
+        state.poll_interval.replace(ec_interval.as_millis() as u64);
+        ec_manager
+            .lock()
+            .unwrap()
+            .set_read_retries_budget(ec_interval / READ_RETRIES_SHARE);
+        process_dbus(dbus_conn, timeout)?;
+        if let Some(server) = &mut socket_server {
+            server.serve(&state);
+        }
 
         // If the config have not been changed in the callback, we keep the old configuration
         if let Some(old_config) = state.old_config.take() {
             state.config.replace(old_config);
         }
 
-        if *state.manual_set_target_speeds.borrow() {
-            let mut prop_changed: PropertiesPropertiesChanged = Default::default();
-            prop_changed.changed_properties.insert(
-                "TargetFansSpeeds".into(),
-                Variant(Box::new(state.target_fans_speeds.borrow().clone())),
+        if state.reload_control_config.replace(false) {
+            match reload_control_config(&state, &ec_manager) {
+                Ok(()) => {}
+                // The config applied is kept if there is one, the fans are only handled without
+                // config when none is applied.
+                Err(e @ ServiceError::ControlConfigLoad { .. })
+                    if ec_manager.lock().unwrap().fan_configs.is_empty() =>
+                {
+                    error!("Error while reloading control config: {}", e);
+                    let mut ec_manager = ec_manager.lock().unwrap();
+                    if let Err(e) = apply_no_config_behavior(&state, &mut ec_manager) {
+                        error!(
+                            "Error while handling the fans without control config: {}",
+                            e
+                        );
+                    }
+                }
+                Err(e) => error!(
+                    "Error while reloading control config: {}
+                Keeping old configuration",
+                    e
+                ),
+            }
+        }
+
+        if let Some(name) = state.pending_profile.take() {
+            if let Err(e) = apply_profile(&state, &ec_manager, &name) {
+                error!(
+                    "Error while loading profile `{}`: {}
+                Keeping old configuration",
+                    name, e
+                );
+            }
+        }
+
+        if state.check_manual_timeout(Instant::now()) {
+            info!("No manual command received during the manual timeout, setting the fans in automatic mode");
+        }
+
+        emit_changed_properties(&state, dbus_conn);
+
+        if state.configs_changed.take() {
+            match state.config_loader.borrow().available_configs() {
+                Ok(configs) => {
+                    let signal = ComMusikidFancyAvailableConfigsChanged { configs };
+                    send_signal(dbus_conn, signal.to_emit_message(&DBUS_PATH));
+                }
+                Err(e) => error!("Error while listing the control configs: {}", e),
+            }
+        }
+
+        // The boost is applied and stopped right away, without waiting for the EC poll interval.
+        let mut boost_changed = false;
+        if let Some(duration) = state.boost_started.take() {
+            info!("Boosting the fans for {} s", duration);
+            send_signal(
+                dbus_conn,
+                ComMusikidFancyBoostStarted { duration }.to_emit_message(&DBUS_PATH),
+            );
+            boost_changed = true;
+        }
+        if matches!(*state.boost_until.borrow(), Some(until) if Instant::now() >= until) {
+            info!("End of the boost");
+            state.boost_until.replace(None);
+            send_signal(
+                dbus_conn,
+                ComMusikidFancyBoostEnded {}.to_emit_message(&DBUS_PATH),
             );
+            boost_changed = true;
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(server) = &metrics_server {
+            server.serve(&state);
+        }
+
+        if state.core.borrow().export_state {
+            match state_exporter.export(&state, &STATE_FILE_PATH) {
+                Ok(_) => export_failed = false,
+                Err(e) if !export_failed => {
+                    error!("Error while exporting the state: {}", e);
+                    export_failed = true;
+                }
+                Err(_) => {}
+            }
+        }
+
+        if last_power_check.map_or(true, |t| t.elapsed() >= POWER_STATE_INTERVAL) {
+            last_power_check = Some(Instant::now());
+            update_power_state(&state, dbus_conn);
+        }
 
-            let _ = dbus_conn.send(prop_changed.to_emit_message(&DBusPath::from(OBJ_PATH_STR)));
+        if *state.suspended.borrow() || *state.ec_failsafe.borrow() {
+            continue;
         }
-        state.manual_set_target_speeds.replace(false);
 
         let mut ec_manager = ec_manager.lock().unwrap();
 
         // TODO: Find a way to optimize that
-        let current_temps = Temperatures::get_temps().context(Sensor {})?;
-        let mut state_temps = state.temps.borrow_mut();
-        current_temps.update_map(&mut state_temps);
-        debug!("Temperatures: {:#?}", state_temps);
+        // The sensors are probed again at each iteration, so they are used again as soon as they
+        // come back.
+        let sensors = state.sensors.borrow();
+        // The commands of the external sensors are run at most once per EC poll, even when
+        // the loop is woken up by a D-Bus request.
+        let mut external_temps =
+            external_sensors.poll(&sensors.external, ec_interval, Instant::now());
+        let labeled_temps = read_labeled_temps(
+            &state.hwmon_class_path.borrow(),
+            &state.sensors_labels.borrow(),
+            sensors
+                .only
+                .iter()
+                .chain(sensors.temperature_source.values()),
+        );
+        for (name, temp) in labeled_temps {
+            external_temps.entry(name).or_insert(temp);
+        }
+        let current_temps = Temperatures::get_temps(external_temps);
+        {
+            let mut available = HashMap::new();
+            if let Ok(temps) = &current_temps {
+                temps.update_map(&mut available);
+            }
+            let missing = missing_sensors(&sensors, &available);
+            let mut state_missing = state.missing_sensors.borrow_mut();
+            for name in missing.iter().filter(|m| !state_missing.contains(m)) {
+                warn!("Sensor {} is not available, ignoring it", name);
+            }
+            for name in state_missing.iter().filter(|m| !missing.contains(m)) {
+                info!("Sensor {} is available again", name);
+            }
+            *state_missing = missing;
+        }
 
-        let temp = match *state.temp_compute.borrow() {
-            TempComputeMethod::CPUOnly => current_temps.cpu_temp,
-            TempComputeMethod::AllSensors => {
-                let temp_values = state_temps.values();
-                temp_values.clone().sum::<f64>() / temp_values.len() as f64
+        let raw_temps = match current_temps {
+            Ok(temps) => {
+                sensors_lost = false;
+                temps
+            }
+            Err(e) => {
+                // Without any temperature, the fans are kept at full speed.
+                if !sensors_lost {
+                    error!("{}, setting the fans to full speed", e);
+                    sensors_lost = true;
+                }
+                ec_manager.bypass_speed_limits = state.core.borrow().critical_bypasses_speed_limits;
+                let mut result = Ok(());
+                for i in 0..ec_manager.fan_configs.len() {
+                    if ec_manager.fan_configs[i].current_speed != Some(100.0) {
+                        result = ec_manager.write_fan_speed(i, 100.0).context(ECIO {});
+                        if result.is_err() {
+                            break;
+                        }
+                    }
+                }
+                if let Err(e) = result {
+                    handle_ec_error(&state, &mut ec_manager, dbus_conn, &mut ec_errors, e)?;
+                }
+                continue;
             }
         };
+        let mut state_temps = state.temps_raw.borrow_mut();
+        raw_temps.update_map(&mut state_temps);
+        // The D-Bus requests wake the loop up before the poll interval, the samples of the
+        // temperature are only counted once per poll.
+        let new_poll = last_poll.map_or(true, |t| t.elapsed() >= timeout);
+        if new_poll {
+            last_poll = Some(Instant::now());
+        }
 
-        debug!("Computed temperature: {}", temp);
+        let loaded = sensors.stuck.timeout > 0
+            && read_load(Path::new(LOADAVG_PATH)).map_or(false, |l| l >= sensors.stuck.load);
+        let stuck = stuck_detector.update(
+            &state_temps,
+            loaded,
+            Instant::now(),
+            Duration::from_secs(sensors.stuck.timeout),
+        );
+        {
+            let mut state_stuck = state.stuck_sensors.borrow_mut();
+            for name in stuck.iter().filter(|s| !state_stuck.contains(s)) {
+                warn!(
+                    "Sensor {} has not changed for a long time under load, it may be stuck",
+                    name
+                );
+            }
+            for name in state_stuck.iter().filter(|s| !stuck.contains(s)) {
+                info!("Sensor {} is changing again", name);
+            }
+            *state_stuck = stuck;
+        }
 
-        let critical_now = *state.critical.borrow();
-        let mut critical_temp = state.critical.borrow_mut();
+        // Everything but `TemperaturesRaw` uses the calibrated temperatures.
+        let mut current_temps = raw_temps.calibrated(&sensors.offsets);
+        if let Some(fallback) = sensors.stuck.fallback_temperature {
+            current_temps = current_temps.raised(&state.stuck_sensors.borrow(), fallback);
+        }
+        let smoothed = current_temps.smoothed(smoothed_temps.as_ref(), sensors.smoothing);
+        smoothed.update_map(&mut state.temps.borrow_mut());
+        debug!("Temperatures: {:#?}", state_temps);
 
-        *critical_temp = if !critical_now {
-            temp as u8 >= ec_manager.critical_temperature
+        // The fans follow the smoothed temperature, but the critical state is based on the raw one
+        // so that peaks are never missed.
+        let temp = smoothed.compute(*state.temp_compute.borrow(), &sensors);
+        let raw_temp = current_temps.compute(*state.temp_compute.borrow(), &sensors);
+        smoothed_temps = Some(smoothed);
+        // A dip of the temperature doesn't slow the fans down until it lasts.
+        let control_temp = if new_poll {
+            rolling_max.update(temp, sensors.rolling_max_window)
         } else {
-            ec_manager.critical_temperature.saturating_sub(temp as u8) <= CRITICAL_INTERVAL
+            rolling_max.current(temp)
         };
-        debug!("Critical state: {}", *critical_temp);
 
-        let mut fans_speeds = state.fans_speeds.borrow_mut();
+        let rule = state.profile_rules.borrow_mut().update(
+            Instant::now(),
+            temp,
+            &state.auto_profiles.borrow(),
+        );
+        if let Some(i) = rule {
+            let profile = state.auto_profiles.borrow().rules[i].profile.to_owned();
+            info!("Switching to profile `{}` (rule #{})", profile, i);
+            state.pending_profile.replace(Some(profile));
+        }
+
+        debug!(
+            "Computed temperature: {} (raw: {}, control: {})",
+            temp, raw_temp, control_temp
+        );
 
-        for i in 0..ec_manager.fan_configs.len() {
-            fans_speeds[i] = ec_manager.read_fan_speed(i).context(ECIO {})?;
-            debug!(
-                "Fan speed for {} with index {}: {:#?}",
-                ec_manager.fan_configs[i].name, i, fans_speeds[i]
+        {
+            let core = state.core.borrow();
+            if core.adaptive_poll {
+                let interval = poll_controller.update(
+                    temp,
+                    base_interval,
+                    Duration::from_millis(core.max_poll_interval),
+                    core.poll_temp_delta,
+                );
+                debug!("Poll interval: {:?}", interval);
+            }
+        }
+
+        let critical_temperature = state.critical_temperature();
+        let critical_now = *state.critical.borrow();
+        let mut critical_temp = state.critical.borrow_mut();
+
+        if new_poll {
+            let core = state.core.borrow();
+            *critical_temp = critical_detector.update(
+                raw_temp,
+                critical_temperature,
+                core.critical_entry_samples,
+                core.critical_exit_hysteresis,
             );
+        }
+        debug!("Critical state: {}", *critical_temp);
 
-            // If there is a target fan speed set by the user
-            let user_defined_speed =
-                !*state.auto.borrow() && state.target_fans_speeds.borrow().get(i).is_some();
+        if *critical_temp && !critical_now {
+            // The hottest sensor is reported as the one which triggered the critical state.
+            let sensor = state_temps
+                .iter()
+                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
+                .map(|(name, _)| name.to_owned())
+                .unwrap_or_default();
+            info!("Critical temperature reached ({}°C, {})", raw_temp, sensor);
 
-            if *critical_temp {
-                ec_manager.write_fan_speed(i, 100.0).context(ECIO {})?;
-            } else if user_defined_speed {
-                debug!(
-                    "Target fan speed for {} with index {}: {}",
-                    ec_manager.fan_configs[i].name,
-                    i,
-                    state.target_fans_speeds.borrow()[i]
-                );
-                ec_manager
-                    .write_fan_speed(i, state.target_fans_speeds.borrow()[i])
-                    .context(ECIO {})?;
+            let signal = ComMusikidFancyCriticalTemperatureReached {
+                sensor,
+                temperature: state.core.borrow().temperature_unit.convert(raw_temp),
+            };
+            send_signal(dbus_conn, signal.to_emit_message(&DBUS_PATH));
+        }
+
+        // A change of the critical state is applied right away, without waiting for the EC poll
+        // interval.
+        if last_tick.elapsed() < ec_interval && *critical_temp == critical_now && !boost_changed {
+            continue;
+        }
+
+        let elapsed = last_tick.elapsed().as_secs_f64();
+        last_tick = Instant::now();
+
+        // The power draw rises before the temperature, so it sets a minimal speed to the fans in
+        // automatic mode.
+        let power_floor = {
+            let power = state.power.borrow();
+            if power.floor.is_empty() {
+                0.0
+            } else {
+                let zone = Path::new(POWERCAP_CLASS_PATH).join(&power.zone);
+                match power_reader.read(&zone, Instant::now()) {
+                    Ok(Some(watts)) => {
+                        power_failed = false;
+                        debug!("Power draw: {:.1} W", watts);
+                        speed_floor(&power.floor, watts)
+                    }
+                    Ok(None) => 0.0,
+                    Err(e) => {
+                        if !power_failed {
+                            error!(
+                                "Error while reading the power draw of {}: {}",
+                                power.zone, e
+                            );
+                            power_failed = true;
+                        }
+                        0.0
+                    }
+                }
             }
-            // If the function returns `true`, the threshold has changed.
-            // Else, there is nothing to change.
-            else if ec_manager.refresh_fan_threshold(current_temps.cpu_temp, i) {
-                let threshold = ec_manager.fan_configs[i].current_threshold;
-                debug!("Selected threshold #{}", threshold);
-                let value = ec_manager.fan_configs[i].thresholds[threshold]
-                    .fan_speed
-                    .into();
-                debug!("Threshold fan speed: {}", value);
+        };
 
-                ec_manager.write_fan_speed(i, value).context(ECIO {})?;
+        // The critical state is read again by the control.
+        drop(critical_temp);
+        let fans_rpm = match control_fans(
+            &state,
+            &mut ec_manager,
+            dbus_conn,
+            &mut calibration,
+            control_temp,
+            elapsed,
+            power_floor,
+        ) {
+            Ok(fans_rpm) => {
+                ec_errors = 0;
+                state.last_ec_read.replace(Some(SystemTime::now()));
+                fans_rpm
             }
+            Err(e) => {
+                handle_ec_error(&state, &mut ec_manager, dbus_conn, &mut ec_errors, e)?;
+                continue;
+            }
+        };
+        {
+            // Only the reported speeds are filtered, the control doesn't use them.
+            let core = state.core.borrow();
+            let mut fans_speeds = state.fans_speeds.borrow_mut();
+            *fans_speeds = speeds_filter.apply(
+                &fans_speeds,
+                core.reported_speeds_smoothing,
+                core.reported_speeds_decimals,
+            );
+            speeds_notifier.exact = core.reported_speeds_decimals.is_some();
+        }
+        let fans_speeds = state.fans_speeds.borrow();
+
+        // The registers are read here so that the inspection never races with the control.
+        if *state.debug_ec.borrow() {
+            match ec_manager.read_registers(0, 256) {
+                Ok(registers) => {
+                    state.ec_registers.replace(registers);
+                }
+                Err(e) => {
+                    error!("Error while reading the EC registers: {}", e);
+                    state.last_ec_error.replace(Some(e.to_string()));
+                }
+            }
+        }
+
+        if let Some(signal) = speeds_notifier.check(&fans_speeds, Instant::now()) {
+            send_signal(dbus_conn, signal.to_emit_message(&DBUS_PATH));
+        }
+
+        state
+            .fans_rpm
+            .replace(fans_rpm.iter().map(|r| r.unwrap_or(0.0)).collect());
+        state.fans_raw_values.replace(
+            ec_manager
+                .fan_configs
+                .iter()
+                .map(|f| f.raw_value.unwrap_or(0))
+                .collect(),
+        );
+        let commanded = state.computed_fans_speeds.borrow();
+        for i in stall_detector.check(&commanded, &fans_rpm) {
+            let name = state.fan_name(i, &ec_manager.fan_configs[i].name);
+            error!(
+                "{} is stalled: it should spin at {}% but reads 0 RPM",
+                name, commanded[i]
+            );
+
+            let signal = ComMusikidFancyFanStalled {
+                index: i as u32,
+                name,
+            };
+            send_signal(dbus_conn, signal.to_emit_message(&DBUS_PATH));
         }
     }
 
     // We exit the loop
     info!("Exiting");
-    let mut ec_manager = ec_manager.lock().unwrap();
-    ec_manager.reset_ec(true).context(ECIO {})
+    if let Some(c) = calibration {
+        ec_manager
+            .lock()
+            .unwrap()
+            .write_register(c.register, c.original_value)
+            .context(ECIO {})?;
+    }
+    Ok(())
 }
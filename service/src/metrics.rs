@@ -0,0 +1,155 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Minimal HTTP server exposing the service state in the Prometheus format on `/metrics`.
+use log::{debug, error};
+
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use crate::state::State;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Escape a label value of the Prometheus format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the metrics from the same data as the D-Bus properties.
+pub(crate) fn render(state: &State) -> String {
+    let mut out = String::new();
+    let names = state.fans_names.borrow();
+    let fan_label = |i: usize| {
+        let name = names.get(i).map(String::as_str).unwrap_or_default();
+        format!("fan=\"{}\",name=\"{}\"", i, escape_label(name))
+    };
+
+    out.push_str("# HELP fancy_fan_speed_percent Current speed of the fan.\n");
+    out.push_str("# TYPE fancy_fan_speed_percent gauge\n");
+    for (i, speed) in state.fans_speeds.borrow().iter().enumerate() {
+        let _ = writeln!(out, "fancy_fan_speed_percent{{{}}} {}", fan_label(i), speed);
+    }
+
+    out.push_str("# HELP fancy_fan_target_speed_percent Speed set by the user for the fan.\n");
+    out.push_str("# TYPE fancy_fan_target_speed_percent gauge\n");
+    for (i, speed) in state.target_fans_speeds.borrow().iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "fancy_fan_target_speed_percent{{{}}} {}",
+            fan_label(i),
+            speed
+        );
+    }
+
+    out.push_str("# HELP fancy_temperature_celsius Temperature of the sensor.\n");
+    out.push_str("# TYPE fancy_temperature_celsius gauge\n");
+    let temps = state.temps.borrow();
+    let mut sensors: Vec<_> = temps.keys().collect();
+    sensors.sort();
+    for sensor in sensors {
+        let _ = writeln!(
+            out,
+            "fancy_temperature_celsius{{sensor=\"{}\"}} {}",
+            escape_label(sensor),
+            temps[sensor]
+        );
+    }
+
+    out.push_str("# HELP fancy_critical Whether the temperature is critical.\n");
+    out.push_str("# TYPE fancy_critical gauge\n");
+    let _ = writeln!(out, "fancy_critical {}", u8::from(*state.critical.borrow()));
+
+    out
+}
+
+/// Serves the metrics without blocking the main loop.
+#[derive(Debug)]
+pub(crate) struct MetricsServer {
+    listener: TcpListener,
+}
+
+impl MetricsServer {
+    pub fn bind(address: &str, port: u16) -> Result<Self> {
+        let listener = TcpListener::bind((address, port))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(MetricsServer { listener })
+    }
+
+    /// Answer all the pending requests.
+    pub fn serve(&self, state: &State) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = handle_request(stream, state) {
+                        debug!("Error while answering a metrics request: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Error while accepting a metrics request: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn handle_request(mut stream: TcpStream, state: &State) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf)?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = request.split_whitespace();
+
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(state)),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn render_metrics() {
+        let state = State {
+            fans_speeds: RefCell::new(vec![42.0]),
+            target_fans_speeds: RefCell::new(vec![50.0]),
+            fans_names: RefCell::new(vec!["CPU \"fan\"".to_owned()]),
+            temps: RefCell::new(vec![("CPU".to_owned(), 55.5)].into_iter().collect()),
+            critical: RefCell::new(true),
+            ..Default::default()
+        };
+
+        let metrics = render(&state);
+        let labels = r#"{fan="0",name="CPU \"fan\""}"#;
+        assert!(metrics.contains(&format!("fancy_fan_speed_percent{} 42\n", labels)));
+        assert!(metrics.contains(&format!("fancy_fan_target_speed_percent{} 50\n", labels)));
+        assert!(metrics.contains("fancy_temperature_celsius{sensor=\"CPU\"} 55.5\n"));
+        assert!(metrics.contains("fancy_critical 1\n"));
+    }
+}
@@ -0,0 +1,193 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Power draw read from the energy counters of the powercap interface (Intel RAPL),
+//! and power source (AC or battery) read from the power supply class.
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::config::service::{PowerConfig, PowerFloor, PowerSourceConfig};
+
+pub const POWERCAP_CLASS_PATH: &str = "/sys/class/powercap";
+pub const POWER_SUPPLY_CLASS_PATH: &str = "/sys/class/power_supply";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Source of power of the system.
+pub(crate) enum PowerState {
+    Ac,
+    Battery,
+    /// There is no AC adapter, as on a desktop.
+    Unknown,
+}
+impl Default for PowerState {
+    fn default() -> Self {
+        PowerState::Unknown
+    }
+}
+impl PowerState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerState::Ac => "ac",
+            PowerState::Battery => "battery",
+            PowerState::Unknown => "unknown",
+        }
+    }
+
+    /// Get the settings of the power source in `config`.
+    pub fn config<'a>(&self, config: &'a PowerConfig) -> Option<&'a PowerSourceConfig> {
+        match self {
+            PowerState::Ac => Some(&config.ac),
+            PowerState::Battery => Some(&config.battery),
+            PowerState::Unknown => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Computes the power draw of a powercap zone from two reads of its energy counter.
+pub(crate) struct PowerReader {
+    /// Zone, instant and energy (in µJ) of the last read.
+    last: Option<(PathBuf, Instant, u64)>,
+}
+
+fn read_counter(path: &Path) -> Result<u64> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+impl PowerReader {
+    /// Read the power draw (in W) of the zone since the last read.
+    ///
+    /// Returns `None` on the first read, or if the zone changed.
+    pub fn read(&mut self, zone: &Path, now: Instant) -> Result<Option<f64>> {
+        let energy = read_counter(&zone.join("energy_uj"))?;
+        let last = self.last.replace((zone.to_owned(), now, energy));
+
+        let (last_instant, last_energy) = match last {
+            Some((last_zone, instant, energy)) if last_zone == zone => (instant, energy),
+            _ => return Ok(None),
+        };
+        let elapsed = now.saturating_duration_since(last_instant).as_secs_f64();
+        if elapsed <= 0.0 {
+            return Ok(None);
+        }
+
+        // The counter wraps around its maximal value.
+        let delta = if energy >= last_energy {
+            energy - last_energy
+        } else {
+            let range = read_counter(&zone.join("max_energy_range_uj"))?;
+            range.saturating_sub(last_energy) + energy
+        };
+        Ok(Some(delta as f64 / 1e6 / elapsed))
+    }
+}
+
+/// Get the power state from the AC adapters (the `Mains` supplies) listed in `class_path`.
+///
+/// The system is on AC if any of them is online.
+pub(crate) fn read_power_state(class_path: &Path) -> PowerState {
+    let entries = match fs::read_dir(class_path) {
+        Ok(entries) => entries,
+        Err(_) => return PowerState::Unknown,
+    };
+
+    let mut state = PowerState::Unknown;
+    for supply in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let is_mains =
+            fs::read_to_string(supply.join("type")).map_or(false, |t| t.trim() == "Mains");
+        if !is_mains {
+            continue;
+        }
+        match fs::read_to_string(supply.join("online")).map(|o| o.trim() == "1") {
+            Ok(true) => return PowerState::Ac,
+            Ok(false) => state = PowerState::Battery,
+            Err(_) => {}
+        }
+    }
+    state
+}
+
+/// Get the minimal speed of the fans for a power draw of `watts`,
+/// interpolated between the points of `floor`.
+///
+/// There is no minimal speed under the first point.
+pub(crate) fn speed_floor(floor: &[PowerFloor], watts: f64) -> f64 {
+    match floor.iter().position(|p| p.watts > watts) {
+        Some(0) => 0.0,
+        Some(i) => {
+            let (low, high) = (&floor[i - 1], &floor[i]);
+            let ratio = (watts - low.watts) / (high.watts - low.watts);
+            low.speed + ratio * (high.speed - low.speed)
+        }
+        None => floor.last().map_or(0.0, |p| p.speed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+    use std::time::Duration;
+
+    #[test]
+    fn power_draw() {
+        let zone = TempDir::new("rapl");
+        fs::write(zone.join("max_energy_range_uj"), "1000000000\n").unwrap();
+        let start = Instant::now();
+        let mut reader = PowerReader::default();
+
+        fs::write(zone.join("energy_uj"), "999000000\n").unwrap();
+        let first = reader.read(&zone, start).unwrap();
+        // 15 J in 500 ms, across the wrap around of the counter
+        fs::write(zone.join("energy_uj"), "14000000\n").unwrap();
+        let second = reader.read(&zone, start + Duration::from_millis(500));
+
+        assert_eq!(first, None);
+        assert_eq!(second.unwrap(), Some(30.0));
+    }
+
+    #[test]
+    fn power_state() {
+        let class = TempDir::new("supply");
+        for (name, kind, online) in [("AC", "Mains", "0"), ("BAT0", "Battery", "1")] {
+            fs::create_dir_all(class.join(name)).unwrap();
+            fs::write(class.join(name).join("type"), format!("{}\n", kind)).unwrap();
+            fs::write(class.join(name).join("online"), format!("{}\n", online)).unwrap();
+        }
+        let battery = read_power_state(&class);
+        fs::write(class.join("AC").join("online"), "1\n").unwrap();
+        let ac = read_power_state(&class);
+
+        assert_eq!(battery, PowerState::Battery);
+        assert_eq!(ac, PowerState::Ac);
+        assert_eq!(
+            read_power_state(&class.join("missing")),
+            PowerState::Unknown
+        );
+    }
+
+    #[test]
+    fn interpolate_speed_floor() {
+        let floor = [
+            PowerFloor {
+                watts: 20.0,
+                speed: 30.0,
+            },
+            PowerFloor {
+                watts: 40.0,
+                speed: 70.0,
+            },
+        ];
+        assert_eq!(speed_floor(&floor, 10.0), 0.0);
+        assert_eq!(speed_floor(&floor, 20.0), 30.0);
+        assert_eq!(speed_floor(&floor, 25.0), 40.0);
+        assert_eq!(speed_floor(&floor, 60.0), 70.0);
+        assert_eq!(speed_floor(&[], 60.0), 0.0);
+    }
+}
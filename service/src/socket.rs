@@ -0,0 +1,355 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Control interface over a Unix socket, mirroring the main D-Bus methods.
+//!
+//! Each message is a JSON document prefixed by its length (a big-endian `u32`).
+//! A request is `{"method": "SetTargetFanSpeed", "args": [0, 50.0]}`,
+//! and the answer is `{"result": ...}` or `{"error": "..."}`.
+use dbus_tree::MethodErr;
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use std::fs::{self, Permissions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::bus::interfaces::ComMusikidFancy;
+use crate::state::State;
+
+/// Time given to a client to send its request and receive the answer, since it connected.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximal number of clients served at the same time, the others are disconnected right away.
+const MAX_CLIENTS: usize = 16;
+/// Maximal length of a request, to not allocate whatever length is received.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    method: String,
+    #[serde(default)]
+    args: Vec<Value>,
+}
+
+/// Serves the requests received on the socket without blocking the main loop.
+#[derive(Debug)]
+pub(crate) struct SocketServer {
+    listener: UnixListener,
+    path: PathBuf,
+    /// Clients whose request hasn't been answered entirely yet.
+    clients: Vec<Client>,
+}
+
+impl SocketServer {
+    pub fn bind(path: &Path) -> Result<Self> {
+        // The socket of a previous run has to be removed to bind it again.
+        if fs::symlink_metadata(path).map_or(false, |m| m.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, Permissions::from_mode(0o660))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(SocketServer {
+            listener,
+            path: path.to_owned(),
+            clients: Vec::new(),
+        })
+    }
+
+    /// Accept the new clients and answer the requests received entirely.
+    ///
+    /// The requests received partially are kept until the rest is received by a later call.
+    pub fn serve(&mut self, state: &State) {
+        let now = Instant::now();
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if self.clients.len() >= MAX_CLIENTS {
+                        debug!("Too many socket clients, disconnecting the new one");
+                        continue;
+                    }
+                    match stream.set_nonblocking(true) {
+                        Ok(()) => self.clients.push(Client::new(stream, now)),
+                        Err(e) => debug!("Error while accepting a socket request: {}", e),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Error while accepting a socket request: {}", e);
+                    break;
+                }
+            }
+        }
+
+        self.clients
+            .retain_mut(|client| match client.progress(state) {
+                Ok(true) => false,
+                Ok(false) if now.saturating_duration_since(client.connected) > CLIENT_TIMEOUT => {
+                    debug!("The socket client timed out");
+                    false
+                }
+                Ok(false) => true,
+                Err(e) => {
+                    debug!("Error while answering a socket request: {}", e);
+                    false
+                }
+            });
+    }
+}
+
+impl Drop for SocketServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Connection of a client sending a single request, on a non-blocking stream.
+#[derive(Debug)]
+struct Client {
+    stream: UnixStream,
+    connected: Instant,
+    /// Bytes of the request received so far.
+    request: Vec<u8>,
+    /// Bytes of the answer not sent yet, once the request has been answered.
+    answer: Option<Vec<u8>>,
+}
+
+impl Client {
+    fn new(stream: UnixStream, connected: Instant) -> Self {
+        Client {
+            stream,
+            connected,
+            request: Vec::new(),
+            answer: None,
+        }
+    }
+
+    /// Receive what the client sent, answer its request once it's complete and send what can be
+    /// sent of the answer. Returns whether the answer has been sent entirely.
+    fn progress(&mut self, state: &State) -> Result<bool> {
+        if self.answer.is_none() {
+            self.receive()?;
+            let request = match parse_message(&self.request)? {
+                Some(request) => request,
+                None => return Ok(false),
+            };
+            let mut answer = Vec::new();
+            write_message(&mut answer, answer_request(state, request).as_bytes())?;
+            self.answer = Some(answer);
+        }
+        self.send()
+    }
+
+    /// Read what has been received, up to the end of the request.
+    fn receive(&mut self) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        while parse_message(&self.request)?.is_none() {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "The connection was closed before the end of the request",
+                    ))
+                }
+                Ok(len) => self.request.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write what can be written of the answer, returns whether it has been written entirely.
+    fn send(&mut self) -> Result<bool> {
+        let answer = match &mut self.answer {
+            Some(answer) => answer,
+            None => return Ok(false),
+        };
+        while !answer.is_empty() {
+            match self.stream.write(answer) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(len) => {
+                    answer.drain(..len);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn answer_request(state: &State, request: &[u8]) -> String {
+    let answer = match serde_json::from_slice::<Request>(request) {
+        Ok(request) => match call(state, &request.method, &request.args) {
+            Ok(result) => json!({ "result": result }),
+            Err(e) => json!({ "error": e }),
+        },
+        Err(e) => json!({ "error": format!("Invalid request: {}", e) }),
+    };
+    answer.to_string()
+}
+
+/// Get the message at the start of `buf`, if it has been received entirely.
+fn parse_message(buf: &[u8]) -> Result<Option<&[u8]>> {
+    let len = match buf.get(..4) {
+        Some(len) => u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize,
+        None => return Ok(None),
+    };
+    if len > MAX_MESSAGE_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "The request is too long",
+        ));
+    }
+
+    Ok(buf.get(4..4 + len))
+}
+
+fn write_message<W: Write>(writer: &mut W, message: &[u8]) -> Result<()> {
+    writer.write_all(&(message.len() as u32).to_be_bytes())?;
+    writer.write_all(message)
+}
+
+/// Get the argument at `index` of a request.
+fn arg<T: DeserializeOwned>(args: &[Value], index: usize) -> std::result::Result<T, String> {
+    let value = args
+        .get(index)
+        .ok_or_else(|| format!("Missing argument #{}", index))?;
+    serde_json::from_value(value.clone()).map_err(|e| format!("Invalid argument #{}: {}", index, e))
+}
+
+/// Call the D-Bus method (or get or set the property) named `method`.
+///
+/// The changed properties are signaled on D-Bus too, which saves the configuration.
+fn call(state: &State, method: &str, args: &[Value]) -> std::result::Result<Value, String> {
+    let changed = |properties: &[&'static str]| {
+        state
+            .changed_properties
+            .borrow_mut()
+            .extend(properties.iter().copied());
+        Value::Null
+    };
+
+    let result: std::result::Result<Value, MethodErr> = match method {
+        "FansSpeeds" => state.fans_speeds().map(|v| json!(v)),
+        "TargetFansSpeeds" => state.target_fans_speeds().map(|v| json!(v)),
+        "SetTargetFansSpeeds" => state
+            .set_target_fans_speeds(arg(args, 0)?)
+            .map(|_| changed(&["TargetFansSpeeds"])),
+        "SetTargetFanSpeed" => state
+            .set_target_fan_speed(arg(args, 0)?, arg(args, 1)?)
+            .map(|_| Value::Null),
+        "SetAllFansSpeed" => state.set_all_fans_speed(arg(args, 0)?).map(|_| Value::Null),
+        "FansNames" => state.fans_names().map(|v| json!(v)),
+        "Auto" => state.auto().map(|v| json!(v)),
+        "SetAuto" => state.set_auto(arg(args, 0)?).map(|_| changed(&["Auto"])),
+        "FansAuto" => state.fans_auto().map(|v| json!(v)),
+        "SetFanAuto" => state
+            .set_fan_auto(arg(args, 0)?, arg(args, 1)?)
+            .map(|_| Value::Null),
+        "Config" => state.config().map(|v| json!(v)),
+        "SetConfig" => state.set_config(arg(args, 0)?).map(|_| {
+            // The config is swapped by the main loop instead of the D-Bus callback.
+            state.old_config.take();
+            state.reload_control_config.replace(true);
+            changed(&["Config"])
+        }),
+        "AvailableConfigs" => state.available_configs().map(|v| json!(v)),
+        "Temperatures" => state.temperatures().map(|v| json!(v)),
+        "Critical" => state.critical().map(|v| json!(v)),
+        _ => return Err(format!("Unknown method `{}`", method)),
+    };
+
+    result.map_err(|e| e.description().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn call_methods() {
+        let state = State {
+            fans_speeds: RefCell::from(vec![40., 50.]),
+            target_fans_speeds: RefCell::from(vec![0., 0.]),
+            fans_auto: RefCell::from(vec![true, true]),
+            ..Default::default()
+        };
+
+        assert_eq!(call(&state, "FansSpeeds", &[]), Ok(json!([40., 50.])));
+        assert_eq!(
+            call(&state, "SetTargetFanSpeed", &[json!(1), json!(80.)]),
+            Ok(Value::Null)
+        );
+        assert_eq!(*state.target_fans_speeds.borrow(), vec![0., 80.]);
+        assert_eq!(call(&state, "FansAuto", &[]), Ok(json!([true, false])));
+
+        assert!(call(&state, "SetTargetFanSpeed", &[json!(1)]).is_err());
+        assert!(call(&state, "SetTargetFanSpeed", &[json!(1), json!(120.)]).is_err());
+        assert!(call(&state, "Unknown", &[]).is_err());
+    }
+
+    #[test]
+    fn length_prefixed_messages() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, b"{\"method\":\"Auto\"}").unwrap();
+        assert_eq!(buf[..4], [0, 0, 0, 17]);
+        assert_eq!(
+            parse_message(&buf).unwrap(),
+            Some(&b"{\"method\":\"Auto\"}"[..])
+        );
+        // The message is only parsed once it's complete.
+        assert_eq!(parse_message(&buf[..2]).unwrap(), None);
+        assert_eq!(parse_message(&buf[..10]).unwrap(), None);
+
+        let too_long = ((MAX_MESSAGE_LEN + 1) as u32).to_be_bytes();
+        assert!(parse_message(&too_long).is_err());
+    }
+
+    #[test]
+    fn partial_requests() {
+        let state = State {
+            auto: RefCell::from(true),
+            ..Default::default()
+        };
+        let (stream, mut other) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut client = Client::new(stream, Instant::now());
+        let mut request = Vec::new();
+        write_message(&mut request, b"{\"method\":\"Auto\"}").unwrap();
+
+        // Nothing is answered until the whole request is received.
+        assert!(!client.progress(&state).unwrap());
+        other.write_all(&request[..6]).unwrap();
+        assert!(!client.progress(&state).unwrap());
+        other.write_all(&request[6..]).unwrap();
+        assert!(client.progress(&state).unwrap());
+
+        let mut answer = vec![0u8; 4 + 15];
+        other.read_exact(&mut answer).unwrap();
+        assert_eq!(
+            parse_message(&answer).unwrap(),
+            Some(&b"{\"result\":true}"[..])
+        );
+
+        drop(other);
+        let (stream, other) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut client = Client::new(stream, Instant::now());
+        drop(other);
+        assert!(client.progress(&state).is_err());
+    }
+}
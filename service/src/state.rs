@@ -2,9 +2,22 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 use crate::config::nbfc_control::ControlConfigLoader;
-use crate::config::service::{ECAccessMode, ServiceConfig, TempComputeMethod};
+use crate::config::service::{
+    AutoProfilesConfig, ConfigSource, CoreConfig, ECAccessMode, PowerConfig, Profile,
+    SensorsConfig, ServiceConfig, TempComputeMethod,
+};
+use crate::nbfc::FanControlConfigV2;
+use crate::power::PowerState;
+use crate::temp::{SensorLabel, HWMON_CLASS_PATH};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Maximal `core.temperature_offset` (in °C), either way.
+pub const MAX_TEMPERATURE_OFFSET: f64 = 10.0;
+/// Maximal duration (in s) of a boost.
+pub const MAX_BOOST_DURATION: u64 = 3600;
 
 #[derive(Debug, Default)]
 /// This struct is shared between the **D-Bus** tree and the `main` function.
@@ -12,50 +25,555 @@ use std::collections::HashMap;
 /// the configuration.
 pub(crate) struct State {
     pub ec_access_mode: RefCell<ECAccessMode>,
+    /// Path of the device used to access the EC.
+    pub ec_dev_path: RefCell<String>,
+    /// Whether the fans were read and written successfully at the last poll,
+    /// `None` before the first one.
+    pub ec_reads_ok: RefCell<Option<bool>>,
+    pub ec_writes_ok: RefCell<Option<bool>>,
+    /// Last EC error which didn't stop the service.
+    pub last_ec_error: RefCell<Option<String>>,
+    /// Time of the last successful read of the fans.
+    pub last_ec_read: RefCell<Option<SystemTime>>,
+    /// Number of iterations of the main loop.
+    pub loop_iterations: RefCell<u64>,
     pub fans_speeds: RefCell<Vec<f64>>,
+    /// Speeds computed by the service for the fans, which may not be reached yet.
+    pub computed_fans_speeds: RefCell<Vec<f64>>,
+    /// Speeds in RPM, `0` for the fans without tachometer register.
+    pub fans_rpm: RefCell<Vec<f64>>,
+    /// Last raw value written to the EC for each fan, `0` before the first write.
+    pub fans_raw_values: RefCell<Vec<u16>>,
+    /// Result of the spin-up test for each fan (empty if it has not been run).
+    pub fans_health: RefCell<Vec<bool>>,
+    /// Sensor which decided the speed of each fan at the last poll, empty if the speed was not
+    /// automatic.
+    pub active_sensors: RefCell<Vec<String>>,
     pub target_fans_speeds: RefCell<Vec<f64>>,
-    pub manual_set_target_speeds: RefCell<bool>,
+    /// Properties changed by a method call, for which a signal should be emitted.
+    pub changed_properties: RefCell<HashSet<&'static str>>,
     /// Used when an error occured while trying to change the configuration.
     pub old_config: RefCell<Option<String>>,
     pub auto: RefCell<bool>,
+    pub fans_auto: RefCell<Vec<bool>>,
     pub critical: RefCell<bool>,
+    /// Critical temperature of the control config.
+    pub config_critical_temperature: RefCell<u8>,
     pub config: RefCell<String>,
+    /// How `config` was chosen.
+    pub config_source: RefCell<ConfigSource>,
+    /// Config saved (with its source) instead of `config` while it is tried for the session only.
+    pub persisted_config: RefCell<Option<(String, ConfigSource)>>,
+    /// Control config with the curves edited over D-Bus, applied instead of `config` until it is
+    /// saved or another config is selected.
+    pub edited_config: RefCell<Option<FanControlConfigV2>>,
+    /// Smoothed temperatures.
     pub temps: RefCell<HashMap<String, f64>>,
+    pub temps_raw: RefCell<HashMap<String, f64>>,
+    /// Directory of the hwmon devices, whose sensors are listed by `AvailableSensors`.
+    pub hwmon_class_path: RefCell<PathBuf>,
+    /// Labels of the lm-sensors configuration, read by the main loop.
+    pub sensors_labels: RefCell<Vec<SensorLabel>>,
+    /// Sensors expected by the sensors config which are not available.
+    pub missing_sensors: RefCell<Vec<String>>,
+    /// Sensors which kept the same value for too long while the system was under load.
+    pub stuck_sensors: RefCell<Vec<String>>,
+    /// Problems detected at startup which don't prevent the service from running.
+    pub warnings: RefCell<Vec<String>>,
+    /// Problems of the fans of the control config, which may not match the machine.
+    pub fans_mismatches: RefCell<Vec<String>>,
+    /// Why the service configuration on the disk was not used, if it was invalid.
+    pub config_error: RefCell<Option<String>>,
     pub temp_compute: RefCell<TempComputeMethod>,
     pub poll_interval: RefCell<u64>,
     pub fans_names: RefCell<Vec<String>>,
+    /// Metadata of the loaded control config.
+    pub config_info: RefCell<HashMap<String, String>>,
+    /// Points (temperature, speed percent) of the curve of each fan.
+    pub fans_curves: RefCell<Vec<Vec<(f64, f64)>>>,
     pub check_control_config: RefCell<bool>,
     pub config_loader: RefCell<ControlConfigLoader>,
+    pub core: RefCell<CoreConfig>,
+    /// Whether the writes to the EC are discarded, from `core.dry_run` or `--dry-run`.
+    pub dry_run: RefCell<bool>,
+    pub sensors: RefCell<SensorsConfig>,
+    pub profiles: RefCell<HashMap<String, Profile>>,
+    pub auto_profiles: RefCell<AutoProfilesConfig>,
+    pub profile_rules: RefCell<ProfileRuleEngine>,
+    pub power: RefCell<PowerConfig>,
+    /// Power source of the system, read by the main loop.
+    pub power_state: RefCell<PowerState>,
+    /// Profile requested over D-Bus, applied by the main loop.
+    pub pending_profile: RefCell<Option<String>>,
+    /// The control config has to be reloaded from the disk by the main loop.
+    pub reload_control_config: RefCell<bool>,
+    /// The list of the available control configs changed, the main loop has to signal it.
+    pub configs_changed: RefCell<bool>,
+    /// All the fans are at full speed until this instant.
+    pub boost_until: RefCell<Option<Instant>>,
+    /// Duration (in s) of a boost requested over D-Bus, signaled by the main loop.
+    pub boost_started: RefCell<Option<u64>>,
+    /// Fan and register to calibrate, requested over D-Bus.
+    pub pending_calibration: RefCell<Option<(usize, u8)>>,
+    /// A calibration is requested or running.
+    pub calibrating: RefCell<bool>,
+    /// The service config has to be reloaded from the disk by the main loop.
+    pub reload_service_config: RefCell<bool>,
+    /// Service config imported over D-Bus without being saved (when `core.persist_on_exit` is
+    /// not set), reloaded instead of the one on the disk.
+    pub pending_service_config: RefCell<Option<ServiceConfig>>,
+    /// The system is going to sleep, the EC must not be accessed.
+    pub suspended: RefCell<bool>,
+    /// Too many EC errors occurred, the fans are left to the firmware until a reload.
+    pub ec_failsafe: RefCell<bool>,
+    /// The EC registers can be inspected over D-Bus (with `--debug-ec`).
+    pub debug_ec: RefCell<bool>,
+    /// Snapshot of the EC registers, refreshed by the main loop when `debug_ec` is set.
+    pub ec_registers: RefCell<Vec<u8>>,
+    /// Time of the last manual command, while some fans are not in automatic mode.
+    pub manual_since: RefCell<Option<Instant>>,
+    /// Last samples recorded by the main loop, the oldest first.
+    pub history: RefCell<VecDeque<HistorySample>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Temperature and fans speeds at a poll.
+pub(crate) struct HistorySample {
+    /// Seconds since the Unix epoch.
+    pub timestamp: f64,
+    /// Computed temperature (in °C).
+    pub temperature: f64,
+    pub fans_speeds: Vec<f64>,
 }
 impl From<ServiceConfig> for State {
     fn from(s: ServiceConfig) -> Self {
-        State {
+        let state = State {
             ec_access_mode: RefCell::new(s.ec_access_mode),
+            ec_dev_path: RefCell::new(String::new()),
+            ec_reads_ok: RefCell::new(None),
+            ec_writes_ok: RefCell::new(None),
+            last_ec_error: RefCell::new(None),
+            last_ec_read: RefCell::new(None),
+            loop_iterations: RefCell::new(0),
             fans_speeds: RefCell::new(Vec::new()),
+            computed_fans_speeds: RefCell::new(Vec::new()),
+            fans_rpm: RefCell::new(Vec::new()),
+            fans_raw_values: RefCell::new(Vec::new()),
+            fans_health: RefCell::new(Vec::new()),
+            active_sensors: RefCell::new(Vec::new()),
             target_fans_speeds: RefCell::new(s.target_fans_speeds),
-            manual_set_target_speeds: RefCell::new(false),
+            changed_properties: RefCell::new(HashSet::new()),
             old_config: RefCell::new(None),
             auto: RefCell::new(s.auto),
+            fans_auto: RefCell::new(s.fans_auto),
             critical: RefCell::new(false),
+            config_critical_temperature: RefCell::new(0),
             config: RefCell::new(s.selected_fan_config),
+            config_source: RefCell::new(s.config_source),
+            persisted_config: RefCell::new(None),
+            edited_config: RefCell::new(None),
             temps: RefCell::new(HashMap::new()),
+            temps_raw: RefCell::new(HashMap::new()),
+            hwmon_class_path: RefCell::new(PathBuf::from(HWMON_CLASS_PATH)),
+            sensors_labels: RefCell::new(Vec::new()),
+            missing_sensors: RefCell::new(Vec::new()),
+            stuck_sensors: RefCell::new(Vec::new()),
+            warnings: RefCell::new(Vec::new()),
+            fans_mismatches: RefCell::new(Vec::new()),
+            config_error: RefCell::new(None),
             temp_compute: RefCell::new(s.temp_compute),
             poll_interval: RefCell::new(0),
             fans_names: RefCell::new(Vec::new()),
+            config_info: RefCell::new(HashMap::new()),
+            fans_curves: RefCell::new(Vec::new()),
             check_control_config: RefCell::new(false),
             config_loader: RefCell::new(ControlConfigLoader::new(false)),
-        }
+            core: RefCell::new(s.core),
+            dry_run: RefCell::new(false),
+            sensors: RefCell::new(s.sensors),
+            profiles: RefCell::new(s.profiles),
+            auto_profiles: RefCell::new(s.auto_profiles),
+            profile_rules: RefCell::new(ProfileRuleEngine::default()),
+            power: RefCell::new(s.power),
+            power_state: RefCell::new(PowerState::Unknown),
+            pending_profile: RefCell::new(None),
+            reload_control_config: RefCell::new(false),
+            configs_changed: RefCell::new(false),
+            boost_until: RefCell::new(None),
+            boost_started: RefCell::new(None),
+            pending_calibration: RefCell::new(None),
+            calibrating: RefCell::new(false),
+            reload_service_config: RefCell::new(false),
+            pending_service_config: RefCell::new(None),
+            suspended: RefCell::new(false),
+            ec_failsafe: RefCell::new(false),
+            debug_ec: RefCell::new(false),
+            ec_registers: RefCell::new(Vec::new()),
+            manual_since: RefCell::new(None),
+            history: RefCell::new(VecDeque::new()),
+        };
+        // The manual mode restored from the configuration times out too.
+        state.refresh_manual_timer(Instant::now());
+        state
     }
 }
 impl State {
+    /// Add a sample to the history, dropping the oldest ones beyond `core.history_length`.
+    pub fn record_history(&self, sample: HistorySample) {
+        let length = self.core.borrow().history_length;
+        let mut history = self.history.borrow_mut();
+        history.push_back(sample);
+        while history.len() > length {
+            history.pop_front();
+        }
+    }
+
+    /// Set the names of the fans from the ones of the control config,
+    /// replaced by their alias in `core.fan_aliases`.
+    pub fn set_fans_names(&self, names: Vec<String>) {
+        let names = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| self.fan_name(i, name))
+            .collect();
+        self.fans_names.replace(names);
+    }
+
+    /// Get the name of the fan at `index`, named `name` in the control config: its alias in
+    /// `core.fan_aliases` if it has one. The fans are named this way everywhere, including in
+    /// the sensors settings.
+    pub fn fan_name(&self, index: usize, name: &str) -> String {
+        match self.core.borrow().fan_aliases.get(index) {
+            Some(alias) if !alias.is_empty() => alias.to_owned(),
+            _ => name.to_owned(),
+        }
+    }
+
+    /// Returns true if the speed of the fan at `index` is automatically handled.
+    pub fn is_fan_auto(&self, index: usize) -> bool {
+        self.fans_auto
+            .borrow()
+            .get(index)
+            .copied()
+            .unwrap_or(*self.auto.borrow())
+    }
+
+    /// Returns the critical temperature set by the user, else the one of the control config.
+    pub fn critical_temperature(&self) -> u8 {
+        self.core
+            .borrow()
+            .critical_temperature
+            .unwrap_or(*self.config_critical_temperature.borrow())
+    }
+
+    /// Returns the offset (in °C) by which the temperature followed by the fans in automatic mode is
+    /// lowered, from the settings of the current power source.
+    pub fn power_temperature_offset(&self) -> f64 {
+        self.power_state
+            .borrow()
+            .config(&self.power.borrow())
+            .map_or(0.0, |c| c.temperature_offset)
+    }
+
+    /// Returns the offset (in °C) by which the temperature followed by the fans in automatic mode is
+    /// lowered, from `core.temperature_offset` and the settings of the current power source.
+    pub fn fans_temperature_offset(&self) -> f64 {
+        let offset = self.core.borrow().temperature_offset;
+        let offset = if offset.is_finite() {
+            offset.clamp(-MAX_TEMPERATURE_OFFSET, MAX_TEMPERATURE_OFFSET)
+        } else {
+            0.0
+        };
+
+        offset + self.power_temperature_offset()
+    }
+
+    /// Select the control config `name` from the disk or a profile, which also replaces the tried
+    /// config (if any) as the saved one. The edited curves are discarded if the config changed.
+    pub fn replace_config(&self, name: String, source: ConfigSource) {
+        if *self.config.borrow() != name {
+            self.edited_config.take();
+        }
+        self.config.replace(name);
+        self.config_source.replace(source);
+        self.persisted_config.take();
+    }
+
+    /// Record an error of the EC, which is reported as failing until a poll succeeds again.
+    pub fn record_ec_error(&self, error: String) {
+        self.last_ec_error.replace(Some(error));
+        self.ec_reads_ok.replace(Some(false));
+    }
+
+    /// Report the control of the fans as given back to the firmware after too many EC errors,
+    /// until the configuration is reloaded.
+    pub fn enter_failsafe(&self) {
+        self.ec_failsafe.replace(true);
+        self.ec_reads_ok.replace(Some(false));
+        self.ec_writes_ok.replace(Some(false));
+    }
+
+    /// Restart the timer of `core.manual_timeout` after a manual command,
+    /// or stop it if all the fans are back in automatic mode.
+    pub fn refresh_manual_timer(&self, now: Instant) {
+        let manual = !*self.auto.borrow() || self.fans_auto.borrow().iter().any(|a| !a);
+        self.manual_since.replace(manual.then(|| now));
+    }
+
+    /// Set all the fans back in automatic mode if no manual command was received
+    /// during `core.manual_timeout`. Returns true if they were.
+    pub fn check_manual_timeout(&self, now: Instant) -> bool {
+        let timeout = match self.core.borrow().manual_timeout {
+            Some(minutes) => Duration::from_secs(minutes * 60),
+            None => return false,
+        };
+        match *self.manual_since.borrow() {
+            Some(since) if now.saturating_duration_since(since) >= timeout => {}
+            _ => return false,
+        }
+
+        self.manual_since.replace(None);
+        self.auto.replace(true);
+        self.fans_auto
+            .borrow_mut()
+            .iter_mut()
+            .for_each(|a| *a = true);
+        self.changed_properties
+            .borrow_mut()
+            .extend(["Auto", "FansAuto"]);
+        true
+    }
+
     pub fn as_service_config(&self) -> ServiceConfig {
+        let (selected_fan_config, config_source) = match &*self.persisted_config.borrow() {
+            Some((config, source)) => (config.to_owned(), *source),
+            None => (
+                self.config.borrow().to_owned(),
+                *self.config_source.borrow(),
+            ),
+        };
+
         ServiceConfig {
             ec_access_mode: *self.ec_access_mode.borrow(),
             auto: *self.auto.borrow(),
+            fans_auto: self.fans_auto.borrow().to_owned(),
             target_fans_speeds: self.target_fans_speeds.borrow().to_owned(),
-            selected_fan_config: self.config.borrow().to_owned(),
+            selected_fan_config,
+            config_source,
             temp_compute: *self.temp_compute.borrow(),
             check_control_config: *self.check_control_config.borrow(),
+            core: self.core.borrow().clone(),
+            sensors: self.sensors.borrow().clone(),
+            profiles: self.profiles.borrow().clone(),
+            auto_profiles: self.auto_profiles.borrow().clone(),
+            power: self.power.borrow().clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Selects the profile to activate from the rules of `auto_profiles`,
+/// with the rolling average of the temperature.
+pub(crate) struct ProfileRuleEngine {
+    samples: VecDeque<(Instant, f64)>,
+    /// Rule which matches since the instant, but not for its whole dwell time yet.
+    candidate: Option<(usize, Instant)>,
+    active: Option<usize>,
+}
+
+impl ProfileRuleEngine {
+    /// Index of the last rule which activated its profile.
+    pub fn active(&self) -> Option<usize> {
+        self.active
+    }
+
+    /// Add the temperature read at `now` to the average.
+    /// Returns the index of the rule whose profile has to be activated, if any.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        temp: f64,
+        config: &AutoProfilesConfig,
+    ) -> Option<usize> {
+        let window = Duration::from_secs(config.window);
+        self.samples.push_back((now, temp));
+        while let Some(&(t, _)) = self.samples.front() {
+            if now.duration_since(t) <= window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        let average = self.samples.iter().map(|(_, t)| t).sum::<f64>() / self.samples.len() as f64;
+
+        let matching = config.rules.iter().position(|r| {
+            r.above.map_or(true, |above| average > above)
+                && r.below.map_or(true, |below| average < below)
+        });
+        let matching = match matching {
+            Some(i) if self.active != Some(i) => i,
+            _ => {
+                self.candidate = None;
+                return None;
+            }
+        };
+
+        let since = match self.candidate {
+            Some((i, since)) if i == matching => since,
+            _ => {
+                self.candidate = Some((matching, now));
+                now
+            }
+        };
+        if now.duration_since(since) >= Duration::from_secs(config.rules[matching].dwell) {
+            self.candidate = None;
+            self.active = Some(matching);
+            Some(matching)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::service::ProfileRule;
+
+    #[test]
+    fn manual_timeout() {
+        let state = State {
+            fans_auto: RefCell::from(vec![true, true]),
+            auto: RefCell::from(true),
+            ..Default::default()
+        };
+        state.core.borrow_mut().manual_timeout = Some(5);
+        let start = Instant::now();
+        let minutes = |m| start + Duration::from_secs(m * 60);
+
+        // The timer doesn't run in automatic mode
+        state.refresh_manual_timer(start);
+        assert!(!state.check_manual_timeout(minutes(10)));
+
+        state.fans_auto.borrow_mut()[1] = false;
+        state.refresh_manual_timer(start);
+        assert!(!state.check_manual_timeout(minutes(4)));
+
+        // A new manual command restarts the timer
+        state.refresh_manual_timer(minutes(4));
+        assert!(!state.check_manual_timeout(minutes(6)));
+        assert!(state.check_manual_timeout(minutes(9)));
+        assert_eq!(*state.fans_auto.borrow(), vec![true, true]);
+        assert!(*state.auto.borrow());
+        assert!(state.changed_properties.borrow().contains("FansAuto"));
+        assert!(state.manual_since.borrow().is_none());
+    }
+
+    #[test]
+    fn manual_timeout_at_startup() {
+        let mut config = ServiceConfig {
+            auto: false,
+            ..Default::default()
+        };
+        config.core.manual_timeout = Some(5);
+        let state = State::from(config);
+        let in_minutes = |m| Instant::now() + Duration::from_secs(m * 60);
+
+        assert!(state.manual_since.borrow().is_some());
+        assert!(!state.check_manual_timeout(in_minutes(4)));
+        assert!(state.check_manual_timeout(in_minutes(6)));
+        assert!(*state.auto.borrow());
+
+        let state = State::from(ServiceConfig {
+            auto: true,
+            ..Default::default()
+        });
+        assert!(state.manual_since.borrow().is_none());
+    }
+
+    #[test]
+    fn fan_aliases() {
+        let state = State::default();
+        state.core.borrow_mut().fan_aliases = vec![String::new(), "GPU fan".to_owned()];
+        state.set_fans_names(vec![
+            "Fan #0".to_owned(),
+            "Fan #1".to_owned(),
+            "Fan #2".to_owned(),
+        ]);
+
+        assert_eq!(
+            *state.fans_names.borrow(),
+            vec![
+                "Fan #0".to_owned(),
+                "GPU fan".to_owned(),
+                "Fan #2".to_owned()
+            ]
+        );
+        assert_eq!(state.fan_name(1, "Fan #1"), "GPU fan");
+        assert_eq!(state.fan_name(2, "Fan #2"), "Fan #2");
+    }
+
+    #[test]
+    fn bounded_history() {
+        let state = State::default();
+        state.core.borrow_mut().history_length = 3;
+        for i in 0..5 {
+            state.record_history(HistorySample {
+                timestamp: f64::from(i),
+                temperature: 50.0,
+                fans_speeds: vec![f64::from(i) * 10.0],
+            });
+        }
+
+        let history = state.history.borrow();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].timestamp, 2.0);
+        assert_eq!(history[2].fans_speeds, vec![40.0]);
+        drop(history);
+
+        state.core.borrow_mut().history_length = 0;
+        state.record_history(HistorySample {
+            timestamp: 5.0,
+            temperature: 50.0,
+            fans_speeds: Vec::new(),
+        });
+        assert!(state.history.borrow().is_empty());
+    }
+
+    #[test]
+    fn profile_rules() {
+        let config = AutoProfilesConfig {
+            window: 10,
+            rules: vec![
+                ProfileRule {
+                    profile: "Performance".to_owned(),
+                    above: Some(80.0),
+                    below: None,
+                    dwell: 5,
+                },
+                ProfileRule {
+                    profile: "Silent".to_owned(),
+                    above: None,
+                    below: Some(50.0),
+                    dwell: 0,
+                },
+            ],
+        };
+        let mut engine = ProfileRuleEngine::default();
+        let start = Instant::now();
+        let at = |s| start + Duration::from_secs(s);
+
+        assert_eq!(engine.update(at(0), 40.0, &config), Some(1));
+        assert_eq!(engine.update(at(1), 45.0, &config), None);
+        assert_eq!(engine.active(), Some(1));
+
+        // A short peak is absorbed by the average
+        assert_eq!(engine.update(at(2), 100.0, &config), None);
+
+        // The average is above 80°C from the 5th second, and must stay so for the dwell time
+        for s in 3..=9 {
+            assert_eq!(engine.update(at(s), 100.0, &config), None);
         }
+        assert_eq!(engine.update(at(10), 100.0, &config), Some(0));
+        assert_eq!(engine.active(), Some(0));
     }
 }
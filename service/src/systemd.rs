@@ -0,0 +1,96 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Minimal implementation of the systemd notification protocol (`sd_notify`).
+//! Every function does nothing when the service is not started by systemd.
+use log::debug;
+
+use std::env::var;
+use std::os::unix::net::UnixDatagram;
+use std::time::{Duration, Instant};
+
+/// Send `state` (e.g. `READY=1`) to the service manager.
+pub(crate) fn notify(state: &str) -> std::io::Result<()> {
+    let socket_path = match var("NOTIFY_SOCKET") {
+        Ok(p) => p,
+        Err(_) => return Ok(()),
+    };
+    // Abstract sockets are not supported.
+    if socket_path.starts_with('@') {
+        debug!("Unsupported abstract notification socket `{}`", socket_path);
+        return Ok(());
+    }
+
+    UnixDatagram::unbound()?
+        .send_to(state.as_bytes(), socket_path)
+        .map(|_| ())
+}
+
+/// Get the interval between two pings from the watchdog environment variables.
+/// The watchdog is pinged twice per timeout, as recommended by systemd.
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    // The watchdog is meant for another process.
+    if matches!(pid.map(str::parse::<u32>), Some(Ok(pid)) if pid != own_pid) {
+        return None;
+    }
+
+    usec.and_then(|u| u.parse::<u64>().ok())
+        .filter(|&u| u > 0)
+        .map(|u| Duration::from_micros(u) / 2)
+}
+
+/// Pings the systemd watchdog, if it is enabled with `WatchdogSec`.
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    interval: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Get the watchdog settings from the environment.
+    pub fn from_env() -> Self {
+        Watchdog {
+            interval: watchdog_interval(
+                var("WATCHDOG_USEC").ok().as_deref(),
+                var("WATCHDOG_PID").ok().as_deref(),
+                std::process::id(),
+            ),
+            last_ping: None,
+        }
+    }
+
+    /// Notify the service manager that the service is alive, if the last ping is old enough.
+    pub fn ping(&mut self) {
+        if let Some(interval) = self.interval {
+            if self
+                .last_ping
+                .map_or(true, |last| last.elapsed() >= interval)
+            {
+                if let Err(e) = notify("WATCHDOG=1") {
+                    debug!("Error while pinging the watchdog: {}", e);
+                }
+                self.last_ping = Some(Instant::now());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_watchdog_interval() {
+        assert_eq!(watchdog_interval(None, None, 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(Some("invalid"), None, 42), None);
+        let interval = Some(Duration::from_secs(15));
+        assert_eq!(watchdog_interval(Some("30000000"), None, 42), interval);
+        assert_eq!(
+            watchdog_interval(Some("30000000"), Some("42"), 42),
+            interval
+        );
+        assert_eq!(watchdog_interval(Some("30000000"), Some("1"), 42), None);
+    }
+}
@@ -1,10 +1,18 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+use log::debug;
 use psutil::sensors;
 use snafu::Snafu;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::config::service::{ExternalSensor, FanAggregation, SensorsConfig, TempComputeMethod};
 
 const CPU_SENSORS_NAMES: &[&str] = &["coretemp", "k10temp"];
 
@@ -13,27 +21,41 @@ const CPU_SENSORS_NAMES: &[&str] = &["coretemp", "k10temp"];
 const GPU_SENSORS_NAMES: &[&str] = &["amdgpu", "radeon", "nouveau"];
 const ACPI_SENSORS_NAMES: &[&str] = &["acpitz"];
 const NVME_SENSORS_NAMES: &[&str] = &["nvme"];
+const DRIVE_SENSORS_NAMES: &[&str] = &["drivetemp"];
+const DRM_CLASS_PATH: &str = "/sys/class/drm";
+/// Prefix of the labeled GPUs temperatures.
+const LABELED_GPU_PREFIX: &str = "GPU_";
+pub const LOADAVG_PATH: &str = "/proc/loadavg";
+pub const HWMON_CLASS_PATH: &str = "/sys/class/hwmon";
+/// Configuration files of lm-sensors, for the labels of the sensors.
+pub const SENSORS_CONF_PATHS: &[&str] = &["/etc/sensors3.conf", "/etc/sensors.d"];
 #[derive(Debug, Snafu)]
 pub(crate) enum SensorError {
-    #[snafu(display("Could not get access to a CPU sensor"))]
-    NoCPUSensorFound,
+    #[snafu(display("Could not get access to any temperature sensor"))]
+    NoSensorFound,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// This structure holds temperatures of various sensors through simple categories.
 pub(crate) struct Temperatures {
-    pub cpu_temp: f64,
+    pub cpu_temp: Option<f64>,
     pub gpu_temp: Option<f64>,
     //TODO: The following sensors should be implemented in another structure
     pub nvme_temp: Option<f64>,
     pub acpi_temp: Option<f64>,
+    /// Hard drives and SATA SSDs (with the `drivetemp` module).
+    pub drive_temp: Option<f64>,
+    /// Labeled temperatures of the GPUs (e.g. `GPU_EDGE`, `GPU_JUNCTION`), read from DRM.
+    pub gpus_temps: HashMap<String, f64>,
+    /// Temperatures of the external sensors and of the labeled hwmon inputs, by name.
+    pub external_temps: HashMap<String, f64>,
 }
 
 impl Temperatures {
     //TODO: Find a way to hold some references to the sensors to not have to refresh everytime.
     //TODO: Manage errors
-    /// Get the current temperatures.
-    pub fn get_temps() -> Result<Self, SensorError> {
+    /// Get the current temperatures, along with `external_temps` read from the external sensors.
+    pub fn get_temps(external_temps: HashMap<String, f64>) -> Result<Self, SensorError> {
         let temperatures = sensors::temperatures();
 
         let cpu_sensors: Vec<f64> = temperatures
@@ -43,9 +65,6 @@ impl Temperatures {
             .map(|s| s.current().celsius() as f64)
             .filter(|x| x.is_normal())
             .collect();
-        if cpu_sensors.is_empty() {
-            return Err(SensorError::NoCPUSensorFound {});
-        }
 
         let gpu_sensors: Vec<f64> = temperatures
             .iter()
@@ -71,8 +90,37 @@ impl Temperatures {
             .filter(|x| x.is_normal())
             .collect();
 
+        let drive_sensors: Vec<f64> = temperatures
+            .iter()
+            .filter_map(|s| s.as_ref().ok())
+            .filter(|s| DRIVE_SENSORS_NAMES.iter().any(|&c| c.contains(s.unit())))
+            .map(|s| s.current().celsius() as f64)
+            .filter(|x| x.is_normal())
+            .collect();
+
+        let gpus_temps = read_gpus_temps(Path::new(DRM_CLASS_PATH));
+
+        if [
+            &cpu_sensors,
+            &gpu_sensors,
+            &acpi_sensors,
+            &nvme_sensors,
+            &drive_sensors,
+        ]
+        .iter()
+        .all(|s| s.is_empty())
+            && gpus_temps.is_empty()
+            && external_temps.is_empty()
+        {
+            return Err(SensorError::NoSensorFound {});
+        }
+
         Ok(Temperatures {
-            cpu_temp: cpu_sensors.iter().fold(0f64, |a, s| a + s) / cpu_sensors.len() as f64,
+            cpu_temp: if !cpu_sensors.is_empty() {
+                Some(cpu_sensors.iter().fold(0f64, |a, s| a + s) / cpu_sensors.len() as f64)
+            } else {
+                None
+            },
             gpu_temp: if !gpu_sensors.is_empty() {
                 Some(gpu_sensors.iter().fold(0f64, |a, s| a + s) / gpu_sensors.len() as f64)
             } else {
@@ -88,11 +136,102 @@ impl Temperatures {
             } else {
                 None
             },
+            drive_temp: if !drive_sensors.is_empty() {
+                Some(drive_sensors.iter().fold(0f64, |a, s| a + s) / drive_sensors.len() as f64)
+            } else {
+                None
+            },
+            gpus_temps,
+            external_temps,
+        })
+    }
+
+    /// Apply `f` to each temperature, with the name of its sensor category.
+    fn map_temps<F: Fn(&str, f64) -> f64>(&self, f: F) -> Temperatures {
+        let map = |temps: &HashMap<String, f64>| {
+            temps
+                .iter()
+                .map(|(name, &t)| (name.to_owned(), f(name, t)))
+                .collect()
+        };
+
+        Temperatures {
+            cpu_temp: self.cpu_temp.map(|t| f("CPU", t)),
+            gpu_temp: self.gpu_temp.map(|t| f("GPU", t)),
+            nvme_temp: self.nvme_temp.map(|t| f("NVME", t)),
+            acpi_temp: self.acpi_temp.map(|t| f("ACPI", t)),
+            drive_temp: self.drive_temp.map(|t| f("DRIVE", t)),
+            gpus_temps: map(&self.gpus_temps),
+            external_temps: map(&self.external_temps),
+        }
+    }
+
+    /// Add to each temperature the offset of its sensor category in `offsets`.
+    pub fn calibrated(&self, offsets: &HashMap<String, f64>) -> Temperatures {
+        self.map_temps(|name, t| t + offsets.get(name).copied().unwrap_or(0.0))
+    }
+
+    /// Raise the temperature of the sensors in `names` to `floor` if they are below.
+    pub fn raised(&self, names: &[String], floor: f64) -> Temperatures {
+        self.map_temps(|name, t| {
+            if names.iter().any(|n| n == name) {
+                t.max(floor)
+            } else {
+                t
+            }
         })
     }
 
+    /// Smooth the temperatures with an exponential moving average of factor `alpha`,
+    /// `previous` being the last smoothed temperatures.
+    ///
+    /// The temperatures are not smoothed if `alpha` is not between `0` and `1` (excluded).
+    pub fn smoothed(&self, previous: Option<&Temperatures>, alpha: f64) -> Temperatures {
+        let previous = match previous {
+            Some(p) if alpha > 0.0 && alpha < 1.0 => p,
+            _ => return self.clone(),
+        };
+        let ema = |current: f64, previous: Option<f64>| match previous {
+            Some(p) => alpha * current + (1.0 - alpha) * p,
+            None => current,
+        };
+
+        Temperatures {
+            cpu_temp: self.cpu_temp.map(|t| ema(t, previous.cpu_temp)),
+            gpu_temp: self.gpu_temp.map(|t| ema(t, previous.gpu_temp)),
+            nvme_temp: self.nvme_temp.map(|t| ema(t, previous.nvme_temp)),
+            acpi_temp: self.acpi_temp.map(|t| ema(t, previous.acpi_temp)),
+            drive_temp: self.drive_temp.map(|t| ema(t, previous.drive_temp)),
+            gpus_temps: self
+                .gpus_temps
+                .iter()
+                .map(|(name, &t)| {
+                    (
+                        name.to_owned(),
+                        ema(t, previous.gpus_temps.get(name).copied()),
+                    )
+                })
+                .collect(),
+            external_temps: self
+                .external_temps
+                .iter()
+                .map(|(name, &t)| {
+                    (
+                        name.to_owned(),
+                        ema(t, previous.external_temps.get(name).copied()),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Replace the temperatures in `m`, removing the sensors which are not available anymore
+    /// (e.g. a sleeping drive).
     pub fn update_map(&self, m: &mut HashMap<String, f64>) {
-        m.insert("CPU".to_owned(), self.cpu_temp);
+        m.clear();
+        if let Some(cpu_temp) = self.cpu_temp {
+            m.insert("CPU".to_owned(), cpu_temp);
+        }
         if let Some(gpu_temp) = self.gpu_temp {
             m.insert("GPU".to_owned(), gpu_temp);
         }
@@ -104,5 +243,1255 @@ impl Temperatures {
         if let Some(nvme_temp) = self.nvme_temp {
             m.insert("NVME".to_owned(), nvme_temp);
         }
+
+        if let Some(drive_temp) = self.drive_temp {
+            m.insert("DRIVE".to_owned(), drive_temp);
+        }
+
+        m.extend(
+            self.gpus_temps
+                .iter()
+                .map(|(name, &t)| (name.to_owned(), t)),
+        );
+        m.extend(
+            self.external_temps
+                .iter()
+                .map(|(name, &t)| (name.to_owned(), t)),
+        );
+    }
+
+    /// Aggregate the temperatures into a single one following `method`.
+    ///
+    /// Only the categories listed in `sensors.only` are used (all if it's empty),
+    /// and `sensors.weights` is only used by `WeightedAverage`.
+    pub fn compute(&self, method: TempComputeMethod, sensors: &SensorsConfig) -> f64 {
+        let mut temps = HashMap::new();
+        self.update_map(&mut temps);
+        temps.retain(|name, _| is_computed_from(sensors, name));
+        if temps.is_empty() {
+            return self.fallback_temp();
+        }
+        let weights = &sensors.weights;
+
+        match method {
+            TempComputeMethod::CPUOnly => self.fallback_temp(),
+            TempComputeMethod::AllSensors => temps.values().sum::<f64>() / temps.len() as f64,
+            TempComputeMethod::Max => temps.values().copied().fold(f64::NEG_INFINITY, f64::max),
+            TempComputeMethod::WeightedAverage => {
+                let (sum, total_weight) = temps
+                    .iter()
+                    .map(|(name, temp)| (temp, weights.get(name).copied().unwrap_or(1.0)))
+                    .filter(|(_, weight)| *weight > 0.0)
+                    .fold((0.0, 0.0), |(sum, total), (temp, weight)| {
+                        (sum + temp * weight, total + weight)
+                    });
+
+                if total_weight > 0.0 {
+                    sum / total_weight
+                } else {
+                    self.fallback_temp()
+                }
+            }
+        }
+    }
+
+    /// Get the CPU temperature, or the highest one if there is no CPU sensor.
+    fn fallback_temp(&self) -> f64 {
+        self.cpu_temp.unwrap_or_else(|| {
+            let mut temps = HashMap::new();
+            self.update_map(&mut temps);
+            temps.values().copied().fold(f64::NEG_INFINITY, f64::max)
+        })
+    }
+}
+
+/// Read the temperatures of the GPUs from the hwmon nodes of the DRM devices in `drm_path`.
+///
+/// The temperatures are named after their label (e.g. `GPU_EDGE`), with the index of the GPU
+/// as a suffix from the second one (e.g. `GPU_EDGE_1`).
+/// The proprietary NVIDIA driver doesn't provide these nodes.
+fn read_gpus_temps(drm_path: &Path) -> HashMap<String, f64> {
+    let mut cards: Vec<_> = fs::read_dir(drm_path)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        // The connectors (e.g. `card0-HDMI-A-1`) are not GPUs.
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .map_or(false, |n| n.starts_with("card") && !n.contains('-'))
+        })
+        .collect();
+    cards.sort();
+
+    let mut temps = HashMap::new();
+    let mut index = 0;
+    for card in cards {
+        let mut card_temps = HashMap::new();
+        let hwmons = fs::read_dir(card.join("device/hwmon"))
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path());
+        for hwmon in hwmons {
+            for entry in fs::read_dir(&hwmon)
+                .into_iter()
+                .flatten()
+                .filter_map(|e| e.ok())
+            {
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                let sensor = match file_name.strip_suffix("_input") {
+                    Some(s) if s.starts_with("temp") => s,
+                    _ => continue,
+                };
+                let label = fs::read_to_string(hwmon.join(format!("{}_label", sensor)))
+                    .map(|l| l.trim().to_uppercase())
+                    .unwrap_or_else(|_| sensor.to_uppercase());
+                if let Some(temp) = read_hwmon_input(&entry.path()) {
+                    card_temps.insert(label, temp);
+                }
+            }
+        }
+
+        if card_temps.is_empty() {
+            continue;
+        }
+        let suffix = if index == 0 {
+            String::new()
+        } else {
+            format!("_{}", index)
+        };
+        temps.extend(
+            card_temps
+                .into_iter()
+                .map(|(label, t)| (format!("{}{}{}", LABELED_GPU_PREFIX, label, suffix), t)),
+        );
+        index += 1;
+    }
+
+    temps
+}
+
+/// Read the temperature (in °C) of a hwmon input, which is in millidegrees.
+fn read_hwmon_input(path: &Path) -> Option<f64> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .map(|v| v / 1000.0)
+        .filter(|v| v.is_normal())
+}
+
+/// Label given to an input of the chips matching a pattern, in the lm-sensors configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SensorLabel {
+    /// Chip pattern, as `k10temp-*` (only the chip name before the first `-` is matched).
+    pub chip: String,
+    /// Input of the chip, as `temp1`.
+    pub input: String,
+    pub label: String,
+}
+
+impl SensorLabel {
+    fn matches(&self, chip: &str, input: &str) -> bool {
+        let name = self.chip.split('-').next().unwrap_or_default();
+        (name == "*" || name == chip) && self.input == input
+    }
+}
+
+/// Split a line of the lm-sensors configuration into words, the quoted ones being kept whole.
+fn config_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            words.push(chars.by_ref().take_while(|&c| c != '"').collect());
+        } else {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// Parse the `label` statements of a lm-sensors configuration file, under their `chip` statement.
+fn parse_sensors_labels(content: &str) -> Vec<SensorLabel> {
+    let mut labels = Vec::new();
+    let mut chips: Vec<String> = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let words = config_words(line);
+        match words.first().map(String::as_str) {
+            Some("chip") => chips = words[1..].to_vec(),
+            Some("label") if words.len() == 3 => {
+                labels.extend(chips.iter().map(|chip| SensorLabel {
+                    chip: chip.to_owned(),
+                    input: words[1].to_owned(),
+                    label: words[2].to_owned(),
+                }));
+            }
+            _ => {}
+        }
+    }
+    labels
+}
+
+/// Read the labels of the lm-sensors configuration from the files (or the directories of files)
+/// in `paths`, as `/etc/sensors3.conf` and `/etc/sensors.d`.
+pub(crate) fn read_sensors_labels(paths: &[&Path]) -> Vec<SensorLabel> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries: Vec<_> = fs::read_dir(path)
+                .into_iter()
+                .flatten()
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    files
+        .iter()
+        .filter_map(|f| fs::read_to_string(f).ok())
+        .flat_map(|content| parse_sensors_labels(&content))
+        .collect()
+}
+
+/// Read the temperatures of the hwmon inputs in `class_path` referenced in `names`,
+/// by their label (e.g. `Tctl`) or their chip and label (e.g. `k10temp/Tctl`).
+///
+/// The labels of the lm-sensors configuration in `labels` take precedence over the ones of the
+/// driver. A name which is the path of an input (e.g. `/sys/class/hwmon/hwmon2/temp1_input`)
+/// is read directly.
+pub(crate) fn read_labeled_temps<'a>(
+    class_path: &Path,
+    labels: &[SensorLabel],
+    names: impl IntoIterator<Item = &'a String>,
+) -> HashMap<String, f64> {
+    let mut names: Vec<&String> = names.into_iter().collect();
+    let mut temps = HashMap::new();
+    names.retain(|name| {
+        if !name.starts_with('/') {
+            return true;
+        }
+        if let Some(temp) = read_hwmon_input(Path::new(name.as_str())) {
+            temps.insert(name.to_string(), temp);
+        }
+        false
+    });
+    if names.is_empty() {
+        return temps;
+    }
+
+    for (chip, label, input) in labeled_hwmon_inputs(class_path, labels) {
+        let chip_label = format!("{}/{}", chip, label);
+        for name in names
+            .iter()
+            .filter(|n| n.as_str() == label || n.as_str() == chip_label)
+        {
+            if let Some(temp) = read_hwmon_input(&input) {
+                temps.entry(name.to_string()).or_insert(temp);
+            }
+        }
+    }
+
+    temps
+}
+
+/// Read every labeled temperature input of the hwmon devices in `class_path`, by chip and label
+/// (e.g. `k10temp/Tctl`), whether it is selected by the sensors config or not.
+pub(crate) fn read_hwmon_temps(class_path: &Path, labels: &[SensorLabel]) -> HashMap<String, f64> {
+    let mut temps = HashMap::new();
+    for (chip, label, input) in labeled_hwmon_inputs(class_path, labels) {
+        if let Some(temp) = read_hwmon_input(&input) {
+            temps.entry(format!("{}/{}", chip, label)).or_insert(temp);
+        }
+    }
+    temps
+}
+
+/// List the temperature inputs of the hwmon devices in `class_path` with their chip and label,
+/// in the order of the devices. The inputs without label are skipped.
+fn labeled_hwmon_inputs(
+    class_path: &Path,
+    labels: &[SensorLabel],
+) -> Vec<(String, String, PathBuf)> {
+    let mut hwmons: Vec<_> = fs::read_dir(class_path)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    hwmons.sort();
+
+    let mut inputs = Vec::new();
+    for hwmon in hwmons {
+        let chip = match fs::read_to_string(hwmon.join("name")) {
+            Ok(chip) => chip.trim().to_owned(),
+            Err(_) => continue,
+        };
+        for entry in fs::read_dir(&hwmon)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+        {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let input = match file_name.strip_suffix("_input") {
+                Some(s) if s.starts_with("temp") => s,
+                _ => continue,
+            };
+            let label = match labels.iter().find(|l| l.matches(&chip, input)) {
+                Some(l) => l.label.to_owned(),
+                None => match fs::read_to_string(hwmon.join(format!("{}_label", input))) {
+                    Ok(label) => label.trim().to_owned(),
+                    Err(_) => continue,
+                },
+            };
+            inputs.push((chip.clone(), label, entry.path()));
+        }
+    }
+    inputs
+}
+
+/// Reads the external sensors by running their command, without waiting for it.
+///
+/// The output of a command is collected at the next poll, and the command is started again
+/// once the interval since its last start is elapsed.
+#[derive(Debug, Default)]
+pub(crate) struct ExternalSensors {
+    /// Running command of each sensor (by name), with its start.
+    running: HashMap<String, (Child, Instant)>,
+    /// Last start of the command of each sensor.
+    started: HashMap<String, Instant>,
+    /// Last temperature read from each sensor.
+    temps: HashMap<String, f64>,
+}
+
+impl ExternalSensors {
+    /// Collect the output of the finished commands, and start them again if they were started
+    /// at least `interval` ago.
+    ///
+    /// Returns the last temperature of each sensor. A sensor is missing until its command
+    /// succeeds if the command failed, printed something else than a temperature or timed out.
+    pub fn poll(
+        &mut self,
+        sensors: &[ExternalSensor],
+        interval: Duration,
+        now: Instant,
+    ) -> HashMap<String, f64> {
+        // The sensors removed from the config are dropped.
+        self.temps
+            .retain(|name, _| sensors.iter().any(|s| &s.name == name));
+        self.started
+            .retain(|name, _| sensors.iter().any(|s| &s.name == name));
+        let removed: Vec<String> = self
+            .running
+            .keys()
+            .filter(|name| !sensors.iter().any(|s| &s.name == *name))
+            .cloned()
+            .collect();
+        for name in removed {
+            if let Some((child, _)) = self.running.remove(&name) {
+                kill(child);
+            }
+        }
+
+        for sensor in sensors {
+            if let Some((child, started)) = self.running.get_mut(&sensor.name) {
+                match child.try_wait() {
+                    Ok(None) => {
+                        let timeout = Duration::from_millis(sensor.timeout);
+                        if now.saturating_duration_since(*started) < timeout {
+                            continue;
+                        }
+                        debug!("The command of the sensor {} timed out", sensor.name);
+                        self.temps.remove(&sensor.name);
+                        if let Some((child, _)) = self.running.remove(&sensor.name) {
+                            kill(child);
+                        }
+                    }
+                    Ok(Some(status)) => {
+                        let mut output = String::new();
+                        let read = child
+                            .stdout
+                            .take()
+                            .map(|mut out| out.read_to_string(&mut output));
+                        let temp = match read {
+                            Some(Ok(_)) if status.success() => parse_temperature(&output),
+                            _ => None,
+                        };
+                        match temp {
+                            Some(temp) => {
+                                self.temps.insert(sensor.name.to_owned(), temp);
+                            }
+                            None => {
+                                debug!(
+                                    "The command of the sensor {} failed ({}): {}",
+                                    sensor.name,
+                                    status,
+                                    output.trim()
+                                );
+                                self.temps.remove(&sensor.name);
+                            }
+                        }
+                        self.running.remove(&sensor.name);
+                    }
+                    Err(e) => {
+                        debug!("Error while waiting for the sensor {}: {}", sensor.name, e);
+                        self.temps.remove(&sensor.name);
+                        self.running.remove(&sensor.name);
+                    }
+                }
+            }
+
+            let due = match self.started.get(&sensor.name) {
+                Some(&started) => now.saturating_duration_since(started) >= interval,
+                None => true,
+            };
+            let (program, args) = match sensor.command.split_first() {
+                Some(command) if due => command,
+                _ => continue,
+            };
+            match Command::new(program)
+                .args(args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            {
+                Ok(child) => {
+                    self.running.insert(sensor.name.to_owned(), (child, now));
+                    self.started.insert(sensor.name.to_owned(), now);
+                }
+                Err(e) => {
+                    self.started.insert(sensor.name.to_owned(), now);
+                    debug!("Error while running the sensor {}: {}", sensor.name, e);
+                    self.temps.remove(&sensor.name);
+                }
+            }
+        }
+
+        self.temps.clone()
+    }
+}
+
+impl Drop for ExternalSensors {
+    fn drop(&mut self) {
+        for (_, (child, _)) in self.running.drain() {
+            kill(child);
+        }
+    }
+}
+
+/// Kill a command and reap it, so that it doesn't stay as a zombie.
+fn kill(mut child: Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Parse the temperature (in °C) printed by an external sensor.
+fn parse_temperature(output: &str) -> Option<f64> {
+    output.trim().parse::<f64>().ok().filter(|t| t.is_finite())
+}
+
+/// Whether the sensor `name` is used to compute the temperature: the ones of `sensors.only`,
+/// or every one but the labeled GPUs temperatures, which are already averaged in `GPU`.
+fn is_computed_from(sensors: &SensorsConfig, name: &str) -> bool {
+    if sensors.only.is_empty() {
+        !name.starts_with(LABELED_GPU_PREFIX)
+    } else {
+        sensors.only.iter().any(|o| o == name)
+    }
+}
+
+/// Get the sensors expected by `sensors` which are not in `temps`.
+///
+/// The expected sensors are the ones listed in `sensors.only`, or the CPU one if it's empty.
+pub(crate) fn missing_sensors(
+    sensors: &SensorsConfig,
+    temps: &HashMap<String, f64>,
+) -> Vec<String> {
+    if sensors.only.is_empty() {
+        if temps.contains_key("CPU") {
+            Vec::new()
+        } else {
+            vec!["CPU".to_owned()]
+        }
+    } else {
+        sensors
+            .only
+            .iter()
+            .filter(|name| !temps.contains_key(*name))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Get the name of the sensor of `temps` giving the temperature computed with `method`, or the
+/// names of the sensors averaged (sorted and joined with `+`).
+pub(crate) fn computed_sensor(
+    temps: &HashMap<String, f64>,
+    method: TempComputeMethod,
+    sensors: &SensorsConfig,
+) -> String {
+    let hottest = |temps: &[(&str, f64)]| {
+        temps
+            .iter()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .map_or(String::new(), |(name, _)| name.to_string())
+    };
+    let mut all: Vec<(&str, f64)> = temps.iter().map(|(name, &t)| (name.as_str(), t)).collect();
+    all.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    let used: Vec<(&str, f64)> = all
+        .iter()
+        .filter(|(name, _)| is_computed_from(sensors, name))
+        .copied()
+        .collect();
+    let join = |temps: Vec<&(&str, f64)>| {
+        temps
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join("+")
+    };
+
+    let name = match method {
+        TempComputeMethod::CPUOnly => String::new(),
+        TempComputeMethod::AllSensors => join(used.iter().collect()),
+        TempComputeMethod::Max => hottest(&used),
+        TempComputeMethod::WeightedAverage => join(
+            used.iter()
+                .filter(|(name, _)| sensors.weights.get(*name).map_or(true, |&w| w > 0.0))
+                .collect(),
+        ),
+    };
+    if !name.is_empty() {
+        return name;
+    }
+
+    // Like the computation, the CPU is used otherwise (or the hottest sensor without CPU).
+    if temps.contains_key("CPU") {
+        "CPU".to_owned()
+    } else {
+        hottest(&all)
+    }
+}
+
+/// Get the sensor followed by the fan named `fan_name` with its temperature in `temps`
+/// (or the hottest one of its sensors with the `max-temperature` aggregation).
+/// Returns `None` if the fan has no source or if the source is not available, the fan then
+/// follows the computed temperature.
+pub(crate) fn fan_sensor_temperature<'a>(
+    sensors: &'a SensorsConfig,
+    fan_name: &str,
+    temps: &HashMap<String, f64>,
+) -> Option<(&'a str, f64)> {
+    if let Some(FanAggregation::MaxTemperature { sensors }) = sensors.fan_aggregation.get(fan_name)
+    {
+        let max = sensors
+            .iter()
+            .filter_map(|name| temps.get(name).map(|&t| (name.as_str(), t)))
+            .fold(None, |max: Option<(&str, f64)>, (name, t)| match max {
+                Some((_, m)) if m >= t => max,
+                _ => Some((name, t)),
+            });
+        if max.is_some() {
+            return max;
+        }
+    }
+
+    sensors
+        .temperature_source
+        .get(fan_name)
+        .and_then(|source| temps.get(source).map(|&t| (source.as_str(), t)))
+}
+
+/// Get the load average over the last minute per online CPU, from `path` (as `/proc/loadavg`).
+pub(crate) fn read_load(path: &Path) -> Option<f64> {
+    let load: f64 = fs::read_to_string(path)
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1);
+    Some(load / cpus as f64)
+}
+
+/// Detects the sensors stuck at the same value while the system is under load,
+/// when the temperature would be expected to move.
+#[derive(Debug, Default)]
+pub(crate) struct StuckDetector {
+    /// Last value of each sensor, with the time spent under load since it changed.
+    sensors: HashMap<String, (f64, Duration)>,
+    last_update: Option<Instant>,
+}
+
+impl StuckDetector {
+    /// Refresh the detector with the temperatures read at `now`,
+    /// `loaded` telling if the system is under load.
+    ///
+    /// Returns the sorted names of the sensors which kept the same value for `timeout` under load.
+    /// Nothing is detected if `timeout` is zero.
+    pub fn update(
+        &mut self,
+        temps: &HashMap<String, f64>,
+        loaded: bool,
+        now: Instant,
+        timeout: Duration,
+    ) -> Vec<String> {
+        let elapsed = match self.last_update.replace(now) {
+            Some(last) if loaded => now.saturating_duration_since(last),
+            _ => Duration::ZERO,
+        };
+
+        self.sensors.retain(|name, _| temps.contains_key(name));
+        for (name, &temp) in temps {
+            match self.sensors.get_mut(name) {
+                Some((value, under_load)) if *value == temp => *under_load += elapsed,
+                _ => {
+                    self.sensors.insert(name.to_owned(), (temp, Duration::ZERO));
+                }
+            }
+        }
+
+        if timeout.is_zero() {
+            return Vec::new();
+        }
+        let mut stuck: Vec<String> = self
+            .sensors
+            .iter()
+            .filter(|(_, (_, under_load))| *under_load >= timeout)
+            .map(|(name, _)| name.to_owned())
+            .collect();
+        stuck.sort_unstable();
+        stuck
+    }
+}
+
+/// Keeps the highest temperature of the last samples, to cool the system until the temperature
+/// actually went down.
+#[derive(Debug, Default)]
+pub(crate) struct RollingMax {
+    samples: VecDeque<f64>,
+}
+
+impl RollingMax {
+    /// Add `temp` to the samples, and get the highest of the last `window` ones.
+    pub fn update(&mut self, temp: f64, window: usize) -> f64 {
+        self.samples.push_back(temp);
+        while self.samples.len() > window.max(1) {
+            self.samples.pop_front();
+        }
+
+        self.current(temp)
+    }
+
+    /// Get the highest of `temp` and the samples, without adding it to them.
+    pub fn current(&self, temp: f64) -> f64 {
+        self.samples.iter().copied().fold(temp, f64::max)
+    }
+}
+
+/// Adapts the poll interval to the temperature volatility.
+#[derive(Debug, Default)]
+pub(crate) struct PollController {
+    interval: Duration,
+    last_temp: Option<f64>,
+}
+
+impl PollController {
+    /// Get the current poll interval.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Refresh the poll interval with the last computed temperature.
+    ///
+    /// The interval doubles (up to `max`) while the temperature changes less than `delta` between two reads,
+    /// and goes back to `base` as soon as a larger change is detected.
+    pub fn update(&mut self, temp: f64, base: Duration, max: Duration, delta: f64) -> Duration {
+        let stable = matches!(self.last_temp, Some(last) if (temp - last).abs() < delta);
+        self.last_temp = Some(temp);
+
+        self.interval = if stable {
+            (self.interval.max(base) * 2).min(max.max(base))
+        } else {
+            base
+        };
+
+        self.interval
+    }
+}
+
+/// Debounces the critical state, so that a single peak of the temperature doesn't trigger it.
+#[derive(Debug, Default)]
+pub(crate) struct CriticalDetector {
+    critical: bool,
+    /// Number of samples in a row at or above the critical temperature.
+    samples_over: u32,
+}
+
+impl CriticalDetector {
+    /// Refresh the critical state with the temperature `temp`.
+    ///
+    /// The state is entered once `entry_samples` samples in a row reached `critical_temperature`,
+    /// and left once the temperature is more than `exit_hysteresis` below it.
+    pub fn update(
+        &mut self,
+        temp: f64,
+        critical_temperature: u8,
+        entry_samples: u32,
+        exit_hysteresis: u8,
+    ) -> bool {
+        let critical_temperature = f64::from(critical_temperature);
+        if temp >= critical_temperature {
+            self.samples_over = self.samples_over.saturating_add(1);
+        } else {
+            self.samples_over = 0;
+        }
+
+        self.critical = if self.critical {
+            critical_temperature - temp <= f64::from(exit_hysteresis)
+        } else {
+            self.samples_over >= entry_samples.max(1)
+        };
+        self.critical
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn compute_temperature() {
+        let temps = Temperatures {
+            cpu_temp: Some(60.0),
+            gpu_temp: Some(80.0),
+            nvme_temp: None,
+            acpi_temp: Some(40.0),
+            drive_temp: None,
+            gpus_temps: HashMap::new(),
+            external_temps: HashMap::new(),
+        };
+        let mut sensors = SensorsConfig::default();
+
+        assert_eq!(temps.compute(TempComputeMethod::CPUOnly, &sensors), 60.0);
+        assert_eq!(temps.compute(TempComputeMethod::AllSensors, &sensors), 60.0);
+        assert_eq!(temps.compute(TempComputeMethod::Max, &sensors), 80.0);
+        assert_eq!(
+            temps.compute(TempComputeMethod::WeightedAverage, &sensors),
+            60.0
+        );
+
+        sensors.weights.insert("GPU".to_owned(), 2.0);
+        sensors.weights.insert("ACPI".to_owned(), 0.0);
+        assert_eq!(
+            temps.compute(TempComputeMethod::WeightedAverage, &sensors),
+            (60.0 + 80.0 * 2.0) / 3.0
+        );
+
+        sensors.weights.insert("CPU".to_owned(), 0.0);
+        sensors.weights.insert("GPU".to_owned(), 0.0);
+        assert_eq!(
+            temps.compute(TempComputeMethod::WeightedAverage, &sensors),
+            60.0
+        );
+    }
+
+    #[test]
+    fn default_computed_temperature() {
+        let temps = Temperatures {
+            cpu_temp: Some(60.0),
+            gpu_temp: Some(90.0),
+            nvme_temp: Some(70.0),
+            acpi_temp: None,
+            drive_temp: None,
+            gpus_temps: HashMap::from([("GPU_EDGE".to_owned(), 95.0)]),
+            external_temps: HashMap::from([("ambient".to_owned(), 99.0)]),
+        };
+        let config = crate::config::service::ServiceConfig::default();
+
+        // The thresholds follow the CPU unless another method is chosen.
+        assert_eq!(config.temp_compute, TempComputeMethod::CPUOnly);
+        assert_eq!(temps.compute(config.temp_compute, &config.sensors), 60.0);
+        assert_eq!(temps.compute(TempComputeMethod::Max, &config.sensors), 99.0);
+    }
+
+    #[test]
+    fn only_sensors() {
+        let temps = Temperatures {
+            cpu_temp: Some(60.0),
+            gpu_temp: Some(80.0),
+            nvme_temp: Some(50.0),
+            acpi_temp: None,
+            drive_temp: Some(40.0),
+            gpus_temps: HashMap::new(),
+            external_temps: HashMap::new(),
+        };
+        let mut sensors = SensorsConfig {
+            only: vec!["NVME".to_owned(), "DRIVE".to_owned()],
+            ..Default::default()
+        };
+
+        assert_eq!(temps.compute(TempComputeMethod::Max, &sensors), 50.0);
+        assert_eq!(temps.compute(TempComputeMethod::AllSensors, &sensors), 45.0);
+
+        // Missing sensors fall back to the CPU
+        sensors.only = vec!["ACPI".to_owned()];
+        assert_eq!(temps.compute(TempComputeMethod::Max, &sensors), 60.0);
+    }
+
+    #[test]
+    fn labeled_gpus_not_counted_twice() {
+        let temps = Temperatures {
+            cpu_temp: Some(60.0),
+            gpu_temp: Some(80.0),
+            nvme_temp: None,
+            acpi_temp: None,
+            drive_temp: None,
+            gpus_temps: HashMap::from([
+                ("GPU_EDGE".to_owned(), 80.0),
+                ("GPU_JUNCTION".to_owned(), 90.0),
+            ]),
+            external_temps: HashMap::new(),
+        };
+        let mut map = HashMap::new();
+        temps.update_map(&mut map);
+        let mut sensors = SensorsConfig::default();
+
+        // The GPU is only counted once by default.
+        assert_eq!(temps.compute(TempComputeMethod::AllSensors, &sensors), 70.0);
+        assert_eq!(
+            temps.compute(TempComputeMethod::WeightedAverage, &sensors),
+            70.0
+        );
+        assert_eq!(
+            computed_sensor(&map, TempComputeMethod::AllSensors, &sensors),
+            "CPU+GPU"
+        );
+
+        sensors.only = vec!["CPU".to_owned(), "GPU_JUNCTION".to_owned()];
+        assert_eq!(temps.compute(TempComputeMethod::AllSensors, &sensors), 75.0);
+        assert_eq!(
+            computed_sensor(&map, TempComputeMethod::AllSensors, &sensors),
+            "CPU+GPU_JUNCTION"
+        );
+    }
+
+    #[test]
+    fn missing_sensor() {
+        let temps = Temperatures {
+            cpu_temp: None,
+            gpu_temp: Some(80.0),
+            nvme_temp: Some(50.0),
+            acpi_temp: None,
+            drive_temp: None,
+            gpus_temps: HashMap::new(),
+            external_temps: HashMap::new(),
+        };
+        let mut map = HashMap::new();
+        temps.update_map(&mut map);
+        let mut sensors = SensorsConfig::default();
+
+        assert_eq!(missing_sensors(&sensors, &map), vec!["CPU".to_owned()]);
+        // Without a CPU sensor, the hottest one is used
+        assert_eq!(temps.compute(TempComputeMethod::CPUOnly, &sensors), 80.0);
+
+        sensors.only = vec!["NVME".to_owned(), "ACPI".to_owned()];
+        assert_eq!(missing_sensors(&sensors, &map), vec!["ACPI".to_owned()]);
+        assert_eq!(temps.compute(TempComputeMethod::Max, &sensors), 50.0);
+    }
+
+    #[test]
+    fn calibrate_temperatures() {
+        let temps = Temperatures {
+            cpu_temp: Some(50.0),
+            gpu_temp: Some(60.0),
+            nvme_temp: None,
+            acpi_temp: Some(40.0),
+            drive_temp: None,
+            gpus_temps: HashMap::from([("GPU_EDGE".to_owned(), 55.0)]),
+            external_temps: HashMap::from([("ambient".to_owned(), 25.0)]),
+        };
+        let offsets = HashMap::from([
+            ("CPU".to_owned(), 8.0),
+            ("GPU_EDGE".to_owned(), -5.0),
+            ("ambient".to_owned(), 1.5),
+            ("NVME".to_owned(), 3.0),
+        ]);
+
+        let calibrated = temps.calibrated(&offsets);
+        assert_eq!(calibrated.cpu_temp, Some(58.0));
+        assert_eq!(calibrated.gpu_temp, Some(60.0));
+        assert_eq!(calibrated.acpi_temp, Some(40.0));
+        assert_eq!(calibrated.nvme_temp, None);
+        assert_eq!(calibrated.gpus_temps["GPU_EDGE"], 50.0);
+        assert_eq!(calibrated.external_temps["ambient"], 26.5);
+
+        let raised = temps.raised(&["CPU".to_owned(), "ambient".to_owned()], 55.0);
+        assert_eq!(raised.cpu_temp, Some(55.0));
+        assert_eq!(raised.gpu_temp, Some(60.0));
+        assert_eq!(raised.external_temps["ambient"], 55.0);
+        assert_eq!(raised.acpi_temp, Some(40.0));
+    }
+
+    #[test]
+    fn smooth_temperatures() {
+        let previous = Temperatures {
+            cpu_temp: Some(50.0),
+            gpu_temp: Some(40.0),
+            nvme_temp: None,
+            acpi_temp: None,
+            drive_temp: Some(30.0),
+            gpus_temps: HashMap::new(),
+            external_temps: HashMap::new(),
+        };
+        let current = Temperatures {
+            cpu_temp: Some(60.0),
+            gpu_temp: Some(50.0),
+            nvme_temp: Some(45.0),
+            acpi_temp: None,
+            drive_temp: None,
+            gpus_temps: HashMap::new(),
+            external_temps: HashMap::new(),
+        };
+
+        let smoothed = current.smoothed(Some(&previous), 0.25);
+        assert_eq!(smoothed.cpu_temp, Some(52.5));
+        assert_eq!(smoothed.gpu_temp, Some(42.5));
+        // New sensors start from their raw value and removed ones are dropped.
+        assert_eq!(smoothed.nvme_temp, Some(45.0));
+        assert_eq!(smoothed.drive_temp, None);
+
+        assert_eq!(current.smoothed(Some(&previous), 0.0).cpu_temp, Some(60.0));
+        assert_eq!(current.smoothed(None, 0.25).cpu_temp, Some(60.0));
+    }
+
+    #[test]
+    fn fan_temperature_source() {
+        let temps: HashMap<String, f64> = vec![("CPU".to_owned(), 60.0), ("GPU".to_owned(), 75.0)]
+            .into_iter()
+            .collect();
+        let mut sensors = SensorsConfig::default();
+        sensors
+            .temperature_source
+            .insert("GPU fan".to_owned(), "GPU".to_owned());
+        sensors
+            .temperature_source
+            .insert("Chassis fan".to_owned(), "NVME".to_owned());
+
+        assert_eq!(
+            fan_sensor_temperature(&sensors, "GPU fan", &temps),
+            Some(("GPU", 75.0))
+        );
+        assert_eq!(fan_sensor_temperature(&sensors, "CPU fan", &temps), None);
+        // Missing sensors fall back to the aggregated temperature
+        assert_eq!(
+            fan_sensor_temperature(&sensors, "Chassis fan", &temps),
+            None
+        );
+
+        sensors.fan_aggregation.insert(
+            "CPU fan".to_owned(),
+            FanAggregation::MaxTemperature {
+                sensors: vec!["CPU".to_owned(), "GPU".to_owned(), "NVME".to_owned()],
+            },
+        );
+        assert_eq!(
+            fan_sensor_temperature(&sensors, "CPU fan", &temps),
+            Some(("GPU", 75.0))
+        );
+    }
+
+    #[test]
+    fn computed_temperature_sensor() {
+        let temps: HashMap<String, f64> = [("CPU", 60.0), ("GPU", 75.0), ("NVME", 50.0)]
+            .iter()
+            .map(|&(name, t)| (name.to_owned(), t))
+            .collect();
+        let mut sensors = SensorsConfig::default();
+        let sensor = |method, sensors: &SensorsConfig| computed_sensor(&temps, method, sensors);
+
+        assert_eq!(sensor(TempComputeMethod::CPUOnly, &sensors), "CPU");
+        assert_eq!(sensor(TempComputeMethod::Max, &sensors), "GPU");
+        assert_eq!(
+            sensor(TempComputeMethod::AllSensors, &sensors),
+            "CPU+GPU+NVME"
+        );
+
+        sensors.weights.insert("GPU".to_owned(), 0.0);
+        assert_eq!(
+            sensor(TempComputeMethod::WeightedAverage, &sensors),
+            "CPU+NVME"
+        );
+        sensors.only = vec!["NVME".to_owned()];
+        assert_eq!(sensor(TempComputeMethod::Max, &sensors), "NVME");
+        sensors.only = vec!["GPU".to_owned()];
+        assert_eq!(sensor(TempComputeMethod::WeightedAverage, &sensors), "CPU");
+    }
+
+    #[test]
+    fn gpus_temperatures() {
+        let root = TempDir::new("drm");
+        let write = |path: &str, content: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write("card0/device/hwmon/hwmon3/temp1_input", "52000\n");
+        write("card0/device/hwmon/hwmon3/temp1_label", "edge\n");
+        write("card0/device/hwmon/hwmon3/temp2_input", "61000\n");
+        write("card0/device/hwmon/hwmon3/temp2_label", "junction\n");
+        write("card0-eDP-1/device/hwmon/hwmon4/temp1_input", "99000\n");
+        write("card1/device/hwmon/hwmon5/temp1_input", "45000\n");
+
+        let temps = read_gpus_temps(&root);
+
+        assert_eq!(temps.len(), 3);
+        assert_eq!(temps["GPU_EDGE"], 52.0);
+        assert_eq!(temps["GPU_JUNCTION"], 61.0);
+        assert_eq!(temps["GPU_TEMP1_1"], 45.0);
+    }
+
+    #[test]
+    fn external_sensors() {
+        let sensor = |name: &str, command: &[&str], timeout| ExternalSensor {
+            name: name.to_owned(),
+            command: command.iter().map(|s| s.to_string()).collect(),
+            timeout,
+        };
+        let sensors = [
+            sensor("PROBE", &["echo", "42.5"], 1000),
+            sensor("BROKEN", &["sh", "-c", "echo 30; exit 1"], 1000),
+            sensor("GARBAGE", &["echo", "hot"], 1000),
+            sensor("SLOW", &["sleep", "10"], 100),
+            sensor("MISSING", &["/nonexistent/sensor"], 1000),
+        ];
+        let mut external = ExternalSensors::default();
+        let interval = Duration::from_secs(1);
+        let start = Instant::now();
+
+        // The commands are only started by the first poll.
+        assert!(external.poll(&sensors, interval, start).is_empty());
+        std::thread::sleep(Duration::from_millis(300));
+        let temps = external.poll(&sensors, interval, start + Duration::from_millis(500));
+
+        assert_eq!(temps.len(), 1);
+        assert_eq!(temps["PROBE"], 42.5);
+        assert!(!external.running.contains_key("MISSING"));
+        // The commands are not started again before the interval, the last values are kept.
+        assert!(external.running.is_empty());
+        let later = start + Duration::from_millis(800);
+        assert_eq!(external.poll(&sensors, interval, later), temps);
+        assert!(external.running.is_empty());
+        let later = start + Duration::from_millis(1000);
+        assert_eq!(external.poll(&sensors, interval, later), temps);
+        assert!(external.running.contains_key("PROBE"));
+
+        // The removed sensors are dropped.
+        assert!(external.poll(&[], interval, Instant::now()).is_empty());
+        assert!(external.running.is_empty());
+    }
+
+    #[test]
+    fn removed_sensors() {
+        let mut map = HashMap::new();
+        map.insert("DRIVE".to_owned(), 35.0);
+
+        let temps = Temperatures {
+            cpu_temp: Some(60.0),
+            gpu_temp: None,
+            nvme_temp: None,
+            acpi_temp: None,
+            drive_temp: None,
+            gpus_temps: HashMap::new(),
+            external_temps: HashMap::new(),
+        };
+        temps.update_map(&mut map);
+
+        assert_eq!(map.len(), 1);
+        assert_eq!(map["CPU"], 60.0);
+    }
+
+    #[test]
+    fn debounce_critical_state() {
+        let mut detector = CriticalDetector::default();
+        let mut update = |temp| detector.update(temp, 90, 3, 10);
+
+        // A single peak is ignored.
+        assert!(!update(95.0));
+        assert!(!update(60.0));
+
+        assert!(!update(91.0));
+        assert!(!update(90.0));
+        assert!(update(92.0));
+
+        // The state is kept until the temperature drops below the hysteresis.
+        assert!(update(80.0));
+        assert!(!update(79.0));
+    }
+
+    #[test]
+    fn rolling_max_temperature() {
+        let mut rolling = RollingMax::default();
+        let temps: Vec<f64> = [60.0, 70.0, 55.0, 58.0, 50.0, 75.0]
+            .iter()
+            .map(|&t| rolling.update(t, 3))
+            .collect();
+        assert_eq!(temps, vec![60.0, 70.0, 70.0, 70.0, 58.0, 75.0]);
+
+        // Without window, the last sample is used as it is.
+        assert_eq!(rolling.update(40.0, 0), 40.0);
+        assert_eq!(rolling.update(45.0, 1), 45.0);
+        // The samples are only added by the updates.
+        assert_eq!(rolling.current(42.0), 45.0);
+        assert_eq!(rolling.current(50.0), 50.0);
+        assert_eq!(rolling.update(42.0, 1), 42.0);
+    }
+
+    #[test]
+    fn adaptive_poll_interval() {
+        let mut controller = PollController::default();
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(5);
+        let update = |c: &mut PollController, temp| c.update(temp, base, max, 2.0);
+
+        // The first read can't be compared to anything.
+        assert_eq!(update(&mut controller, 50.0), base);
+        // The interval grows while the temperature is stable, up to the maximum.
+        let intervals: Vec<u64> = [51.0, 50.5, 51.5, 52.0]
+            .iter()
+            .map(|&t| update(&mut controller, t).as_secs())
+            .collect();
+        assert_eq!(intervals, vec![2, 4, 5, 5]);
+        assert_eq!(controller.interval(), max);
+
+        // A jump of the temperature shrinks it back right away.
+        assert_eq!(update(&mut controller, 60.0), base);
+        assert_eq!(update(&mut controller, 59.0), base * 2);
+        assert_eq!(update(&mut controller, 55.0), base);
+
+        // A maximum below the base interval is ignored.
+        update(&mut controller, 55.0);
+        assert_eq!(controller.update(55.0, base, Duration::ZERO, 2.0), base);
+    }
+    #[test]
+    fn stuck_sensors() {
+        let mut detector = StuckDetector::default();
+        let timeout = Duration::from_secs(60);
+        let start = Instant::now();
+        let temps =
+            |cpu: f64, gpu: f64| HashMap::from([("CPU".to_owned(), cpu), ("GPU".to_owned(), gpu)]);
+
+        assert!(detector
+            .update(&temps(50., 40.), true, start, timeout)
+            .is_empty());
+        // Idle, the temperatures can be stable.
+        let idle = start + Duration::from_secs(120);
+        assert!(detector
+            .update(&temps(50., 41.), false, idle, timeout)
+            .is_empty());
+        let loaded = idle + Duration::from_secs(30);
+        assert!(detector
+            .update(&temps(50., 45.), true, loaded, timeout)
+            .is_empty());
+        let loaded = loaded + Duration::from_secs(30);
+        assert_eq!(
+            detector.update(&temps(50., 48.), true, loaded, timeout),
+            vec!["CPU".to_owned()]
+        );
+        assert!(detector
+            .update(&temps(50., 48.), true, loaded, Duration::ZERO)
+            .is_empty());
+        // The sensor is not suspect anymore once its value changes.
+        let loaded = loaded + Duration::from_secs(30);
+        assert!(detector
+            .update(&temps(51., 52.), true, loaded, timeout)
+            .is_empty());
+    }
+
+    #[test]
+    fn load_average() {
+        let dir = TempDir::new("loadavg");
+        let path = dir.join("loadavg");
+        fs::write(&path, "4.00 2.50 1.00 3/512 4242\n").unwrap();
+        let load = read_load(&path);
+
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1);
+        assert_eq!(load, Some(4.0 / cpus as f64));
+        assert_eq!(read_load(&dir.join("missing")), None);
+    }
+    #[test]
+    fn labeled_sensors() {
+        let root = TempDir::new("hwmon");
+        let class = root.join("hwmon");
+        let conf = root.join("sensors.d");
+        for (hwmon, name, inputs) in [
+            ("hwmon0", "k10temp", vec![("temp1", Some("Tctl"), "45500")]),
+            (
+                "hwmon1",
+                "nvme",
+                vec![
+                    ("temp1", Some("Composite"), "38000"),
+                    ("temp2", None, "40000"),
+                ],
+            ),
+        ] {
+            fs::create_dir_all(class.join(hwmon)).unwrap();
+            fs::write(class.join(hwmon).join("name"), format!("{}\n", name)).unwrap();
+            for (input, label, value) in inputs {
+                let dir = class.join(hwmon);
+                fs::write(dir.join(format!("{}_input", input)), value).unwrap();
+                if let Some(label) = label {
+                    fs::write(dir.join(format!("{}_label", input)), label).unwrap();
+                }
+            }
+        }
+        fs::create_dir_all(&conf).unwrap();
+        fs::write(
+            conf.join("nvme.conf"),
+            "chip \"nvme-pci-*\"  # the SSD\n    label temp2 \"Sensor 2\"\n",
+        )
+        .unwrap();
+
+        let labels = read_sensors_labels(&[&conf]);
+        let names: Vec<String> = [
+            "Tctl",
+            "nvme/Sensor 2",
+            "k10temp/Composite",
+            "CPU",
+            class.join("hwmon1/temp1_input").to_str().unwrap(),
+        ]
+        .iter()
+        .map(|n| n.to_string())
+        .collect();
+        let temps = read_labeled_temps(&class, &labels, &names);
+
+        assert_eq!(
+            labels,
+            vec![SensorLabel {
+                chip: "nvme-pci-*".to_owned(),
+                input: "temp2".to_owned(),
+                label: "Sensor 2".to_owned(),
+            }]
+        );
+        assert_eq!(temps.len(), 3);
+        assert_eq!(temps["Tctl"], 45.5);
+        assert_eq!(temps["nvme/Sensor 2"], 40.0);
+        assert_eq!(temps[&names[4]], 38.0);
     }
 }
@@ -0,0 +1,79 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Helpers shared by the tests.
+use crate::nbfc::FanConfiguration;
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary directory, unique to each test even when they run in parallel,
+/// removed with its content when it's dropped (including when the test fails).
+#[derive(Debug)]
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty directory, whose name starts with `fancy-{name}`.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "fancy-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Left by a previous run which had the same pid.
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Fan reading its speed at the register `1` and writing it at `2`, from `0` to `100`,
+/// without thresholds or overrides.
+pub(crate) fn fan_config() -> FanConfiguration {
+    FanConfiguration {
+        read_register: 1,
+        write_register: 2,
+        min_speed_value: 0,
+        max_speed_value: 100,
+        independent_read_min_max_values: false,
+        min_speed_value_read: 0,
+        max_speed_value_read: 0,
+        reset_required: false,
+        fan_speed_reset_value: None,
+        fan_display_name: None,
+        temperature_thresholds: Vec::new(),
+        fan_speed_percentage_overrides: None,
+        rpm_read_register: None,
+        interpolate_overrides: false,
+        read_register_high: None,
+        write_register_high: None,
+        kickstart_speed: None,
+        kickstart_duration: None,
+    }
+}
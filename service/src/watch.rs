@@ -0,0 +1,224 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Watches the configuration files with inotify, to reload them when they are modified.
+//!
+//! The events are received on the thread of `notify` and only collected by the main loop,
+//! which is never blocked by the watcher.
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+/// Time without any new change before the changes are reported, since an editor can write a file
+/// several times (or through a temporary file) when it's saved.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(500);
+
+/// Configurations modified on the disk.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct ConfigChanges {
+    pub service_config: bool,
+    /// Names of the modified files of the control configs directory, without their extension.
+    pub control_configs: BTreeSet<String>,
+}
+
+impl ConfigChanges {
+    fn any(&self) -> bool {
+        self.service_config || !self.control_configs.is_empty()
+    }
+
+    fn merge(&mut self, other: ConfigChanges) {
+        self.service_config |= other.service_config;
+        self.control_configs.extend(other.control_configs);
+    }
+}
+
+/// Groups the changes received in a row, to report them once they stop.
+#[derive(Debug, Default)]
+struct Debouncer {
+    pending: ConfigChanges,
+    last_change: Option<Instant>,
+}
+
+impl Debouncer {
+    fn add(&mut self, changes: ConfigChanges, now: Instant) {
+        if changes.any() {
+            self.pending.merge(changes);
+            self.last_change = Some(now);
+        }
+    }
+
+    /// Take the pending changes if there has been no new one for `delay`.
+    fn take(&mut self, now: Instant, delay: Duration) -> Option<ConfigChanges> {
+        match self.last_change {
+            Some(last) if now.saturating_duration_since(last) >= delay => {
+                self.last_change = None;
+                Some(std::mem::take(&mut self.pending))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ConfigWatcher {
+    /// Watches as long as it's not dropped.
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    service_config_paths: Vec<PathBuf>,
+    control_configs_dir: PathBuf,
+    debouncer: Debouncer,
+}
+
+impl ConfigWatcher {
+    /// Watch the service configuration, which can be at any of `service_config_paths`, and the
+    /// control configs in `control_configs_dir`.
+    ///
+    /// The directories are watched rather than the files, since the files may not exist yet
+    /// and the editors often replace them when they are saved.
+    pub fn watch(
+        service_config_paths: &[&Path],
+        control_configs_dir: &Path,
+    ) -> notify::Result<Self> {
+        let (sender, events) = channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+
+        let mut dirs: Vec<&Path> = service_config_paths
+            .iter()
+            .filter_map(|p| p.parent())
+            .collect();
+        dirs.push(control_configs_dir);
+        dirs.dedup();
+        // The directories which don't exist can't be watched, but the others still are.
+        for dir in dirs.into_iter().filter(|d| d.is_dir()) {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+
+        Ok(ConfigWatcher {
+            _watcher: watcher,
+            events,
+            service_config_paths: service_config_paths
+                .iter()
+                .map(|p| p.to_path_buf())
+                .collect(),
+            control_configs_dir: control_configs_dir.to_owned(),
+            debouncer: Debouncer::default(),
+        })
+    }
+
+    /// Collect the events received since the last call, and return the changes once the files
+    /// have not been modified for a while.
+    pub fn changes(&mut self) -> Option<ConfigChanges> {
+        let now = Instant::now();
+        while let Ok(event) = self.events.try_recv() {
+            // An error of the watcher doesn't tell which file changed.
+            if let Ok(event) = event {
+                let changes = self.classify(&event);
+                self.debouncer.add(changes, now);
+            }
+        }
+
+        self.debouncer.take(now, DEBOUNCE_DELAY)
+    }
+
+    /// Find which configurations are affected by `event`.
+    fn classify(&self, event: &Event) -> ConfigChanges {
+        let mut changes = ConfigChanges::default();
+        // The files are read by the service itself.
+        if matches!(event.kind, EventKind::Access(_)) {
+            return changes;
+        }
+
+        for path in &event.paths {
+            if self.service_config_paths.contains(path) {
+                changes.service_config = true;
+            }
+            if path.parent() == Some(self.control_configs_dir.as_path()) {
+                if let Some(name) = path.file_stem() {
+                    changes
+                        .control_configs
+                        .insert(name.to_string_lossy().into_owned());
+                }
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+    use notify::event::{AccessKind, ModifyKind};
+
+    #[test]
+    fn debounce_changes() {
+        let mut debouncer = Debouncer::default();
+        let start = Instant::now();
+        let delay = Duration::from_millis(500);
+        let service_config = ConfigChanges {
+            service_config: true,
+            ..Default::default()
+        };
+        let control_configs = |name: &str| ConfigChanges {
+            control_configs: [name.to_owned()].into_iter().collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(debouncer.take(start, delay), None);
+        debouncer.add(service_config, start);
+        debouncer.add(ConfigChanges::default(), start + Duration::from_millis(300));
+        debouncer.add(control_configs("quiet"), start + Duration::from_millis(350));
+        debouncer.add(control_configs("base"), start + Duration::from_millis(400));
+        // The last change resets the delay.
+        assert_eq!(
+            debouncer.take(start + Duration::from_millis(600), delay),
+            None
+        );
+        assert_eq!(
+            debouncer.take(start + Duration::from_millis(900), delay),
+            Some(ConfigChanges {
+                service_config: true,
+                control_configs: ["base".to_owned(), "quiet".to_owned()]
+                    .into_iter()
+                    .collect(),
+            })
+        );
+        assert_eq!(debouncer.take(start + Duration::from_secs(2), delay), None);
+    }
+
+    #[test]
+    fn classify_events() {
+        let root = TempDir::new("watch");
+        let configs_dir = root.join("configs");
+        std::fs::create_dir_all(&configs_dir).unwrap();
+        let service_config = root.join("config.toml");
+        let watcher = ConfigWatcher::watch(&[&service_config], &configs_dir).unwrap();
+        let modify = |paths: &[PathBuf]| {
+            paths.iter().fold(
+                Event::new(EventKind::Modify(ModifyKind::Any)),
+                |event, path| event.add_path(path.to_owned()),
+            )
+        };
+
+        let changes = watcher.classify(&modify(&[
+            configs_dir.join("quiet.json"),
+            configs_dir.join("base.xml"),
+            root.join("other.json"),
+        ]));
+        assert!(!changes.service_config);
+        assert_eq!(
+            changes.control_configs,
+            ["base".to_owned(), "quiet".to_owned()]
+                .into_iter()
+                .collect()
+        );
+        assert!(watcher.classify(&modify(&[service_config])).service_config);
+        let access =
+            Event::new(EventKind::Access(AccessKind::Any)).add_path(configs_dir.join("quiet.json"));
+        assert!(!watcher.classify(&access).any());
+    }
+}